@@ -0,0 +1,58 @@
+//! Measures writing out the accounts. Compares writing them a line at a time, as writing to an
+//! unlocked stdout does, with writing them through a buffer, as the binary does, and the CSV with
+//! the summary format. They're written to `/dev/null`, so only the cost of serializing and writing
+//! them is measured. Every client has an account, so this is about the most accounts there can be.
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::fs::File;
+use std::io::{BufWriter, LineWriter, Write};
+use support::LogSpec;
+use transaction_processor::engine::{
+    display_accounts, process_transactions, DisplayOptions, OutputFormat, Processor,
+};
+use transaction_processor::transaction::{iter_over_reader, ParseOptions};
+
+fn bench_output(c: &mut Criterion) {
+    let log = LogSpec {
+        records: 1_000_000,
+        clients: 65_536,
+        dispute_ratio: 0.1,
+    }
+    .generate();
+    let txs = iter_over_reader(log.as_bytes(), ParseOptions::default()).unwrap();
+    let processor = process_transactions(txs, Processor::builder().build()).unwrap();
+    let accounts = processor.accounts();
+    let null = || File::create("/dev/null").unwrap();
+    let buffered = |options| {
+        let mut file = BufWriter::new(null());
+        display_accounts(accounts.values(), options, &mut file).unwrap();
+        file.flush().unwrap()
+    };
+
+    let mut group = c.benchmark_group("output");
+    group.throughput(Throughput::Elements(accounts.len() as u64));
+    group.bench_function("line_at_a_time", |b| {
+        b.iter(|| {
+            let file = LineWriter::new(null());
+            display_accounts(accounts.values(), DisplayOptions::default(), file).unwrap()
+        })
+    });
+    group.bench_function("buffered", |b| {
+        b.iter(|| buffered(DisplayOptions::default()))
+    });
+    group.bench_function("summary", |b| {
+        b.iter(|| {
+            buffered(DisplayOptions {
+                format: OutputFormat::Summary,
+                ..DisplayOptions::default()
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_output);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+//! Measures how fast a transaction log is parsed, without applying the transactions.
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use support::LogSpec;
+use transaction_processor::transaction::{iter_over_reader, ParseOptions};
+
+fn bench_parsing(c: &mut Criterion) {
+    let spec = LogSpec::default();
+    let log = spec.generate();
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(log.len() as u64));
+    group.bench_function("iter_over_reader", |b| {
+        b.iter(|| {
+            iter_over_reader(log.as_bytes(), ParseOptions::default())
+                .unwrap()
+                .map(Result::unwrap)
+                .count()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parsing);
+criterion_main!(benches);
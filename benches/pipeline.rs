@@ -0,0 +1,43 @@
+//! Compares processing a transaction log on one thread with parsing it on a thread of its own.
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::io::Cursor;
+use std::sync::Arc;
+use support::LogSpec;
+use transaction_processor::engine::{process_transactions, Processor};
+use transaction_processor::transaction::{iter_over_reader, parse_in_background, ParseOptions};
+
+fn bench_pipeline(c: &mut Criterion) {
+    let spec = LogSpec::default();
+    let log: Arc<[u8]> = spec.generate().into_bytes().into();
+    let mut group = c.benchmark_group("process");
+    group.throughput(Throughput::Elements(u64::from(spec.records)));
+    group.bench_function("single_thread", |b| {
+        b.iter_batched(
+            || Cursor::new(Arc::clone(&log)),
+            |reader| {
+                let txs = iter_over_reader(reader, ParseOptions::default()).unwrap();
+                process_transactions(txs, Processor::builder().build()).unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("pipeline", |b| {
+        b.iter_batched(
+            || Cursor::new(Arc::clone(&log)),
+            |reader| {
+                let txs = iter_over_reader(reader, ParseOptions::default()).unwrap();
+                process_transactions(parse_in_background(txs), Processor::builder().build())
+                    .unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_pipeline);
+criterion_main!(benches);
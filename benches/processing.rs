@@ -0,0 +1,34 @@
+//! Measures processing a transaction log end to end, from parsing it to the final accounts, for
+//! logs of different shapes.
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use support::LogSpec;
+use transaction_processor::engine::{process_transactions, Processor};
+use transaction_processor::transaction::{iter_over_reader, ParseOptions};
+
+fn bench_processing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("end_to_end");
+    for (clients, dispute_ratio) in [(10, 0.0), (1000, 0.0), (1000, 0.1), (65_536, 0.1)] {
+        let spec = LogSpec {
+            clients,
+            dispute_ratio,
+            ..LogSpec::default()
+        };
+        let log = spec.generate();
+        group.throughput(Throughput::Elements(u64::from(spec.records)));
+        let id = format!("{}_clients/{}_disputes", clients, dispute_ratio);
+        group.bench_function(BenchmarkId::from_parameter(id), |b| {
+            b.iter(|| {
+                let txs = iter_over_reader(log.as_bytes(), ParseOptions::default()).unwrap();
+                process_transactions(txs, Processor::builder().build()).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_processing);
+criterion_main!(benches);
@@ -1,112 +1,1220 @@
-use rust_decimal::Decimal;
-use serde::Serialize;
-use std::collections::HashMap;
-use std::error::Error;
-
-/// A structure represening a single user account.
-#[derive(Default)]
-pub struct Account {
+use crate::amount::Amount;
+use crate::error::TxError;
+use crate::transaction::{Currency, Operation};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+
+/// What to do when a dispute is raised for more than the account's available funds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DisputePolicy {
+    /// Ignore the dispute.
+    #[default]
+    Reject,
+    /// Hold the disputed funds anyway, letting the available balance go negative.
+    AllowNegative,
+}
+
+/// What to do when a transaction that already has an open dispute is disputed again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicateDisputePolicy {
+    /// Reject the second dispute as an error.
+    #[default]
+    Reject,
+    /// Treat the second dispute as a no-op, since the funds are already held.
+    Ignore,
+}
+
+/// What to do when a dispute would take the available balance below the negative limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NegativeLimitAction {
+    /// Ignore the dispute.
+    #[default]
+    Reject,
+    /// Only hold as much of the disputed funds as the limit allows.
+    Cap,
+}
+
+/// Configurable business rules that govern how an account handles transactions.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AccountPolicy {
+    pub dispute: DisputePolicy,
+    pub duplicate_dispute: DuplicateDisputePolicy,
+    /// Whether a locked account can still receive deposits (e.g. to repay a negative balance) and
+    /// settle disputes that were already open, by resolving or charging them back. Everything else
+    /// stays blocked.
+    pub allow_deposits_when_locked: bool,
+    /// Whether withdrawals can draw on funds held for disputes once the available funds run out.
+    /// This is an administrative override, as it means the held funds no longer cover the disputes.
+    pub allow_withdrawing_held: bool,
+    /// How far below zero a dispute can take the available balance, when the dispute policy allows
+    /// it to go negative at all (no limit if not set).
+    pub negative_limit: Option<Decimal>,
+    pub negative_limit_action: NegativeLimitAction,
+    /// The most disputes that can be open against the account at once (no limit if not set).
+    pub max_open_disputes: Option<usize>,
+    /// How many of the client's records a deposit is held for before its funds are available (0 to
+    /// make them available straight away).
+    pub deposit_hold: u64,
+}
+
+/// Which way the funds moved in a disputed transaction. This determines how the dispute is
+/// settled: disputing a deposit holds the funds until it's resolved (releasing them) or charged
+/// back (removing them), while a disputed withdrawal holds nothing, and a chargeback returns the
+/// withdrawn funds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputeDirection {
+    /// A deposit, which credited the account.
+    Credit,
+    /// A withdrawal, which debited the account.
+    Debit,
+}
+
+/// A deposit (or withdrawal) that might later be disputed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DisputableTx<N: Amount = Decimal> {
+    pub amount: N,
+    pub direction: DisputeDirection,
+    /// The line of the transaction log the transaction was read from.
+    pub line: u64,
+}
+
+/// Looks up the transactions that disputes refer to. Transaction IDs are global, so these are
+/// stored outside of the accounts, but the account needs them to know how much is disputed.
+pub trait TxLookup<N: Amount = Decimal> {
+    /// The disputable transaction with the given ID, if there is one.
+    fn disputable(&self, tx_id: u32) -> Option<DisputableTx<N>>;
+}
+
+impl<N: Amount> TxLookup<N> for HashMap<u32, DisputableTx<N>> {
+    fn disputable(&self, tx_id: u32) -> Option<DisputableTx<N>> {
+        self.get(&tx_id).copied()
+    }
+}
+
+/// A dispute that has been raised against one of the account's transactions. It's removed once
+/// resolved, so the transaction can be disputed again, but kept once charged back, as that's
+/// final.
+#[derive(Debug, Clone, Copy)]
+struct OpenDispute<N: Amount> {
+    /// The amount disputed, which for a deposit is also the amount held.
+    amount: N,
+    direction: DisputeDirection,
+    /// Whether the dispute has since been charged back.
+    charged_back: bool,
+    /// The processor's sequence number of the transaction that opened the dispute.
+    opened_at: u64,
+}
+
+// When the dispute was opened is bookkeeping, like the account's count of records, so isn't
+// compared.
+impl<N: Amount> PartialEq for OpenDispute<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.amount == other.amount
+            && self.direction == other.direction
+            && self.charged_back == other.charged_back
+    }
+}
+
+/// A deposit that is held until it settles, after which its funds become available.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct PendingDeposit<N: Amount = Decimal> {
+    tx: u32,
+    /// The amount still pending, which is less than the deposit if part of it has been disputed.
+    amount: N,
+    /// The record count at which the deposit settles.
+    settles_at: u64,
+}
+
+/// A way in which an account's state is inconsistent, which means there's a bug in how it was
+/// updated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvariantViolation {
+    /// The held funds don't match the deposits that are disputed or yet to settle.
+    HeldMismatch {
+        held: Decimal,
+        expected: Decimal,
+    },
+    NegativeHeld {
+        held: Decimal,
+    },
+    /// The available funds are negative, even though the policy doesn't allow that.
+    NegativeAvailable {
+        available: Decimal,
+    },
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvariantViolation::HeldMismatch { held, expected } => write!(
+                f,
+                "held funds of {} should be {} for the open disputes and pending deposits",
+                held, expected
+            ),
+            InvariantViolation::NegativeHeld { held } => {
+                write!(f, "held funds of {} are negative", held)
+            }
+            InvariantViolation::NegativeAvailable { available } => {
+                write!(f, "available funds of {} are negative", available)
+            }
+        }
+    }
+}
+
+/// A transaction that was applied to an account, along with the balances it left the account
+/// with.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AppliedOp {
+    #[serde(rename = "type")]
+    pub op: &'static str,
+    pub tx: u32,
+    pub amount: Option<Decimal>,
+    pub available: Decimal,
+    pub held: Decimal,
+}
+
+/// A structure represening a single user account. It can be serialized to save its full state,
+/// including open disputes, but not its policy, which is configured separately for each run.
+#[derive(Debug, Default)]
+pub struct Account<N: Amount = Decimal> {
+    client: u16,
+    available: N,
+    held: N,
+    /// Whether the account was locked by a chargeback, which is final.
+    locked: bool,
+    /// Whether the account was frozen by an administrator, until it's thawed.
+    frozen: bool,
+    closed: bool,
+    /// The currency of the account, inferred from the first transaction that had one.
+    currency: Option<Currency>,
+
+    disputes: HashMap<u32, OpenDispute<N>>,
+    /// Deposits that haven't settled yet, whose funds are held, in the order they settle.
+    pending: VecDeque<PendingDeposit<N>>,
+    /// How many records the account has seen, which is used to settle pending deposits.
+    records: u64,
+    /// The sequence number of the transaction being applied, counted across all clients, which
+    /// the disputes it opens are aged from.
+    sequence: u64,
+    /// Withdrawals that have been authorized but not yet committed or released, by reservation ID.
+    reservations: BTreeMap<u32, N>,
+    /// The total funds reserved, which are neither available nor held for disputes.
+    reserved: N,
+    /// Funds that were already held when the account was seeded, which aren't held for any
+    /// dispute known here.
+    opening_held: N,
+    /// How many chargebacks the account has had, which can be more than one if disputes that were
+    /// already open can be settled once it's locked.
+    chargeback_count: u32,
+    /// Every transaction applied to the account, if its history is being tracked.
+    history: Option<Vec<AppliedOp>>,
+    /// Whether no transaction has been applied to the account yet. An account is kept even when
+    /// the transaction it was created for is rejected.
+    fresh: bool,
+    policy: AccountPolicy,
+}
+
+/// The serialized form of an account, which is kept stable so saved state can be loaded by later
+/// versions. Any field added after the first version must have a default, for when it's missing
+/// from older state.
+#[derive(Serialize, Deserialize)]
+pub struct PersistedAccount {
     client: u16,
     available: Decimal,
     held: Decimal,
     locked: bool,
+    #[serde(default)]
+    frozen: bool,
+    #[serde(default)]
+    closed: bool,
+    #[serde(default)]
+    currency: Option<Currency>,
+    /// Every dispute raised against the account's transactions, in transaction ID order.
+    #[serde(default)]
+    disputes: Vec<PersistedDispute>,
+    #[serde(default)]
+    pending: VecDeque<PendingDeposit>,
+    #[serde(default)]
+    records: u64,
+    #[serde(default)]
+    reservations: BTreeMap<u32, Decimal>,
+    #[serde(default)]
+    opening_held: Decimal,
+    #[serde(default)]
+    chargeback_count: u32,
+    #[serde(default)]
+    fresh: bool,
+}
+
+/// The serialized form of a dispute raised against one of an account's transactions. Only a
+/// dispute that was charged back is kept once settled, so that's what `settled` means (which is
+/// why state saved in version 1, which also kept resolved disputes, can't be loaded).
+#[derive(Serialize, Deserialize)]
+struct PersistedDispute {
+    tx: u32,
+    amount: Decimal,
+    direction: DisputeDirection,
+    #[serde(default)]
+    settled: bool,
+    /// Missing from state saved by earlier versions, in which case the dispute's age is counted
+    /// from the first transaction.
+    #[serde(default)]
+    opened_at: u64,
+}
+
+impl From<&Account> for PersistedAccount {
+    fn from(account: &Account) -> Self {
+        let mut disputes: Vec<_> = account
+            .disputes
+            .iter()
+            .map(|(tx, dispute)| PersistedDispute {
+                tx: *tx,
+                amount: dispute.amount,
+                direction: dispute.direction,
+                settled: dispute.charged_back,
+                opened_at: dispute.opened_at,
+            })
+            .collect();
+        disputes.sort_unstable_by_key(|dispute| dispute.tx);
 
-    disputes: HashMap<u32, Decimal>,
+        Self {
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            locked: account.locked,
+            frozen: account.frozen,
+            closed: account.closed,
+            currency: account.currency,
+            disputes,
+            pending: account.pending.clone(),
+            records: account.records,
+            reservations: account.reservations.clone(),
+            opening_held: account.opening_held,
+            chargeback_count: account.chargeback_count,
+            fresh: account.fresh,
+        }
+    }
+}
+
+impl From<PersistedAccount> for Account {
+    /// Restore an account from its saved state, with the default policy.
+    fn from(persisted: PersistedAccount) -> Self {
+        let disputes = persisted
+            .disputes
+            .into_iter()
+            .map(|dispute| {
+                let open = OpenDispute {
+                    amount: dispute.amount,
+                    direction: dispute.direction,
+                    charged_back: dispute.settled,
+                    opened_at: dispute.opened_at,
+                };
+                (dispute.tx, open)
+            })
+            .collect();
+
+        Self {
+            client: persisted.client,
+            available: persisted.available,
+            held: persisted.held,
+            locked: persisted.locked,
+            frozen: persisted.frozen,
+            closed: persisted.closed,
+            currency: persisted.currency,
+            disputes,
+            pending: persisted.pending,
+            records: persisted.records,
+            sequence: 0,
+            reserved: persisted.reservations.values().sum(),
+            reservations: persisted.reservations,
+            opening_held: persisted.opening_held,
+            chargeback_count: persisted.chargeback_count,
+            history: None,
+            fresh: persisted.fresh,
+            policy: AccountPolicy::default(),
+        }
+    }
+}
+
+/// Accounts are equal if they're in the same state. Their policies, histories, the count of records
+/// used to settle pending deposits, when disputes were opened and whether anything has been applied
+/// yet are bookkeeping rather than state, so aren't compared.
+impl<N: Amount> PartialEq for Account<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.client == other.client
+            && self.available == other.available
+            && self.held == other.held
+            && self.locked == other.locked
+            && self.frozen == other.frozen
+            && self.closed == other.closed
+            && self.currency == other.currency
+            && self.disputes == other.disputes
+            && self.pending == other.pending
+            && self.reservations == other.reservations
+            && self.chargeback_count == other.chargeback_count
+    }
+}
+
+impl Serialize for Account {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PersistedAccount::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Account {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        PersistedAccount::deserialize(deserializer).map(Account::from)
+    }
 }
 
 /// A structure containing the details for how to display an account. This is a separate
 /// struct as there are some fields on the main account that we don't want to display (such as
 /// active disputes), and there is some information we want to display that is not directly
 /// stored in the account (e.g. total balance).
-#[derive(Debug, Serialize)]
+///
+/// The amounts are output with exactly four decimal places, however many the balances have.
+#[derive(Debug, PartialEq, Serialize)]
 pub struct AccountDisplay {
     client: u16,
+    #[serde(serialize_with = "four_places")]
     available: Decimal,
+    #[serde(serialize_with = "four_places")]
     held: Decimal,
+    #[serde(serialize_with = "four_places")]
     total: Decimal,
     locked: bool,
+
+    /// Only included in the extended output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<AccountStatus>,
+    /// Only included in the extended output.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "four_places_opt"
+    )]
+    held_from_deposits: Option<Decimal>,
+    /// Only included in the extended output.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "four_places_opt"
+    )]
+    held_from_withdrawals: Option<Decimal>,
+    /// Only included in the extended output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chargeback_count: Option<u32>,
+}
+
+/// A footer row summing the accounts in the output, with the same columns as `AccountDisplay`.
+/// The client column holds `total`, and the locked column the number of locked accounts.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TotalsDisplay {
+    client: &'static str,
+    #[serde(serialize_with = "four_places")]
+    available: Decimal,
+    #[serde(serialize_with = "four_places")]
+    held: Decimal,
+    #[serde(serialize_with = "four_places")]
+    total: Decimal,
+    locked: usize,
+
+    /// Blank in the extended output, as the accounts can have different statuses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'static str>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "four_places_opt"
+    )]
+    held_from_deposits: Option<Decimal>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "four_places_opt"
+    )]
+    held_from_withdrawals: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chargeback_count: Option<u64>,
+}
+
+impl AccountDisplay {
+    /// Format the row as space-separated `column=value` pairs, with the same columns and values as
+    /// the CSV output.
+    pub fn summary(&self) -> String {
+        summary_line(
+            [
+                ("client", Some(self.client.to_string())),
+                ("available", Some(format_amount(self.available))),
+                ("held", Some(format_amount(self.held))),
+                ("total", Some(format_amount(self.total))),
+                ("locked", Some(self.locked.to_string())),
+                (
+                    "status",
+                    self.status.map(|status| status.as_str().to_string()),
+                ),
+            ],
+            self.held_from_deposits,
+            self.held_from_withdrawals,
+            self.chargeback_count.map(u64::from),
+        )
+    }
+}
+
+impl Default for TotalsDisplay {
+    fn default() -> Self {
+        Self {
+            client: "total",
+            available: Decimal::ZERO,
+            held: Decimal::ZERO,
+            total: Decimal::ZERO,
+            locked: 0,
+            status: None,
+            held_from_deposits: None,
+            held_from_withdrawals: None,
+            chargeback_count: None,
+        }
+    }
+}
+
+impl TotalsDisplay {
+    /// Add an account to the totals. The totals can overflow even though each account's balances
+    /// can't, in which case they saturate.
+    pub fn add(&mut self, account: &AccountDisplay) {
+        let sum = |total: Option<Decimal>, amount: Option<Decimal>| {
+            amount.map(|amount| total.unwrap_or_default().saturating_add(amount))
+        };
+        self.available = self.available.saturating_add(account.available);
+        self.held = self.held.saturating_add(account.held);
+        self.total = self.total.saturating_add(account.total);
+        self.locked += usize::from(account.locked);
+        self.status = account.status.map(|_| "");
+        self.held_from_deposits = sum(self.held_from_deposits, account.held_from_deposits);
+        self.held_from_withdrawals = sum(self.held_from_withdrawals, account.held_from_withdrawals);
+        self.chargeback_count = account.chargeback_count.map(|count| {
+            self.chargeback_count
+                .unwrap_or_default()
+                .saturating_add(u64::from(count))
+        });
+    }
+}
+
+impl TotalsDisplay {
+    /// Format the row as space-separated `column=value` pairs, with the same columns and values as
+    /// the CSV output.
+    pub fn summary(&self) -> String {
+        summary_line(
+            [
+                ("client", Some(self.client.to_string())),
+                ("available", Some(format_amount(self.available))),
+                ("held", Some(format_amount(self.held))),
+                ("total", Some(format_amount(self.total))),
+                ("locked", Some(self.locked.to_string())),
+                ("status", self.status.map(str::to_string)),
+            ],
+            self.held_from_deposits,
+            self.held_from_withdrawals,
+            self.chargeback_count,
+        )
+    }
+}
+
+// Helper function that joins the columns of a row as `column=value` pairs, followed by the extended
+// columns that are present.
+fn summary_line(
+    columns: [(&str, Option<String>); 6],
+    held_from_deposits: Option<Decimal>,
+    held_from_withdrawals: Option<Decimal>,
+    chargeback_count: Option<u64>,
+) -> String {
+    columns
+        .into_iter()
+        .chain([
+            ("held_from_deposits", held_from_deposits.map(format_amount)),
+            (
+                "held_from_withdrawals",
+                held_from_withdrawals.map(format_amount),
+            ),
+            (
+                "chargeback_count",
+                chargeback_count.map(|count| count.to_string()),
+            ),
+        ])
+        .filter_map(|(column, value)| value.map(|value| format!("{}={}", column, value)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Format an amount with exactly four decimal places. Halfway cases are rounded to even
+/// (banker's rounding), so rounding doesn't skew totals in one direction. This is only for
+/// display: the balances themselves are never rounded.
+pub(crate) fn format_amount(amount: Decimal) -> String {
+    let mut rounded = amount.round_dp_with_strategy(4, RoundingStrategy::MidpointNearestEven);
+    // Rounding a tiny negative amount can leave a negative zero.
+    rounded.set_sign_positive(rounded.is_sign_positive() || rounded.is_zero());
+
+    // Pad with zeros rather than formatting with a precision, which can't cope with the largest
+    // amounts.
+    let mut text = rounded.to_string();
+    let places = text
+        .split_once('.')
+        .map_or(0, |(_, fraction)| fraction.len());
+    if places == 0 {
+        text.push('.');
+    }
+    text.extend(std::iter::repeat_n('0', 4 - places));
+    text
+}
+
+// Helper function that serializes an amount with `format_amount`.
+fn four_places<S: Serializer>(amount: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_amount(*amount))
+}
+
+// Helper function that serializes an optional amount with `four_places`. Missing amounts are
+// skipped rather than serialized.
+fn four_places_opt<S: Serializer>(
+    amount: &Option<Decimal>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => four_places(amount, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// The overall state of an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    Open,
+    /// Locked by a chargeback, or frozen.
+    Locked,
+    /// Closed at the client's request, after which it accepts no further transactions.
+    Closed,
+}
+
+impl AccountStatus {
+    /// The status as it's written in the output.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccountStatus::Open => "open",
+            AccountStatus::Locked => "locked",
+            AccountStatus::Closed => "closed",
+        }
+    }
 }
 
 impl Account {
     /// Create a new account for the specified user.
     pub fn new(client: u16) -> Self {
+        Self::empty(client)
+    }
+}
+
+impl<N: Amount> Account<N> {
+    /// Create a new account for the specified user, keeping its balances in `N` rather than
+    /// `Decimal`.
+    pub fn empty(client: u16) -> Self {
+        Self {
+            client,
+            fresh: true,
+            ..Default::default()
+        }
+    }
+
+    /// An account carried over from another system, with the balances it had there. The held
+    /// funds aren't held for any dispute known here, so they stay held: resolving or charging back
+    /// a dispute raised elsewhere fails like any other unknown dispute.
+    pub fn with_balances(client: u16, available: N, held: N, locked: bool) -> Self {
         Self {
             client,
+            available,
+            held,
+            locked,
+            opening_held: held,
             ..Default::default()
         }
     }
 
-    /// Calculate the user's total balance.
-    pub fn total_balance(&self) -> Decimal {
-        self.available + self.held
+    /// Set the business rules the account follows.
+    pub fn with_policy(self, policy: AccountPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    /// Record every transaction applied to the account from now on, which can be retrieved with
+    /// `history`. This isn't done by default, as the history grows with every transaction.
+    pub fn with_history(self) -> Self {
+        Self {
+            history: Some(Vec::new()),
+            ..self
+        }
+    }
+
+    /// The transactions applied to the account since its history started being tracked, in the
+    /// order they were applied.
+    pub fn history(&self) -> &[AppliedOp] {
+        self.history.as_deref().unwrap_or_default()
+    }
+
+    /// The client the account belongs to.
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// The funds that can be withdrawn.
+    pub fn available(&self) -> N {
+        self.available
+    }
+
+    /// The funds held for disputes.
+    pub fn held(&self) -> N {
+        self.held
+    }
+
+    /// The funds reserved for withdrawals that haven't been committed or released yet.
+    pub fn reserved(&self) -> N {
+        self.reserved
+    }
+
+    /// The funds reserved by an outstanding reservation, if there is one with that ID.
+    pub fn reservation(&self, id: u32) -> Option<N> {
+        self.reservations.get(&id).copied()
+    }
+
+    /// Whether no transaction has been applied to the account yet, e.g. because the one it was
+    /// created for was rejected. An account carried over from another system isn't fresh.
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// Calculate the user's total balance, including reserved funds. This can't overflow, since
+    /// every update to the balances checks the total is still representable.
+    pub fn total_balance(&self) -> N {
+        self.available + self.held + self.reserved
+    }
+
+    /// Apply a transaction to the account. Disputes find the transaction they refer to in `txs`,
+    /// so can only ever dispute the amount that was actually deposited or withdrawn.
+    pub fn apply(
+        &mut self,
+        tx_id: u32,
+        op: &Operation<N>,
+        txs: &impl TxLookup<N>,
+    ) -> Result<(), TxError> {
+        let result = match *op {
+            Operation::Deposit(amount) if self.policy.deposit_hold > 0 => {
+                self.deposit_pending(tx_id, amount, self.policy.deposit_hold)
+            }
+            Operation::Deposit(amount) | Operation::OpeningBalance(amount) => self.deposit(amount),
+            Operation::Withdrawal(amount) => self.withdraw(amount),
+            Operation::Dispute(claimed) => {
+                let disputed = txs
+                    .disputable(tx_id)
+                    .ok_or(TxError::UnknownTx { tx: tx_id })?;
+                self.dispute(tx_id, disputed, claimed)
+            }
+            Operation::Resolve => self.resolve(tx_id),
+            Operation::Chargeback => self.chargeback(tx_id),
+            Operation::Close => self.close(),
+            Operation::Reserve(amount) => self.reserve(tx_id, amount),
+            Operation::Commit => self.commit(tx_id),
+            Operation::Release => self.release(tx_id),
+            Operation::Freeze => self.freeze(),
+            Operation::Thaw => self.thaw(),
+        };
+
+        if result.is_ok() {
+            self.fresh = false;
+        }
+        if let (Ok(()), Some(history)) = (&result, &mut self.history) {
+            let amount = match *op {
+                Operation::Deposit(amount)
+                | Operation::Withdrawal(amount)
+                | Operation::OpeningBalance(amount)
+                | Operation::Reserve(amount) => Some(amount),
+                Operation::Dispute(claimed) => claimed,
+                _ => None,
+            };
+            history.push(AppliedOp {
+                op: op.name(),
+                tx: tx_id,
+                amount: amount.map(N::to_decimal),
+                available: self.available.to_decimal(),
+                held: self.held.to_decimal(),
+            });
+        }
+        result
     }
 
     /// Deposit funds into the user's account.
-    pub fn deposit(&mut self, amount: Decimal) -> Result<(), Box<dyn Error>> {
-        self.fail_if_locked()?;
-        self.available += amount;
+    fn deposit(&mut self, amount: N) -> Result<(), TxError> {
+        if self.policy.allow_deposits_when_locked {
+            self.fail_if_closed()?;
+        } else {
+            self.fail_if_locked()?;
+        }
+        let available = checked(self.available.checked_add(amount))?;
+        self.set_balances(available, self.held)
+    }
+
+    /// Deposit funds into the user's account, holding them until `hold` more records have been
+    /// seen for the account.
+    fn deposit_pending(&mut self, tx_id: u32, amount: N, hold: u64) -> Result<(), TxError> {
+        if self.policy.allow_deposits_when_locked {
+            self.fail_if_closed()?;
+        } else {
+            self.fail_if_locked()?;
+        }
+        let held = checked(self.held.checked_add(amount))?;
+        self.set_balances(self.available, held)?;
+        self.pending.push_back(PendingDeposit {
+            tx: tx_id,
+            amount,
+            settles_at: self.records + hold,
+        });
         Ok(())
     }
 
+    /// Count another record for the account, making the funds of any deposits that have now
+    /// settled available. This can't fail, as the funds are just moving between balances.
+    pub fn settle_deposits(&mut self) {
+        self.records += 1;
+        while let Some(deposit) = self.pending.front() {
+            if deposit.settles_at > self.records {
+                break;
+            }
+            // Held funds that were withdrawn have already left the account, so can't be released.
+            let amount = deposit.amount.min(self.held.max(N::ZERO));
+            self.available += amount;
+            self.held -= amount;
+            self.pending.pop_front();
+        }
+    }
+
     /// Withdraw funds from the account, returning an error if there are insufficient funds.
-    pub fn withdraw(&mut self, amount: Decimal) -> Result<(), Box<dyn Error>> {
+    ///
+    /// A negative available balance (left by a dispute under a policy that allows it) is a debt
+    /// to be repaid, so nothing can be withdrawn from the available funds until it's back above
+    /// zero. When withdrawing held funds is allowed the debt is covered by the held funds first, so
+    /// only what's left of the total balance can be withdrawn.
+    fn withdraw(&mut self, amount: N) -> Result<(), TxError> {
         self.fail_if_locked()?;
 
         if self.available >= amount {
-            self.available -= amount;
-            Ok(())
-        } else {
-            Err("Insufficeint funds".into())
+            let available = checked(self.available.checked_sub(amount))?;
+            return self.set_balances(available, self.held);
+        }
+
+        let total = checked(self.available.checked_add(self.held))?;
+        if !self.policy.allow_withdrawing_held || total < amount {
+            return Err(TxError::InsufficientFunds);
         }
+        // Use up whatever is available first, and take the rest from the held funds. A debt stays
+        // in the available balance.
+        let from_available = self.available.max(N::ZERO);
+        let available = checked(self.available.checked_sub(from_available))?;
+        let held = checked(
+            self.held
+                .checked_sub(checked(amount.checked_sub(from_available))?),
+        )?;
+        self.set_balances(available, held)
+    }
+
+    /// Reserve funds for a withdrawal that will be committed or released later. Only the available
+    /// funds can be reserved, and not on a locked account, just as for a withdrawal.
+    fn reserve(&mut self, id: u32, amount: N) -> Result<(), TxError> {
+        self.fail_if_locked()?;
+
+        if self.available < amount {
+            return Err(TxError::InsufficientFunds);
+        }
+        let available = checked(self.available.checked_sub(amount))?;
+        self.set_balances(available, self.held)?;
+        *self.reservations.entry(id).or_default() += amount;
+        self.reserved += amount;
+        Ok(())
+    }
+
+    /// Complete a reserved withdrawal, removing the reserved funds from the account for good. This
+    /// is allowed on a locked account, as the withdrawal was authorized before it was locked.
+    fn commit(&mut self, id: u32) -> Result<(), TxError> {
+        self.fail_if_closed()?;
+
+        let amount = self.take_reservation(id)?;
+        self.reserved -= amount;
+        Ok(())
+    }
+
+    /// Cancel a reserved withdrawal, returning the reserved funds to the available balance.
+    fn release(&mut self, id: u32) -> Result<(), TxError> {
+        self.fail_if_closed()?;
+
+        let amount = self.take_reservation(id)?;
+        self.reserved -= amount;
+        self.available += amount;
+        Ok(())
     }
 
-    pub fn dispute(&mut self, tx_id: u32, amount: Decimal) -> Result<(), Box<dyn Error>> {
+    // Helper function that removes an outstanding reservation, returning the funds it reserved.
+    fn take_reservation(&mut self, id: u32) -> Result<N, TxError> {
+        self.reservations
+            .remove(&id)
+            .ok_or(TxError::UnknownReservation { id })
+    }
+
+    /// Open (or add to) a dispute of a transaction. Disputing a deposit holds the funds until the
+    /// dispute is resolved or charged back. Nothing is held for a withdrawal, but if the dispute is
+    /// charged back the disputed funds are returned to the account. If `claimed` is given only that
+    /// portion of the transaction is disputed, and further partial disputes can be stacked on top of
+    /// it up to the full amount.
+    fn dispute(
+        &mut self,
+        tx_id: u32,
+        disputed: DisputableTx<N>,
+        claimed: Option<N>,
+    ) -> Result<(), TxError> {
         self.fail_if_locked()?;
+        let DisputableTx {
+            amount: tx_amount,
+            direction,
+            ..
+        } = disputed;
+
+        let existing = self.disputes.get(&tx_id);
+        if existing.is_some_and(|dispute| dispute.charged_back) {
+            return Err(TxError::AlreadyChargedBack { tx: tx_id });
+        }
+        let already_disputed = existing.map(|dispute| dispute.amount);
+        if already_disputed.is_some() && claimed.is_none() {
+            return match self.policy.duplicate_dispute {
+                DuplicateDisputePolicy::Reject => Err(TxError::DisputeAlreadyOpen { tx: tx_id }),
+                DuplicateDisputePolicy::Ignore => Ok(()),
+            };
+        }
+
+        // Adding to a dispute that's already open doesn't count towards the limit.
+        let at_limit = self
+            .policy
+            .max_open_disputes
+            .is_some_and(|max| self.open_disputes().count() >= max);
+        if already_disputed.is_none() && at_limit {
+            return Err(TxError::TooManyDisputes {
+                client: self.client,
+            });
+        }
 
-        if self.disputes.contains_key(&tx_id) {
-            return Err(format!("dispute already in progress for transaction {}", tx_id).into());
+        // A partial dispute can only claim the portion of the transaction that isn't already
+        // disputed.
+        let amount = claimed.unwrap_or(tx_amount);
+        let undisputed = tx_amount - already_disputed.unwrap_or(N::ZERO);
+        if amount <= N::ZERO || amount > undisputed {
+            return Err(TxError::InvalidDisputeAmount {
+                tx: tx_id,
+                amount: amount.to_decimal(),
+            });
         }
 
+        let amount = match direction {
+            // The funds of a deposit that hasn't settled are already held, so just stop them from
+            // being released when it settles.
+            DisputeDirection::Credit if self.pending.iter().any(|d| d.tx == tx_id) => {
+                for deposit in self.pending.iter_mut().filter(|d| d.tx == tx_id) {
+                    deposit.amount -= amount;
+                }
+                amount
+            }
+            DisputeDirection::Credit => {
+                let amount = self.holdable(amount)?;
+                let available = checked(self.available.checked_sub(amount))?;
+                let held = checked(self.held.checked_add(amount))?;
+                self.set_balances(available, held)?;
+                amount
+            }
+            DisputeDirection::Debit => amount,
+        };
+        let dispute = self.disputes.entry(tx_id).or_insert(OpenDispute {
+            amount: N::ZERO,
+            direction,
+            charged_back: false,
+            opened_at: self.sequence,
+        });
+        dispute.amount += amount;
+        Ok(())
+    }
+
+    // Helper function that works out how much of a dispute for `amount` can be held.
+    fn holdable(&self, amount: N) -> Result<N, TxError> {
         if self.available >= amount {
-            self.available -= amount;
-            self.held += amount;
-            self.disputes.insert(tx_id, amount);
-            Ok(())
-        } else {
-            // Unclear what we should do if there aren't enough funds to hold for the dispute.
-            // I'll assume we can just ignore the transation.
-            Err("Insufficeint funds".into())
+            return Ok(amount);
+        }
+
+        // Unclear what we should do if there aren't enough funds to hold for the dispute. By default
+        // I'll assume we can just ignore the transation, but this can be overridden by the policy.
+        if self.policy.dispute == DisputePolicy::Reject {
+            return Err(TxError::InsufficientFunds);
+        }
+        // A limit too large to represent is no limit at all.
+        let Some(limit) = self.policy.negative_limit.and_then(N::from_decimal) else {
+            return Ok(amount);
+        };
+
+        let headroom = checked(self.available.checked_add(limit))?;
+        match self.policy.negative_limit_action {
+            _ if amount <= headroom => Ok(amount),
+            NegativeLimitAction::Cap if headroom > N::ZERO => Ok(headroom),
+            _ => Err(TxError::InsufficientFunds),
         }
     }
 
-    pub fn resolve(&mut self, tx_id: u32) -> Result<(), Box<dyn Error>> {
-        self.fail_if_locked()?;
+    /// Check a transaction in `currency` is in the same currency as the account's earlier
+    /// transactions. If the account's currency isn't known yet, it becomes `currency`.
+    pub fn check_currency(&mut self, currency: Option<Currency>) -> Result<(), TxError> {
+        match (self.currency, currency) {
+            (Some(expected), Some(found)) if expected != found => Err(TxError::CurrencyMismatch {
+                client: self.client,
+                expected,
+                found,
+            }),
+            (None, Some(_)) => {
+                self.currency = currency;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
 
-        let amount = self
-            .disputes
+    /// Whether the account has been locked, by a chargeback or a freeze.
+    pub fn is_locked(&self) -> bool {
+        self.locked || self.frozen
+    }
+
+    /// Whether the account has been frozen, and not yet thawed.
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// How many chargebacks the account has had. Once locked it can only have more if disputes
+    /// that were already open can still be settled.
+    pub fn chargeback_count(&self) -> u32 {
+        self.chargeback_count
+    }
+
+    /// Whether a transaction currently has a dispute open against it.
+    pub fn is_disputed(&self, tx_id: u32) -> bool {
+        self.disputes
             .get(&tx_id)
-            .ok_or(format!("could not find dispute with TX ID {}", tx_id))?;
-        self.available += amount;
-        self.held -= amount;
+            .is_some_and(|dispute| !dispute.charged_back)
+    }
+
+    /// The IDs of the transactions with a dispute open against them, or that were charged back.
+    pub fn disputed_txs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.disputes.keys().copied()
+    }
+
+    /// The disputes that haven't been resolved or charged back yet, along with the amount disputed.
+    pub fn open_disputes(&self) -> impl Iterator<Item = (u32, N)> + '_ {
+        self.disputes
+            .iter()
+            .filter(|(_, dispute)| !dispute.charged_back)
+            .map(|(tx, dispute)| (*tx, dispute.amount))
+    }
+
+    /// Set the sequence number of the next transaction applied, counted across all clients, which
+    /// a dispute it opens is aged from. The processor does this before applying each transaction.
+    pub fn set_sequence(&mut self, sequence: u64) {
+        self.sequence = sequence;
+    }
+
+    /// How long each open dispute has been open as of the transaction with sequence number
+    /// `current_index` (usually [`Processor::sequence`](crate::engine::Processor::sequence)), in
+    /// transactions since the one that opened it, whichever client they were for. A dispute
+    /// opened by the current transaction is 0 transactions old.
+    pub fn dispute_ages(&self, current_index: u64) -> impl Iterator<Item = (u32, u64)> + '_ {
+        self.disputes
+            .iter()
+            .filter(|(_, dispute)| !dispute.charged_back)
+            .map(move |(tx, dispute)| (*tx, current_index.saturating_sub(dispute.opened_at)))
+    }
+
+    fn resolve(&mut self, tx_id: u32) -> Result<(), TxError> {
+        if self.policy.allow_deposits_when_locked {
+            self.fail_if_closed()?;
+        } else {
+            self.fail_if_locked()?;
+        }
+
+        let dispute = self.find_dispute(tx_id)?;
+        match dispute.direction {
+            // The deposit hasn't settled yet, so the funds stay held until it does.
+            DisputeDirection::Credit if self.pending.iter().any(|d| d.tx == tx_id) => {
+                for deposit in self.pending.iter_mut().filter(|d| d.tx == tx_id) {
+                    deposit.amount += dispute.amount;
+                }
+            }
+            DisputeDirection::Credit => {
+                let held = self.release_held(tx_id, dispute.amount)?;
+                let available = checked(self.available.checked_add(dispute.amount))?;
+                self.set_balances(available, held)?;
+            }
+            // Nothing was held for a disputed withdrawal, so there's nothing to release.
+            DisputeDirection::Debit => {}
+        }
+        // The transaction can be disputed again, as though it never had been.
+        self.disputes.remove(&tx_id);
         Ok(())
     }
 
-    pub fn chargeback(&mut self, tx_id: u32) -> Result<(), Box<dyn Error>> {
+    fn chargeback(&mut self, tx_id: u32) -> Result<(), TxError> {
+        if self.policy.allow_deposits_when_locked && self.is_disputed(tx_id) {
+            self.fail_if_closed()?;
+        } else {
+            self.fail_if_locked()?;
+        }
+
+        let dispute = self.find_dispute(tx_id)?;
+        match dispute.direction {
+            DisputeDirection::Credit => {
+                let held = self.release_held(tx_id, dispute.amount)?;
+                self.set_balances(self.available, held)?;
+            }
+            DisputeDirection::Debit => {
+                let available = checked(self.available.checked_add(dispute.amount))?;
+                self.set_balances(available, self.held)?;
+            }
+        }
+        if let Some(dispute) = self.disputes.get_mut(&tx_id) {
+            dispute.charged_back = true;
+        }
+        self.locked = true;
+        self.chargeback_count += 1;
+        Ok(())
+    }
+
+    // Helper function that finds the open dispute for a transaction. A dispute that has already
+    // been settled can't be settled again.
+    fn find_dispute(&self, tx_id: u32) -> Result<OpenDispute<N>, TxError> {
+        self.disputes
+            .get(&tx_id)
+            .filter(|dispute| !dispute.charged_back)
+            .copied()
+            .ok_or(TxError::UnknownDispute { tx: tx_id })
+    }
+
+    /// Close the account, which must have no funds left, so that it accepts no further
+    /// transactions.
+    fn close(&mut self) -> Result<(), TxError> {
         self.fail_if_locked()?;
 
-        let amount = self
+        if self.available != N::ZERO || self.held != N::ZERO || self.reserved != N::ZERO {
+            return Err(TxError::FundsRemaining {
+                client: self.client,
+            });
+        }
+        self.closed = true;
+        Ok(())
+    }
+
+    /// Lock the account without a chargeback, e.g. while fraud is investigated.
+    fn freeze(&mut self) -> Result<(), TxError> {
+        self.fail_if_closed()?;
+        self.frozen = true;
+        Ok(())
+    }
+
+    /// Unfreeze the account. If it was also locked by a chargeback it stays locked, as that's
+    /// final.
+    fn thaw(&mut self) -> Result<(), TxError> {
+        self.fail_if_closed()?;
+        self.frozen = false;
+        Ok(())
+    }
+
+    /// Check the account's state is consistent, returning all the ways it isn't. This should
+    /// always be empty, so is only worth calling to debug the balance arithmetic.
+    pub fn check_invariants(&self) -> Vec<InvariantViolation> {
+        let mut violations = Vec::new();
+
+        // Withdrawing held funds means they no longer cover the disputes, so only then can the held
+        // funds be less than what's disputed.
+        let expected = self
             .disputes
-            .get(&tx_id)
-            .ok_or(format!("could not find dispute with TX ID {}", tx_id))?;
-        self.held -= amount;
-        self.locked = true;
+            .values()
+            .filter(|d| !d.charged_back && d.direction == DisputeDirection::Credit)
+            .map(|d| d.amount)
+            .chain(self.pending.iter().map(|d| d.amount))
+            .fold(self.opening_held, |total, amount| {
+                total.saturating_add(amount)
+            });
+        if self.held != expected && !(self.policy.allow_withdrawing_held && self.held < expected) {
+            violations.push(InvariantViolation::HeldMismatch {
+                held: self.held.to_decimal(),
+                expected: expected.to_decimal(),
+            });
+        }
+        if self.held < N::ZERO {
+            violations.push(InvariantViolation::NegativeHeld {
+                held: self.held.to_decimal(),
+            });
+        }
+        if self.available < N::ZERO && self.policy.dispute == DisputePolicy::Reject {
+            violations.push(InvariantViolation::NegativeAvailable {
+                available: self.available.to_decimal(),
+            });
+        }
+        violations
+    }
+
+    /// The overall state of the account.
+    pub fn status(&self) -> AccountStatus {
+        if self.closed {
+            AccountStatus::Closed
+        } else if self.is_locked() {
+            AccountStatus::Locked
+        } else {
+            AccountStatus::Open
+        }
+    }
+
+    // Helper function that calculates the held funds after releasing those held for a dispute. Held
+    // funds can never go negative, so if that would happen the dispute bookkeeping has gone wrong
+    // and we return an Err rather than produce nonsensical balances.
+    fn release_held(&self, tx_id: u32, amount: N) -> Result<N, TxError> {
+        if self.held < amount {
+            return Err(TxError::NegativeHeld { tx: tx_id });
+        }
+        checked(self.held.checked_sub(amount))
+    }
+
+    // Helper function that updates the balances, as long as their total doesn't overflow. Nothing is
+    // changed if it would, so a failed transaction leaves the account as it was.
+    fn set_balances(&mut self, available: N, held: N) -> Result<(), TxError> {
+        checked(
+            available
+                .checked_add(held)
+                .and_then(|total| total.checked_add(self.reserved)),
+        )?;
+        self.available = available;
+        self.held = held;
         Ok(())
     }
 
-    // Helper function that returns an Err if the account is locked, which makes checking for this condition easier.
-    fn fail_if_locked(&self) -> Result<(), Box<dyn Error>> {
-        if self.locked {
-            Err(format!("Account {} is locked", self.client).into())
+    // Helper function that returns an Err if the account is locked (or closed), which makes checking for this condition easier.
+    fn fail_if_locked(&self) -> Result<(), TxError> {
+        self.fail_if_closed()?;
+        if self.is_locked() {
+            Err(TxError::AccountLocked {
+                client: self.client,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    // Helper function that returns an Err if the account is closed.
+    fn fail_if_closed(&self) -> Result<(), TxError> {
+        if self.closed {
+            Err(TxError::AccountClosed {
+                client: self.client,
+            })
         } else {
             Ok(())
         }
@@ -115,103 +1223,1256 @@ impl Account {
     /// Create a display representation for this account.
     pub fn to_display(&self) -> AccountDisplay {
         AccountDisplay {
-            client: self.client,
-            available: self.available,
-            held: self.held,
-            total: self.total_balance(),
-            locked: self.locked,
+            client: self.client(),
+            available: self.available().to_decimal(),
+            // Reserved funds can't be withdrawn, so they're shown as held.
+            held: (self.held() + self.reserved()).to_decimal(),
+            total: self.total_balance().to_decimal(),
+            locked: self.is_locked(),
+            status: None,
+            held_from_deposits: None,
+            held_from_withdrawals: None,
+            chargeback_count: None,
+        }
+    }
+
+    /// Create a display representation for this account, including the extra details shown in
+    /// the extended output.
+    pub fn to_extended_display(&self) -> AccountDisplay {
+        // Disputing a withdrawal doesn't hold anything (the funds have already left the account),
+        // so the funds held for disputes are all from deposits, whether disputed or still waiting
+        // to settle. Funds are only held for withdrawals while they're reserved.
+        AccountDisplay {
+            status: Some(self.status()),
+            held_from_deposits: Some(self.held().to_decimal()),
+            held_from_withdrawals: Some(self.reserved().to_decimal()),
+            chargeback_count: Some(self.chargeback_count),
+            ..self.to_display()
+        }
+    }
+}
+
+impl fmt::Display for AccountDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client={} available={} held={} total={} locked={}",
+            self.client, self.available, self.held, self.total, self.locked
+        )
+    }
+}
+
+/// Builds an account in a known state for tests, without having to apply the transactions that
+/// would lead to it. Nothing checks the state is consistent, e.g. that the held funds cover the
+/// disputes. Outside this crate's own tests it needs the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
+pub struct AccountBuilder {
+    account: Account,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl AccountBuilder {
+    /// Start from a new, empty account for `client`.
+    pub fn new(client: u16) -> Self {
+        Self {
+            account: Account::new(client),
         }
     }
+
+    /// The policy the account applies transactions with.
+    pub fn policy(mut self, policy: AccountPolicy) -> Self {
+        self.account.policy = policy;
+        self
+    }
+
+    /// The funds available.
+    pub fn available(mut self, available: Decimal) -> Self {
+        self.account.available = available;
+        self
+    }
+
+    /// The funds held, which are taken to be for disputes.
+    pub fn held(mut self, held: Decimal) -> Self {
+        self.account.held = held;
+        self
+    }
+
+    /// Whether the account is locked, as if by a chargeback.
+    pub fn locked(mut self, locked: bool) -> Self {
+        self.account.locked = locked;
+        self
+    }
+
+    /// Open a dispute for `amount` of the deposit `tx_id`. This doesn't hold any funds, so set the
+    /// held funds to match.
+    pub fn dispute(mut self, tx_id: u32, amount: Decimal) -> Self {
+        let dispute = OpenDispute {
+            amount,
+            direction: DisputeDirection::Credit,
+            charged_back: false,
+            opened_at: self.account.sequence,
+        };
+        self.account.disputes.insert(tx_id, dispute);
+        self
+    }
+
+    /// The account in the state built up.
+    pub fn build(self) -> Account {
+        self.account
+    }
+}
+
+// Helper function that turns the result of a checked calculation into an Err if it overflowed.
+fn checked<N: Amount>(value: Option<N>) -> Result<N, TxError> {
+    value.ok_or(TxError::Overflow)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::amount::Cents;
     use rust_decimal_macros::dec;
+    use std::ops::Deref;
+    use Operation::*;
+
+    /// An account along with the deposits and withdrawals applied to it, so they can be disputed.
+    struct TestAccount {
+        account: Account,
+        txs: HashMap<u32, DisputableTx>,
+    }
+
+    impl TestAccount {
+        fn new(policy: AccountPolicy) -> Self {
+            Self {
+                account: Account::new(1).with_policy(policy),
+                txs: HashMap::new(),
+            }
+        }
+
+        fn apply(&mut self, tx_id: u32, op: Operation) -> Result<(), TxError> {
+            let result = self.account.apply(tx_id, &op, &self.txs);
+            let (amount, direction) = match op {
+                Deposit(amount) => (amount, DisputeDirection::Credit),
+                Withdrawal(amount) => (amount, DisputeDirection::Debit),
+                _ => return result,
+            };
+            if result.is_ok() {
+                let line = 0;
+                self.txs.insert(
+                    tx_id,
+                    DisputableTx {
+                        amount,
+                        direction,
+                        line,
+                    },
+                );
+            }
+            result
+        }
+    }
+
+    impl From<Account> for TestAccount {
+        fn from(account: Account) -> Self {
+            Self {
+                account,
+                txs: HashMap::new(),
+            }
+        }
+    }
+
+    impl Deref for TestAccount {
+        type Target = Account;
+
+        fn deref(&self) -> &Account {
+            &self.account
+        }
+    }
 
     #[test]
     fn test_deposit_withdrawal() {
-        let mut acc = Account::new(1);
-        assert!(acc.deposit(dec!(1.0)).is_ok());
-        assert!(acc.deposit(dec!(2.0)).is_ok());
-        assert!(acc.withdraw(dec!(1.2)).is_ok());
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(1.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(2.0))).is_ok());
+        assert!(acc.apply(3, Withdrawal(dec!(1.2))).is_ok());
 
-        assert_eq!(acc.available, dec!(1.8));
-        assert_eq!(acc.held, dec!(0.0));
+        assert_eq!(acc.available(), dec!(1.8));
+        assert_eq!(acc.held(), dec!(0.0));
         assert_eq!(acc.total_balance(), dec!(1.8));
     }
 
+    #[test]
+    fn test_dispute_ages() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        // The gaps in the sequence are other clients' transactions.
+        let mut apply = |sequence, tx_id, op| {
+            acc.account.set_sequence(sequence);
+            acc.apply(tx_id, op).unwrap();
+        };
+        apply(1, 1, Deposit(dec!(5.0)));
+        apply(2, 2, Deposit(dec!(3.0)));
+        apply(5, 3, Deposit(dec!(2.0)));
+        apply(6, 1, Dispute(None));
+        apply(9, 4, Withdrawal(dec!(0.5)));
+        apply(10, 3, Dispute(None));
+        apply(12, 5, Deposit(dec!(1.0)));
+        apply(14, 2, Dispute(None));
+        apply(15, 2, Resolve);
+        apply(20, 2, Dispute(None));
+        apply(21, 3, Chargeback);
+
+        // Dispute 2 was reopened by the 20th transaction. Dispute 3 was charged back, so is no
+        // longer open.
+        let ages = |current| {
+            let mut ages: Vec<_> = acc.dispute_ages(current).collect();
+            ages.sort_unstable();
+            ages
+        };
+        assert_eq!(ages(21), [(1, 15), (2, 1)]);
+        assert_eq!(ages(100), [(1, 94), (2, 80)]);
+        assert_eq!(ages(20), [(1, 14), (2, 0)]);
+
+        // The ages survive saving and restoring the account.
+        let restored: Account =
+            serde_json::from_str(&serde_json::to_string(&*acc).unwrap()).unwrap();
+        let mut restored_ages: Vec<_> = restored.dispute_ages(100).collect();
+        restored_ages.sort_unstable();
+        assert_eq!(restored_ages, [(1, 94), (2, 80)]);
+    }
+
+    #[test]
+    fn test_integer_cents() {
+        // Apply the same transactions to an account of type `N`, with amounts given in cents.
+        fn run<N: Amount>(cents: impl Fn(i64) -> N) -> (Account<N>, Vec<Result<(), TxError>>) {
+            let ops = [
+                (1, Deposit(cents(1050))),
+                (2, Deposit(cents(250))),
+                (3, Withdrawal(cents(400))),
+                (1, Dispute(Some(cents(300)))),
+                (2, Dispute(None)),
+                (4, Withdrawal(cents(1000))),
+                (1, Resolve),
+                (2, Chargeback),
+                (5, Deposit(cents(100))),
+            ];
+            let mut account = Account::empty(1);
+            let mut txs = HashMap::new();
+            let results = ops
+                .into_iter()
+                .map(|(tx_id, op)| {
+                    let result = account.apply(tx_id, &op, &txs);
+                    if let (Ok(()), Deposit(amount)) = (&result, op) {
+                        let direction = DisputeDirection::Credit;
+                        let deposit = DisputableTx {
+                            amount,
+                            direction,
+                            line: 0,
+                        };
+                        txs.insert(tx_id, deposit);
+                    }
+                    result
+                })
+                .collect();
+            (account, results)
+        }
+
+        let (decimal, decimal_results) = run(|cents| Decimal::new(cents, 2));
+        let (integer, integer_results) = run(|cents| Cents(i128::from(cents)));
+        assert_eq!(decimal_results, integer_results);
+        assert_eq!(decimal.available(), dec!(6.50));
+        assert_eq!(integer.available(), Cents(650));
+        assert_eq!(integer.available().to_decimal(), decimal.available());
+        assert_eq!(integer.held().to_decimal(), decimal.held());
+        assert_eq!(integer.status(), AccountStatus::Locked);
+        assert_eq!(integer.chargeback_count(), decimal.chargeback_count());
+    }
+
     #[test]
     fn test_dispute_resolve() {
-        let mut acc = Account::new(1);
+        let mut acc = TestAccount::new(AccountPolicy::default());
 
-        assert!(acc.deposit(dec!(1.0)).is_ok());
-        assert!(acc.deposit(dec!(2.0)).is_ok());
-        assert!(acc.dispute(33, dec!(1.2)).is_ok());
+        assert!(acc.apply(1, Deposit(dec!(1.2))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(1.8))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
 
-        assert_eq!(acc.available, dec!(1.8));
-        assert_eq!(acc.held, dec!(1.2));
+        assert_eq!(acc.available(), dec!(1.8));
+        assert_eq!(acc.held(), dec!(1.2));
         assert_eq!(acc.total_balance(), dec!(3.0));
 
-        assert!(acc.resolve(33).is_ok());
-        assert_eq!(acc.available, dec!(3.0));
-        assert_eq!(acc.held, dec!(0.0));
+        assert!(acc.apply(1, Resolve).is_ok());
+        assert_eq!(acc.available(), dec!(3.0));
+        assert_eq!(acc.held(), dec!(0.0));
         assert_eq!(acc.total_balance(), dec!(3.0));
     }
 
     #[test]
     fn test_dispute_chargeback() {
-        let mut acc = Account::new(1);
+        let mut acc = TestAccount::new(AccountPolicy::default());
 
-        assert!(acc.deposit(dec!(1.0)).is_ok());
-        assert!(acc.deposit(dec!(2.0)).is_ok());
-        assert!(acc.dispute(33, dec!(1.2)).is_ok());
-        assert!(acc.chargeback(33).is_ok());
+        assert!(acc.apply(1, Deposit(dec!(1.2))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(1.8))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(1, Chargeback).is_ok());
 
-        assert_eq!(acc.available, dec!(1.8));
-        assert_eq!(acc.held, dec!(0.0));
+        assert_eq!(acc.available(), dec!(1.8));
+        assert_eq!(acc.held(), dec!(0.0));
         assert_eq!(acc.total_balance(), dec!(1.8));
 
         // Further transactions fail.
-        assert!(acc.deposit(dec!(1.0)).is_err());
-        assert!(acc.withdraw(dec!(1.0)).is_err());
-        assert!(acc.dispute(66, dec!(1.0)).is_err());
-        assert!(acc.resolve(66).is_err());
+        assert_eq!(
+            acc.apply(3, Deposit(dec!(1.0))),
+            Err(TxError::AccountLocked { client: 1 })
+        );
+        assert_eq!(
+            acc.apply(4, Withdrawal(dec!(1.0))),
+            Err(TxError::AccountLocked { client: 1 })
+        );
+        assert_eq!(
+            acc.apply(2, Dispute(None)),
+            Err(TxError::AccountLocked { client: 1 })
+        );
+        assert_eq!(
+            acc.apply(2, Resolve),
+            Err(TxError::AccountLocked { client: 1 })
+        );
+    }
+
+    #[test]
+    fn test_dispute_uses_deposited_amount() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+
+        // A dispute can't be applied for a transaction that was never made.
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert_eq!(
+            acc.apply(2, Dispute(None)),
+            Err(TxError::UnknownTx { tx: 2 })
+        );
+
+        // Nor can it claim more than was deposited.
+        assert_eq!(
+            acc.apply(1, Dispute(Some(dec!(6.0)))),
+            Err(TxError::InvalidDisputeAmount {
+                tx: 1,
+                amount: dec!(6.0)
+            })
+        );
+        assert_eq!(acc.held(), dec!(0.0));
+
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert_eq!(acc.available(), dec!(0.0));
+        assert_eq!(acc.held(), dec!(5.0));
+    }
+
+    #[test]
+    fn test_deposits_allowed_when_locked() {
+        let policy = AccountPolicy {
+            allow_deposits_when_locked: true,
+            ..Default::default()
+        };
+        let mut acc = TestAccount::from(
+            AccountBuilder::new(1)
+                .policy(policy)
+                .available(dec!(2.0))
+                .held(dec!(2.0))
+                .dispute(2, dec!(2.0))
+                .locked(true)
+                .build(),
+        );
+
+        // Deposits still land, and the open dispute can be resolved.
+        assert!(acc.apply(4, Deposit(dec!(1.0))).is_ok());
+        assert!(acc.apply(2, Resolve).is_ok());
+        assert_eq!(acc.available(), dec!(5.0));
+        assert_eq!(acc.held(), dec!(0.0));
+
+        // Everything else is still blocked.
+        let locked = Err(TxError::AccountLocked { client: 1 });
+        assert_eq!(acc.apply(5, Withdrawal(dec!(1.0))), locked);
+        assert_eq!(acc.apply(4, Dispute(None)), locked);
+        assert_eq!(acc.apply(2, Chargeback), locked);
+        assert_eq!(acc.available(), dec!(5.0));
+    }
+
+    #[test]
+    fn test_held_cannot_go_negative() {
+        let mut acc = TestAccount::new(AccountPolicy {
+            allow_withdrawing_held: true,
+            ..Default::default()
+        });
+        assert!(acc.apply(1, Deposit(dec!(3.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(1.2))).is_ok());
+        assert!(acc.apply(2, Dispute(None)).is_ok());
+        // Withdrawing the held funds leaves less held than the dispute claims, so settling it would
+        // release funds that aren't held.
+        assert!(acc.apply(3, Withdrawal(dec!(4.0))).is_ok());
+        assert_eq!(acc.held(), dec!(0.2));
+
+        assert_eq!(acc.apply(2, Resolve), Err(TxError::NegativeHeld { tx: 2 }));
+        assert_eq!(
+            acc.apply(2, Chargeback),
+            Err(TxError::NegativeHeld { tx: 2 })
+        );
+        assert_eq!(acc.available(), dec!(0.0));
+        assert_eq!(acc.held(), dec!(0.2));
+        assert!(!acc.is_locked());
+        assert!(acc.check_invariants().is_empty());
+    }
+
+    #[test]
+    fn test_settled_dispute_cannot_be_settled_again() {
+        let mut acc = TestAccount::new(AccountPolicy {
+            dispute: DisputePolicy::AllowNegative,
+            ..Default::default()
+        });
+        assert!(acc.apply(1, Deposit(dec!(1.2))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(1.8))).is_ok());
+        assert!(acc.apply(3, Withdrawal(dec!(1.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(2, Dispute(None)).is_ok());
+        assert!(acc.apply(3, Dispute(None)).is_ok());
+        assert!(acc.apply(1, Resolve).is_ok());
+        assert!(acc.apply(3, Resolve).is_ok());
+
+        // Neither would release the funds held for the other dispute, or return a withdrawal that
+        // was resolved.
+        for tx in [1, 3] {
+            let unknown = Err(TxError::UnknownDispute { tx });
+            assert_eq!(acc.apply(tx, Resolve), unknown);
+            assert_eq!(acc.apply(tx, Chargeback), unknown);
+        }
+        assert_eq!(acc.available(), dec!(0.2));
+        assert_eq!(acc.held(), dec!(1.8));
+        assert!(!acc.is_locked());
+    }
+
+    #[test]
+    fn test_resolved_dispute_can_be_reopened() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(1, Resolve).is_ok());
+        assert!(!acc.is_disputed(1));
+
+        // Disputing it again holds the full amount again, and it can be charged back this time.
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert_eq!(acc.available(), dec!(0.0));
+        assert_eq!(acc.held(), dec!(5.0));
+        assert!(acc.apply(1, Chargeback).is_ok());
+        assert_eq!(acc.total_balance(), dec!(0.0));
+        assert!(acc.is_locked());
+        assert_eq!(acc.check_invariants(), vec![]);
+    }
+
+    #[test]
+    fn test_thaw_only_undoes_freeze() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.apply(0, Freeze).is_ok());
+        assert!(acc.is_frozen());
+        assert_eq!(acc.status(), AccountStatus::Locked);
+
+        // The freeze survives saving and restoring the account.
+        let restored: Account =
+            serde_json::from_str(&serde_json::to_string(&*acc).unwrap()).unwrap();
+        assert!(restored.is_frozen());
+
+        assert!(acc.apply(0, Thaw).is_ok());
+        assert!(!acc.is_locked());
+
+        // A chargeback while frozen still locks the account once it's thawed.
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(0, Freeze).is_ok());
+        acc.account.policy.allow_deposits_when_locked = true;
+        assert!(acc.apply(1, Chargeback).is_ok());
+        assert!(acc.apply(0, Thaw).is_ok());
+        assert!(!acc.is_frozen());
+        assert!(acc.is_locked());
+    }
+
+    #[test]
+    fn test_charged_back_dispute_cannot_be_reopened() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(3.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(1, Chargeback).is_ok());
+
+        // Thawing the account only undoes a freeze, so it stays locked.
+        assert!(acc.apply(0, Thaw).is_ok());
+        assert!(acc.is_locked());
+        assert_eq!(
+            acc.apply(1, Dispute(None)),
+            Err(TxError::AccountLocked { client: 1 })
+        );
+
+        // Even if it was unlocked some other way, e.g. by editing its saved state, the chargeback
+        // is final, so nothing more is held.
+        acc.account.locked = false;
+        assert_eq!(
+            acc.apply(1, Dispute(None)),
+            Err(TxError::AlreadyChargedBack { tx: 1 })
+        );
+        assert_eq!(
+            acc.apply(1, Dispute(Some(dec!(1.0)))),
+            Err(TxError::AlreadyChargedBack { tx: 1 })
+        );
+        assert_eq!(
+            acc.apply(1, Resolve),
+            Err(TxError::UnknownDispute { tx: 1 })
+        );
+        assert_eq!(acc.available(), dec!(3.0));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert_eq!(acc.chargeback_count(), 1);
     }
 
     #[test]
     fn test_resolve_unrecognized_dispute() {
-        let mut acc = Account::new(1);
+        let account = || {
+            AccountBuilder::new(1)
+                .available(dec!(2.0))
+                .held(dec!(1.0))
+                .dispute(1, dec!(1.0))
+                .build()
+        };
+        let mut acc = TestAccount::from(account());
 
-        assert!(acc.deposit(dec!(1.0)).is_ok());
-        assert!(acc.deposit(dec!(2.0)).is_ok());
-        assert!(acc.dispute(33, dec!(1.2)).is_ok());
-        assert!(acc.resolve(36).is_err());
+        assert_eq!(
+            acc.apply(2, Resolve),
+            Err(TxError::UnknownDispute { tx: 2 })
+        );
+        assert_eq!(
+            acc.apply(3, Resolve),
+            Err(TxError::UnknownDispute { tx: 3 })
+        );
+        assert_eq!(acc.account, account());
     }
 
     #[test]
     fn test_multiple_disputes() {
-        let mut acc = Account::new(1);
+        let mut acc = TestAccount::new(AccountPolicy::default());
 
-        assert!(acc.deposit(dec!(1.0)).is_ok());
-        assert!(acc.deposit(dec!(2.0)).is_ok());
-        assert!(acc.dispute(33, dec!(1.2)).is_ok());
-        assert!(acc.dispute(66, dec!(1.0)).is_ok());
+        assert!(acc.apply(1, Deposit(dec!(1.2))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(1.0))).is_ok());
+        assert!(acc.apply(3, Deposit(dec!(0.8))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(2, Dispute(None)).is_ok());
 
-        assert_eq!(acc.available, dec!(0.8));
-        assert_eq!(acc.held, dec!(2.2));
+        assert_eq!(acc.available(), dec!(0.8));
+        assert_eq!(acc.held(), dec!(2.2));
         assert_eq!(acc.total_balance(), dec!(3.0));
 
         // Resolve the second dispute
-        assert!(acc.resolve(66).is_ok());
-        assert_eq!(acc.available, dec!(1.8));
-        assert_eq!(acc.held, dec!(1.2));
+        assert!(acc.apply(2, Resolve).is_ok());
+        assert_eq!(acc.available(), dec!(1.8));
+        assert_eq!(acc.held(), dec!(1.2));
         assert_eq!(acc.total_balance(), dec!(3.0));
 
         // Chargeback the first
-        assert!(acc.chargeback(33).is_ok());
-        assert_eq!(acc.available, dec!(1.8));
-        assert_eq!(acc.held, dec!(0.0));
+        assert!(acc.apply(1, Chargeback).is_ok());
+        assert_eq!(acc.available(), dec!(1.8));
+        assert_eq!(acc.held(), dec!(0.0));
         assert_eq!(acc.total_balance(), dec!(1.8));
     }
+
+    #[test]
+    fn test_dispute_insufficient_funds() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(2.0))).is_ok());
+        assert!(acc.apply(2, Withdrawal(dec!(1.5))).is_ok());
+        assert_eq!(acc.apply(1, Dispute(None)), Err(TxError::InsufficientFunds));
+        assert_eq!(acc.available(), dec!(0.5));
+        assert_eq!(acc.held(), dec!(0.0));
+
+        let policy = AccountPolicy {
+            dispute: DisputePolicy::AllowNegative,
+            ..Default::default()
+        };
+        let mut acc = TestAccount::new(policy);
+        assert!(acc.apply(1, Deposit(dec!(2.0))).is_ok());
+        assert!(acc.apply(2, Withdrawal(dec!(1.5))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert_eq!(acc.available(), dec!(-1.5));
+        assert_eq!(acc.held(), dec!(2.0));
+        assert_eq!(acc.total_balance(), dec!(0.5));
+    }
+
+    #[test]
+    fn test_withdrawal_with_negative_available() {
+        let records = || {
+            [
+                (1, Deposit(dec!(2.0))),
+                (2, Deposit(dec!(3.0))),
+                (3, Withdrawal(dec!(4.0))),
+                (1, Dispute(None)),
+            ]
+        };
+        let policy = AccountPolicy {
+            dispute: DisputePolicy::AllowNegative,
+            ..Default::default()
+        };
+        let mut acc = TestAccount::new(policy);
+        for (tx_id, op) in records() {
+            assert!(acc.apply(tx_id, op).is_ok());
+        }
+        assert_eq!(acc.available(), dec!(-1.0));
+
+        // Nothing can be withdrawn while the available balance is negative, not even nothing.
+        for amount in [dec!(0.5), dec!(0.0)] {
+            assert_eq!(
+                acc.apply(4, Withdrawal(amount)),
+                Err(TxError::InsufficientFunds)
+            );
+        }
+
+        // Once the debt is repaid, only the funds above zero can be withdrawn.
+        assert!(acc.apply(5, Deposit(dec!(1.5))).is_ok());
+        assert_eq!(
+            acc.apply(6, Withdrawal(dec!(1.0))),
+            Err(TxError::InsufficientFunds)
+        );
+        assert!(acc.apply(7, Withdrawal(dec!(0.5))).is_ok());
+        assert_eq!(acc.available(), dec!(0.0));
+
+        // When held funds can be withdrawn, they cover the debt first.
+        let mut acc = TestAccount::new(AccountPolicy {
+            allow_withdrawing_held: true,
+            ..policy
+        });
+        for (tx_id, op) in records() {
+            assert!(acc.apply(tx_id, op).is_ok());
+        }
+        assert_eq!(
+            acc.apply(4, Withdrawal(dec!(1.5))),
+            Err(TxError::InsufficientFunds)
+        );
+        assert!(acc.apply(5, Withdrawal(dec!(1.0))).is_ok());
+        assert_eq!(acc.available(), dec!(-1.0));
+        assert_eq!(acc.held(), dec!(1.0));
+        assert_eq!(acc.total_balance(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_duplicate_dispute() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(1.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(2.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert_eq!(
+            acc.apply(1, Dispute(None)),
+            Err(TxError::DisputeAlreadyOpen { tx: 1 })
+        );
+        assert_eq!(acc.held(), dec!(1.0));
+
+        let policy = AccountPolicy {
+            duplicate_dispute: DuplicateDisputePolicy::Ignore,
+            ..Default::default()
+        };
+        let mut acc = TestAccount::new(policy);
+        assert!(acc.apply(1, Deposit(dec!(1.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(2.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+
+        // The funds are only held once.
+        assert_eq!(acc.available(), dec!(2.0));
+        assert_eq!(acc.held(), dec!(1.0));
+
+        assert!(acc.apply(1, Resolve).is_ok());
+        assert_eq!(acc.available(), dec!(3.0));
+        assert_eq!(acc.held(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_partial_dispute_chargeback() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+
+        assert!(acc.apply(1, Deposit(dec!(4.0))).is_ok());
+        assert!(acc.apply(1, Dispute(Some(dec!(2.0)))).is_ok());
+
+        assert_eq!(acc.available(), dec!(2.0));
+        assert_eq!(acc.held(), dec!(2.0));
+        assert_eq!(acc.total_balance(), dec!(4.0));
+
+        // Only the disputed half is charged back, the rest stays available.
+        assert!(acc.apply(1, Chargeback).is_ok());
+        assert_eq!(acc.available(), dec!(2.0));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert_eq!(acc.total_balance(), dec!(2.0));
+    }
+
+    #[test]
+    fn test_partial_dispute_cannot_exceed_deposit() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+
+        assert!(acc.apply(1, Deposit(dec!(4.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(4.0))).is_ok());
+        assert_eq!(
+            acc.apply(1, Dispute(Some(dec!(4.5)))),
+            Err(TxError::InvalidDisputeAmount {
+                tx: 1,
+                amount: dec!(4.5)
+            })
+        );
+        assert_eq!(
+            acc.apply(1, Dispute(Some(dec!(-1.0)))),
+            Err(TxError::InvalidDisputeAmount {
+                tx: 1,
+                amount: dec!(-1.0)
+            })
+        );
+
+        assert_eq!(acc.available(), dec!(8.0));
+        assert_eq!(acc.held(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_stacked_partial_disputes() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+
+        assert!(acc.apply(1, Deposit(dec!(10.0))).is_ok());
+        assert!(acc.apply(1, Dispute(Some(dec!(3.5)))).is_ok());
+        assert!(acc.apply(1, Dispute(Some(dec!(6.0)))).is_ok());
+
+        // Only 0.5 of the deposit is left undisputed.
+        assert_eq!(
+            acc.apply(1, Dispute(Some(dec!(1.0)))),
+            Err(TxError::InvalidDisputeAmount {
+                tx: 1,
+                amount: dec!(1.0)
+            })
+        );
+        assert_eq!(acc.available(), dec!(0.5));
+        assert_eq!(acc.held(), dec!(9.5));
+
+        // A single chargeback covers everything held for the transaction.
+        assert!(acc.apply(1, Chargeback).is_ok());
+        assert_eq!(acc.available(), dec!(0.5));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert!(acc.is_locked());
+    }
+
+    #[test]
+    fn test_balance_overflow() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+
+        assert!(acc.apply(1, Deposit(Decimal::MAX)).is_ok());
+        assert_eq!(acc.apply(2, Deposit(dec!(1.0))), Err(TxError::Overflow));
+        assert_eq!(acc.available(), Decimal::MAX);
+
+        // Each balance fits, but the total wouldn't.
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert_eq!(acc.apply(2, Deposit(dec!(1.0))), Err(TxError::Overflow));
+        assert_eq!(acc.available(), dec!(0.0));
+        assert_eq!(acc.held(), Decimal::MAX);
+        assert_eq!(acc.total_balance(), Decimal::MAX);
+    }
+
+    #[test]
+    fn test_close() {
+        // Once closed, further deposits are refused, even if they'd be allowed into a locked account.
+        for allow_deposits_when_locked in [false, true] {
+            let mut acc = TestAccount::new(AccountPolicy {
+                allow_deposits_when_locked,
+                ..Default::default()
+            });
+
+            // The account can't be closed while it still has funds.
+            assert!(acc.apply(1, Deposit(dec!(2.0))).is_ok());
+            assert_eq!(
+                acc.apply(0, Close),
+                Err(TxError::FundsRemaining { client: 1 })
+            );
+            assert_eq!(acc.status(), AccountStatus::Open);
+
+            assert!(acc.apply(2, Withdrawal(dec!(2.0))).is_ok());
+            assert!(acc.apply(0, Close).is_ok());
+            assert_eq!(acc.status(), AccountStatus::Closed);
+
+            assert_eq!(
+                acc.apply(3, Deposit(dec!(1.0))),
+                Err(TxError::AccountClosed { client: 1 })
+            );
+            assert_eq!(acc.total_balance(), dec!(0.0));
+        }
+    }
+
+    #[test]
+    fn test_negative_limit() {
+        let policy = AccountPolicy {
+            dispute: DisputePolicy::AllowNegative,
+            negative_limit: Some(dec!(3.0)),
+            ..Default::default()
+        };
+        let mut acc = TestAccount::new(policy);
+        assert!(acc.apply(1, Deposit(dec!(10.0))).is_ok());
+        assert!(acc.apply(2, Withdrawal(dec!(8.0))).is_ok());
+
+        // Holding all 10.0 would take available to -8.0, beyond the limit of -3.0.
+        assert_eq!(acc.apply(1, Dispute(None)), Err(TxError::InsufficientFunds));
+        assert_eq!(acc.available(), dec!(2.0));
+        assert_eq!(acc.held(), dec!(0.0));
+
+        // When capping, as much as the limit allows is held instead.
+        let mut acc = TestAccount::new(AccountPolicy {
+            negative_limit_action: NegativeLimitAction::Cap,
+            ..policy
+        });
+        assert!(acc.apply(1, Deposit(dec!(10.0))).is_ok());
+        assert!(acc.apply(3, Deposit(dec!(1.0))).is_ok());
+        assert!(acc.apply(2, Withdrawal(dec!(9.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert_eq!(acc.available(), dec!(-3.0));
+        assert_eq!(acc.held(), dec!(5.0));
+
+        // There's no room left for any further disputes.
+        assert_eq!(acc.apply(3, Dispute(None)), Err(TxError::InsufficientFunds));
+        assert!(acc.apply(1, Resolve).is_ok());
+        assert_eq!(acc.available(), dec!(2.0));
+        assert_eq!(acc.held(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_chargeback() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+
+        assert!(acc.apply(1, Deposit(dec!(10.0))).is_ok());
+        assert!(acc.apply(2, Withdrawal(dec!(4.0))).is_ok());
+        assert!(acc.apply(2, Dispute(None)).is_ok());
+
+        // Nothing is held for a disputed withdrawal.
+        assert_eq!(acc.available(), dec!(6.0));
+        assert_eq!(acc.held(), dec!(0.0));
+
+        // The chargeback returns the withdrawn funds.
+        assert!(acc.apply(2, Chargeback).is_ok());
+        assert_eq!(acc.available(), dec!(10.0));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert_eq!(acc.total_balance(), dec!(10.0));
+        assert!(acc.is_locked());
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_resolve() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+
+        assert!(acc.apply(1, Deposit(dec!(10.0))).is_ok());
+        assert!(acc.apply(2, Withdrawal(dec!(4.0))).is_ok());
+        assert!(acc.apply(2, Dispute(Some(dec!(1.5)))).is_ok());
+        assert!(acc.apply(2, Resolve).is_ok());
+
+        assert_eq!(acc.available(), dec!(6.0));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert!(!acc.is_locked());
+    }
+
+    #[test]
+    fn test_max_open_disputes() {
+        let policy = AccountPolicy {
+            max_open_disputes: Some(2),
+            ..Default::default()
+        };
+        let mut acc = TestAccount::new(policy);
+        assert!(acc.apply(1, Deposit(dec!(1.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(3.0))).is_ok());
+        assert!(acc.apply(3, Deposit(dec!(1.0))).is_ok());
+
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(2, Dispute(Some(dec!(1.0)))).is_ok());
+        assert_eq!(
+            acc.apply(3, Dispute(None)),
+            Err(TxError::TooManyDisputes { client: 1 })
+        );
+
+        // Adding to an open dispute is still allowed.
+        assert!(acc.apply(2, Dispute(Some(dec!(1.0)))).is_ok());
+
+        // Resolving a dispute frees up room for another.
+        assert!(acc.apply(1, Resolve).is_ok());
+        assert!(acc.apply(3, Dispute(None)).is_ok());
+        assert_eq!(acc.held(), dec!(3.0));
+    }
+
+    #[test]
+    fn test_pending_deposits() {
+        let policy = AccountPolicy {
+            deposit_hold: 2,
+            ..Default::default()
+        };
+        let mut acc = TestAccount::new(policy);
+
+        acc.account.settle_deposits();
+        assert!(acc.apply(1, OpeningBalance(dec!(1.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(5.0))).is_ok());
+        assert_eq!(acc.available(), dec!(1.0));
+        assert_eq!(acc.held(), dec!(5.0));
+
+        // Only the settled funds can be withdrawn while the deposit is held.
+        acc.account.settle_deposits();
+        assert_eq!(
+            acc.apply(3, Withdrawal(dec!(3.0))),
+            Err(TxError::InsufficientFunds)
+        );
+
+        acc.account.settle_deposits();
+        assert!(acc.apply(4, Withdrawal(dec!(3.0))).is_ok());
+        assert_eq!(acc.available(), dec!(3.0));
+        assert_eq!(acc.held(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_dispute_pending_deposit() {
+        let policy = AccountPolicy {
+            deposit_hold: 1,
+            ..Default::default()
+        };
+        let mut acc = TestAccount::new(policy);
+
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.apply(1, Dispute(Some(dec!(2.0)))).is_ok());
+        assert_eq!(acc.held(), dec!(5.0));
+
+        // Only the undisputed part of the deposit is released when it settles.
+        acc.account.settle_deposits();
+        assert_eq!(acc.available(), dec!(3.0));
+        assert_eq!(acc.held(), dec!(2.0));
+
+        assert!(acc.apply(1, Resolve).is_ok());
+        assert_eq!(acc.available(), dec!(5.0));
+        assert_eq!(acc.held(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_resolve_pending_deposit() {
+        let policy = AccountPolicy {
+            deposit_hold: 3,
+            ..Default::default()
+        };
+        let mut acc = TestAccount::new(policy);
+
+        acc.account.settle_deposits();
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        acc.account.settle_deposits();
+        assert!(acc.apply(1, Dispute(Some(dec!(2.0)))).is_ok());
+
+        // Resolving the dispute before the deposit settles leaves all of it held until it does.
+        acc.account.settle_deposits();
+        assert!(acc.apply(1, Resolve).is_ok());
+        assert_eq!(acc.available(), dec!(0.0));
+        assert_eq!(acc.held(), dec!(5.0));
+        assert!(acc.account.check_invariants().is_empty());
+
+        acc.account.settle_deposits();
+        assert_eq!(acc.available(), dec!(5.0));
+        assert_eq!(acc.held(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_open_disputes() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(2.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(3.0))).is_ok());
+        assert!(acc.apply(3, Deposit(dec!(1.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(2, Dispute(Some(dec!(1.0)))).is_ok());
+        assert!(acc.apply(3, Dispute(None)).is_ok());
+        assert!(acc.apply(3, Resolve).is_ok());
+
+        let mut open: Vec<_> = acc.open_disputes().collect();
+        open.sort();
+        assert_eq!(open, [(1, dec!(2.0)), (2, dec!(1.0))]);
+    }
+
+    #[test]
+    fn test_withdrawing_held_funds() {
+        let records = || {
+            [
+                (1, Deposit(dec!(5.0))),
+                (2, Deposit(dec!(3.0))),
+                (1, Dispute(None)),
+            ]
+        };
+
+        // By default only the available funds can be withdrawn, never the held ones.
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        for (tx_id, op) in records() {
+            assert!(acc.apply(tx_id, op).is_ok());
+        }
+        assert_eq!(
+            acc.apply(3, Withdrawal(dec!(4.0))),
+            Err(TxError::InsufficientFunds)
+        );
+        assert_eq!(acc.available(), dec!(3.0));
+        assert_eq!(acc.held(), dec!(5.0));
+
+        let policy = AccountPolicy {
+            allow_withdrawing_held: true,
+            ..Default::default()
+        };
+        let mut acc = TestAccount::new(policy);
+        for (tx_id, op) in records() {
+            assert!(acc.apply(tx_id, op).is_ok());
+        }
+        assert!(acc.apply(3, Withdrawal(dec!(4.0))).is_ok());
+        assert_eq!(acc.available(), dec!(0.0));
+        assert_eq!(acc.held(), dec!(4.0));
+
+        // There's still a limit of the total funds.
+        assert_eq!(
+            acc.apply(4, Withdrawal(dec!(5.0))),
+            Err(TxError::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn test_held_breakdown() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(3.0))).is_ok());
+        assert!(acc.apply(3, Withdrawal(dec!(2.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(3, Dispute(None)).is_ok());
+        assert_eq!(acc.open_disputes().count(), 2);
+        assert!(acc.apply(4, Reserve(dec!(1.0))).is_ok());
+
+        // The breakdown is only in the extended output.
+        assert_eq!(acc.to_display().held_from_deposits, None);
+        assert_eq!(acc.to_display().held_from_withdrawals, None);
+
+        let display = acc.to_extended_display();
+        let from_deposits = display.held_from_deposits.unwrap();
+        let from_withdrawals = display.held_from_withdrawals.unwrap();
+        // Disputing the withdrawal holds nothing, but the reserved withdrawal is held.
+        assert_eq!(from_deposits, dec!(5.0));
+        assert_eq!(from_withdrawals, dec!(1.0));
+        assert_eq!(from_deposits + from_withdrawals, display.held);
+    }
+
+    #[test]
+    fn test_reserve_commit() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.apply(2, Reserve(dec!(2.0))).is_ok());
+        assert_eq!(acc.available(), dec!(3.0));
+        assert_eq!(acc.reserved(), dec!(2.0));
+        assert_eq!(acc.total_balance(), dec!(5.0));
+        assert_eq!(acc.to_display().held, dec!(2.0));
+
+        // Reserved funds can't be reserved again.
+        assert_eq!(
+            acc.apply(3, Reserve(dec!(4.0))),
+            Err(TxError::InsufficientFunds)
+        );
+
+        assert!(acc.apply(2, Commit).is_ok());
+        assert_eq!(acc.available(), dec!(3.0));
+        assert_eq!(acc.reserved(), dec!(0.0));
+        assert_eq!(acc.total_balance(), dec!(3.0));
+
+        // A reservation can only be committed once, and can't be released afterwards.
+        assert_eq!(
+            acc.apply(2, Commit),
+            Err(TxError::UnknownReservation { id: 2 })
+        );
+        assert_eq!(
+            acc.apply(2, Release),
+            Err(TxError::UnknownReservation { id: 2 })
+        );
+        assert_eq!(acc.total_balance(), dec!(3.0));
+    }
+
+    #[test]
+    fn test_reserve_release() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.apply(2, Reserve(dec!(2.0))).is_ok());
+        assert!(acc.apply(2, Release).is_ok());
+        assert_eq!(acc.available(), dec!(5.0));
+        assert_eq!(acc.reserved(), dec!(0.0));
+        assert_eq!(
+            acc.apply(2, Commit),
+            Err(TxError::UnknownReservation { id: 2 })
+        );
+    }
+
+    #[test]
+    fn test_reserve_on_locked_account() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(3.0))).is_ok());
+        assert!(acc.apply(3, Reserve(dec!(1.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(1, Chargeback).is_ok());
+
+        assert_eq!(
+            acc.apply(4, Reserve(dec!(1.0))),
+            Err(TxError::AccountLocked { client: 1 })
+        );
+        assert_eq!(acc.available(), dec!(2.0));
+
+        // A withdrawal authorized before the account was locked can still be completed.
+        assert!(acc.apply(3, Commit).is_ok());
+        assert_eq!(acc.total_balance(), dec!(2.0));
+    }
+
+    #[test]
+    fn test_history() {
+        let mut acc = TestAccount::from(Account::new(1).with_history());
+        let mut balances = Vec::new();
+        for (tx_id, op) in [
+            (1, Deposit(dec!(5.0))),
+            (2, Withdrawal(dec!(1.0))),
+            (1, Dispute(Some(dec!(2.0)))),
+            (3, Withdrawal(dec!(10.0))),
+            (1, Resolve),
+        ] {
+            if acc.apply(tx_id, op).is_ok() {
+                balances.push((acc.available(), acc.held()));
+            }
+        }
+
+        // Only the transactions that were applied are recorded, with the balances they left.
+        let history = acc.history();
+        assert_eq!(history.len(), 4);
+        let recorded: Vec<_> = history.iter().map(|h| (h.available, h.held)).collect();
+        assert_eq!(recorded, balances);
+        assert_eq!(history[2].op, "dispute");
+        assert_eq!(history[2].tx, 1);
+        assert_eq!(history[2].amount, Some(dec!(2.0)));
+        assert_eq!(history[3].amount, None);
+
+        // Nothing is recorded unless asked for.
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.history().is_empty());
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(3.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(1, Resolve).is_ok());
+        assert!(acc.check_invariants().is_empty());
+
+        // Corrupt the state the way resolving the same dispute twice once did.
+        acc.account.available = dec!(13.0);
+        acc.account.held = dec!(-5.0);
+        assert_eq!(
+            acc.check_invariants(),
+            vec![
+                InvariantViolation::HeldMismatch {
+                    held: dec!(-5.0),
+                    expected: dec!(0.0)
+                },
+                InvariantViolation::NegativeHeld { held: dec!(-5.0) },
+            ]
+        );
+
+        acc.account.held = dec!(0.0);
+        acc.account.available = dec!(-1.0);
+        assert_eq!(
+            acc.check_invariants(),
+            vec![InvariantViolation::NegativeAvailable {
+                available: dec!(-1.0)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_getters_are_unrounded() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert_eq!(acc.apply(1, Deposit(dec!(1.23456789))), Ok(()));
+        assert_eq!(acc.apply(2, Deposit(dec!(0.00004))), Ok(()));
+        assert_eq!(acc.apply(2, Dispute(None)), Ok(()));
+
+        assert_eq!(acc.client(), 1);
+        assert_eq!(acc.available(), dec!(1.23456789));
+        assert_eq!(acc.held(), dec!(0.00004));
+        assert_eq!(acc.total_balance(), dec!(1.23460789));
+        assert!(!acc.is_locked());
+
+        // Whereas the output rounds them.
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.serialize(acc.to_display()).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "client,available,held,total,locked\n1,1.2346,0.0000,1.2346,false\n"
+        );
+    }
+
+    #[test]
+    fn test_display_four_places() {
+        let display = |available, held| {
+            let account = AccountBuilder::new(1)
+                .available(available)
+                .held(held)
+                .build();
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            writer.serialize(account.to_display()).unwrap();
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        };
+
+        assert_eq!(
+            display(dec!(1.5), dec!(2)),
+            "client,available,held,total,locked\n1,1.5000,2.0000,3.5000,false\n"
+        );
+        // Halfway cases round to even.
+        assert_eq!(
+            display(dec!(1.49999999), dec!(0.00005)),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+        assert_eq!(
+            display(dec!(-0.00015), dec!(0.12345)),
+            "client,available,held,total,locked\n1,-0.0002,0.1234,0.1233,false\n"
+        );
+        assert_eq!(
+            display(dec!(-0.00001), Decimal::MAX),
+            format!(
+                "client,available,held,total,locked\n1,0.0000,{0}.0000,{0}.0000,false\n",
+                Decimal::MAX
+            )
+        );
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let mut acc = TestAccount::new(AccountPolicy::default());
+        assert!(acc.apply(1, Deposit(dec!(5.0))).is_ok());
+        assert!(acc.apply(2, Deposit(dec!(3.0))).is_ok());
+        assert!(acc.apply(3, Deposit(dec!(1.0))).is_ok());
+        assert!(acc.apply(1, Dispute(None)).is_ok());
+        assert!(acc.apply(2, Dispute(Some(dec!(1.0)))).is_ok());
+        assert!(acc.apply(4, Reserve(dec!(1.0))).is_ok());
+
+        let saved = serde_json::to_string(&acc.account).unwrap();
+        let restored: Account = serde_json::from_str(&saved).unwrap();
+        assert_eq!(restored, acc.account);
+        acc.account = restored;
+
+        // The restored disputes and reservations can still be settled.
+        assert!(acc.apply(2, Resolve).is_ok());
+        assert!(acc.apply(1, Chargeback).is_ok());
+        assert!(acc.apply(4, Release).is_ok());
+        assert_eq!(acc.available(), dec!(4.0));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert!(acc.is_locked());
+    }
+
+    #[test]
+    fn test_deserialize_without_newer_fields() {
+        let saved = r#"{
+            "client": 7,
+            "available": "2.5",
+            "held": "1.0",
+            "locked": false,
+            "disputes": [{"tx": 3, "amount": "1.0", "direction": "Credit"}]
+        }"#;
+        let mut acc: Account = serde_json::from_str(saved).unwrap();
+        assert_eq!(acc.client(), 7);
+        assert_eq!(acc.status(), AccountStatus::Open);
+
+        assert!(acc.apply(3, &Resolve, &HashMap::new()).is_ok());
+        assert_eq!(acc.available(), dec!(3.5));
+        assert_eq!(acc.held(), dec!(0.0));
+    }
 }
@@ -0,0 +1,150 @@
+//! The numeric types account balances can be kept in.
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+
+/// A type an [`crate::account::Account`] can keep its balances in. `Decimal` is the default, as
+/// it's exact for any amount in the transaction log, but [`Cents`] is faster where every amount is
+/// a whole number of cents.
+///
+/// Amounts are read and reported as `Decimal`s, e.g. in the transaction log, errors and the
+/// output, so each type converts to and from them in its own unit.
+pub trait Amount:
+    Copy + Default + Ord + fmt::Debug + Add<Output = Self> + Sub<Output = Self> + AddAssign + SubAssign
+{
+    const ZERO: Self;
+
+    /// Add, or return `None` if the result can't be represented.
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    /// Subtract, or return `None` if the result can't be represented.
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    /// Add, limiting the result to the largest or smallest amount that can be represented.
+    fn saturating_add(self, other: Self) -> Self;
+
+    /// The amount as a `Decimal`, saturating if it's out of range.
+    fn to_decimal(self) -> Decimal;
+
+    /// The amount nearest a `Decimal` towards zero, or `None` if it's out of range.
+    fn from_decimal(amount: Decimal) -> Option<Self>;
+
+    /// The amount equal to a `Decimal`, or `None` if there isn't one, e.g. for a fraction of a
+    /// cent.
+    fn from_decimal_exact(amount: Decimal) -> Option<Self> {
+        Self::from_decimal(amount).filter(|converted| converted.to_decimal() == amount)
+    }
+}
+
+impl Amount for Decimal {
+    const ZERO: Self = Decimal::ZERO;
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Decimal::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        Decimal::checked_sub(self, other)
+    }
+
+    fn saturating_add(self, other: Self) -> Self {
+        Decimal::saturating_add(self, other)
+    }
+
+    fn to_decimal(self) -> Decimal {
+        self
+    }
+
+    fn from_decimal(amount: Decimal) -> Option<Self> {
+        Some(amount)
+    }
+}
+
+/// An amount kept as a whole number of cents (hundredths), which is faster to add up than a
+/// `Decimal`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cents(pub i128);
+
+impl Add for Cents {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Cents(self.0 + other.0)
+    }
+}
+
+impl Sub for Cents {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Cents(self.0 - other.0)
+    }
+}
+
+impl AddAssign for Cents {
+    fn add_assign(&mut self, other: Self) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Cents {
+    fn sub_assign(&mut self, other: Self) {
+        self.0 -= other.0;
+    }
+}
+
+impl Amount for Cents {
+    const ZERO: Self = Cents(0);
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Cents)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Cents)
+    }
+
+    fn saturating_add(self, other: Self) -> Self {
+        Cents(self.0.saturating_add(other.0))
+    }
+
+    fn to_decimal(self) -> Decimal {
+        Decimal::try_from_i128_with_scale(self.0, 2).unwrap_or(if self.0 < 0 {
+            Decimal::MIN
+        } else {
+            Decimal::MAX
+        })
+    }
+
+    fn from_decimal(amount: Decimal) -> Option<Self> {
+        amount
+            .checked_mul(Decimal::ONE_HUNDRED)?
+            .trunc()
+            .to_i128()
+            .map(Cents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_cents() {
+        assert_eq!(Amount::checked_add(Cents(i128::MAX), Cents(1)), None);
+        assert_eq!(
+            Amount::saturating_add(Cents(i128::MAX), Cents(1)),
+            Cents(i128::MAX)
+        );
+        assert_eq!(Cents(1234).to_decimal(), dec!(12.34));
+        assert_eq!(Cents(i128::MAX).to_decimal(), Decimal::MAX);
+        assert_eq!(Cents::from_decimal(dec!(5.00)), Some(Cents(500)));
+        assert_eq!(Cents::from_decimal(dec!(-12.999)), Some(Cents(-1299)));
+        assert_eq!(Cents::from_decimal(Decimal::MAX), None);
+        assert_eq!(Cents::from_decimal_exact(dec!(0.1)), Some(Cents(10)));
+        assert_eq!(Cents::from_decimal_exact(dec!(0.001)), None);
+    }
+}
@@ -0,0 +1,424 @@
+use crate::account::{DisputableTx, DisputeDirection};
+use crate::engine::SeenTx;
+use crate::error::StoreError;
+use crate::store::{DepositStore, StoreSize};
+use rust_decimal::Decimal;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+// How many slots a new store has. It doubles whenever it gets half full.
+const INITIAL_CAPACITY: u64 = 1 << 16;
+
+// The layout of a slot: flags, the transaction ID, the deposit (its amount, direction and line),
+// then where the transaction was seen (its line, client, direction and whether it was paired).
+const FLAGS: usize = 0;
+const TX_ID: usize = 1;
+const AMOUNT: usize = TX_ID + 4;
+const DIRECTION: usize = AMOUNT + 16;
+const LINE: usize = DIRECTION + 1;
+const SEEN_LINE: usize = LINE + 8;
+const SEEN_CLIENT: usize = SEEN_LINE + 8;
+const SEEN_DIRECTION: usize = SEEN_CLIENT + 2;
+const SEEN_PAIRED: usize = SEEN_DIRECTION + 1;
+const SLOT_LEN: usize = SEEN_PAIRED + 1;
+
+// The flags of a slot in use, and which of its parts are present.
+const OCCUPIED: u8 = 1;
+const HAS_DEPOSIT: u8 = 2;
+const HAS_SEEN: u8 = 4;
+
+/// The transactions that might be disputed, and where every deposit and withdrawal was first seen,
+/// kept in a hash table in a file rather than in memory, so the memory used stays bounded however
+/// many transactions a log has. Lookups and updates read and write single slots of the file,
+/// leaving it to the OS to cache the parts that are used often.
+///
+/// The file is deleted once the store is dropped.
+pub struct DiskDeposits {
+    file: File,
+    path: PathBuf,
+    // The number of slots in the file, which is always a power of two.
+    capacity: u64,
+    // The number of slots in use, and how many of them keep a deposit, and where a transaction
+    // was seen.
+    len: u64,
+    deposits: usize,
+    seen: usize,
+}
+
+/// What's kept for a transaction ID.
+#[derive(Default)]
+struct Slot {
+    deposit: Option<DisputableTx>,
+    seen: Option<SeenTx>,
+}
+
+impl DiskDeposits {
+    /// Create a store in a new file at `path`, which is deleted along with the store.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_capacity(path.as_ref().to_path_buf(), INITIAL_CAPACITY)
+    }
+
+    fn with_capacity(path: PathBuf, capacity: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        file.set_len(capacity * SLOT_LEN as u64)?;
+        Ok(Self {
+            file,
+            path,
+            capacity,
+            len: 0,
+            deposits: 0,
+            seen: 0,
+        })
+    }
+
+    // Find the slot for a transaction ID, returning its index and what's kept in it, if anything.
+    // The table is never full, so there's always an empty slot to stop at.
+    fn find(&self, tx_id: u32) -> io::Result<(u64, Option<Slot>)> {
+        let mask = self.capacity - 1;
+        // Fibonacci hashing spreads out sequential IDs.
+        let mut index = (u64::from(tx_id).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) & mask;
+        let mut bytes = [0; SLOT_LEN];
+        loop {
+            self.file
+                .read_exact_at(&mut bytes, index * SLOT_LEN as u64)?;
+            if bytes[FLAGS] & OCCUPIED == 0 {
+                return Ok((index, None));
+            }
+            if read_u32(&bytes, TX_ID) == tx_id {
+                return Ok((index, Some(decode(&bytes))));
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    // Update what's kept for a transaction ID.
+    fn update(&mut self, tx_id: u32, update: impl FnOnce(&mut Slot)) -> io::Result<()> {
+        let (index, slot) = self.find(tx_id)?;
+        let is_new = slot.is_none();
+        let mut slot = slot.unwrap_or_default();
+        let (had_deposit, had_seen) = (slot.deposit.is_some(), slot.seen.is_some());
+        update(&mut slot);
+        self.file
+            .write_all_at(&encode(tx_id, &slot), index * SLOT_LEN as u64)?;
+        self.deposits =
+            self.deposits + usize::from(slot.deposit.is_some()) - usize::from(had_deposit);
+        self.seen = self.seen + usize::from(slot.seen.is_some()) - usize::from(had_seen);
+
+        if is_new {
+            self.len += 1;
+            if self.len * 2 > self.capacity {
+                self.grow()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Move everything into a file with twice as many slots, which replaces this one.
+    fn grow(&mut self) -> io::Result<()> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".grow");
+        let mut grown = Self::with_capacity(path.into(), self.capacity * 2)?;
+
+        let mut reader = BufReader::new(&self.file);
+        let mut bytes = [0; SLOT_LEN];
+        for _ in 0..self.capacity {
+            reader.read_exact(&mut bytes)?;
+            if bytes[FLAGS] & OCCUPIED != 0 {
+                let (index, _) = grown.find(read_u32(&bytes, TX_ID))?;
+                grown.file.write_all_at(&bytes, index * SLOT_LEN as u64)?;
+            }
+        }
+        grown.len = self.len;
+        grown.deposits = self.deposits;
+        grown.seen = self.seen;
+
+        std::fs::rename(&grown.path, &self.path)?;
+        // Once swapped, dropping the old store tries to delete the file that was renamed, rather
+        // than the one replacing it.
+        std::mem::swap(&mut grown.path, &mut self.path);
+        std::mem::swap(self, &mut grown);
+        Ok(())
+    }
+}
+
+impl Drop for DiskDeposits {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn read_u32(bytes: &[u8; SLOT_LEN], at: usize) -> u32 {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().expect("four bytes"))
+}
+
+fn read_u64(bytes: &[u8; SLOT_LEN], at: usize) -> u64 {
+    u64::from_be_bytes(bytes[at..at + 8].try_into().expect("eight bytes"))
+}
+
+fn encode_direction(direction: DisputeDirection) -> u8 {
+    match direction {
+        DisputeDirection::Credit => 0,
+        DisputeDirection::Debit => 1,
+    }
+}
+
+fn decode_direction(byte: u8) -> DisputeDirection {
+    match byte {
+        0 => DisputeDirection::Credit,
+        _ => DisputeDirection::Debit,
+    }
+}
+
+fn encode(tx_id: u32, slot: &Slot) -> [u8; SLOT_LEN] {
+    let mut bytes = [0; SLOT_LEN];
+    bytes[FLAGS] = OCCUPIED;
+    bytes[TX_ID..AMOUNT].copy_from_slice(&tx_id.to_be_bytes());
+    if let Some(deposit) = &slot.deposit {
+        bytes[FLAGS] |= HAS_DEPOSIT;
+        bytes[AMOUNT..DIRECTION].copy_from_slice(&deposit.amount.serialize());
+        bytes[DIRECTION] = encode_direction(deposit.direction);
+        bytes[LINE..SEEN_LINE].copy_from_slice(&deposit.line.to_be_bytes());
+    }
+    if let Some(seen) = &slot.seen {
+        bytes[FLAGS] |= HAS_SEEN;
+        bytes[SEEN_LINE..SEEN_CLIENT].copy_from_slice(&seen.line.to_be_bytes());
+        bytes[SEEN_CLIENT..SEEN_DIRECTION].copy_from_slice(&seen.client.to_be_bytes());
+        bytes[SEEN_DIRECTION] = encode_direction(seen.direction);
+        bytes[SEEN_PAIRED] = u8::from(seen.paired);
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8; SLOT_LEN]) -> Slot {
+    let deposit = (bytes[FLAGS] & HAS_DEPOSIT != 0).then(|| DisputableTx {
+        amount: Decimal::deserialize(bytes[AMOUNT..DIRECTION].try_into().expect("16 bytes")),
+        direction: decode_direction(bytes[DIRECTION]),
+        line: read_u64(bytes, LINE),
+    });
+    let seen = (bytes[FLAGS] & HAS_SEEN != 0).then(|| SeenTx {
+        line: read_u64(bytes, SEEN_LINE),
+        client: u16::from_be_bytes(
+            bytes[SEEN_CLIENT..SEEN_DIRECTION]
+                .try_into()
+                .expect("two bytes"),
+        ),
+        direction: decode_direction(bytes[SEEN_DIRECTION]),
+        paired: bytes[SEEN_PAIRED] != 0,
+    });
+    Slot { deposit, seen }
+}
+
+impl DepositStore for DiskDeposits {
+    fn disputable(&self, tx_id: u32) -> Result<Option<DisputableTx>, StoreError> {
+        let (_, slot) = self.find(tx_id).map_err(StoreError::new)?;
+        Ok(slot.and_then(|slot| slot.deposit))
+    }
+
+    fn insert_deposit(&mut self, tx_id: u32, deposit: DisputableTx) -> Result<(), StoreError> {
+        self.update(tx_id, |slot| {
+            slot.deposit.get_or_insert(deposit);
+        })
+        .map_err(StoreError::new)
+    }
+
+    fn remove_deposit(&mut self, tx_id: u32) -> Result<(), StoreError> {
+        if self.contains_tx(tx_id)? {
+            self.update(tx_id, |slot| slot.deposit = None)
+                .map_err(StoreError::new)?;
+        }
+        Ok(())
+    }
+
+    fn contains_tx(&self, tx_id: u32) -> Result<bool, StoreError> {
+        Ok(self.disputable(tx_id)?.is_some())
+    }
+
+    fn seen(&self, tx_id: u32) -> Result<Option<SeenTx>, StoreError> {
+        let (_, slot) = self.find(tx_id).map_err(StoreError::new)?;
+        Ok(slot.and_then(|slot| slot.seen))
+    }
+
+    fn mark_seen(&mut self, tx_id: u32, seen: SeenTx) -> Result<(), StoreError> {
+        self.update(tx_id, |slot| slot.seen = Some(seen))
+            .map_err(StoreError::new)
+    }
+
+    fn size(&self) -> Result<StoreSize, StoreError> {
+        Ok(StoreSize {
+            disputable: self.deposits,
+            seen: self.seen,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{process_transactions, AccountDb, ProcessError, Processor};
+    use crate::transaction::{self, Operation, ParseError, Transaction};
+    use rust_decimal_macros::dec;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("txproc-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_disk_matches_in_memory() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.25
+withdrawal,1,3,2.5
+dispute,1,1,
+resolve,1,1,
+dispute,2,2,
+chargeback,2,2,
+deposit,1,4,0.1234
+dispute,1,4,
+dispute,1,99,
+deposit,1,1,3.0
+";
+        let records = || {
+            transaction::iter_over_reader(input.as_bytes(), Default::default())
+                .unwrap()
+                .collect::<Vec<_>>()
+        };
+
+        let in_memory = process_transactions(records(), Processor::builder().build()).unwrap();
+        let deposits = DiskDeposits::create(temp_path("matches")).unwrap();
+        let disk = process_transactions(
+            records(),
+            Processor::builder().build_with_stores(AccountDb::new(), deposits),
+        )
+        .unwrap();
+        assert_eq!(disk.accounts(), in_memory.accounts());
+        assert_eq!(
+            disk.stats().total_skipped(),
+            in_memory.stats().total_skipped()
+        );
+        assert_eq!(disk.accounts()[&1].held(), dec!(0.1234));
+        assert_eq!(
+            disk.deposits().size().unwrap(),
+            in_memory.deposits().size().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_io_errors_abort_processing() {
+        let path = temp_path("io-errors");
+        let mut deposits = DiskDeposits::create(&path).unwrap();
+        // Only reading the file, writes to it fail.
+        deposits.file = File::open(&path).unwrap();
+        assert!(deposits
+            .insert_deposit(
+                1,
+                DisputableTx {
+                    amount: dec!(1.0),
+                    direction: DisputeDirection::Credit,
+                    line: 2,
+                }
+            )
+            .is_err());
+
+        let records = [Ok(Transaction::new(1, 1, Operation::Deposit(dec!(1.0))))];
+        let processor = Processor::builder().build_with_stores(AccountDb::new(), deposits);
+        let err = process_transactions(records, processor).err().unwrap();
+        assert!(matches!(err, ProcessError::Store(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_growth() {
+        let path = temp_path("growth");
+        let mut deposits = DiskDeposits::with_capacity(path.clone(), 4).unwrap();
+        let deposit = |id: u32| DisputableTx {
+            amount: Decimal::new(i64::from(id), 4),
+            direction: DisputeDirection::Credit,
+            line: u64::from(id) + 1,
+        };
+        let seen = |id: u32| SeenTx {
+            line: u64::from(id) + 1,
+            client: id as u16,
+            direction: DisputeDirection::Debit,
+            paired: id.is_multiple_of(2),
+        };
+
+        let ids = || (0..1000).map(|id| id * 7919);
+        for id in ids() {
+            deposits.insert_deposit(id, deposit(id)).unwrap();
+            deposits.mark_seen(id, seen(id)).unwrap();
+        }
+        // Only the first deposit with an ID is kept.
+        deposits.insert_deposit(0, deposit(1)).unwrap();
+        assert_eq!(deposits.capacity, 2048);
+        assert_eq!(deposits.len, 1000);
+
+        for id in ids() {
+            let found = deposits.disputable(id).unwrap().unwrap();
+            assert_eq!(found.amount, deposit(id).amount);
+            assert_eq!(found.line, deposit(id).line);
+            assert_eq!(deposits.seen(id).unwrap(), Some(seen(id)));
+        }
+        assert!(deposits.disputable(1).unwrap().is_none());
+        assert!(!deposits.contains_tx(1).unwrap());
+        assert!(deposits.seen(1).unwrap().is_none());
+
+        drop(deposits);
+        assert!(!path.exists());
+    }
+
+    // The peak memory used by the process so far, in bytes.
+    fn peak_memory() -> u64 {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|l| l.starts_with("VmHWM:")).unwrap();
+        let kb: u64 = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+        kb * 1024
+    }
+
+    // Processes several million deposits, some of which are disputed and charged back, checking
+    // the memory used stays within a budget that keeping them all in memory would far exceed. This
+    // is slow, and measures the whole process, so only runs on its own:
+    // `cargo test --release -- --ignored --test-threads 1`
+    #[test]
+    #[ignore]
+    fn test_bounded_memory() {
+        const DEPOSITS: u32 = 2_000_000;
+        const BUDGET: u64 = 32 * 1024 * 1024;
+
+        let records = (1..=DEPOSITS).flat_map(|id| {
+            let client = (id % 1000) as u16;
+            let deposit = Transaction::new(id, client, Operation::Deposit(dec!(1.5)));
+            // Charge back an earlier deposit every so often, each for a different client.
+            let disputes = id.is_multiple_of(100_000).then(|| {
+                let disputed = id / 2 + id / 100_000;
+                let client = (disputed % 1000) as u16;
+                [
+                    Transaction::new(disputed, client, Operation::Dispute(None)),
+                    Transaction::new(disputed, client, Operation::Chargeback),
+                ]
+            });
+            std::iter::once(deposit)
+                .chain(disputes.into_iter().flatten())
+                .map(Ok::<_, ParseError>)
+        });
+
+        let baseline = peak_memory();
+        let deposits = DiskDeposits::create(temp_path("bounded")).unwrap();
+        let processor = Processor::builder().build_with_stores(AccountDb::new(), deposits);
+        let processor = process_transactions(records, processor).unwrap();
+        let used = peak_memory() - baseline;
+        assert!(used < BUDGET, "used {} bytes", used);
+
+        let locked = processor
+            .accounts()
+            .values()
+            .filter(|account| account.is_locked())
+            .count();
+        assert_eq!(locked, 20);
+    }
+}
@@ -0,0 +1,4023 @@
+//! The engine that applies transactions to client accounts, and writes out the results.
+
+use crate::account::{
+    Account, AccountDisplay, AccountPolicy, DisputableTx, DisputeDirection, DisputePolicy,
+    DuplicateDisputePolicy, NegativeLimitAction, TotalsDisplay, TxLookup,
+};
+use crate::amount::Amount;
+use crate::error::{StoreError, TxError, TxIdConflict};
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::stats::{ErrorCategory, ProcessingStats};
+use crate::store::{AccountStore, DepositStore};
+use crate::transaction::{
+    LedgerFormat, LedgerWriter, NormalizedWriter, Operation, ParseError, Transaction,
+};
+
+use csv::Writer;
+#[cfg(feature = "tokio")]
+use futures::{Stream, StreamExt};
+use log::{debug, error, info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+/// How many rejected records a processor keeps the details of, unless configured otherwise.
+const DEFAULT_MAX_REJECTIONS: usize = 1000;
+
+/// How many transactions are processed between updates to the metrics being served. Counting the
+/// locked accounts means going through them all, so isn't done for every transaction.
+#[cfg(feature = "metrics")]
+const METRICS_INTERVAL: usize = 100;
+
+/// Configuration for how transactions are applied to accounts.
+#[derive(Debug, Default, Clone)]
+pub struct ProcessorConfig {
+    account: AccountPolicy,
+    /// Abort on a row of the transaction log that can't be parsed instead of skipping it.
+    strict: bool,
+    /// Treat a conflicting transaction ID as fatal instead of skipping the record.
+    unique_tx_ids: bool,
+    /// Whether a deposit and a withdrawal can share a transaction ID.
+    tx_id_scope: TxIdScope,
+    /// How many records to wait for a transaction referenced before it appears (0 to not wait).
+    reorder_window: usize,
+    /// Treat a resolve for a known transaction with no open dispute as a no-op.
+    lenient_resolve: bool,
+    /// Allow withdrawals to be disputed, as well as deposits.
+    dispute_withdrawals: bool,
+    /// How many records after a deposit it can still be disputed (no limit if not set).
+    dispute_window: Option<u64>,
+    /// How many deposits to keep to be disputed, evicting the oldest (no limit if not set).
+    deposit_retention: Option<usize>,
+    /// Whether administrative operations, which freeze and thaw accounts, are applied.
+    allow_admin_ops: bool,
+    /// Skip deposits and withdrawals that exactly repeat an earlier one, e.g. when part of the log
+    /// is re-delivered.
+    dedupe: bool,
+    /// How many of the latest records to look for repeats among (all of them if not set).
+    dedupe_window: Option<usize>,
+    /// The only clients whose transactions are processed (all clients if not set).
+    clients: Option<BTreeSet<u16>>,
+    /// The most funds that can be held across all accounts before `held_limit_action` is taken.
+    max_total_held: Option<Decimal>,
+    held_limit_action: HeldLimitAction,
+}
+
+/// What a transaction ID identifies.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TxIdScope {
+    /// Every deposit and withdrawal has its own ID.
+    #[default]
+    Operation,
+    /// A deposit and a withdrawal for the same client can share an ID.
+    Pair,
+}
+
+/// What to do when the funds held across all accounts exceed the configured limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum HeldLimitAction {
+    /// Log a warning and carry on.
+    #[default]
+    Warn,
+    /// Abort processing with an error.
+    Halt,
+}
+
+impl ProcessorConfig {
+    /// A line identifying the version of the processor and its configuration, which starts with
+    /// `#` to mark it as a comment. The configuration's debug representation is deterministic (the
+    /// clients are a sorted set) so its hash is stable between runs.
+    pub fn provenance(&self) -> String {
+        let config_hash = Sha256::digest(format!("{:?}", self));
+        format!(
+            "# {} {} config-sha256 {:x}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            config_hash
+        )
+    }
+}
+
+/// Builds a `Processor`, starting from the default configuration.
+#[derive(Debug, Default, Clone)]
+pub struct ProcessorBuilder {
+    config: ProcessorConfig,
+}
+
+impl ProcessorBuilder {
+    /// Whether a row of the transaction log that can't be parsed aborts processing rather than
+    /// being skipped.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.config.strict = strict;
+        self
+    }
+
+    /// How to handle a dispute for more than the client's available funds.
+    pub fn dispute_policy(mut self, policy: DisputePolicy) -> Self {
+        self.config.account.dispute = policy;
+        self
+    }
+
+    /// How to handle a dispute for a transaction that is already under dispute.
+    pub fn duplicate_dispute(mut self, policy: DuplicateDisputePolicy) -> Self {
+        self.config.account.duplicate_dispute = policy;
+        self
+    }
+
+    /// Whether locked accounts can still receive deposits and settle open disputes.
+    pub fn allow_deposits_when_locked(mut self, allow: bool) -> Self {
+        self.config.account.allow_deposits_when_locked = allow;
+        self
+    }
+
+    /// Whether withdrawals can draw on held funds once the available funds run out.
+    pub fn allow_withdrawing_held(mut self, allow: bool) -> Self {
+        self.config.account.allow_withdrawing_held = allow;
+        self
+    }
+
+    /// How far below zero a dispute can take available funds when that's allowed at all, and what
+    /// to do with a dispute that would go beyond that.
+    pub fn negative_limit(mut self, limit: Decimal, action: NegativeLimitAction) -> Self {
+        self.config.account.negative_limit = Some(limit);
+        self.config.account.negative_limit_action = action;
+        self
+    }
+
+    /// The most disputes that can be open against an account at once.
+    pub fn max_open_disputes(mut self, max: Option<usize>) -> Self {
+        self.config.account.max_open_disputes = max;
+        self
+    }
+
+    /// Whether a conflicting transaction ID aborts processing rather than being skipped.
+    pub fn unique_tx_ids(mut self, unique: bool) -> Self {
+        self.config.unique_tx_ids = unique;
+        self
+    }
+
+    /// Whether a deposit and a withdrawal can share a transaction ID.
+    pub fn tx_id_scope(mut self, scope: TxIdScope) -> Self {
+        self.config.tx_id_scope = scope;
+        self
+    }
+
+    /// How many records to wait for a referenced transaction that hasn't appeared yet.
+    pub fn reorder_window(mut self, window: usize) -> Self {
+        self.config.reorder_window = window;
+        self
+    }
+
+    /// Whether a resolve for a known transaction with no open dispute is ignored rather than
+    /// skipped as an error.
+    pub fn lenient_resolve(mut self, lenient: bool) -> Self {
+        self.config.lenient_resolve = lenient;
+        self
+    }
+
+    /// Whether withdrawals can be disputed, as well as deposits.
+    pub fn dispute_withdrawals(mut self, allow: bool) -> Self {
+        self.config.dispute_withdrawals = allow;
+        self
+    }
+
+    /// How many records after a deposit it can still be disputed.
+    pub fn dispute_window(mut self, window: Option<u64>) -> Self {
+        self.config.dispute_window = window;
+        self
+    }
+
+    /// Whether a deposit or withdrawal that exactly repeats an earlier one (the same type, client,
+    /// transaction ID and amount) is skipped, e.g. so a re-delivered chunk of the log isn't applied
+    /// twice.
+    pub fn dedupe(mut self, dedupe: bool) -> Self {
+        self.config.dedupe = dedupe;
+        self
+    }
+
+    /// How many of the latest records to look for repeats among when deduplicating, if not all
+    /// of them, so memory stays bounded.
+    pub fn dedupe_window(mut self, window: Option<usize>) -> Self {
+        self.config.dedupe_window = window;
+        self
+    }
+
+    /// How many deposits (and withdrawals) to keep to be disputed, if not all of them. Once there
+    /// are more, the oldest that aren't under dispute are evicted, and disputes for them skipped.
+    pub fn deposit_retention(mut self, retention: Option<usize>) -> Self {
+        self.config.deposit_retention = retention;
+        self
+    }
+
+    /// How many of the client's records a deposit is held for before its funds are available.
+    pub fn deposit_hold(mut self, hold: u64) -> Self {
+        self.config.account.deposit_hold = hold;
+        self
+    }
+
+    /// Whether administrative operations (freezing and thawing accounts) are applied, rather than
+    /// skipped.
+    pub fn allow_admin_ops(mut self, allow: bool) -> Self {
+        self.config.allow_admin_ops = allow;
+        self
+    }
+
+    /// Only process transactions for these clients, or for all clients if there are none.
+    pub fn clients(mut self, clients: impl IntoIterator<Item = u16>) -> Self {
+        let clients: BTreeSet<u16> = clients.into_iter().collect();
+        self.config.clients = (!clients.is_empty()).then_some(clients);
+        self
+    }
+
+    /// The most funds that can be held across all accounts, and what to do if that is exceeded.
+    pub fn max_total_held(mut self, limit: Decimal, action: HeldLimitAction) -> Self {
+        self.config.max_total_held = Some(limit);
+        self.config.held_limit_action = action;
+        self
+    }
+
+    /// Build a processor with the configuration so far.
+    pub fn build(self) -> Processor {
+        Processor::new(self.config)
+    }
+
+    /// Build a processor with the configuration so far, which keeps the accounts and disputable
+    /// deposits in the given (empty) stores rather than hashmaps.
+    pub fn build_with_stores<A: AccountStore, D: DepositStore>(
+        self,
+        accounts: A,
+        deposits: D,
+    ) -> Processor<A, D> {
+        Processor::with_stores(self.config, accounts, deposits)
+    }
+}
+
+/// The client accounts, by client ID. This "database" is just a hashmap, where in production it would
+/// probably be a separate scalable and reliable database.
+pub type AccountDb = HashMap<u16, Account>;
+
+/// Why the accounts from two shards of a transaction log couldn't be merged. Either means the log
+/// wasn't sharded by client correctly.
+#[derive(Debug, PartialEq)]
+pub enum MergeConflict {
+    /// Both shards have an account for the client.
+    Client(u16),
+    /// Both shards have a dispute open for the transaction.
+    Dispute(u32),
+}
+
+impl fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeConflict::Client(client) => {
+                write!(f, "client {} appears in more than one shard", client)
+            }
+            MergeConflict::Dispute(tx) => {
+                write!(f, "transaction {} is disputed in more than one shard", tx)
+            }
+        }
+    }
+}
+
+impl Error for MergeConflict {}
+
+/// Merge the accounts from two shards of a transaction log, which was sharded by client. Nothing
+/// is overwritten: if the shards overlap, the merge is refused.
+pub fn merge_account_dbs(mut db: AccountDb, other: AccountDb) -> Result<AccountDb, MergeConflict> {
+    if let Some(client) = other.keys().find(|client| db.contains_key(client)) {
+        return Err(MergeConflict::Client(*client));
+    }
+    let disputed: HashSet<u32> = db.values().flat_map(Account::disputed_txs).collect();
+    if let Some(tx) = other
+        .values()
+        .flat_map(Account::disputed_txs)
+        .find(|tx| disputed.contains(tx))
+    {
+        return Err(MergeConflict::Dispute(tx));
+    }
+
+    db.extend(other);
+    Ok(db)
+}
+
+// Store transactions that might be disputed in a "database" implemented as a hashmap of tx ID ->
+// transaction.
+type DisputableDb = HashMap<u32, DisputableTx>;
+
+// The IDs of every deposit and withdrawal seen so far, regardless of client, so that replayed or
+// conflicting transactions can be rejected.
+type SeenTxIds = HashMap<u32, SeenTx>;
+
+/// The transactions that might be disputed, and where every deposit and withdrawal was first seen,
+/// by transaction ID. Like [`AccountDb`], this "database" is just a pair of hashmaps.
+#[derive(Debug, Default)]
+pub struct DepositDb {
+    pub(crate) disputable: DisputableDb,
+    pub(crate) seen: SeenTxIds,
+}
+
+impl DepositDb {
+    /// How many transactions are kept to be disputed.
+    pub fn disputable_count(&self) -> usize {
+        self.disputable.len()
+    }
+
+    /// How many deposit and withdrawal IDs have been seen. These are kept for good, even once the
+    /// deposit itself is no longer kept to be disputed, so that reused IDs are still caught.
+    pub fn seen_count(&self) -> usize {
+        self.seen.len()
+    }
+}
+
+/// Where a deposit or withdrawal was first seen.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SeenTx {
+    pub line: u64,
+    pub client: u16,
+    pub direction: DisputeDirection,
+    /// Whether the other half of a deposit and withdrawal pair has been seen with the same ID.
+    pub paired: bool,
+}
+
+/// What's known about a deposit or withdrawal, as returned by [`Processor::deposit`].
+#[derive(Debug, Clone, Copy)]
+pub struct DepositStatus {
+    /// Where it was first seen.
+    pub seen: SeenTx,
+    /// The transaction as kept to be disputed, unless it isn't kept, e.g. because it was skipped
+    /// or has been evicted.
+    pub disputable: Option<DisputableTx>,
+    /// Whether it's under dispute.
+    pub disputed: bool,
+}
+
+/// A transaction for a client whose account was already output, when the transaction log was
+/// meant to be sorted by client.
+#[derive(Debug)]
+pub struct NotPresorted {
+    client: u16,
+    line: u64,
+}
+
+impl fmt::Display for NotPresorted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "transaction log is not sorted by client: client {} appears again at line {}",
+            self.client, self.line
+        )
+    }
+}
+
+impl Error for NotPresorted {}
+
+/// The funds held across all accounts went over the configured limit.
+#[derive(Debug)]
+pub struct HeldLimitExceeded {
+    total_held: Decimal,
+    limit: Decimal,
+    line: u64,
+}
+
+impl fmt::Display for HeldLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "total held funds of {} exceed the limit of {} at line {}",
+            self.total_held, self.limit, self.line
+        )
+    }
+}
+
+impl Error for HeldLimitExceeded {}
+
+/// The funds across all accounts don't match the funds that moved in and out of them.
+#[derive(Debug)]
+pub struct ConservationViolated {
+    discrepancy: Decimal,
+}
+
+impl fmt::Display for ConservationViolated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "account totals differ from the funds deposited and withdrawn by {}",
+            self.discrepancy
+        )
+    }
+}
+
+impl Error for ConservationViolated {}
+
+/// Why saved state couldn't be loaded.
+#[derive(Debug)]
+pub enum LoadStateError {
+    /// The state isn't valid JSON, or doesn't match its version's format.
+    Malformed(serde_json::Error),
+    /// The state was saved in a version of the format this can't read, or without a version.
+    UnsupportedVersion(Option<u64>),
+}
+
+impl fmt::Display for LoadStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadStateError::Malformed(e) => write!(f, "malformed state: {}", e),
+            LoadStateError::UnsupportedVersion(Some(version)) => write!(
+                f,
+                "state is in version {} of the format, but only version {} can be read",
+                version, STATE_VERSION
+            ),
+            LoadStateError::UnsupportedVersion(None) => {
+                write!(f, "state has no version, so can't be read")
+            }
+        }
+    }
+}
+
+impl Error for LoadStateError {}
+
+/// An account's balances as given in an initial accounts file.
+#[derive(Deserialize)]
+struct InitialAccount {
+    client: u16,
+    available: Decimal,
+    #[serde(default)]
+    held: Decimal,
+    #[serde(default)]
+    locked: bool,
+}
+
+/// An error reading an initial accounts file.
+#[derive(Debug)]
+pub enum InitialAccountsError {
+    /// The file couldn't be read, or a row is malformed.
+    Malformed(csv::Error),
+    /// The same client has more than one row.
+    DuplicateClient { client: u16, line: u64 },
+}
+
+impl fmt::Display for InitialAccountsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitialAccountsError::Malformed(e) => write!(f, "malformed initial accounts: {}", e),
+            InitialAccountsError::DuplicateClient { client, line } => write!(
+                f,
+                "client {} is given more than one initial balance (again on line {})",
+                client, line
+            ),
+        }
+    }
+}
+
+impl Error for InitialAccountsError {}
+
+/// Read the balances accounts start off with from CSV with `client`, `available`, `held` and
+/// `locked` columns, of which `held` and `locked` are optional. Other columns are ignored, so the
+/// accounts output by an earlier run can be read back in, including its provenance comment and
+/// totals row.
+pub fn read_initial_accounts(reader: impl Read) -> Result<Vec<Account>, InitialAccountsError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_reader(reader);
+    let headers = reader
+        .headers()
+        .map_err(InitialAccountsError::Malformed)?
+        .clone();
+    let client_column = headers.iter().position(|header| header == "client");
+    let mut clients = HashSet::new();
+    let mut accounts = Vec::new();
+    let mut row = csv::StringRecord::new();
+    while reader
+        .read_record(&mut row)
+        .map_err(InitialAccountsError::Malformed)?
+    {
+        if client_column.and_then(|column| row.get(column)) == Some("total") {
+            continue;
+        }
+        let record: InitialAccount = row
+            .deserialize(Some(&headers))
+            .map_err(InitialAccountsError::Malformed)?;
+        if !clients.insert(record.client) {
+            return Err(InitialAccountsError::DuplicateClient {
+                client: record.client,
+                line: row.position().map_or(0, csv::Position::line),
+            });
+        }
+        accounts.push(Account::with_balances(
+            record.client,
+            record.available,
+            record.held,
+            record.locked,
+        ));
+    }
+    Ok(accounts)
+}
+
+impl EngineState {
+    /// Write the state as JSON, along with the version of its format.
+    pub fn save(&self, writer: impl Write) -> Result<(), serde_json::Error> {
+        serde_json::to_writer(writer, self)
+    }
+
+    /// Read state written by [`EngineState::save`], which must be in the current version of the
+    /// format.
+    pub fn load(reader: impl Read) -> Result<Self, LoadStateError> {
+        // The version is checked first, as the rest of an unknown version can't be relied on to
+        // parse, let alone mean the same thing.
+        let state: serde_json::Value =
+            serde_json::from_reader(reader).map_err(LoadStateError::Malformed)?;
+        let version = state.get("version").and_then(serde_json::Value::as_u64);
+        if version != Some(u64::from(STATE_VERSION)) {
+            return Err(LoadStateError::UnsupportedVersion(version));
+        }
+        serde_json::from_value(state).map_err(LoadStateError::Malformed)
+    }
+}
+
+/// A record that was skipped, rather than applied, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    /// The line of the transaction log the record is on (0 if unknown).
+    pub line: u64,
+    /// The client, transaction ID and type of the transaction, unless the row couldn't be parsed.
+    pub client: Option<u16>,
+    pub tx: Option<u32>,
+    pub op_kind: Option<&'static str>,
+    pub reason: RejectionReason,
+}
+
+/// Why a record was skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RejectionReason {
+    /// The row couldn't be read, e.g. because a numeric field wasn't a number.
+    Malformed(String),
+    /// The row was read, but doesn't describe a valid transaction.
+    InvalidRecord(TxError),
+    /// The transaction couldn't be applied to the account.
+    Failed(TxError),
+}
+
+impl Rejection {
+    /// The rejection of a transaction that couldn't be applied because of `e`.
+    pub fn failed(tx: &Transaction, e: &TxError) -> Self {
+        Rejection {
+            line: tx.line,
+            client: Some(tx.client),
+            tx: Some(tx.id),
+            op_kind: Some(tx.op.name()),
+            reason: RejectionReason::Failed(e.clone()),
+        }
+    }
+
+    /// The rejection of a row of the transaction log that couldn't be parsed.
+    pub fn malformed(e: &ParseError) -> Self {
+        let reason = match e {
+            ParseError::InvalidRecord { reason, .. } => {
+                RejectionReason::InvalidRecord(reason.clone())
+            }
+            ParseError::MalformedField { source, .. } => {
+                RejectionReason::Malformed(source.to_string())
+            }
+            ParseError::MalformedJson { source, .. } => {
+                RejectionReason::Malformed(source.to_string())
+            }
+            e @ ParseError::InvalidHeader { .. } => RejectionReason::Malformed(e.to_string()),
+        };
+        Rejection {
+            line: e.line(),
+            client: None,
+            tx: None,
+            op_kind: None,
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}", self.line)?;
+        if let (Some(client), Some(tx), Some(op_kind)) = (self.client, self.tx, self.op_kind) {
+            write!(f, " ({} {} for client {})", op_kind, tx, client)?;
+        }
+        match &self.reason {
+            RejectionReason::Malformed(e) => write!(f, ": {}", e),
+            RejectionReason::InvalidRecord(e) => write!(f, ": {}", e),
+            RejectionReason::Failed(e) => write!(f, ": {}", e),
+        }
+    }
+}
+
+/// What processing a transaction did with it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApplyOutcome {
+    /// The transaction was applied to the client's existing account.
+    Applied,
+    /// The transaction was the first applied to the client's account, which was created for it
+    /// (or for a transaction that was rejected).
+    AccountCreated,
+    /// The transaction was a resolve for a transaction that isn't under dispute, so did nothing, as
+    /// lenient resolves allow.
+    NoOpLenient,
+    /// The transaction couldn't be applied, so was skipped.
+    Skipped(TxError),
+    /// The transaction refers to one that hasn't been seen yet, so is waiting for it to appear.
+    Pending,
+    /// The transaction is for a client whose transactions aren't processed, so was ignored.
+    Filtered,
+}
+
+/// Told about each transaction as it's applied or skipped, to attach side effects such as
+/// metrics, tracing or an audit trail without changing how transactions are processed. Register
+/// one with [`Processor::observe`]. `N` is the type the accounts keep their balances in.
+pub trait TxObserver<N: Amount = Decimal> {
+    /// `tx` was applied, leaving the client's account as `account`.
+    fn on_applied(&mut self, _tx: &Transaction, _account: &Account<N>) {}
+
+    /// `tx` was skipped because of `error`.
+    fn on_rejected(&mut self, _tx: &Transaction, _error: &TxError) {}
+
+    /// A row of the transaction log couldn't be parsed, so was skipped.
+    fn on_malformed(&mut self, _error: &ParseError) {}
+
+    /// `tx` is for a client whose transactions aren't processed, so was ignored.
+    fn on_filtered(&mut self, _tx: &Transaction) {}
+
+    /// An account was created for `client` by the first transaction applied to it.
+    fn on_account_created(&mut self, _client: u16) {}
+
+    /// `account` was locked, by a chargeback or a freeze.
+    fn on_account_locked(&mut self, _account: &Account<N>) {}
+}
+
+/// Lets the caller keep a handle on an observer once it's registered, to look at what it was
+/// told once processing is done.
+impl<N: Amount, O: TxObserver<N>> TxObserver<N> for Rc<RefCell<O>> {
+    fn on_applied(&mut self, tx: &Transaction, account: &Account<N>) {
+        self.borrow_mut().on_applied(tx, account);
+    }
+
+    fn on_rejected(&mut self, tx: &Transaction, error: &TxError) {
+        self.borrow_mut().on_rejected(tx, error);
+    }
+
+    fn on_malformed(&mut self, error: &ParseError) {
+        self.borrow_mut().on_malformed(error);
+    }
+
+    fn on_filtered(&mut self, tx: &Transaction) {
+        self.borrow_mut().on_filtered(tx);
+    }
+
+    fn on_account_created(&mut self, client: u16) {
+        self.borrow_mut().on_account_created(client);
+    }
+
+    fn on_account_locked(&mut self, account: &Account<N>) {
+        self.borrow_mut().on_account_locked(account);
+    }
+}
+
+/// Why processing was aborted, rather than just skipping the transaction at fault.
+#[derive(Debug)]
+pub enum ProcessError {
+    /// A row of the transaction log couldn't be parsed, in strict mode.
+    Malformed(ParseError),
+    /// A transaction ID was reused, when IDs have to be unique.
+    TxIdConflict(TxIdConflict),
+    /// The transaction log isn't sorted by client, which streaming the accounts relies on.
+    NotPresorted(NotPresorted),
+    /// The funds held across all accounts went over the limit, when that halts processing.
+    HeldLimitExceeded(HeldLimitExceeded),
+    /// The accounts or transactions being written out couldn't be.
+    Output(csv::Error),
+    /// The deposit store failed to look up or keep a transaction.
+    Store(StoreError),
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::Malformed(e) => e.fmt(f),
+            ProcessError::TxIdConflict(e) => e.fmt(f),
+            ProcessError::NotPresorted(e) => e.fmt(f),
+            ProcessError::HeldLimitExceeded(e) => e.fmt(f),
+            ProcessError::Output(e) => write!(f, "failed to write output: {}", e),
+            ProcessError::Store(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for ProcessError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProcessError::Output(e) => Some(e),
+            ProcessError::Store(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<csv::Error> for ProcessError {
+    fn from(e: csv::Error) -> Self {
+        ProcessError::Output(e)
+    }
+}
+
+impl From<std::io::Error> for ProcessError {
+    fn from(e: std::io::Error) -> Self {
+        ProcessError::Output(e.into())
+    }
+}
+
+impl From<StoreError> for ProcessError {
+    fn from(e: StoreError) -> Self {
+        ProcessError::Store(e)
+    }
+}
+
+// Why a record wasn't applied: either it was rejected, which only skips it, or the deposit store
+// failed, which aborts processing.
+#[derive(Debug)]
+enum RecordFailure {
+    Rejected(TxError),
+    Store(StoreError),
+}
+
+impl From<TxError> for RecordFailure {
+    fn from(e: TxError) -> Self {
+        RecordFailure::Rejected(e)
+    }
+}
+
+impl From<StoreError> for RecordFailure {
+    fn from(e: StoreError) -> Self {
+        RecordFailure::Store(e)
+    }
+}
+
+// The transaction a dispute, resolve or chargeback refers to, looked up in the deposit store
+// beforehand, so the account can find it without the lookup being able to fail.
+struct Referenced<N: Amount>(u32, Option<DisputableTx<N>>);
+
+impl<N: Amount> TxLookup<N> for Referenced<N> {
+    fn disputable(&self, tx_id: u32) -> Option<DisputableTx<N>> {
+        self.1.filter(|_| tx_id == self.0)
+    }
+}
+
+/// Check that funds were neither created nor lost, given the net funds that moved into the
+/// accounts (deposits less withdrawals, adjusted for chargebacks). Returns by how much the total
+/// across all accounts differs from that, if it does.
+fn verify_conservation<'a, N: Amount + 'a>(
+    net_funds: Decimal,
+    accounts: impl Iterator<Item = &'a Account<N>>,
+) -> Option<Decimal> {
+    let total = accounts.fold(Decimal::ZERO, |total, account| {
+        total.saturating_add(account.total_balance().to_decimal())
+    });
+    let discrepancy = total.saturating_sub(net_funds);
+    (!discrepancy.is_zero()).then_some(discrepancy)
+}
+
+/// Check that a transaction doesn't conflict with an earlier deposit or withdrawal. New deposits
+/// and withdrawals must have an unseen ID, and other transactions must reference a deposit or
+/// withdrawal belonging to the same client. Opening balances have synthetic IDs, so are ignored.
+/// When IDs are scoped to a pair, a deposit and a withdrawal for the same client can share an ID.
+fn check_tx_id(
+    tx: &Transaction,
+    seen: &mut impl DepositStore,
+    scope: TxIdScope,
+) -> Result<(), RecordFailure> {
+    let direction = match tx.op {
+        // These don't create a transaction that could be referenced later.
+        Operation::OpeningBalance(_) | Operation::Freeze | Operation::Thaw => return Ok(()),
+        Operation::Withdrawal(_) | Operation::Reserve(_) => DisputeDirection::Debit,
+        _ => DisputeDirection::Credit,
+    };
+    let current = SeenTx {
+        line: tx.line,
+        client: tx.client,
+        direction,
+        paired: false,
+    };
+    let creates_tx = !tx.op.references_tx();
+
+    match seen.seen(tx.id)? {
+        Some(first)
+            if creates_tx
+                && scope == TxIdScope::Pair
+                && first.client == tx.client
+                && first.direction != direction
+                && !first.paired =>
+        {
+            seen.mark_seen(
+                tx.id,
+                SeenTx {
+                    paired: true,
+                    ..first
+                },
+            )?;
+            Ok(())
+        }
+        Some(first) if creates_tx || first.client != tx.client => {
+            Err(TxError::DuplicateTx(TxIdConflict {
+                tx: tx.id,
+                line: tx.line,
+                client: tx.client,
+                first_line: first.line,
+                first_client: first.client,
+            })
+            .into())
+        }
+        Some(_) => Ok(()),
+        None => {
+            if creates_tx {
+                seen.mark_seen(tx.id, current)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The version of the format [`EngineState`] is saved in, which changes whenever the format changes
+/// in a way earlier versions can't read, or its meaning changes. Version 2 keeps only the disputes
+/// that were charged back once settled, where version 1 also kept those that were resolved, which
+/// can't be told apart from them.
+pub const STATE_VERSION: u32 = 2;
+
+/// Everything a processor needs to carry on where it left off, e.g. after a restart, so that disputes
+/// can still reference earlier deposits and be resolved or charged back.
+#[derive(Serialize, Deserialize)]
+pub struct EngineState {
+    version: u32,
+    accounts: AccountDb,
+    disputable: DisputableDb,
+    seen: SeenTxIds,
+    /// Missing from state saved before it was kept, in which case disputes are aged from the
+    /// first transaction.
+    #[serde(default)]
+    sequence: u64,
+}
+
+/// Applies transactions to the client accounts, keeping track of the state needed to do so. The
+/// accounts and disputable deposits are kept in hashmaps unless other stores are given to
+/// [`ProcessorBuilder::build_with_stores`], which can also keep the balances in another
+/// [`Amount`] type than `Decimal`, e.g. in a `HashMap<u16, Account<Cents>>`.
+pub struct Processor<A: AccountStore = AccountDb, D = DepositDb> {
+    config: ProcessorConfig,
+
+    // Create a "database" to store the client accounts. In production this would probably be a separate
+    // scalable and reliable database. For this problem default to a hashmap.
+    accounts: A,
+
+    // Create a "database" to store deposits that might be disputed.
+    // Again, in production this would be a separate DB, but we default to a hashmap.
+    //
+    // NOTE: It is unclear from the problem statement if withdrawals can also be disputed. Realistically it seems
+    // like they could be. But the description for dispute handling suggests it only covers deposits. So
+    // withdrawals are only stored here if `dispute_withdrawals` is configured.
+    disputable: D,
+
+    // The only transactions worth keeping to be disputed, if they're known in advance, e.g. from a
+    // first pass over the transaction log.
+    referenced: Option<HashSet<u32>>,
+
+    // Each deposit and withdrawal seen, with the line it was first seen on, if deduplicating, and
+    // the records in the order they were seen, to forget the oldest once there are more than the
+    // window.
+    seen_records: HashMap<RecordKey, u64>,
+    seen_order: VecDeque<RecordKey>,
+
+    // The deposits being kept to be disputed, oldest first, with their client and line, if only so
+    // many are kept. And the line of the latest one evicted, if any have been.
+    retained: VecDeque<(u32, u16, u64)>,
+    evicted_through: Option<u64>,
+
+    // Disputes, resolves and chargebacks that arrived before the transaction they reference, along with
+    // how many more records they will wait for it. Each waits for at most `reorder_window` records and
+    // at most one is added per record, so this never holds more than `reorder_window` entries.
+    pending: VecDeque<(Transaction, usize)>,
+
+    stats: ProcessingStats,
+
+    // The funds held across all accounts, kept up to date as transactions are applied, and whether
+    // that is currently over the configured limit.
+    total_held: Decimal,
+    held_limit_exceeded: bool,
+
+    // The net funds moved into the accounts by the transactions applied, tracked separately from the
+    // balances so that they can be checked against each other.
+    net_funds: Decimal,
+
+    // Where to write the transactions that were applied successfully, if anywhere.
+    normalized: Option<NormalizedWriter<Box<dyn Write>>>,
+    ledger: Option<LedgerWriter<Box<dyn Write>>>,
+
+    // The sequence number of the latest transaction processed, counted across all clients and
+    // carried over from the state of earlier runs, which disputes are aged by.
+    sequence: u64,
+
+    // How many transactions have been processed, and the clients whose accounts have changed since
+    // the last snapshot of the accounts, if they are being written.
+    processed: usize,
+    dirty: HashSet<u16>,
+    snapshots: Option<Snapshots>,
+
+    // Where to stream accounts once they're final, if they're not all output at the end.
+    stream: Option<AccountStream>,
+
+    // Whether each account records the transactions applied to it.
+    track_history: bool,
+
+    // Whether to check each account's invariants after every transaction applied to it.
+    paranoid: bool,
+
+    // The earliest records that were skipped, up to `max_rejections` of them.
+    rejections: Vec<Rejection>,
+    max_rejections: usize,
+
+    // Told about each transaction applied or skipped, in the order they were registered.
+    observers: Vec<Box<dyn TxObserver<A::Amount>>>,
+
+    // Where to publish metrics, if they're being served.
+    #[cfg(feature = "metrics")]
+    metrics: Option<metrics::MetricsServer>,
+}
+
+/// What makes a deposit or withdrawal the same as another, for deduplicating: its type, client,
+/// transaction ID and amount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct RecordKey(&'static str, u16, u32, Decimal);
+
+/// Where accounts are streamed to as soon as they're final.
+struct AccountStream {
+    display: DisplayOptions,
+    writer: Writer<Box<dyn Write>>,
+    /// The client whose transactions are currently being processed.
+    current: Option<u16>,
+    /// The clients whose accounts have already been output.
+    finished: HashSet<u16>,
+    /// How many accounts were left out of the output.
+    excluded: usize,
+}
+
+impl AccountStream {
+    fn write<N: Amount>(&mut self, account: &Account<N>) -> Result<(), csv::Error> {
+        if !write_account(&mut self.writer, account, self.display)? {
+            self.excluded += 1;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Where and how often to write snapshots of the accounts while processing.
+pub struct Snapshots {
+    /// How many transactions to process between snapshots.
+    pub every: usize,
+    /// Only include the accounts that changed since the last snapshot.
+    pub changed_only: bool,
+    /// How the accounts in each snapshot are displayed.
+    pub display: DisplayOptions,
+    /// Where the snapshots are written.
+    pub output: Box<dyn Write>,
+}
+
+impl Processor {
+    /// Start building a processor from the default configuration.
+    pub fn builder() -> ProcessorBuilder {
+        ProcessorBuilder::default()
+    }
+
+    fn new(config: ProcessorConfig) -> Self {
+        Processor::with_stores(config, HashMap::new(), DepositDb::default())
+    }
+
+    /// Merge in the results of processing another shard of the transaction log, which was sharded
+    /// by client.
+    pub fn merge_shard(&mut self, shard: Processor) -> Result<(), MergeConflict> {
+        self.accounts = merge_account_dbs(std::mem::take(&mut self.accounts), shard.accounts)?;
+        self.stats.merge(shard.stats);
+        self.total_held = self.total_held.saturating_add(shard.total_held);
+        for rejection in shard.rejections {
+            self.reject(rejection);
+        }
+        self.net_funds = self.net_funds.saturating_add(shard.net_funds);
+        Ok(())
+    }
+
+    /// Carry on from the state saved by an earlier run. The accounts follow this processor's
+    /// policy, rather than the one they were saved with.
+    pub fn restore(mut self, state: EngineState) -> Self {
+        let policy = self.config.account;
+        self.accounts = state
+            .accounts
+            .into_iter()
+            .map(|(client, account)| (client, account.with_policy(policy)))
+            .collect();
+        self.total_held = self
+            .accounts
+            .values()
+            .fold(Decimal::ZERO, |total, account| {
+                total.saturating_add(account.held())
+            });
+        // Only the funds moved from here on can be checked.
+        self.net_funds = self
+            .accounts
+            .values()
+            .fold(Decimal::ZERO, |total, account| {
+                total.saturating_add(account.total_balance())
+            });
+        self.disputable = DepositDb {
+            disputable: state.disputable,
+            seen: state.seen,
+        };
+        self.sequence = state.sequence;
+        self
+    }
+
+    /// The state a later run needs to carry on where this one left off.
+    pub fn into_state(self) -> EngineState {
+        EngineState {
+            version: STATE_VERSION,
+            accounts: self.accounts,
+            disputable: self.disputable.disputable,
+            seen: self.disputable.seen,
+            sequence: self.sequence,
+        }
+    }
+}
+
+impl<D: DepositStore> Processor<AccountDb, D> {
+    /// Record the history of every account, including any restored from an earlier run, from now
+    /// on.
+    pub fn track_history(mut self) -> Self {
+        self.track_history = true;
+        self.accounts = std::mem::take(&mut self.accounts)
+            .into_iter()
+            .map(|(client, account)| (client, account.with_history()))
+            .collect();
+        self
+    }
+}
+
+impl<A: AccountStore<Amount = Decimal>, D: DepositStore> Processor<A, D> {
+    /// Start the accounts off with balances carried over from elsewhere, as read by
+    /// [`read_initial_accounts`], replacing any accounts for the same clients. The accounts follow
+    /// this processor's policy. Like restored state, only the funds moved from here on can be
+    /// checked for conservation.
+    pub fn seed_accounts(mut self, accounts: impl IntoIterator<Item = Account>) -> Self {
+        for account in accounts {
+            let mut account = account.with_policy(self.config.account);
+            if self.track_history {
+                account = account.with_history();
+            }
+            let client = account.client();
+            let slot = self.accounts.get_or_create(client, || Account::new(client));
+            self.total_held = self
+                .total_held
+                .saturating_sub(slot.held())
+                .saturating_add(account.held());
+            self.net_funds = self
+                .net_funds
+                .saturating_sub(slot.total_balance())
+                .saturating_add(account.total_balance());
+            *slot = account;
+        }
+        self
+    }
+}
+
+impl<A: AccountStore, D: DepositStore> Processor<A, D> {
+    fn with_stores(config: ProcessorConfig, accounts: A, disputable: D) -> Self {
+        Self {
+            config,
+            accounts,
+            disputable,
+            referenced: None,
+            seen_records: HashMap::new(),
+            seen_order: VecDeque::new(),
+            retained: VecDeque::new(),
+            evicted_through: None,
+            pending: VecDeque::new(),
+            stats: ProcessingStats::default(),
+            total_held: Decimal::ZERO,
+            held_limit_exceeded: false,
+            net_funds: Decimal::ZERO,
+            normalized: None,
+            ledger: None,
+            sequence: 0,
+            processed: 0,
+            dirty: HashSet::new(),
+            snapshots: None,
+            stream: None,
+            track_history: false,
+            paranoid: cfg!(debug_assertions),
+            rejections: Vec::new(),
+            max_rejections: DEFAULT_MAX_REJECTIONS,
+            observers: Vec::new(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Only keep the deposits (and withdrawals) with these IDs to be disputed, rather than all of
+    /// them, to save memory. The IDs must include every transaction a dispute, resolve or chargeback
+    /// will refer to, as found by [`referenced_tx_ids`], or those will fail.
+    pub fn keep_only_referenced(mut self, ids: HashSet<u32>) -> Self {
+        self.referenced = Some(ids);
+        self
+    }
+
+    /// The configuration transactions are applied with.
+    pub fn config(&self) -> &ProcessorConfig {
+        &self.config
+    }
+
+    /// The accounts of the clients whose transactions have been processed. Accounts that have
+    /// already been streamed out aren't kept. They're in no particular order, but are always
+    /// written out sorted by client, so the same log always gives byte-identical output.
+    pub fn accounts(&self) -> &A {
+        &self.accounts
+    }
+
+    /// The store the accounts are kept in, e.g. to commit them once processing is done.
+    pub fn accounts_mut(&mut self) -> &mut A {
+        &mut self.accounts
+    }
+
+    /// The store of deposits (and withdrawals) that might be disputed.
+    pub fn deposits(&self) -> &D {
+        &self.disputable
+    }
+
+    /// The sequence number of the latest transaction processed, counting from 1 across all clients
+    /// and including any processed before the state was saved by an earlier run. Pass it to
+    /// [`Account::dispute_ages`] to age the open disputes as of now.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// How many transactions were applied and skipped so far.
+    pub fn stats(&self) -> &ProcessingStats {
+        &self.stats
+    }
+
+    /// The funds held across all accounts, for open disputes and unsettled deposits, including
+    /// accounts that have already been streamed out. This is kept up to date as transactions are
+    /// applied, rather than summed over the accounts.
+    pub fn total_held(&self) -> Decimal {
+        self.total_held
+    }
+
+    /// The account of `client`, if any of their transactions have been processed, even if none
+    /// were applied. Accounts that have already been streamed out aren't kept.
+    pub fn account(&self, client: u16) -> Option<&Account<A::Amount>> {
+        self.accounts.get(client)
+    }
+
+    /// The accounts of the clients whose transactions have been processed, in order of client.
+    pub fn accounts_iter(&self) -> impl Iterator<Item = &Account<A::Amount>> {
+        let mut accounts: Vec<_> = self.accounts.iter_accounts().collect();
+        accounts.sort_unstable_by_key(|account| account.client());
+        accounts.into_iter()
+    }
+
+    /// What's known about the deposit or withdrawal with the ID, if it has been seen.
+    pub fn deposit(&self, tx: u32) -> Result<Option<DepositStatus>, StoreError> {
+        let Some(seen) = self.disputable.seen(tx)? else {
+            return Ok(None);
+        };
+        Ok(Some(DepositStatus {
+            seen,
+            disputable: self.disputable.disputable(tx)?,
+            disputed: self
+                .accounts
+                .get(seen.client)
+                .is_some_and(|account| account.is_disputed(tx)),
+        }))
+    }
+
+    /// The records that were skipped, in the order they were skipped, along with why. Only the
+    /// first so many are kept, as configured by `retain_rejections`.
+    pub fn rejections(&self) -> &[Rejection] {
+        &self.rejections
+    }
+
+    /// How many accounts were left out when streaming them, or `None` if they aren't streamed.
+    pub fn excluded_from_stream(&self) -> Option<usize> {
+        self.stream.as_ref().map(|stream| stream.excluded)
+    }
+
+    /// Check that the funds in the accounts add up to the funds the transactions applied moved into
+    /// them.
+    pub fn verify_conservation(&self) -> Result<(), ConservationViolated> {
+        match verify_conservation(self.net_funds, self.accounts.iter_accounts()) {
+            Some(discrepancy) => Err(ConservationViolated { discrepancy }),
+            None => Ok(()),
+        }
+    }
+
+    /// Process a single transaction, returning what was done with it. If it fails the transaction
+    /// is skipped, and an error is only returned if it means processing should be aborted.
+    pub fn process(&mut self, tx: &Transaction) -> Result<ApplyOutcome, ProcessError> {
+        self.process_reporting(tx, &mut |_, _| {})
+    }
+
+    // Process a single transaction like `process`, calling `on_processed` with what came of it and
+    // then of each waiting transaction that is retried, or dropped for having waited too long.
+    fn process_reporting(
+        &mut self,
+        tx: &Transaction,
+        on_processed: &mut dyn FnMut(&Transaction, &ApplyOutcome),
+    ) -> Result<ApplyOutcome, ProcessError> {
+        self.advance_stream(tx)?;
+        self.stats.record_read();
+        self.sequence += 1;
+
+        let outcome = if self.is_selected(tx.client) {
+            let park = self.config.reorder_window > 0 && self.references_unknown_tx(tx)?;
+            let outcome = if let Some(first_line) = self.repeated_record(tx) {
+                let e = TxError::RepeatedRecord { first_line };
+                self.skip(tx, &e);
+                ApplyOutcome::Skipped(e)
+            } else if park {
+                ApplyOutcome::Pending
+            } else {
+                self.apply(tx)?
+            };
+            on_processed(tx, &outcome);
+
+            self.retry_pending(on_processed)?;
+
+            if park {
+                self.pending
+                    .push_back((tx.clone(), self.config.reorder_window));
+            }
+            outcome
+        } else {
+            // Still track the ID, so conflicts are detected just as they would be without the filter.
+            if let Err(RecordFailure::Store(e)) =
+                check_tx_id(tx, &mut self.disputable, self.config.tx_id_scope)
+            {
+                return Err(e.into());
+            }
+            for observer in &mut self.observers {
+                observer.on_filtered(tx);
+            }
+            on_processed(tx, &ApplyOutcome::Filtered);
+            self.retry_pending(on_processed)?;
+            ApplyOutcome::Filtered
+        };
+
+        self.processed += 1;
+        #[cfg(feature = "metrics")]
+        if self.processed.is_multiple_of(METRICS_INTERVAL) {
+            self.publish_metrics();
+        }
+        self.write_snapshot_if_due()?;
+        Ok(outcome)
+    }
+
+    /// If deduplicating, whether the deposit or withdrawal exactly repeats an earlier one,
+    /// returning the line that was first seen on. Otherwise it's remembered, forgetting the oldest
+    /// record remembered if there are more than the window.
+    ///
+    /// Disputes, resolves and chargebacks are never repeats, as the same one can legitimately
+    /// appear more than once, e.g. a transaction disputed again after the first dispute was
+    /// resolved.
+    fn repeated_record(&mut self, tx: &Transaction) -> Option<u64> {
+        let key = match tx.op {
+            _ if !self.config.dedupe => return None,
+            Operation::Deposit(amount) | Operation::Withdrawal(amount) => {
+                RecordKey(tx.op.name(), tx.client, tx.id, amount)
+            }
+            _ => return None,
+        };
+        if let Some(first_line) = self.seen_records.get(&key) {
+            return Some(*first_line);
+        }
+
+        self.seen_records.insert(key, tx.line);
+        self.seen_order.push_back(key);
+        if self
+            .config
+            .dedupe_window
+            .is_some_and(|window| self.seen_order.len() > window)
+        {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen_records.remove(&oldest);
+            }
+        }
+        None
+    }
+
+    /// Serve metrics about the processing from `server`, which are updated every
+    /// `METRICS_INTERVAL` transactions.
+    #[cfg(feature = "metrics")]
+    pub fn serve_metrics(mut self, server: metrics::MetricsServer) -> Self {
+        self.metrics = Some(server);
+        self
+    }
+
+    /// Update the metrics being served, if they are.
+    #[cfg(feature = "metrics")]
+    fn publish_metrics(&self) {
+        if let Some(metrics) = &self.metrics {
+            let locked = self
+                .accounts
+                .iter_accounts()
+                .filter(|acc| acc.is_locked())
+                .count();
+            metrics.publish(&self.stats, locked, self.total_held);
+        }
+    }
+
+    /// Stream each account to `output` as soon as it's final, rather than keeping it, which relies
+    /// on the transaction log being sorted by client.
+    pub fn stream_accounts(mut self, display: DisplayOptions, output: Box<dyn Write>) -> Self {
+        self.stream = Some(AccountStream {
+            display,
+            writer: Writer::from_writer(output),
+            current: None,
+            finished: HashSet::new(),
+            excluded: 0,
+        });
+        self
+    }
+
+    /// When streaming accounts, output the previous client's account once a transaction for a
+    /// different client arrives, as it won't change again.
+    fn advance_stream(&mut self, tx: &Transaction) -> Result<(), ProcessError> {
+        let Some(stream) = &mut self.stream else {
+            return Ok(());
+        };
+        if stream.current == Some(tx.client) {
+            return Ok(());
+        }
+        if stream.finished.contains(&tx.client) {
+            return Err(ProcessError::NotPresorted(NotPresorted {
+                client: tx.client,
+                line: tx.line,
+            }));
+        }
+
+        if let Some(previous) = stream.current.replace(tx.client) {
+            stream.finished.insert(previous);
+            if let Some(account) = self.accounts.remove(previous) {
+                stream.write(&account)?;
+            }
+        }
+        #[cfg(feature = "metrics")]
+        self.publish_metrics();
+        Ok(())
+    }
+
+    /// Also write a snapshot of the accounts every so often, as configured by `snapshots`.
+    pub fn emit_snapshots(mut self, snapshots: Snapshots) -> Self {
+        self.snapshots = Some(snapshots);
+        self
+    }
+
+    /// Write a snapshot of the accounts if enough transactions have been processed since the last.
+    fn write_snapshot_if_due(&mut self) -> Result<(), ProcessError> {
+        let Some(snapshots) = &mut self.snapshots else {
+            return Ok(());
+        };
+        if !self.processed.is_multiple_of(snapshots.every) {
+            return Ok(());
+        }
+
+        let dirty = std::mem::take(&mut self.dirty);
+        if snapshots.changed_only {
+            let changed = dirty.iter().filter_map(|client| self.accounts.get(*client));
+            display_accounts(changed, snapshots.display, &mut snapshots.output)?;
+        } else {
+            display_accounts(
+                self.accounts.iter_accounts(),
+                snapshots.display,
+                &mut snapshots.output,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Whether the configuration selects a client's transactions to be processed.
+    fn is_selected(&self, client: u16) -> bool {
+        self.config
+            .clients
+            .as_ref()
+            .is_none_or(|clients| clients.contains(&client))
+    }
+
+    /// Skip a row of the transaction log that couldn't be parsed. In strict mode an error is
+    /// returned instead, as processing should be aborted.
+    pub fn skip_malformed(&mut self, e: ParseError) -> Result<(), ProcessError> {
+        if self.config.strict {
+            return Err(ProcessError::Malformed(e));
+        }
+        warn!("Skipping {}", e);
+        self.stats.record_read();
+        self.stats.record_skip(ErrorCategory::from(&e), e.line());
+        for observer in &mut self.observers {
+            observer.on_malformed(&e);
+        }
+        self.reject(Rejection::malformed(&e));
+        Ok(())
+    }
+
+    /// Finish processing once there are no more transactions. Anything still waiting for the
+    /// transaction it references is skipped.
+    pub fn finish(&mut self) -> Result<(), ProcessError> {
+        self.finish_reporting(&mut |_, _| {})
+    }
+
+    // Finish processing like `finish`, calling `on_processed` with each waiting transaction that is
+    // skipped.
+    fn finish_reporting(
+        &mut self,
+        on_processed: &mut dyn FnMut(&Transaction, &ApplyOutcome),
+    ) -> Result<(), ProcessError> {
+        for (tx, _) in std::mem::take(&mut self.pending) {
+            let e = TxError::UnknownTx { tx: tx.id };
+            self.skip(&tx, &e);
+            on_processed(&tx, &ApplyOutcome::Skipped(e));
+        }
+        if let Some(normalized) = &mut self.normalized {
+            normalized.flush()?;
+        }
+        if let Some(ledger) = &mut self.ledger {
+            ledger.flush()?;
+        }
+        if let Some(stream) = &mut self.stream {
+            if let Some(account) = stream.current.and_then(|last| self.accounts.remove(last)) {
+                stream.write(&account)?;
+            }
+        }
+        #[cfg(feature = "metrics")]
+        self.publish_metrics();
+        Ok(())
+    }
+
+    /// Log and count a transaction that is being skipped.
+    fn skip(&mut self, tx: &Transaction, e: &TxError) {
+        let category = ErrorCategory::from(e);
+        // Transactions arriving for frozen accounts are worth monitoring, so make them more visible.
+        // As are clients raising lots of disputes at once, which could be an attempt at fraud.
+        if category == ErrorCategory::Locked {
+            warn!(
+                "Rejecting {} {} for locked account {}",
+                tx.op.name(),
+                tx.id,
+                tx.client
+            );
+        } else if category == ErrorCategory::TooManyDisputes {
+            warn!(
+                "Rejecting dispute {} for client {}, who has too many open disputes",
+                tx.id, tx.client
+            );
+        } else {
+            info!("Skipping {}: {}", tx, e);
+        }
+        self.stats.record_rejected(tx.op.name(), category, tx.line);
+        for observer in &mut self.observers {
+            observer.on_rejected(tx, e);
+        }
+        self.reject(Rejection::failed(tx, e));
+    }
+
+    /// Keep the details of a skipped record, unless enough have been kept already.
+    fn reject(&mut self, rejection: Rejection) {
+        if self.rejections.len() < self.max_rejections {
+            self.rejections.push(rejection);
+        }
+    }
+
+    /// Keep the details of at most `max` skipped records, rather than the default of 1000.
+    pub fn retain_rejections(mut self, max: usize) -> Self {
+        self.max_rejections = max;
+        self.rejections.truncate(max);
+        self
+    }
+
+    /// The accounts of the clients whose transactions have been processed, once processing is done.
+    pub fn into_accounts(self) -> A {
+        self.accounts
+    }
+
+    /// Check each account's invariants after every transaction applied to it, logging any that
+    /// are violated.
+    pub fn paranoid(mut self) -> Self {
+        self.paranoid = true;
+        self
+    }
+
+    /// Also write each transaction that is applied successfully to `writer`, as a normalized
+    /// transaction log that can be re-run to reproduce the same balances.
+    pub fn emit_normalized(mut self, writer: Box<dyn Write>) -> Self {
+        self.normalized = Some(NormalizedWriter::new(writer));
+        self
+    }
+
+    /// Tell `observer` about each transaction applied or skipped from now on, after any observers
+    /// already registered.
+    pub fn observe(mut self, observer: Box<dyn TxObserver<A::Amount>>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Also write a ledger of each transaction applied successfully to `writer`, numbered in the
+    /// order they were applied, as an audit trail that can be replayed to reproduce the balances.
+    pub fn write_ledger(mut self, writer: Box<dyn Write>, format: LedgerFormat) -> Self {
+        self.ledger = Some(LedgerWriter::new(writer, format));
+        self
+    }
+
+    /// Apply a transaction, logging and skipping it if it fails.
+    fn apply(&mut self, tx: &Transaction) -> Result<ApplyOutcome, ProcessError> {
+        let outcome = match self.handle_record(tx) {
+            Ok(outcome) => {
+                self.stats.record_applied(tx.op.name());
+                self.dirty.insert(tx.client);
+                if let Some(account) = self.accounts.get(tx.client) {
+                    for observer in &mut self.observers {
+                        observer.on_applied(tx, account);
+                    }
+                }
+                if let Some(normalized) = &mut self.normalized {
+                    normalized.write(tx)?;
+                }
+                if let Some(ledger) = &mut self.ledger {
+                    ledger.write(tx)?;
+                }
+                outcome
+            }
+            Err(RecordFailure::Store(e)) => return Err(e.into()),
+            Err(RecordFailure::Rejected(TxError::DuplicateTx(conflict)))
+                if self.config.unique_tx_ids =>
+            {
+                return Err(ProcessError::TxIdConflict(conflict));
+            }
+            Err(RecordFailure::Rejected(e)) => {
+                self.skip(tx, &e);
+                ApplyOutcome::Skipped(e)
+            }
+        };
+        self.evict_deposits()?;
+        self.check_held_limit(tx)?;
+        Ok(outcome)
+    }
+
+    /// Stop keeping the oldest deposits once there are more than the retention allows. A deposit
+    /// under dispute is kept until the dispute is settled, going to the back of the queue.
+    fn evict_deposits(&mut self) -> Result<(), StoreError> {
+        let Some(retention) = self.config.deposit_retention else {
+            return Ok(());
+        };
+        let mut disputed = 0;
+        while self.retained.len() > retention && disputed < self.retained.len() {
+            let Some((tx_id, client, line)) = self.retained.pop_front() else {
+                break;
+            };
+            if self
+                .accounts
+                .get(client)
+                .is_some_and(|account| account.is_disputed(tx_id))
+            {
+                self.retained.push_back((tx_id, client, line));
+                disputed += 1;
+            } else {
+                self.disputable.remove_deposit(tx_id)?;
+                self.evicted_through = self.evicted_through.max(Some(line));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply any waiting transactions whose referenced transaction has now appeared, and drop any
+    /// that have waited too long, calling `on_processed` with what came of each.
+    fn retry_pending(
+        &mut self,
+        on_processed: &mut dyn FnMut(&Transaction, &ApplyOutcome),
+    ) -> Result<(), ProcessError> {
+        for (tx, remaining) in std::mem::take(&mut self.pending) {
+            if !self.references_unknown_tx(&tx)? {
+                let outcome = self.apply(&tx)?;
+                on_processed(&tx, &outcome);
+            } else if remaining > 1 {
+                self.pending.push_back((tx, remaining - 1));
+            } else {
+                let e = TxError::UnknownTx { tx: tx.id };
+                self.skip(&tx, &e);
+                on_processed(&tx, &ApplyOutcome::Skipped(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a transaction refers to a deposit or withdrawal that hasn't been seen.
+    fn references_unknown_tx(&self, tx: &Transaction) -> Result<bool, StoreError> {
+        Ok(tx.op.references_tx() && self.disputable.seen(tx.id)?.is_none())
+    }
+
+    /// Process a single transaction record. Returns how it was applied, or why it failed.
+    fn handle_record(&mut self, tx: &Transaction) -> Result<ApplyOutcome, RecordFailure> {
+        // Whatever happens to the account, including deposits settling before the transaction is
+        // rejected, is reflected in the total held. The total across all accounts can overflow even
+        // though each account's can't. It's only used to check against the limit, so saturating is
+        // good enough.
+        let held = |processor: &Self| {
+            let account = processor.accounts.get(tx.client);
+            account.map(|account| account.held().to_decimal())
+        };
+        let held_before = held(self).unwrap_or_default();
+        let result = self.apply_record(tx);
+        let held_after = held(self).unwrap_or_default();
+        self.total_held = self.total_held.saturating_add(held_after - held_before);
+        result
+    }
+
+    // Apply a single transaction record to the client's account, creating it if need be.
+    fn apply_record(&mut self, tx: &Transaction) -> Result<ApplyOutcome, RecordFailure> {
+        let policy = self.config.account;
+        let track_history = self.track_history;
+        // The account's only new to the transaction that's first applied to it.
+        let is_new_account = self.accounts.get(tx.client).is_none_or(Account::is_fresh);
+        let account = self.accounts.get_or_create(tx.client, || {
+            let account = Account::empty(tx.client).with_policy(policy);
+            if track_history {
+                account.with_history()
+            } else {
+                account
+            }
+        });
+        account.settle_deposits();
+        account.set_sequence(self.sequence);
+        // The amount is in the account's type from here on, which may not be able to represent it.
+        let op = tx.op.to_amount().ok_or_else(|| TxError::InexactAmount {
+            amount: tx.op.amount().unwrap_or_default(),
+        })?;
+        check_tx_id(tx, &mut self.disputable, self.config.tx_id_scope)?;
+        account.check_currency(tx.currency)?;
+        let referenced = if tx.op.references_tx() {
+            self.disputable.disputable(tx.id)?
+        } else {
+            None
+        };
+
+        // Record what later disputes need to know, and apply the rules that depend on the rest of the
+        // transaction log rather than just the account.
+        match tx.op {
+            Operation::Dispute(_) => {
+                if let Some(disputed) = referenced {
+                    let age = tx.line.saturating_sub(disputed.line);
+                    if self
+                        .config
+                        .dispute_window
+                        .is_some_and(|window| age > window)
+                    {
+                        return Err(TxError::DisputeWindowExpired { tx: tx.id, age }.into());
+                    }
+                } else if let Some(through) = self.evicted_through {
+                    if self
+                        .disputable
+                        .seen(tx.id)?
+                        .is_some_and(|seen| seen.line <= through)
+                    {
+                        return Err(TxError::DepositEvicted { tx: tx.id }.into());
+                    }
+                }
+            }
+            Operation::Resolve
+                if self.config.lenient_resolve
+                    && referenced.is_some()
+                    && !account.is_disputed(tx.id) =>
+            {
+                debug!("Ignoring resolve for undisputed transaction {}", tx.id);
+                return Ok(ApplyOutcome::NoOpLenient);
+            }
+            // Only the client's first transaction can be an opening balance. It's not stored as a
+            // deposit, so that it can't be disputed.
+            Operation::OpeningBalance(_) if !is_new_account => {
+                return Err(TxError::LateOpeningBalance { client: tx.client }.into());
+            }
+            Operation::Freeze | Operation::Thaw if !self.config.allow_admin_ops => {
+                return Err(TxError::AdminOpNotAllowed { op: tx.op.name() }.into());
+            }
+            _ => {}
+        }
+
+        // Work out the funds the transaction moves in or out of the account independently of how the
+        // account applies it.
+        let moved = match tx.op {
+            Operation::Deposit(amount) | Operation::OpeningBalance(amount) => amount,
+            Operation::Withdrawal(amount) => -amount,
+            Operation::Commit => -account.reservation(tx.id).unwrap_or_default().to_decimal(),
+            Operation::Chargeback => {
+                let disputed = account
+                    .open_disputes()
+                    .find(|(id, _)| *id == tx.id)
+                    .map_or(Decimal::ZERO, |(_, amount)| amount.to_decimal());
+                match referenced {
+                    Some(DisputableTx {
+                        direction: DisputeDirection::Debit,
+                        ..
+                    }) => disputed,
+                    _ => -disputed,
+                }
+            }
+            _ => Decimal::ZERO,
+        };
+
+        let locked_before = account.is_locked();
+        // The deposit's amount was representable when it was applied.
+        let referenced = referenced.and_then(|deposit| {
+            Some(DisputableTx {
+                amount: A::Amount::from_decimal_exact(deposit.amount)?,
+                direction: deposit.direction,
+                line: deposit.line,
+            })
+        });
+        let result = account.apply(tx.id, &op, &Referenced(tx.id, referenced));
+        if result.is_ok() {
+            self.net_funds = self.net_funds.saturating_add(moved);
+        }
+        if !locked_before && account.is_locked() {
+            self.stats.record_account_locked();
+            for observer in &mut self.observers {
+                observer.on_account_locked(account);
+            }
+        }
+        if self.paranoid {
+            for violation in account.check_invariants() {
+                error!(
+                    "Account {} is inconsistent after {} {} on line {}: {}",
+                    tx.client,
+                    tx.op.name(),
+                    tx.id,
+                    tx.line,
+                    violation
+                );
+            }
+        }
+        // Only store a deposit or withdrawal that went through, so a chargeback can't take back
+        // funds that never arrived or return funds that were never withdrawn. When a deposit and
+        // withdrawal share an ID, disputes apply to whichever came first.
+        match tx.op {
+            Operation::Deposit(amount)
+                if result.is_ok() && is_referenced(&self.referenced, tx.id) =>
+            {
+                if self.config.deposit_retention.is_some() && !self.disputable.contains_tx(tx.id)? {
+                    self.retained.push_back((tx.id, tx.client, tx.line));
+                }
+                self.disputable.insert_deposit(
+                    tx.id,
+                    DisputableTx {
+                        amount,
+                        direction: DisputeDirection::Credit,
+                        line: tx.line,
+                    },
+                )?;
+            }
+            Operation::Withdrawal(amount)
+                if result.is_ok()
+                    && self.config.dispute_withdrawals
+                    && is_referenced(&self.referenced, tx.id) =>
+            {
+                if self.config.deposit_retention.is_some() && !self.disputable.contains_tx(tx.id)? {
+                    self.retained.push_back((tx.id, tx.client, tx.line));
+                }
+                self.disputable.insert_deposit(
+                    tx.id,
+                    DisputableTx {
+                        amount,
+                        direction: DisputeDirection::Debit,
+                        line: tx.line,
+                    },
+                )?;
+            }
+            _ => {}
+        }
+        result?;
+        if is_new_account {
+            self.stats.record_account_created();
+            for observer in &mut self.observers {
+                observer.on_account_created(tx.client);
+            }
+        }
+        Ok(if is_new_account {
+            ApplyOutcome::AccountCreated
+        } else {
+            ApplyOutcome::Applied
+        })
+    }
+
+    /// Check whether the total funds held across all accounts has gone over the configured limit,
+    /// which indicates excessive dispute exposure. Depending on the configuration this either warns
+    /// or returns an error to abort processing.
+    fn check_held_limit(&mut self, tx: &Transaction) -> Result<(), ProcessError> {
+        let Some(limit) = self.config.max_total_held else {
+            return Ok(());
+        };
+
+        let exceeded = self.total_held > limit;
+        if exceeded && !self.held_limit_exceeded {
+            let e = HeldLimitExceeded {
+                total_held: self.total_held,
+                limit,
+                line: tx.line,
+            };
+            match self.config.held_limit_action {
+                HeldLimitAction::Warn => warn!("{}", e),
+                HeldLimitAction::Halt => return Err(ProcessError::HeldLimitExceeded(e)),
+            }
+        }
+        self.held_limit_exceeded = exceeded;
+        Ok(())
+    }
+}
+
+// Whether a transaction might be referenced by a dispute, so is worth keeping.
+fn is_referenced(referenced: &Option<HashSet<u32>>, tx_id: u32) -> bool {
+    referenced.as_ref().is_none_or(|ids| ids.contains(&tx_id))
+}
+
+/// The IDs of every transaction referred to by a dispute, resolve or chargeback, which are the only
+/// ones that need keeping to be disputed. Rows that can't be parsed are ignored.
+pub fn referenced_tx_ids<I>(txs: I) -> HashSet<u32>
+where
+    I: IntoIterator<Item = Result<Transaction, ParseError>>,
+{
+    txs.into_iter()
+        .flatten()
+        .filter(|tx| {
+            matches!(
+                tx.op,
+                Operation::Dispute(_) | Operation::Resolve | Operation::Chargeback
+            )
+        })
+        .map(|tx| tx.id)
+        .collect()
+}
+
+/// Apply a stream of transactions (or rows that couldn't be parsed) using a processor, returning
+/// the processor once all of them have been processed.
+pub fn process_transactions<I, A, D>(
+    txs: I,
+    processor: Processor<A, D>,
+) -> Result<Processor<A, D>, ProcessError>
+where
+    I: IntoIterator<Item = Result<Transaction, ParseError>>,
+    A: AccountStore,
+    D: DepositStore,
+{
+    process_transactions_with(txs, processor, |_, _| {})
+}
+
+/// Like [`process_transactions`], but calls `on_processed` with each transaction and what came of
+/// it once it has been processed, e.g. to emit it to a message bus. Rows that couldn't be parsed
+/// have no transaction, so aren't passed to it. A transaction waiting for the one it references
+/// is passed first as [`ApplyOutcome::Pending`], then again once it's retried or dropped. To be told about transactions applied along with
+/// the account they left, register a [`TxObserver`] instead.
+pub fn process_transactions_with<I, A, D, F>(
+    txs: I,
+    mut processor: Processor<A, D>,
+    mut on_processed: F,
+) -> Result<Processor<A, D>, ProcessError>
+where
+    I: IntoIterator<Item = Result<Transaction, ParseError>>,
+    A: AccountStore,
+    D: DepositStore,
+    F: FnMut(&Transaction, &ApplyOutcome),
+{
+    for tx in txs {
+        match tx {
+            Ok(tx) => {
+                processor.process_reporting(&tx, &mut on_processed)?;
+            }
+            Err(e) => processor.skip_malformed(e)?,
+        }
+    }
+    processor.finish_reporting(&mut on_processed)?;
+    Ok(processor)
+}
+
+/// Process every transaction from an async stream, e.g. one read from the network, as each arrives,
+/// then finish processing once the stream ends. Applying a transaction is synchronous and fast, so
+/// this only waits on the stream.
+#[cfg(feature = "tokio")]
+pub async fn process_stream<S, A, D>(
+    txs: S,
+    mut processor: Processor<A, D>,
+) -> Result<Processor<A, D>, ProcessError>
+where
+    S: Stream<Item = Result<Transaction, ParseError>>,
+    A: AccountStore,
+    D: DepositStore,
+{
+    let mut txs = std::pin::pin!(txs);
+    while let Some(tx) = txs.next().await {
+        match tx {
+            Ok(tx) => {
+                processor.process(&tx)?;
+            }
+            Err(e) => processor.skip_malformed(e)?,
+        }
+    }
+    processor.finish()?;
+    Ok(processor)
+}
+
+/// Sort transactions by when they happened, for a transaction log that isn't in chronological
+/// order. The sort is stable, so transactions with the same timestamp stay in the order they were
+/// logged. A row without a timestamp, or that couldn't be parsed, is sorted along with the row
+/// before it. The whole log is buffered to sort it, so it needs to fit in memory.
+pub fn sort_by_timestamp<I>(txs: I) -> Vec<Result<Transaction, ParseError>>
+where
+    I: IntoIterator<Item = Result<Transaction, ParseError>>,
+{
+    let mut previous = None;
+    let mut keyed: Vec<_> = txs
+        .into_iter()
+        .map(|tx| {
+            if let Ok(Transaction {
+                timestamp: Some(timestamp),
+                ..
+            }) = tx
+            {
+                previous = Some(timestamp);
+            }
+            (previous, tx)
+        })
+        .collect();
+    keyed.sort_by_key(|(timestamp, _)| *timestamp);
+    keyed.into_iter().map(|(_, tx)| tx).collect()
+}
+
+/// Options for how accounts are displayed.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DisplayOptions {
+    /// Leave locked accounts out.
+    pub exclude_locked: bool,
+    /// Include extra columns.
+    pub extended: bool,
+    /// End with a row of totals across the accounts.
+    pub totals: bool,
+    /// How each account is written.
+    pub format: OutputFormat,
+}
+
+/// The format accounts are displayed in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// CSV, with a header.
+    #[default]
+    Csv,
+    /// A line per account of space-separated `column=value` pairs, e.g.
+    /// `client=1 available=1.8000 held=0.0000 total=1.8000 locked=false`, for grepping.
+    Summary,
+}
+
+/// Display accounts to `output`, sorted by client so the output is deterministic, however the
+/// accounts are stored. Returns the number of accounts left out.
+pub fn display_accounts<'a, N: Amount + 'a, W: Write>(
+    accounts: impl IntoIterator<Item = &'a Account<N>>,
+    options: DisplayOptions,
+    output: W,
+) -> Result<usize, csv::Error> {
+    let mut writer = Writer::from_writer(output);
+    let mut excluded = 0;
+    let mut totals = TotalsDisplay::default();
+    let mut accounts: Vec<_> = accounts.into_iter().collect();
+    accounts.sort_unstable_by_key(|acc| acc.client());
+    for acc in accounts {
+        if write_account(&mut writer, acc, options)? {
+            totals.add(&account_display(acc, options));
+        } else {
+            excluded += 1;
+        }
+    }
+    if options.totals {
+        write_row(&mut writer, &totals, options.format)?;
+    }
+    writer.flush()?;
+    Ok(excluded)
+}
+
+// Helper function that creates the display representation of an account.
+fn account_display<N: Amount>(acc: &Account<N>, options: DisplayOptions) -> AccountDisplay {
+    if options.extended {
+        acc.to_extended_display()
+    } else {
+        acc.to_display()
+    }
+}
+
+/// Write a single account to `writer`, unless the options leave it out. Returns whether it was
+/// written.
+fn write_account<N: Amount, W: Write>(
+    writer: &mut Writer<W>,
+    acc: &Account<N>,
+    options: DisplayOptions,
+) -> Result<bool, csv::Error> {
+    if options.exclude_locked && acc.is_locked() {
+        return Ok(false);
+    }
+    write_row(writer, &account_display(acc, options), options.format)?;
+    Ok(true)
+}
+
+// A row of the output: an account, or the totals.
+trait OutputRow: Serialize {
+    fn summary(&self) -> String;
+}
+
+impl OutputRow for AccountDisplay {
+    fn summary(&self) -> String {
+        AccountDisplay::summary(self)
+    }
+}
+
+impl OutputRow for TotalsDisplay {
+    fn summary(&self) -> String {
+        TotalsDisplay::summary(self)
+    }
+}
+
+// Write a row of the output in the format given. A summary line has no header, so it's written as
+// a record of its own rather than serialized.
+fn write_row<W: Write>(
+    writer: &mut Writer<W>,
+    row: &impl OutputRow,
+    format: OutputFormat,
+) -> Result<(), csv::Error> {
+    match format {
+        OutputFormat::Csv => writer.serialize(row),
+        OutputFormat::Summary => writer.write_record([row.summary()]),
+    }
+}
+
+/// Write the details of an account, for debugging: its status and balances, then each of its open
+/// disputes on a line of its own.
+pub fn write_account_detail<N: Amount, W: Write>(
+    account: &Account<N>,
+    mut output: W,
+) -> std::io::Result<()> {
+    writeln!(
+        output,
+        "Client {} ({}): available {}, held {}, total {}",
+        account.client(),
+        account.status().as_str(),
+        account.available().to_decimal(),
+        account.held().to_decimal(),
+        account.total_balance().to_decimal()
+    )?;
+    for (tx, amount) in account.open_disputes() {
+        writeln!(
+            output,
+            "  dispute of {} holding {}",
+            tx,
+            amount.to_decimal()
+        )?;
+    }
+    Ok(())
+}
+
+/// Write the history of an account as CSV, one row for each transaction applied to it.
+pub fn write_history<N: Amount, W: Write>(
+    account: &Account<N>,
+    output: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = Writer::from_writer(output);
+    for applied in account.history() {
+        writer.serialize(applied)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Cents;
+    use crate::transaction::{self, ParseOptions};
+    use proptest::prelude::*;
+    use rust_decimal_macros::dec;
+    use std::fs::File;
+
+    fn tx(id: u32, client: u16, op: Operation) -> Transaction {
+        Transaction::new(id, client, op)
+    }
+
+    // Number transactions with the lines they'd be on in a log, after its header.
+    fn numbered<const N: usize>(records: [Transaction; N]) -> [Transaction; N] {
+        let mut line = 1;
+        records.map(|tx| {
+            line += 1;
+            Transaction { line, ..tx }
+        })
+    }
+
+    // Process a transaction, returning whether it was applied rather than skipped.
+    fn applied(processor: &mut Processor, tx: &Transaction) -> bool {
+        let skipped = processor.stats().total_skipped();
+        processor.process(tx).unwrap();
+        processor.stats().total_skipped() == skipped
+    }
+
+    // Process a log with the processor, in strict mode or not.
+    fn process_log(input: &str, strict: bool) -> Result<Processor, ProcessError> {
+        process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            Processor::builder().strict(strict).build(),
+        )
+    }
+
+    #[test]
+    fn test_out_of_range_client_is_skipped() {
+        let input = "type,client,tx,amount\ndeposit,999999,1,1.0\ndeposit,1,2,1.0\n";
+        let processor = process_log(input, false).unwrap();
+        assert_eq!(processor.accounts().len(), 1);
+        assert_eq!(processor.accounts()[&1].available(), dec!(1.0));
+        assert_eq!(processor.stats().parse_failures(), 1);
+        assert_eq!(processor.rejections()[0].line, 2);
+        assert_eq!(processor.rejections()[0].client, None);
+    }
+
+    #[test]
+    fn test_out_of_range_client_fails_in_strict_mode() {
+        let input = "type,client,tx,amount\ndeposit,999999,1,1.0\ndeposit,1,2,1.0\n";
+        match process_log(input, true) {
+            Err(ProcessError::Malformed(ParseError::MalformedField { line, .. })) => {
+                assert_eq!(line, 2)
+            }
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_bad_type_fails_in_strict_mode() {
+        let input = "type,client,tx,amount\nincorrect,1,1,2.0\n";
+        match process_log(input, true) {
+            Err(ProcessError::Malformed(ParseError::InvalidRecord { line, .. })) => {
+                assert_eq!(line, 2)
+            }
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("expected an error"),
+        }
+        // Outside strict mode, the row is just skipped.
+        let processor = process_log(input, false).unwrap();
+        assert!(processor.accounts().is_empty());
+        assert_eq!(processor.stats().total_skipped(), 1);
+    }
+
+    #[test]
+    fn test_replayed_withdrawal_is_rejected() {
+        let mut processor = Processor::builder().build();
+
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 1, Operation::Withdrawal(dec!(2.0))),
+        ];
+        for record in &records {
+            assert!(applied(&mut processor, record));
+        }
+        assert!(!applied(&mut processor, &records[1]));
+
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(3.0));
+    }
+
+    #[test]
+    fn test_withdrawal_reusing_deposit_id_is_rejected() {
+        let mut processor = Processor::builder().build();
+
+        let deposit = tx(1, 1, Operation::Deposit(dec!(5.0)));
+        assert!(applied(&mut processor, &deposit));
+
+        // Reusing the ID is rejected whether it comes from the same client or a different one.
+        let withdrawal = tx(1, 1, Operation::Withdrawal(dec!(2.0)));
+        assert!(!applied(&mut processor, &withdrawal));
+        let other_client = tx(1, 2, Operation::Deposit(dec!(2.0)));
+        assert!(!applied(&mut processor, &other_client));
+
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(5.0));
+        assert_eq!(processor.accounts[&2].total_balance(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_builder_configures_processor() {
+        let records = || {
+            [
+                tx(1, 1, Operation::Deposit(dec!(2.0))),
+                tx(2, 1, Operation::Withdrawal(dec!(1.5))),
+                tx(1, 1, Operation::Dispute(None)),
+                tx(1, 1, Operation::Dispute(None)),
+            ]
+            .into_iter()
+        };
+
+        // By default the disputes are rejected as there aren't enough funds available.
+        let mut processor = Processor::builder().build();
+        let results: Vec<_> = records().map(|r| applied(&mut processor, &r)).collect();
+        assert!(!results[2] && !results[3]);
+        assert_eq!(processor.accounts[&1].available(), dec!(0.5));
+        assert_eq!(processor.accounts[&1].held(), dec!(0.0));
+
+        let mut processor = Processor::builder()
+            .dispute_policy(DisputePolicy::AllowNegative)
+            .duplicate_dispute(DuplicateDisputePolicy::Ignore)
+            .build();
+        let results: Vec<_> = records().map(|r| applied(&mut processor, &r)).collect();
+        assert!(results.iter().all(|&applied| applied));
+        assert_eq!(processor.accounts[&1].available(), dec!(-1.5));
+        assert_eq!(processor.accounts[&1].held(), dec!(2.0));
+    }
+
+    #[test]
+    fn test_cross_client_dispute_is_rejected() {
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(2.0))),
+            tx(1, 2, Operation::Dispute(None)),
+        ];
+        let accounts =
+            process_transactions(records.into_iter().map(Ok), Processor::builder().build())
+                .unwrap()
+                .accounts;
+
+        assert_eq!(accounts[&1].total_balance(), dec!(2.0));
+        assert_eq!(accounts[&2].to_display(), Account::new(2).to_display());
+    }
+
+    #[test]
+    fn test_write_history() {
+        let processor = process_transactions(
+            [
+                tx(1, 1, Operation::Deposit(dec!(5.0))),
+                tx(2, 2, Operation::Deposit(dec!(3.0))),
+                tx(1, 1, Operation::Dispute(None)),
+                tx(3, 1, Operation::Withdrawal(dec!(1.0))),
+                tx(1, 1, Operation::Resolve),
+            ]
+            .into_iter()
+            .map(Ok),
+            Processor::builder().build().track_history(),
+        )
+        .unwrap();
+
+        let mut output = Vec::new();
+        write_history(&processor.accounts[&1], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "type,tx,amount,available,held
+deposit,1,5.0,5.0,0
+dispute,1,,0.0,5.0
+resolve,1,,5.0,0.0
+"
+        );
+    }
+
+    #[test]
+    fn test_resume_from_saved_state() {
+        let first = [
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 1, Operation::Deposit(dec!(3.0))),
+            tx(1, 1, Operation::Dispute(None)),
+        ];
+        let second = [
+            tx(1, 1, Operation::Resolve),
+            // Still conflicts with the deposit from before the restart.
+            tx(2, 2, Operation::Deposit(dec!(1.0))),
+        ];
+
+        let processor = Processor::builder().build();
+        let processor = process_transactions(first.into_iter().map(Ok), processor).unwrap();
+        let saved = serde_json::to_string(&processor.into_state()).unwrap();
+
+        let state: EngineState = serde_json::from_str(&saved).unwrap();
+        let processor = Processor::builder().build().restore(state);
+        assert_eq!(processor.total_held, dec!(5.0));
+        let processor = process_transactions(second.into_iter().map(Ok), processor).unwrap();
+
+        assert_eq!(processor.accounts[&1].available(), dec!(8.0));
+        assert_eq!(processor.accounts[&1].held(), dec!(0.0));
+        assert_eq!(processor.accounts[&2].total_balance(), dec!(0.0));
+        assert_eq!(processor.total_held, dec!(0.0));
+    }
+
+    #[test]
+    fn test_state_version() {
+        let processor = process_transactions(
+            [tx(1, 1, Operation::Deposit(dec!(5.0)))]
+                .into_iter()
+                .map(Ok),
+            Processor::builder().build(),
+        )
+        .unwrap();
+        let mut saved = Vec::new();
+        processor.into_state().save(&mut saved).unwrap();
+        let mut state: serde_json::Value = serde_json::from_slice(&saved).unwrap();
+        assert_eq!(state["version"], STATE_VERSION);
+        assert!(EngineState::load(saved.as_slice()).is_ok());
+
+        state["version"] = (STATE_VERSION + 1).into();
+        let e = EngineState::load(state.to_string().as_bytes())
+            .err()
+            .unwrap();
+        assert!(matches!(e, LoadStateError::UnsupportedVersion(Some(3))));
+        assert_eq!(
+            e.to_string(),
+            "state is in version 3 of the format, but only version 2 can be read"
+        );
+
+        state.as_object_mut().unwrap().remove("version");
+        let e = EngineState::load(state.to_string().as_bytes())
+            .err()
+            .unwrap();
+        assert!(matches!(e, LoadStateError::UnsupportedVersion(None)));
+        let e = EngineState::load("{".as_bytes()).err().unwrap();
+        assert!(matches!(e, LoadStateError::Malformed(_)));
+    }
+
+    #[test]
+    fn test_version_1_state_is_rejected() {
+        // A resolved dispute, which version 1 kept as settled, just like a charged back one.
+        let state = r#"{"version":1,"accounts":{"1":{"client":1,"available":"5.0","held":"0",
+            "locked":false,"disputes":[{"tx":1,"amount":"5.0","direction":"Credit","settled":true}]}},
+            "disputable":{"1":{"amount":"5.0","direction":"Credit","line":2}},
+            "seen":{"1":{"line":2,"client":1,"direction":"Credit","paired":false}}}"#;
+        let e = EngineState::load(state.as_bytes()).err().unwrap();
+        assert!(matches!(e, LoadStateError::UnsupportedVersion(Some(1))));
+
+        // The same state is read once it's in the current version.
+        let state = state.replace(r#""version":1"#, r#""version":2"#);
+        let state = EngineState::load(state.as_bytes()).unwrap();
+        let mut processor = Processor::builder().build().restore(state);
+        assert_eq!(
+            processor
+                .process(&tx(1, 1, Operation::Dispute(None)))
+                .unwrap(),
+            ApplyOutcome::Skipped(TxError::AlreadyChargedBack { tx: 1 })
+        );
+    }
+
+    #[test]
+    fn test_total_held() {
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 2, Operation::Deposit(dec!(3.0))),
+            tx(3, 3, Operation::Deposit(dec!(2.5))),
+            tx(4, 1, Operation::Deposit(dec!(1.0))),
+            tx(1, 1, Operation::Dispute(None)),
+            tx(2, 2, Operation::Dispute(None)),
+            tx(4, 1, Operation::Dispute(None)),
+            tx(3, 3, Operation::Dispute(Some(dec!(1.5)))),
+            tx(1, 1, Operation::Resolve),
+            tx(2, 2, Operation::Chargeback),
+            // Skipped, so don't change what's held.
+            tx(2, 2, Operation::Resolve),
+            tx(9, 3, Operation::Dispute(None)),
+            tx(3, 3, Operation::Resolve),
+        ];
+
+        // The running total matches the sum over the accounts after every transaction.
+        let mut processor = Processor::builder().build();
+        let mut most_held = Decimal::ZERO;
+        for tx in &records {
+            processor.process(tx).unwrap();
+            let held: Decimal = processor.accounts.values().map(Account::held).sum();
+            assert_eq!(processor.total_held(), held);
+            most_held = most_held.max(held);
+        }
+        assert_eq!(most_held, dec!(10.5));
+        assert_eq!(processor.total_held(), dec!(1.0));
+
+        // Including when the accounts are streamed out, so are no longer there to sum.
+        let mut sorted = records.clone();
+        sorted.sort_by_key(|tx| tx.client);
+        let processor = Processor::builder()
+            .build()
+            .stream_accounts(DisplayOptions::default(), Box::new(std::io::sink()));
+        let processor = process_transactions(sorted.into_iter().map(Ok), processor).unwrap();
+        assert!(processor.accounts.is_empty());
+        assert_eq!(processor.total_held(), dec!(1.0));
+
+        // Deposits that are held until they settle count too, and stop counting once they settle,
+        // even when the record that settles them is rejected.
+        let mut processor = Processor::builder().deposit_hold(1).build();
+        for (tx, expected) in [
+            (tx(1, 1, Operation::Deposit(dec!(5.0))), dec!(5.0)),
+            (tx(2, 1, Operation::Deposit(dec!(1.0))), dec!(1.0)),
+            (tx(3, 1, Operation::Deposit(dec!(1.0))), dec!(1.0)),
+            // A reused ID, so rejected.
+            (tx(3, 1, Operation::Deposit(dec!(2.0))), dec!(0.0)),
+        ] {
+            processor.process(&tx).unwrap();
+            let held: Decimal = processor.accounts.values().map(Account::held).sum();
+            assert_eq!(held, expected);
+            assert_eq!(processor.total_held(), held);
+        }
+    }
+
+    #[test]
+    fn test_max_open_disputes() {
+        let records = numbered([
+            tx(1, 1, Operation::Deposit(dec!(1.0))),
+            tx(2, 1, Operation::Deposit(dec!(1.0))),
+            tx(3, 1, Operation::Deposit(dec!(1.0))),
+            tx(1, 1, Operation::Dispute(None)),
+            tx(2, 1, Operation::Dispute(None)),
+            tx(3, 1, Operation::Dispute(None)),
+            tx(1, 1, Operation::Resolve),
+            tx(3, 1, Operation::Dispute(None)),
+        ]);
+
+        let mut processor = Processor::builder().max_open_disputes(Some(2)).build();
+        for record in records {
+            processor.apply(&record).unwrap();
+        }
+        assert_eq!(processor.accounts[&1].held(), dec!(2.0));
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 1 record(s)\n  too many disputes: 1 (e.g. line 7)"
+        );
+    }
+
+    #[test]
+    fn test_tx_id_per_pair() {
+        let records = || {
+            [
+                tx(2, 1, Operation::Deposit(dec!(10.0))),
+                tx(1, 1, Operation::Deposit(dec!(5.0))),
+                tx(1, 1, Operation::Withdrawal(dec!(2.0))),
+                tx(1, 1, Operation::Dispute(None)),
+                // A pair can only be formed once, and only for the same client.
+                tx(1, 1, Operation::Withdrawal(dec!(1.0))),
+                tx(1, 2, Operation::Withdrawal(dec!(1.0))),
+            ]
+            .into_iter()
+        };
+
+        // By default the withdrawal reuses the deposit's ID, so is skipped.
+        let mut processor = Processor::builder().build();
+        let results: Vec<_> = records().map(|r| applied(&mut processor, &r)).collect();
+        assert!(!results[2] && results[3]);
+        assert!(!results[4] && !results[5]);
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(15.0));
+        assert_eq!(processor.accounts[&1].held(), dec!(5.0));
+
+        // When IDs are per pair the withdrawal goes through, and the dispute applies to the
+        // deposit, as it came first.
+        let mut processor = Processor::builder().tx_id_scope(TxIdScope::Pair).build();
+        let results: Vec<_> = records().map(|r| applied(&mut processor, &r)).collect();
+        assert!(results[2] && results[3]);
+        assert!(!results[4] && !results[5]);
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(13.0));
+        assert_eq!(processor.accounts[&1].held(), dec!(5.0));
+    }
+
+    #[test]
+    fn test_unique_tx_ids_aborts_on_reuse() {
+        let records = || {
+            [
+                Transaction {
+                    line: 2,
+                    ..tx(1, 1, Operation::Deposit(dec!(2.0)))
+                },
+                Transaction {
+                    line: 3,
+                    ..tx(1, 2, Operation::Withdrawal(dec!(1.0)))
+                },
+            ]
+            .into_iter()
+            .map(Ok)
+        };
+
+        // By default the conflicting record is just skipped.
+        assert!(process_transactions(records(), Processor::builder().build()).is_ok());
+
+        let processor = Processor::builder().unique_tx_ids(true).build();
+        let err = process_transactions(records(), processor).err().unwrap();
+        let ProcessError::TxIdConflict(conflict) = err else {
+            panic!("expected a conflict, got {}", err);
+        };
+        assert_eq!(conflict.tx, 1);
+        assert_eq!((conflict.first_line, conflict.first_client), (2, 1));
+        assert_eq!((conflict.line, conflict.client), (3, 2));
+    }
+
+    #[test]
+    fn test_unique_tx_ids_aborts_on_cross_client_reference() {
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(2.0))),
+            tx(1, 1, Operation::Dispute(None)),
+            tx(1, 1, Operation::Resolve),
+            tx(1, 2, Operation::Resolve),
+        ];
+        let processor = Processor::builder().unique_tx_ids(true).build();
+        let err = process_transactions(records.into_iter().map(Ok), processor)
+            .err()
+            .unwrap();
+        assert!(matches!(err, ProcessError::TxIdConflict(_)));
+    }
+
+    #[test]
+    fn test_reorder_window() {
+        let records = || {
+            [
+                tx(1, 1, Operation::Dispute(None)),
+                tx(2, 2, Operation::Deposit(dec!(1.0))),
+                tx(3, 2, Operation::Deposit(dec!(1.0))),
+                tx(1, 1, Operation::Deposit(dec!(2.0))),
+            ]
+            .into_iter()
+            .map(Ok)
+        };
+
+        let processor = Processor::builder().reorder_window(5).build();
+        let accounts = process_transactions(records(), processor).unwrap().accounts;
+        assert_eq!(accounts[&1].available(), dec!(0.0));
+        assert_eq!(accounts[&1].held(), dec!(2.0));
+        assert_eq!(accounts[&2].total_balance(), dec!(2.0));
+
+        let processor = Processor::builder().reorder_window(1).build();
+        let accounts = process_transactions(records(), processor).unwrap().accounts;
+        assert_eq!(accounts[&1].available(), dec!(2.0));
+        assert_eq!(accounts[&1].held(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_reorder_window_keeps_order_of_waiting_transactions() {
+        let records = [
+            tx(1, 1, Operation::Dispute(None)),
+            tx(1, 1, Operation::Chargeback),
+            tx(1, 1, Operation::Deposit(dec!(2.0))),
+            tx(2, 1, Operation::Deposit(dec!(1.0))),
+        ];
+
+        let processor = Processor::builder().reorder_window(3).build();
+        let accounts = process_transactions(records.into_iter().map(Ok), processor)
+            .unwrap()
+            .accounts;
+
+        // The dispute and chargeback are applied in order once the deposit arrives, locking
+        // the account before the second deposit.
+        assert_eq!(accounts[&1].total_balance(), dec!(0.0));
+        assert!(accounts[&1].is_locked());
+    }
+
+    #[test]
+    fn test_skipped_records_are_summarized() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 5.0
+bogus, 1, 3, 1.0
+deposit, 99999999, 4, 1.0
+deposit, 1, 1, 1.0
+dispute, 1, 1
+chargeback, 1, 1
+deposit, 1, 5, 1.0
+resolve, 1, 6
+";
+        let processor = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            Processor::builder().build(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 6 record(s)
+  parse error: 1 (e.g. line 5)
+  unknown type: 1 (e.g. line 4)
+  insufficient funds: 1 (e.g. line 3)
+  account locked: 2 (e.g. lines 9, 10)
+  duplicate: 1 (e.g. line 6)"
+        );
+    }
+
+    #[test]
+    fn test_total_held_limit() {
+        let records = || {
+            [
+                tx(1, 1, Operation::Deposit(dec!(5.0))),
+                tx(2, 2, Operation::Deposit(dec!(5.0))),
+                tx(1, 1, Operation::Dispute(None)),
+                tx(2, 2, Operation::Dispute(None)),
+                tx(1, 1, Operation::Resolve),
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(i, record)| {
+                Ok(Transaction {
+                    line: i as u64 + 2,
+                    ..record
+                })
+            })
+        };
+
+        // When warning, processing carries on as normal.
+        let processor = Processor::builder()
+            .max_total_held(dec!(8.0), HeldLimitAction::Warn)
+            .build();
+        let processor = process_transactions(records(), processor).unwrap();
+        assert_eq!(processor.total_held, dec!(5.0));
+        assert!(!processor.held_limit_exceeded);
+
+        // Otherwise processing stops once the second dispute takes the total over the limit.
+        let processor = Processor::builder()
+            .max_total_held(dec!(8.0), HeldLimitAction::Halt)
+            .build();
+        let err = process_transactions(records(), processor).err().unwrap();
+        let ProcessError::HeldLimitExceeded(exceeded) = err else {
+            panic!("expected the limit to be exceeded, got {}", err);
+        };
+        assert_eq!(exceeded.total_held, dec!(10.0));
+        assert_eq!(exceeded.line, 5);
+    }
+
+    #[test]
+    fn test_lenient_resolve() {
+        let records = || {
+            [
+                tx(1, 1, Operation::Deposit(dec!(5.0))),
+                tx(1, 1, Operation::Resolve),
+                tx(2, 1, Operation::Resolve),
+            ]
+            .map(Ok)
+        };
+
+        // Resolving a known transaction without a dispute is a no-op, but resolving an unknown one
+        // is still skipped as an error.
+        let processor = Processor::builder().lenient_resolve(true).build();
+        let processor = process_transactions(records(), processor).unwrap();
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(5.0));
+        assert_eq!(processor.stats.total_skipped(), 1);
+
+        // Without the flag, both resolves are skipped.
+        let processor = process_transactions(records(), Processor::builder().build()).unwrap();
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(5.0));
+        assert_eq!(processor.stats.total_skipped(), 2);
+    }
+
+    #[test]
+    fn test_emit_normalized_reruns_to_same_balances() {
+        let input = r"type,    client, tx, amount
+deposit,    1, 1, 10.0
+deposit,    2, 2, 3.00
+withdrawal, 1, 3, 20.0
+bogus,      1, 4, 1.0
+dispute,    1, 1, 4.0
+dispute,    2, 9,
+withdrawal, 1, 5, 1.5
+chargeback, 1, 1,
+deposit,    1, 6, 1.0
+";
+        let path = std::env::temp_dir().join(format!("normalized-{}.csv", std::process::id()));
+
+        let processor = Processor::builder()
+            .build()
+            .emit_normalized(Box::new(File::create(&path).unwrap()));
+        let original = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            processor,
+        )
+        .unwrap();
+        assert_eq!(original.stats.total_skipped(), 4);
+
+        // None of the skipped records make it into the normalized log.
+        let rerun = process_transactions(
+            transaction::iter_over_file(path.to_str().unwrap(), ParseOptions::default()).unwrap(),
+            Processor::builder().build(),
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rerun.stats.total_skipped(), 0);
+        assert_eq!(original.accounts, rerun.accounts);
+    }
+
+    #[test]
+    fn test_ledger() {
+        let input = r"type,    client, tx, amount
+deposit,    1, 1, 10.0
+deposit,    1, 2, 3.00
+withdrawal, 1, 3, 20.0
+dispute,    1, 1,
+dispute,    1, 9,
+resolve,    1, 1,
+dispute,    1, 2,
+chargeback, 1, 2,
+deposit,    1, 4, 1.0
+";
+        let path = std::env::temp_dir().join(format!("ledger-{}.csv", std::process::id()));
+        let processor = Processor::builder()
+            .build()
+            .write_ledger(Box::new(File::create(&path).unwrap()), LedgerFormat::Csv);
+        let original = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            processor,
+        )
+        .unwrap();
+        let ledger = std::fs::read_to_string(&path).unwrap();
+
+        // The rejected records are left out, and the disputes settled in the order they were.
+        assert_eq!(
+            ledger,
+            "seq,type,client,tx,amount
+1,deposit,1,1,10
+2,deposit,1,2,3
+3,dispute,1,1,
+4,resolve,1,1,
+5,dispute,1,2,
+6,chargeback,1,2,
+"
+        );
+
+        // Replaying the ledger reproduces the same accounts.
+        let replay = process_transactions(
+            transaction::iter_over_file(path.to_str().unwrap(), ParseOptions::default()).unwrap(),
+            Processor::builder().build(),
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(original.accounts, replay.accounts);
+
+        let path = std::env::temp_dir().join(format!("ledger-{}.ndjson", std::process::id()));
+        let processor = Processor::builder()
+            .build()
+            .write_ledger(Box::new(File::create(&path).unwrap()), LedgerFormat::Ndjson);
+        process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            processor,
+        )
+        .unwrap();
+        let ledger = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            ledger.lines().next(),
+            Some(r#"{"seq":1,"type":"deposit","client":1,"tx":1,"amount":"10"}"#)
+        );
+        assert_eq!(ledger.lines().count(), 6);
+    }
+
+    #[test]
+    fn test_sort_by_timestamp() {
+        let input = r"type, client, tx, amount, timestamp
+deposit, 1, 1, 5.0, 100
+dispute, 1, 2, , 300
+chargeback, 1, 2, ,
+withdrawal, 1, 3, 1.0, 150
+deposit, 1, 2, 2.0, 200
+bogus, 1, 4, 1.0, 50
+";
+        let run = |sort: bool| {
+            let txs = transaction::iter_over_reader(input.as_bytes(), ParseOptions::default())
+                .unwrap()
+                .collect::<Vec<_>>();
+            let txs = if sort { sort_by_timestamp(txs) } else { txs };
+            process_transactions(txs, Processor::builder().build()).unwrap()
+        };
+
+        // In file order the dispute and chargeback come before the deposit they refer to, so are
+        // skipped.
+        let unsorted = run(false);
+        assert_eq!(unsorted.accounts[&1].total_balance(), dec!(6.0));
+        assert!(!unsorted.accounts[&1].is_locked());
+        assert_eq!(unsorted.stats.total_skipped(), 3);
+
+        // The chargeback without a timestamp is sorted along with the dispute before it, and only
+        // the bogus row is skipped.
+        let sorted = run(true);
+        assert_eq!(sorted.accounts[&1].total_balance(), dec!(4.0));
+        assert!(sorted.accounts[&1].is_locked());
+        assert_eq!(sorted.stats.total_skipped(), 1);
+    }
+
+    #[test]
+    fn test_rejections() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, 5.0
+withdrawal, 1, 2, 9.0
+bogus, 1, 3, 1.0
+deposit, x, 4, 1.0
+dispute, 2, 1,
+deposit, 1, 1, 1.0
+deposit, 1, 5, 1.0
+";
+        let process = |processor: Processor| {
+            process_transactions(
+                transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+                processor,
+            )
+            .unwrap()
+        };
+
+        let processor = process(Processor::builder().build());
+        let rejections = processor.rejections();
+        assert_eq!(rejections.len(), 5);
+        assert_eq!(
+            rejections[0],
+            Rejection {
+                line: 3,
+                client: Some(1),
+                tx: Some(2),
+                op_kind: Some("withdrawal"),
+                reason: RejectionReason::Failed(TxError::InsufficientFunds),
+            }
+        );
+        assert_eq!(
+            rejections[1],
+            Rejection {
+                line: 4,
+                client: None,
+                tx: None,
+                op_kind: None,
+                reason: RejectionReason::InvalidRecord(TxError::UnknownType("bogus".to_string())),
+            }
+        );
+        assert_eq!((rejections[2].line, rejections[2].client), (5, None));
+        assert!(matches!(
+            rejections[2].reason,
+            RejectionReason::Malformed(_)
+        ));
+        // The dispute references another client's deposit, and the deposit reuses its ID.
+        assert!(matches!(
+            &rejections[3],
+            Rejection {
+                line: 6,
+                client: Some(2),
+                reason: RejectionReason::Failed(TxError::DuplicateTx(conflict)),
+                ..
+            } if conflict.first_client == 1
+        ));
+        assert!(matches!(
+            &rejections[4],
+            Rejection {
+                line: 7,
+                op_kind: Some("deposit"),
+                reason: RejectionReason::Failed(TxError::DuplicateTx(_)),
+                ..
+            }
+        ));
+        assert_eq!(
+            rejections[0].to_string(),
+            "line 3 (withdrawal 2 for client 1): Insufficient funds"
+        );
+
+        // Only the earliest are kept, though they're all counted.
+        let processor = process(Processor::builder().build().retain_rejections(2));
+        assert_eq!(processor.rejections(), &rejections[..2]);
+        assert_eq!(processor.stats.total_skipped(), 5);
+    }
+
+    #[test]
+    fn test_deposit_hold() {
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 1, Operation::Withdrawal(dec!(5.0))),
+            tx(3, 2, Operation::Deposit(dec!(1.0))),
+            tx(4, 1, Operation::Withdrawal(dec!(5.0))),
+        ];
+
+        // The withdrawal fails until the deposit has settled, which only counts the client's own
+        // records.
+        let mut processor = Processor::builder().deposit_hold(2).build();
+        let results: Vec<_> = records.iter().map(|r| applied(&mut processor, r)).collect();
+        assert!(!results[1] && results[3]);
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(0.0));
+        assert_eq!(processor.accounts[&2].held(), dec!(1.0));
+    }
+
+    #[test]
+    fn test_dispute_window() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 5.0
+deposit, 1, 3, 5.0
+dispute, 1, 2,
+dispute, 1, 1,
+";
+        let processor = Processor::builder().dispute_window(Some(2)).build();
+        let processor = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            processor,
+        )
+        .unwrap();
+
+        // The dispute of tx 2 is within the window, but tx 1 was deposited 4 records earlier.
+        assert_eq!(processor.accounts[&1].held(), dec!(5.0));
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 1 record(s)\n  late dispute: 1 (e.g. line 6)"
+        );
+    }
+
+    #[test]
+    fn test_dispute_ages() {
+        let first = [
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 2, Operation::Deposit(dec!(3.0))),
+            tx(3, 2, Operation::Deposit(dec!(1.0))),
+            tx(1, 1, Operation::Dispute(None)),
+            tx(4, 2, Operation::Withdrawal(dec!(0.5))),
+        ];
+        let second = [
+            tx(5, 1, Operation::Deposit(dec!(2.0))),
+            tx(2, 2, Operation::Dispute(None)),
+            tx(6, 2, Operation::Deposit(dec!(1.0))),
+        ];
+        let ages = |processor: &Processor, client| {
+            let account = processor.account(client).unwrap();
+            let mut ages: Vec<_> = account.dispute_ages(processor.sequence()).collect();
+            ages.sort_unstable();
+            ages
+        };
+
+        let processor = Processor::builder().build();
+        let processor = process_transactions(first.into_iter().map(Ok), processor).unwrap();
+        // Aged by every client's transactions, not just client 1's.
+        assert_eq!(processor.sequence(), 5);
+        assert_eq!(ages(&processor, 1), [(1, 1)]);
+
+        // The sequence carries on from where the saved state left off.
+        let saved = serde_json::to_string(&processor.into_state()).unwrap();
+        let state: EngineState = serde_json::from_str(&saved).unwrap();
+        let processor = Processor::builder().build().restore(state);
+        let processor = process_transactions(second.into_iter().map(Ok), processor).unwrap();
+        assert_eq!(processor.sequence(), 8);
+        assert_eq!(ages(&processor, 1), [(1, 4)]);
+        assert_eq!(ages(&processor, 2), [(2, 1)]);
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let mut block = String::new();
+        for id in 1..=50u32 {
+            let client = id % 5;
+            match id % 10 {
+                3 => block.push_str(&format!("withdrawal,{},{},0.5\n", client, id)),
+                7 => block.push_str(&format!("dispute,{},{},\n", client, id - 5)),
+                _ => block.push_str(&format!("deposit,{},{},{}.25\n", client, id, id % 4)),
+            }
+        }
+        let process = |log: &str, builder: ProcessorBuilder| {
+            let log = format!("type,client,tx,amount\n{}", log);
+            let processor = process_transactions(
+                transaction::iter_over_reader(log.as_bytes(), ParseOptions::default()).unwrap(),
+                builder.build(),
+            )
+            .unwrap();
+            let mut output = Vec::new();
+            display_accounts(
+                processor.accounts.values(),
+                DisplayOptions::default(),
+                &mut output,
+            )
+            .unwrap();
+            (processor, String::from_utf8(output).unwrap())
+        };
+
+        let (single, once) = process(&block, Processor::builder());
+        let twice = block.repeat(2);
+        let (processor, deduped) = process(&twice, Processor::builder().dedupe(true));
+        assert_eq!(deduped, once);
+        // The repeated disputes aren't repeats, but are skipped as the deposits are already
+        // disputed.
+        assert_eq!(
+            processor.stats.total_skipped(),
+            single.stats.total_skipped() + 50
+        );
+        let repeats = |processor: &Processor| {
+            processor
+                .rejections
+                .iter()
+                .filter(|rejection| {
+                    matches!(
+                        rejection.reason,
+                        RejectionReason::Failed(TxError::RepeatedRecord { .. })
+                    )
+                })
+                .count()
+        };
+        assert_eq!(repeats(&processor), 45);
+        assert!(processor.rejections.iter().any(|rejection| rejection.reason
+            == RejectionReason::Failed(TxError::RepeatedRecord { first_line: 2 })));
+
+        // Without deduplicating, the repeats are still skipped, but as conflicting IDs.
+        let (processor, repeated) = process(&twice, Processor::builder());
+        assert_eq!(repeated, once);
+        assert!(processor.rejections.iter().any(|rejection| matches!(
+            rejection.reason,
+            RejectionReason::Failed(TxError::DuplicateTx(_))
+        )));
+
+        // The window has to reach back to the first block's deposits and withdrawals to catch
+        // the repeats.
+        let repeats_within = |window| {
+            let builder = Processor::builder()
+                .dedupe(true)
+                .dedupe_window(Some(window));
+            repeats(&process(&twice, builder).0)
+        };
+        assert_eq!(repeats_within(45), 45);
+        assert_eq!(repeats_within(44), 0);
+
+        // A transaction can be disputed again once the first dispute is resolved, which isn't a
+        // repeat.
+        let (processor, _) = process(
+            "deposit,1,1,2.0\ndispute,1,1,\nresolve,1,1,\ndispute,1,1,\n",
+            Processor::builder().dedupe(true),
+        );
+        assert_eq!(processor.stats.total_skipped(), 0);
+        assert_eq!(processor.accounts[&1].held(), dec!(2.0));
+    }
+
+    #[test]
+    fn test_deposit_retention() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 4.0
+dispute, 1, 2,
+deposit, 2, 3, 3.0
+deposit, 2, 4, 2.0
+dispute, 1, 1,
+dispute, 2, 4,
+resolve, 1, 2,
+dispute, 2, 3,
+";
+        let processor = Processor::builder().deposit_retention(Some(2)).build();
+        let processor = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            processor,
+        )
+        .unwrap();
+
+        // Tx 1 was evicted to make way for tx 3. Tx 2 was under dispute, so tx 3 was evicted in
+        // its place to make way for tx 4.
+        assert_eq!(processor.accounts[&1].held(), dec!(0));
+        assert_eq!(processor.accounts[&2].held(), dec!(2.0));
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 2 record(s)\n  late dispute: 2 (e.g. lines 7, 10)"
+        );
+        assert!(matches!(
+            processor.rejections()[0].reason,
+            RejectionReason::Failed(TxError::DepositEvicted { tx: 1 })
+        ));
+        assert_eq!(processor.deposits().disputable_count(), 2);
+        assert_eq!(processor.deposits().seen_count(), 4);
+
+        // Only the deposits kept to be disputed are bounded. Every ID seen is still kept.
+        let deposits = (1..=1000).map(|id| Ok(tx(id, 1, Operation::Deposit(dec!(1.0)))));
+        let processor = Processor::builder().deposit_retention(Some(10)).build();
+        let processor = process_transactions(deposits, processor).unwrap();
+        assert_eq!(processor.deposits().disputable_count(), 10);
+        assert_eq!(processor.deposits().seen_count(), 1000);
+    }
+
+    #[test]
+    fn test_locked_account_rejections_are_counted() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 5.0
+dispute, 1, 1,
+chargeback, 1, 1,
+deposit, 1, 3, 1.0
+withdrawal, 1, 4, 1.0
+dispute, 1, 2,
+deposit, 2, 5, 1.0
+";
+        let processor = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            Processor::builder().build(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 3 record(s)\n  account locked: 3 (e.g. lines 6, 7, 8)"
+        );
+    }
+
+    #[test]
+    fn test_exclude_locked_accounts() {
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 2, Operation::Deposit(dec!(5.0))),
+            tx(1, 1, Operation::Dispute(None)),
+            tx(1, 1, Operation::Chargeback),
+        ];
+        let processor =
+            process_transactions(records.map(Ok), Processor::builder().build()).unwrap();
+
+        let mut output = Vec::new();
+        let excluded = display_accounts(
+            processor.accounts.values(),
+            DisplayOptions {
+                exclude_locked: true,
+                ..Default::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(excluded, 1);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n2,5.0000,0.0000,5.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_overflowing_deposits_are_skipped() {
+        let records = numbered([
+            tx(1, 1, Operation::Deposit(Decimal::MAX)),
+            tx(2, 1, Operation::Deposit(Decimal::MAX)),
+            tx(3, 2, Operation::Deposit(dec!(2.5))),
+            tx(4, 1, Operation::Deposit(Decimal::MAX)),
+        ]);
+        let processor =
+            process_transactions(records.map(Ok), Processor::builder().build()).unwrap();
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 2 record(s)\n  overflow: 2 (e.g. lines 3, 5)"
+        );
+
+        let mut output = Vec::new();
+        display_accounts(
+            processor.accounts.values(),
+            DisplayOptions::default(),
+            &mut output,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains(&format!("1,{0}.0000,0.0000,{0}.0000,false", Decimal::MAX)));
+        assert!(output.contains("2,2.5000,0.0000,2.5000,false"));
+    }
+
+    #[test]
+    fn test_overflowing_deposit_cannot_be_disputed() {
+        let records = numbered([
+            tx(4, 3, Operation::Deposit(Decimal::MAX)),
+            tx(5, 3, Operation::Deposit(dec!(1.0))),
+            tx(5, 3, Operation::Dispute(None)),
+        ]);
+        let processor =
+            process_transactions(records.map(Ok), Processor::builder().build()).unwrap();
+
+        // The deposit never arrived, so there's nothing to hold.
+        assert_eq!(processor.accounts[&3].available(), Decimal::MAX);
+        assert_eq!(processor.accounts[&3].held(), dec!(0.0));
+        assert_eq!(
+            processor.rejections()[1].reason,
+            RejectionReason::Failed(TxError::UnknownTx { tx: 5 })
+        );
+    }
+
+    #[test]
+    fn test_client_filter() {
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 2, Operation::Deposit(dec!(5.0))),
+            tx(3, 3, Operation::Deposit(dec!(5.0))),
+            tx(2, 2, Operation::Dispute(None)),
+            tx(1, 2, Operation::Deposit(dec!(1.0))),
+            tx(4, 2, Operation::Withdrawal(dec!(1.0))),
+        ];
+        let processor = Processor::builder().clients([2]).build();
+        let processor = process_transactions(records.map(Ok), processor).unwrap();
+
+        // The deposit reusing client 1's tx ID is still rejected, so the withdrawal fails too.
+        let mut output = Vec::new();
+        display_accounts(
+            processor.accounts.values(),
+            DisplayOptions::default(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n2,0.0000,5.0000,5.0000,false\n"
+        );
+        assert_eq!(processor.stats.total_skipped(), 2);
+    }
+
+    #[test]
+    fn test_extended_output_status() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, 5.0
+withdrawal, 1, 2, 5.0
+close, 1, 3,
+deposit, 1, 4, 1.0
+deposit, 2, 5, 5.0
+close, 2, 6,
+";
+        let processor = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            Processor::builder().clients([1]).build(),
+        )
+        .unwrap();
+
+        let mut output = Vec::new();
+        display_accounts(
+            processor.accounts.values(),
+            DisplayOptions {
+                extended: true,
+                ..Default::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,status,held_from_deposits,held_from_withdrawals,chargeback_count\n1,0.0000,0.0000,0.0000,false,closed,0.0000,0.0000,0\n"
+        );
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 1 record(s)\n  account closed: 1 (e.g. line 5)"
+        );
+    }
+
+    #[test]
+    fn test_totals_footer() {
+        let processor = process_transactions(
+            [
+                tx(1, 1, Operation::Deposit(dec!(5.0))),
+                tx(2, 2, Operation::Deposit(dec!(2.5))),
+                tx(3, 3, Operation::Deposit(dec!(1.25))),
+                tx(4, 3, Operation::Deposit(dec!(3.0))),
+                tx(2, 2, Operation::Dispute(None)),
+                tx(3, 3, Operation::Dispute(None)),
+                tx(3, 3, Operation::Chargeback),
+            ]
+            .into_iter()
+            .map(Ok),
+            Processor::builder().build(),
+        )
+        .unwrap();
+
+        let mut output = Vec::new();
+        let options = DisplayOptions {
+            totals: true,
+            ..Default::default()
+        };
+        display_accounts(processor.accounts.values(), options, &mut output).unwrap();
+
+        let mut reader = csv::Reader::from_reader(output.as_slice());
+        let mut rows: Vec<_> = reader.records().map(Result::unwrap).collect();
+        let footer = rows.pop().unwrap();
+        assert_eq!(rows.len(), 3);
+        for (column, name) in [(1, "available"), (2, "held"), (3, "total")] {
+            let sum: Decimal = rows
+                .iter()
+                .map(|row| row[column].parse::<Decimal>().unwrap())
+                .sum();
+            assert_eq!(footer[column].parse::<Decimal>().unwrap(), sum, "{}", name);
+        }
+        assert_eq!(
+            footer,
+            csv::StringRecord::from(vec!["total", "8.0000", "2.5000", "10.5000", "1"])
+        );
+
+        // Without the flag, there's no footer.
+        let mut output = Vec::new();
+        display_accounts(
+            processor.accounts.values(),
+            DisplayOptions::default(),
+            &mut output,
+        )
+        .unwrap();
+        assert!(!String::from_utf8(output).unwrap().contains("\ntotal,"));
+    }
+
+    #[test]
+    fn test_summary_output() {
+        let processor = process_transactions(
+            [
+                tx(1, 1, Operation::Deposit(dec!(2.0))),
+                tx(2, 1, Operation::Withdrawal(dec!(0.2))),
+                tx(3, 2, Operation::Deposit(dec!(1.5))),
+                tx(3, 2, Operation::Dispute(None)),
+            ]
+            .into_iter()
+            .map(Ok),
+            Processor::builder().build(),
+        )
+        .unwrap();
+
+        let mut output = Vec::new();
+        let options = DisplayOptions {
+            totals: true,
+            format: OutputFormat::Summary,
+            ..Default::default()
+        };
+        display_accounts(processor.accounts.values(), options, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client=1 available=1.8000 held=0.0000 total=1.8000 locked=false
+client=2 available=0.0000 held=1.5000 total=1.5000 locked=false
+client=total available=1.8000 held=1.5000 total=3.3000 locked=0
+"
+        );
+
+        // The extended columns are included too.
+        let mut output = Vec::new();
+        let options = DisplayOptions {
+            extended: true,
+            ..options
+        };
+        display_accounts(processor.accounts.values(), options, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client=1 available=1.8000 held=0.0000 total=1.8000 locked=false status=open held_from_deposits=0.0000 \
+held_from_withdrawals=0.0000 chargeback_count=0
+client=2 available=0.0000 held=1.5000 total=1.5000 locked=false status=open held_from_deposits=1.5000 \
+held_from_withdrawals=0.0000 chargeback_count=0
+client=total available=1.8000 held=1.5000 total=3.3000 locked=0 status= held_from_deposits=1.5000 \
+held_from_withdrawals=0.0000 chargeback_count=0
+"
+        );
+    }
+
+    #[test]
+    fn test_mixed_currencies_are_rejected() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, $100.00
+deposit, 1, 2, €50
+deposit, 2, 3, €50
+withdrawal, 1, 4, 10
+";
+        let options = ParseOptions {
+            currency_symbols: true,
+            ..Default::default()
+        };
+        let processor = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), options).unwrap(),
+            Processor::builder().build(),
+        )
+        .unwrap();
+
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(90.00));
+        assert_eq!(processor.accounts[&2].total_balance(), dec!(50));
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 1 record(s)\n  other: 1 (e.g. line 3)"
+        );
+    }
+
+    #[test]
+    fn test_skip_report_is_line_ordered() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, 5.0
+dispute, 1, 9,
+resolve, 1, 1,
+";
+        let processor = Processor::builder().reorder_window(3).build();
+        let processor = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            processor,
+        )
+        .unwrap();
+
+        // The dispute is only skipped at the end, once it's clear tx 9 never arrives.
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 2 record(s)\n  unknown dispute: 2 (e.g. lines 3, 4)"
+        );
+    }
+
+    #[test]
+    fn test_opening_balance() {
+        let input = r"type, client, tx, amount
+opening_balance, 1, 0, 1234.5600
+opening_balance, 2, 0, 10.0
+withdrawal, 1, 1, 234.56
+dispute, 1, 0,
+deposit, 3, 2, 1.0
+opening_balance, 3, 0, 5.0
+";
+        let processor = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            Processor::builder().build(),
+        )
+        .unwrap();
+
+        // The opening balance can't be disputed, and can't follow client 3's deposit.
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(1000.0000));
+        assert_eq!(processor.accounts[&2].total_balance(), dec!(10.0));
+        assert_eq!(processor.accounts[&3].total_balance(), dec!(1.0));
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 2 record(s)\n  unknown dispute: 1 (e.g. line 5)\n  other: 1 (e.g. line 7)"
+        );
+    }
+
+    #[test]
+    fn test_chargeback_count() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+dispute,1,1,
+dispute,1,2,
+chargeback,1,1,
+chargeback,1,2,
+thaw,1,0,
+";
+        // The second dispute was already open when the first chargeback locked the account.
+        let processor = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            Processor::builder()
+                .allow_deposits_when_locked(true)
+                .allow_admin_ops(true)
+                .build(),
+        )
+        .unwrap();
+        assert_eq!(processor.accounts[&1].chargeback_count(), 2);
+        assert_eq!(processor.stats.total_skipped(), 0);
+
+        let mut output = Vec::new();
+        display_accounts(
+            processor.accounts.values(),
+            DisplayOptions {
+                extended: true,
+                totals: true,
+                ..Default::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(
+            lines[1],
+            "1,0.0000,0.0000,0.0000,true,locked,0.0000,0.0000,2"
+        );
+        assert_eq!(lines[2], "total,0.0000,0.0000,0.0000,1,,0.0000,0.0000,2");
+    }
+
+    // Records each call it gets, in a log shared with the test.
+    struct RecordingObserver(Rc<RefCell<Vec<String>>>);
+
+    impl TxObserver for RecordingObserver {
+        fn on_applied(&mut self, tx: &Transaction, account: &Account) {
+            self.0.borrow_mut().push(format!(
+                "applied {} {}: {}",
+                tx.op.name(),
+                tx.id,
+                account.available()
+            ));
+        }
+
+        fn on_rejected(&mut self, tx: &Transaction, error: &TxError) {
+            self.0
+                .borrow_mut()
+                .push(format!("rejected {} {}: {}", tx.op.name(), tx.id, error));
+        }
+    }
+
+    #[test]
+    fn test_observers() {
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 1, Operation::Withdrawal(dec!(8.0))),
+            tx(3, 1, Operation::Withdrawal(dec!(2.0))),
+            tx(1, 1, Operation::Dispute(None)),
+        ];
+        let first = Rc::new(RefCell::new(Vec::new()));
+        let second = Rc::new(RefCell::new(Vec::new()));
+        let processor = Processor::builder()
+            .build()
+            .observe(Box::new(RecordingObserver(Rc::clone(&first))))
+            .observe(Box::new(RecordingObserver(Rc::clone(&second))));
+        process_transactions(records.map(Ok), processor).unwrap();
+
+        let expected = [
+            "applied deposit 1: 5.0",
+            "rejected withdrawal 2: Insufficient funds",
+            "applied withdrawal 3: 3.0",
+            "rejected dispute 1: Insufficient funds",
+        ];
+        assert_eq!(*first.borrow(), expected);
+        assert_eq!(*second.borrow(), expected);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_process_stream() {
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 2, Operation::Deposit(dec!(3.0))),
+            tx(3, 1, Operation::Withdrawal(dec!(1.5))),
+            tx(2, 2, Operation::Dispute(None)),
+        ];
+        let processor = process_stream(
+            futures::stream::iter(records.map(Ok)),
+            Processor::builder().build(),
+        )
+        .await
+        .unwrap();
+
+        let accounts = processor.accounts();
+        assert_eq!(accounts[&1].available(), dec!(3.5));
+        assert_eq!(accounts[&2].available(), dec!(0.0));
+        assert_eq!(accounts[&2].held(), dec!(3.0));
+    }
+
+    #[test]
+    fn test_admin_ops() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+freeze,1,0,
+deposit,1,2,1.0
+thaw,1,0,
+deposit,1,3,2.0
+";
+        let process = |builder: ProcessorBuilder| {
+            process_transactions(
+                transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+                builder.build(),
+            )
+            .unwrap()
+        };
+
+        // The frozen account rejects the deposit until it's thawed.
+        let processor = process(Processor::builder().allow_admin_ops(true));
+        assert_eq!(processor.accounts[&1].available(), dec!(7.0));
+        assert!(!processor.accounts[&1].is_locked());
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 1 record(s)\n  account locked: 1 (e.g. line 4)"
+        );
+
+        // Without the flag, neither is applied.
+        let processor = process(Processor::builder());
+        assert_eq!(processor.accounts[&1].available(), dec!(8.0));
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 2 record(s)\n  other: 2 (e.g. lines 3, 5)"
+        );
+    }
+
+    #[test]
+    fn test_deposit_rejected_while_frozen_cannot_be_disputed() {
+        let input = "type,client,tx,amount
+deposit,2,2,500
+freeze,2,0,
+deposit,2,3,100
+thaw,2,0,
+dispute,2,3,
+chargeback,2,3,
+";
+        let processor = process_transactions(
+            transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+            Processor::builder().allow_admin_ops(true).build(),
+        )
+        .unwrap();
+
+        // The deposit was never credited, so it can't be disputed or charged back.
+        assert_eq!(processor.accounts[&2].available(), dec!(500));
+        assert_eq!(processor.accounts[&2].held(), dec!(0));
+        assert!(!processor.accounts[&2].is_locked());
+        let reasons: Vec<_> = processor.rejections().iter().map(|r| &r.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                &RejectionReason::Failed(TxError::AccountLocked { client: 2 }),
+                &RejectionReason::Failed(TxError::UnknownTx { tx: 3 }),
+                &RejectionReason::Failed(TxError::UnknownDispute { tx: 3 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deposits_when_locked() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,1,
+chargeback,1,1,
+deposit,1,2,3.0
+withdrawal,1,3,1.0
+";
+        let process = |builder: ProcessorBuilder| {
+            process_transactions(
+                transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap(),
+                builder.build(),
+            )
+            .unwrap()
+        };
+
+        // Only the deposit lands in the locked account; the withdrawal is still refused.
+        let processor = process(Processor::builder().allow_deposits_when_locked(true));
+        assert_eq!(processor.accounts[&1].available(), dec!(3.0));
+        assert!(processor.accounts[&1].is_locked());
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 1 record(s)\n  account locked: 1 (e.g. line 6)"
+        );
+
+        let processor = process(Processor::builder());
+        assert_eq!(processor.accounts[&1].available(), dec!(0.0));
+        assert_eq!(
+            processor.stats.to_string(),
+            "Skipped 2 record(s)\n  account locked: 2 (e.g. lines 5, 6)"
+        );
+    }
+
+    #[test]
+    fn test_dispute_withdrawals() {
+        let records = || {
+            [
+                tx(1, 1, Operation::Deposit(dec!(10.0))),
+                tx(2, 1, Operation::Withdrawal(dec!(4.0))),
+                tx(3, 1, Operation::Withdrawal(dec!(40.0))),
+                tx(2, 1, Operation::Dispute(None)),
+                tx(3, 1, Operation::Dispute(None)),
+                tx(2, 1, Operation::Chargeback),
+            ]
+            .map(Ok)
+        };
+
+        // By default withdrawals can't be disputed.
+        let processor = process_transactions(records(), Processor::builder().build()).unwrap();
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(6.0));
+        assert!(!processor.accounts[&1].is_locked());
+
+        // The failed withdrawal still can't be disputed, but the other is charged back.
+        let processor = Processor::builder().dispute_withdrawals(true).build();
+        let processor = process_transactions(records(), processor).unwrap();
+        assert_eq!(processor.accounts[&1].total_balance(), dec!(10.0));
+        assert!(processor.accounts[&1].is_locked());
+        assert_eq!(processor.stats.total_skipped(), 2);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let records = || {
+            [
+                tx(1, 1, Operation::Deposit(dec!(1.0))),
+                tx(2, 2, Operation::Deposit(dec!(2.0))),
+                tx(3, 3, Operation::Deposit(dec!(3.0))),
+                tx(4, 1, Operation::Deposit(dec!(1.0))),
+                tx(5, 3, Operation::Withdrawal(dec!(9.0))),
+            ]
+            .map(Ok)
+        };
+        let path = std::env::temp_dir().join(format!("snapshots-{}.csv", std::process::id()));
+        let snapshots = |changed_only| {
+            let processor = Processor::builder().build().emit_snapshots(Snapshots {
+                every: 2,
+                changed_only,
+                display: DisplayOptions::default(),
+                output: Box::new(File::create(&path).unwrap()),
+            });
+            process_transactions(records(), processor).unwrap();
+            std::fs::read_to_string(&path).unwrap()
+        };
+
+        assert_eq!(
+            snapshots(false),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n2,2.0000,0.0000,2.0000,false\n\
+             client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n2,2.0000,0.0000,2.0000,false\n3,3.0000,0.0000,3.0000,false\n"
+        );
+        // The failed withdrawal doesn't count as a change, and isn't due a snapshot anyway.
+        assert_eq!(
+            snapshots(true),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n2,2.0000,0.0000,2.0000,false\n\
+             client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n3,3.0000,0.0000,3.0000,false\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_presorted_streaming() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, 5.0
+withdrawal, 1, 2, 2.0
+deposit, 2, 3, 4.0
+dispute, 2, 3,
+deposit, 3, 4, 1.0
+";
+        let records =
+            || transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+        let path = std::env::temp_dir().join(format!("presorted-{}.csv", std::process::id()));
+        let mut processor = Processor::builder().build().stream_accounts(
+            DisplayOptions::default(),
+            Box::new(File::create(&path).unwrap()),
+        );
+
+        // Each account is output as soon as the next client's transactions start.
+        let mut it = records().map(Result::unwrap);
+        for tx in it.by_ref().take(3) {
+            processor.process(&tx).unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n"
+        );
+        assert_eq!(processor.accounts.len(), 1);
+
+        for tx in it {
+            processor.process(&tx).unwrap();
+        }
+        processor.finish().unwrap();
+        assert!(processor.accounts.is_empty());
+
+        let batch = process_transactions(records(), Processor::builder().build()).unwrap();
+        let mut output = Vec::new();
+        display_accounts(
+            batch.accounts.values(),
+            DisplayOptions::default(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            String::from_utf8(output).unwrap()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_presorted_streaming_rejects_unsorted_input() {
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 2, Operation::Deposit(dec!(5.0))),
+            tx(3, 1, Operation::Deposit(dec!(5.0))),
+        ];
+        let processor = Processor::builder()
+            .build()
+            .stream_accounts(DisplayOptions::default(), Box::new(std::io::sink()));
+        let err = process_transactions(records.map(Ok), processor)
+            .err()
+            .unwrap();
+        assert!(matches!(err, ProcessError::NotPresorted(_)));
+    }
+
+    #[test]
+    fn test_merge_account_dbs() {
+        let shard = |records: Vec<Transaction>| {
+            process_transactions(records.into_iter().map(Ok), Processor::builder().build())
+                .unwrap()
+                .accounts
+        };
+        let first = || {
+            shard(vec![
+                tx(1, 1, Operation::Deposit(dec!(5.0))),
+                tx(1, 1, Operation::Dispute(None)),
+            ])
+        };
+
+        let merged = merge_account_dbs(
+            first(),
+            shard(vec![tx(2, 2, Operation::Deposit(dec!(3.0)))]),
+        )
+        .unwrap();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[&1].held(), dec!(5.0));
+        assert_eq!(merged[&2].total_balance(), dec!(3.0));
+
+        let collision = shard(vec![tx(2, 1, Operation::Deposit(dec!(3.0)))]);
+        assert_eq!(
+            merge_account_dbs(first(), collision).err(),
+            Some(MergeConflict::Client(1))
+        );
+
+        let overlapping_dispute = shard(vec![
+            tx(1, 2, Operation::Deposit(dec!(3.0))),
+            tx(1, 2, Operation::Dispute(None)),
+        ]);
+        assert_eq!(
+            merge_account_dbs(first(), overlapping_dispute).err(),
+            Some(MergeConflict::Dispute(1))
+        );
+    }
+
+    /// Generate a transaction from a small range of clients and IDs, so that disputes and the like
+    /// often reference a real transaction, and amounts with up to four decimal places.
+    fn arb_transaction() -> impl Strategy<Value = Transaction> {
+        let amount = (1i64..1_000_000).prop_map(|units| Decimal::new(units, 4));
+        let op = prop_oneof![
+            3 => amount.clone().prop_map(Operation::Deposit),
+            2 => amount.clone().prop_map(Operation::Withdrawal),
+            2 => proptest::option::of(amount.clone()).prop_map(Operation::Dispute),
+            1 => Just(Operation::Resolve),
+            1 => Just(Operation::Chargeback),
+            1 => amount.prop_map(Operation::Reserve),
+            1 => Just(Operation::Commit),
+            1 => Just(Operation::Release),
+        ];
+        (1u32..30, 1u16..4, op).prop_map(|(id, client, op)| tx(id, client, op))
+    }
+
+    proptest! {
+        #[test]
+        fn test_funds_are_conserved_and_accounts_consistent(
+            records in proptest::collection::vec(arb_transaction(), 0..200),
+            allow_negative: bool,
+            dispute_withdrawals: bool,
+            allow_withdrawing_held: bool,
+            deposit_hold in 0u64..3,
+        ) {
+            let dispute_policy = if allow_negative {
+                DisputePolicy::AllowNegative
+            } else {
+                DisputePolicy::Reject
+            };
+            let processor = Processor::builder()
+                .dispute_policy(dispute_policy)
+                .dispute_withdrawals(dispute_withdrawals)
+                .allow_withdrawing_held(allow_withdrawing_held)
+                .deposit_hold(deposit_hold)
+                .build();
+            let processor = process_transactions(records.into_iter().map(Ok), processor).unwrap();
+            prop_assert_eq!(
+                verify_conservation(processor.net_funds, processor.accounts.values()),
+                None
+            );
+            for account in processor.accounts.values() {
+                prop_assert_eq!(account.check_invariants(), vec![]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_conservation_reports_discrepancy() {
+        let processor = process_transactions(
+            [
+                tx(1, 1, Operation::Deposit(dec!(5.0))),
+                tx(2, 1, Operation::Withdrawal(dec!(2.0))),
+            ]
+            .into_iter()
+            .map(Ok),
+            Processor::builder().build(),
+        )
+        .unwrap();
+        assert_eq!(
+            verify_conservation(processor.net_funds, processor.accounts.values()),
+            None
+        );
+        assert_eq!(
+            verify_conservation(dec!(4.0), processor.accounts.values()),
+            Some(dec!(-1.0))
+        );
+    }
+
+    #[test]
+    fn test_apply_outcome() {
+        let mut processor = Processor::builder()
+            .lenient_resolve(true)
+            .reorder_window(2)
+            .clients([1, 2])
+            .build();
+        let mut process = |tx| processor.process(&tx).unwrap();
+
+        // The first transaction for a client creates their account.
+        assert_eq!(
+            process(Transaction::new(1, 1, Operation::Deposit(dec!(5.0)))),
+            ApplyOutcome::AccountCreated
+        );
+        assert_eq!(
+            process(Transaction::new(2, 1, Operation::Deposit(dec!(1.0)))),
+            ApplyOutcome::Applied
+        );
+        assert_eq!(
+            process(Transaction::new(3, 1, Operation::Withdrawal(dec!(10.0)))),
+            ApplyOutcome::Skipped(TxError::InsufficientFunds)
+        );
+        assert_eq!(
+            process(Transaction::new(1, 1, Operation::Resolve)),
+            ApplyOutcome::NoOpLenient
+        );
+        assert_eq!(
+            process(Transaction::new(4, 3, Operation::Deposit(dec!(1.0)))),
+            ApplyOutcome::Filtered
+        );
+        assert_eq!(
+            process(Transaction::new(5, 2, Operation::Dispute(None))),
+            ApplyOutcome::Pending
+        );
+
+        // The account is created by the first transaction applied to it, not one that's rejected,
+        // so it can still have an opening balance.
+        assert_eq!(
+            process(Transaction::new(6, 2, Operation::Withdrawal(dec!(1.0)))),
+            ApplyOutcome::Skipped(TxError::InsufficientFunds)
+        );
+        assert_eq!(
+            process(Transaction::new(7, 2, Operation::OpeningBalance(dec!(3.0)))),
+            ApplyOutcome::AccountCreated
+        );
+        assert_eq!(processor.stats().accounts_created(), 2);
+    }
+
+    #[test]
+    fn test_retried_outcomes_are_reported() {
+        let records = [
+            tx(1, 1, Operation::Dispute(None)),
+            tx(9, 1, Operation::Dispute(None)),
+            tx(1, 1, Operation::Deposit(dec!(5.0))),
+            tx(2, 1, Operation::Deposit(dec!(1.0))),
+        ];
+        let mut outcomes = Vec::new();
+        let processor = Processor::builder().reorder_window(3).build();
+        process_transactions_with(records.map(Ok), processor, |tx, outcome| {
+            outcomes.push((tx.id, outcome.clone()))
+        })
+        .unwrap();
+
+        // The dispute is applied once the deposit it refers to turns up, but the other is still
+        // waiting when the log ends.
+        assert_eq!(
+            outcomes,
+            [
+                (1, ApplyOutcome::Pending),
+                (9, ApplyOutcome::Pending),
+                (1, ApplyOutcome::AccountCreated),
+                (1, ApplyOutcome::Applied),
+                (2, ApplyOutcome::Applied),
+                (9, ApplyOutcome::Skipped(TxError::UnknownTx { tx: 9 })),
+            ]
+        );
+    }
+
+    // Process `records`, returning what came of each of them and the accounts they left.
+    fn run_with<A: AccountStore>(
+        records: &[Transaction],
+        processor: Processor<A>,
+    ) -> (Vec<ApplyOutcome>, String) {
+        let mut outcomes = Vec::new();
+        let processor =
+            process_transactions_with(records.iter().cloned().map(Ok), processor, |_, outcome| {
+                outcomes.push(outcome.clone())
+            })
+            .unwrap();
+        let mut output = Vec::new();
+        display_accounts(
+            processor.accounts_iter(),
+            DisplayOptions::default(),
+            &mut output,
+        )
+        .unwrap();
+        (outcomes, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_integer_cents_processor() {
+        let records = [
+            tx(1, 1, Operation::Deposit(dec!(10.50))),
+            tx(2, 2, Operation::Deposit(dec!(2.50))),
+            tx(3, 1, Operation::Withdrawal(dec!(4.00))),
+            tx(4, 2, Operation::Withdrawal(dec!(2.00))),
+            tx(5, 2, Operation::Withdrawal(dec!(9.00))),
+            // These take the accounts 4.00 and 2.00 below zero, which a limit of 5.00 allows, so
+            // long as it's 500 cents rather than 5.
+            tx(1, 1, Operation::Dispute(None)),
+            tx(2, 2, Operation::Dispute(None)),
+            tx(1, 1, Operation::Resolve),
+            tx(2, 2, Operation::Chargeback),
+            tx(6, 3, Operation::Deposit(dec!(0.01))),
+        ];
+        let builder = || {
+            Processor::builder()
+                .dispute_policy(DisputePolicy::AllowNegative)
+                .negative_limit(dec!(5.00), NegativeLimitAction::Reject)
+        };
+        let decimal = run_with(&records, builder().build());
+        let cents = run_with(
+            &records,
+            builder()
+                .build_with_stores(HashMap::<u16, Account<Cents>>::new(), DepositDb::default()),
+        );
+        assert_eq!(cents, decimal);
+        assert_eq!(
+            cents.0[5..7],
+            [ApplyOutcome::Applied, ApplyOutcome::Applied]
+        );
+
+        // An amount in fractions of a cent can't be applied to an account kept in cents.
+        let fraction = [tx(1, 1, Operation::Deposit(dec!(0.005)))];
+        let (outcomes, _) = run_with(
+            &fraction,
+            Processor::builder()
+                .build_with_stores(HashMap::<u16, Account<Cents>>::new(), DepositDb::default()),
+        );
+        assert_eq!(
+            outcomes,
+            [ApplyOutcome::Skipped(TxError::InexactAmount {
+                amount: dec!(0.005)
+            })]
+        );
+        let (outcomes, _) = run_with(&fraction, Processor::builder().build());
+        assert_eq!(outcomes, [ApplyOutcome::AccountCreated]);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::transaction::Currency;
+use rust_decimal::Decimal;
+use std::error::Error;
+use std::fmt;
+
+/// The reasons a transaction can fail to be applied, or a record of the transaction log can fail
+/// to describe one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxError {
+    /// The client's account is locked.
+    AccountLocked { client: u16 },
+    /// The client's account has been closed.
+    AccountClosed { client: u16 },
+    /// The client's account can't be closed while it still holds funds.
+    FundsRemaining { client: u16 },
+    /// The transaction is in a different currency to the client's earlier transactions.
+    CurrencyMismatch {
+        client: u16,
+        expected: Currency,
+        found: Currency,
+    },
+    /// An opening balance arrived for an account that already had transactions.
+    LateOpeningBalance { client: u16 },
+    /// The client doesn't have enough available funds.
+    InsufficientFunds,
+    /// The referenced transaction doesn't exist (or isn't one that can be disputed).
+    UnknownTx { tx: u32 },
+    /// There is no open dispute for the referenced transaction.
+    UnknownDispute { tx: u32 },
+    /// There is no outstanding reservation with the referenced ID.
+    UnknownReservation { id: u32 },
+    /// The referenced transaction is already under dispute.
+    DisputeAlreadyOpen { tx: u32 },
+    /// The referenced transaction was already charged back, which is final.
+    AlreadyChargedBack { tx: u32 },
+    /// The account already has as many disputes open as it's allowed.
+    TooManyDisputes { client: u16 },
+    /// The amount claimed by a dispute isn't valid for the disputed transaction.
+    InvalidDisputeAmount { tx: u32, amount: Decimal },
+    /// The dispute was filed too long after the deposit it references.
+    DisputeWindowExpired { tx: u32, age: u64 },
+    /// The referenced deposit is too old to still be kept, so can no longer be disputed.
+    DepositEvicted { tx: u32 },
+    /// The record exactly repeats an earlier one, so is taken to have been delivered twice.
+    RepeatedRecord { first_line: u64 },
+    /// The transaction reuses the ID of an earlier deposit or withdrawal, or references one that
+    /// belongs to a different client.
+    DuplicateTx(TxIdConflict),
+    /// The transaction would take a balance beyond what can be represented.
+    Overflow,
+    /// Releasing the funds held for a dispute would leave the held balance negative, which means
+    /// the dispute bookkeeping has gone wrong.
+    NegativeHeld { tx: u32 },
+    /// The transaction is an administrative operation, such as a freeze, which isn't allowed.
+    AdminOpNotAllowed { op: &'static str },
+    /// The amount can't be represented exactly in the type the accounts keep their balances in,
+    /// e.g. a fraction of a cent.
+    InexactAmount { amount: Decimal },
+    /// The record's type isn't one of the transaction types.
+    UnknownType(String),
+    /// The record's type of transaction needs an amount, but it has none.
+    MissingAmount,
+    /// The row has more fields than the header, e.g. because an amount contained an unquoted
+    /// thousands separator.
+    TooManyFields,
+    /// The amount isn't a plain decimal number.
+    InvalidAmount(String),
+    /// The amount is negative, which no transaction allows.
+    NegativeAmount(Decimal),
+    /// The row has an amount, but its type of transaction doesn't take one.
+    UnexpectedAmount(String),
+    /// The amount has more decimal places than the configured scale allows.
+    TooPrecise { amount: Decimal, scale: u32 },
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::AccountLocked { client } => write!(f, "Account {} is locked", client),
+            TxError::AccountClosed { client } => write!(f, "Account {} is closed", client),
+            TxError::FundsRemaining { client } => {
+                write!(f, "Account {} cannot be closed while it has funds", client)
+            }
+            TxError::CurrencyMismatch {
+                client,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Account {} is in {}, but the transaction is in {}",
+                client, expected, found
+            ),
+            TxError::LateOpeningBalance { client } => write!(
+                f,
+                "Account {} already has transactions, so cannot have an opening balance",
+                client
+            ),
+            TxError::InsufficientFunds => write!(f, "Insufficient funds"),
+            TxError::UnknownTx { tx } => write!(f, "no transaction with ID {}", tx),
+            TxError::UnknownDispute { tx } => {
+                write!(f, "could not find dispute with TX ID {}", tx)
+            }
+            TxError::UnknownReservation { id } => {
+                write!(f, "no outstanding reservation with ID {}", id)
+            }
+            TxError::DisputeAlreadyOpen { tx } => {
+                write!(f, "dispute already in progress for transaction {}", tx)
+            }
+            TxError::AlreadyChargedBack { tx } => {
+                write!(f, "transaction {} was already charged back", tx)
+            }
+            TxError::TooManyDisputes { client } => {
+                write!(f, "Account {} has too many open disputes", client)
+            }
+            TxError::InvalidDisputeAmount { tx, amount } => {
+                write!(f, "cannot dispute {} of transaction {}", amount, tx)
+            }
+            TxError::DisputeWindowExpired { tx, age } => {
+                write!(
+                    f,
+                    "dispute of transaction {} filed {} records after the deposit",
+                    tx, age
+                )
+            }
+            TxError::RepeatedRecord { first_line } => {
+                write!(f, "repeats the record on line {}", first_line)
+            }
+            TxError::DepositEvicted { tx } => {
+                write!(f, "transaction {} is too old to dispute", tx)
+            }
+            TxError::DuplicateTx(conflict) => conflict.fmt(f),
+            TxError::Overflow => write!(f, "balance would overflow"),
+            TxError::NegativeHeld { tx } => {
+                write!(
+                    f,
+                    "releasing transaction {} would leave held funds negative",
+                    tx
+                )
+            }
+            TxError::AdminOpNotAllowed { op } => {
+                write!(f, "administrative operation {} is not allowed", op)
+            }
+            TxError::InexactAmount { amount } => {
+                write!(f, "amount {} can't be represented exactly", amount)
+            }
+            TxError::UnknownType(t) => write!(f, "Unregognized transaction type {}", t),
+            TxError::MissingAmount => write!(f, "No amount value present"),
+            TxError::TooManyFields => write!(f, "More fields than the header"),
+            TxError::InvalidAmount(amount) => write!(f, "Invalid amount {}", amount),
+            TxError::NegativeAmount(amount) => write!(f, "Negative amount {}", amount),
+            TxError::UnexpectedAmount(t) => write!(f, "Unexpected amount for {}", t),
+            TxError::TooPrecise { amount, scale } => {
+                write!(
+                    f,
+                    "Amount {} has more than {} decimal places",
+                    amount, scale
+                )
+            }
+        }
+    }
+}
+
+impl Error for TxError {}
+
+/// Where a transaction ID that was already taken was used again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxIdConflict {
+    pub tx: u32,
+    /// The line and client of the transaction that reused the ID.
+    pub line: u64,
+    pub client: u16,
+    /// The line and client of the deposit or withdrawal that first had the ID.
+    pub first_line: u64,
+    pub first_client: u16,
+}
+
+impl fmt::Display for TxIdConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "transaction ID {} on line {} (client {}) conflicts with line {} (client {})",
+            self.tx, self.line, self.client, self.first_line, self.first_client
+        )
+    }
+}
+
+impl Error for TxIdConflict {}
+
+/// A deposit store failed to look up or keep a transaction, e.g. because the file or database it's
+/// kept in couldn't be read or written. Carrying on would mistake what it failed to find for a
+/// transaction that was never seen, so processing is aborted.
+#[derive(Debug)]
+pub struct StoreError(Box<dyn Error + Send + Sync>);
+
+impl StoreError {
+    pub fn new(e: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        StoreError(e.into())
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "deposit store failed: {}", self.0)
+    }
+}
+
+impl Error for StoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.0)
+    }
+}
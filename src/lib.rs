@@ -0,0 +1,38 @@
+//! Processes a log of client transactions — deposits, withdrawals and the disputes raised against
+//! them — into the balances of each client's account.
+//!
+//! The [`engine::Processor`] applies [`transaction::Transaction`]s to [`account::Account`]s. Build
+//! one with [`engine::Processor::builder`], feed it transactions, either read from a CSV file with
+//! [`transaction::iter_over_file`] or constructed directly, and inspect the accounts afterwards:
+//!
+//! ```
+//! use rust_decimal_macros::dec;
+//! use transaction_processor::engine::{process_transactions, Processor};
+//! use transaction_processor::transaction::{Operation, Transaction};
+//!
+//! let txs = vec![
+//!     Transaction::new(1, 1, Operation::Deposit(dec!(10))),
+//!     Transaction::new(2, 1, Operation::Withdrawal(dec!(4))),
+//! ];
+//! let processor = process_transactions(txs.into_iter().map(Ok), Processor::builder().build())?;
+//! assert_eq!(processor.accounts()[&1].available(), dec!(6));
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub mod account;
+pub mod amount;
+#[cfg(unix)]
+pub mod disk;
+pub mod engine;
+pub mod error;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "serve")]
+pub mod serve;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+pub mod store;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transaction;
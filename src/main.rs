@@ -1,90 +1,1464 @@
-mod account;
-mod transaction;
-
-use account::Account;
-use transaction::{iter_over_file, Operation, Transaction};
+use transaction_processor::account::{
+    Account, AccountDisplay, DisputePolicy, DuplicateDisputePolicy, NegativeLimitAction,
+};
+#[cfg(unix)]
+use transaction_processor::disk::DiskDeposits;
+use transaction_processor::engine::{
+    display_accounts, process_transactions, process_transactions_with, read_initial_accounts,
+    referenced_tx_ids, sort_by_timestamp, write_account_detail, write_history, AccountDb,
+    ApplyOutcome, DisplayOptions, EngineState, HeldLimitAction, OutputFormat, ProcessError,
+    Processor, ProcessorBuilder, Rejection, Snapshots, TxIdScope, TxObserver,
+};
+use transaction_processor::error::TxError;
+#[cfg(feature = "metrics")]
+use transaction_processor::metrics;
+#[cfg(feature = "serve")]
+use transaction_processor::serve;
+#[cfg(feature = "sqlite")]
+use transaction_processor::sqlite;
+use transaction_processor::stats::{ErrorCategory, ProcessingStats};
+use transaction_processor::store::{AccountStore, DepositStore};
+use transaction_processor::transaction::{
+    iter_over_dir, iter_over_file, iter_over_reader, parse_in_background, CountingReader,
+    FileOrder, LedgerFormat, ParseError, ParseOptions, PrecisionPolicy, Transaction,
+};
 
+use clap::builder::RangedU64ValueParser;
 use clap::Parser;
-use csv::Writer;
+use log::warn;
 use rust_decimal::Decimal;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
 use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, IsTerminal, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 /// Program to process a transaction log stored in a CSV file.
 ///
 /// The program applies transactionsi in chronological order and outputs the resulting
 /// client account details (including balances).
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Path to the file containing the transaction log
-    tx_log: String,
+    /// Path to the file containing the transaction log, or to a directory of them, whose `.csv`
+    /// files are processed one after another in order of filename
+    #[cfg_attr(not(feature = "serve"), arg(required = true))]
+    #[cfg_attr(feature = "serve", arg(required_unless_present = "listen"))]
+    tx_log: Option<String>,
+
+    /// The order to process the transaction logs in a directory in, by filename
+    #[arg(long, value_enum, default_value_t)]
+    file_order: FileOrder,
+
+    /// Abort on the first malformed row in the transaction log instead of skipping it
+    #[arg(long)]
+    strict: bool,
+
+    /// How to handle a dispute for more than the client's available funds
+    #[arg(long, value_enum, default_value_t)]
+    dispute_policy: DisputePolicy,
+
+    /// How to handle a dispute for a transaction that is already under dispute
+    #[arg(long, value_enum, default_value_t)]
+    duplicate_dispute: DuplicateDisputePolicy,
+
+    /// Let locked accounts keep receiving deposits, and resolving or charging back disputes that
+    /// were already open
+    #[arg(long, alias = "allow-deposit-when-locked")]
+    allow_deposits_when_locked: bool,
+
+    /// Apply administrative operations from the transaction log: `freeze` locks the client's
+    /// account and `thaw` unlocks it, unless it was also locked by a chargeback. They're skipped
+    /// without this
+    #[arg(long)]
+    allow_admin_ops: bool,
+
+    /// Administrative override letting withdrawals draw on funds held for disputes once the
+    /// available funds run out. Held funds are never withdrawable without this
+    #[arg(long)]
+    allow_withdrawing_held: bool,
+
+    /// Also process the log under a second policy, given as flags that override this run's
+    /// (e.g. --compare-policy="--dispute-policy allow-negative"), and report any accounts whose
+    /// balances differ to stderr
+    #[arg(long, value_name = "FLAGS", allow_hyphen_values = true)]
+    compare_policy: Option<String>,
+
+    /// How far below zero a dispute can take a client's available funds under
+    /// --dispute-policy allow-negative
+    #[arg(long, value_name = "AMOUNT")]
+    negative_limit: Option<Decimal>,
+
+    /// What to do when a dispute would take available funds beyond the --negative-limit
+    #[arg(long, value_enum, default_value_t)]
+    negative_limit_action: NegativeLimitAction,
+
+    /// The most disputes that can be open against a client's account at once
+    #[arg(long, value_name = "N")]
+    max_open_disputes: Option<usize>,
+
+    /// Abort if a transaction ID is reused, or referenced by a different client, rather than
+    /// skipping the offending record
+    #[arg(long)]
+    unique_tx_ids: bool,
+
+    /// Whether transaction IDs are unique per deposit or withdrawal, or shared by a deposit and a
+    /// withdrawal pair. Disputes against a shared ID apply to the first of the pair
+    #[arg(long, value_enum, default_value_t)]
+    tx_id_per: TxIdScope,
+
+    /// Hold disputes, resolves and chargebacks that reference an unknown transaction for up to
+    /// this many further records, in case the transaction they reference arrives late
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    reorder_window: usize,
+
+    /// Ignore a resolve for a known transaction that has no open dispute, rather than skipping it
+    /// as an error, for upstream systems that resolve holds they handled themselves
+    #[arg(long)]
+    lenient_resolve: bool,
+
+    /// Allow withdrawals to be disputed, as well as deposits. Charging back a withdrawal returns
+    /// the withdrawn funds to the client
+    #[arg(long)]
+    dispute_withdrawals: bool,
+
+    /// Hold each deposit's funds until this many further records have been seen for the client,
+    /// to simulate settlement delay
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    deposit_hold: u64,
+
+    /// Skip disputes filed more than this many records after the deposit they reference
+    #[arg(long, value_name = "N")]
+    dispute_window: Option<u64>,
+
+    /// Only keep this many of the latest deposits to be disputed, and skip disputes for older
+    /// ones. Deposits under dispute are kept until it's settled. The IDs of older deposits are
+    /// still kept, so memory still grows, if much more slowly
+    #[arg(long, value_name = "N")]
+    deposit_retention: Option<usize>,
+
+    /// Skip deposits and withdrawals that exactly repeat an earlier one (the same type, client,
+    /// transaction ID and amount), e.g. when upstream re-delivers part of the log. They're counted
+    /// as duplicates
+    #[arg(long)]
+    dedupe: bool,
+
+    /// Only look for repeats among this many of the latest deposits and withdrawals when
+    /// deduplicating, so memory stays bounded
+    #[arg(long, value_name = "N", requires = "dedupe")]
+    dedupe_window: Option<usize>,
+
+    /// The most funds that can be held for disputes across all accounts before taking the
+    /// --held-limit-action
+    #[arg(long, value_name = "AMOUNT")]
+    max_total_held: Option<Decimal>,
+
+    /// What to do when the funds held across all accounts exceed --max-total-held
+    #[arg(long, value_enum, default_value_t)]
+    held_limit_action: HeldLimitAction,
+
+    /// Write the transactions that were applied successfully to this file, as a normalized
+    /// transaction log that can be re-run to reproduce the same balances
+    #[arg(long, value_name = "PATH", visible_alias = "emit-applied")]
+    emit_normalized: Option<String>,
+
+    /// Write a ledger of the transactions that were applied successfully to this file, each
+    /// numbered in the order it was applied
+    #[arg(long, value_name = "PATH")]
+    ledger: Option<String>,
+
+    /// The format to write the --ledger in
+    #[arg(long, value_enum, default_value_t, requires = "ledger")]
+    ledger_format: LedgerFormat,
+
+    /// Leave locked accounts out of the output, only reporting how many there were
+    #[arg(long)]
+    exclude_locked: bool,
+
+    /// Include extra columns in the output, such as each account's status (open, locked or closed)
+    #[arg(long)]
+    extended_output: bool,
+
+    /// End the output with a row of totals across the accounts output, with `total` in the client
+    /// column and the number of locked accounts in the locked column
+    #[arg(long, conflicts_with = "presorted")]
+    totals: bool,
+
+    /// The format to write the accounts in
+    #[arg(long, value_enum, default_value_t)]
+    output_format: OutputFormat,
+
+    /// Accept amounts prefixed with a currency symbol (e.g. $100.00), and skip transactions in a
+    /// different currency to the client's earlier ones
+    #[arg(long)]
+    currency_symbols: bool,
+
+    /// Accept another name for a transaction type (e.g. --type-alias credit=deposit), for logs
+    /// from systems that use different names (can be given more than once)
+    #[arg(long = "type-alias", value_name = "NAME=TYPE", value_parser = parse_type_alias)]
+    type_aliases: Vec<(String, String)>,
+
+    /// Skip a dispute, resolve, chargeback or other row with an amount when its type shouldn't
+    /// have one, rather than ignoring the amount (or, for a dispute, disputing just that much)
+    #[arg(long)]
+    reject_unexpected_amounts: bool,
+
+    /// The most decimal places a deposit or withdrawal amount can have
+    #[arg(long, value_name = "N")]
+    scale: Option<u32>,
+
+    /// What to do with a deposit or withdrawal amount that has more decimal places than --scale
+    #[arg(long, value_enum, default_value_t, requires = "scale")]
+    precision_policy: PrecisionPolicy,
+
+    /// List up to N of the skipped records on stderr once processing is done, the earliest in the
+    /// log first, each with the reason it was skipped
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    max_rejections_retained: usize,
+
+    /// Write the accounts (and anything else written to stdout, such as snapshots) to this file
+    /// rather than stdout, replacing it once they've all been written
+    #[arg(long, short, value_name = "PATH")]
+    output: Option<String>,
+
+    /// Print a SHA-256 hash of the output to stderr, to check runs produced identical results
+    #[arg(long)]
+    emit_hash: bool,
+
+    /// Print statistics from processing to stderr: how many records were read, and how many
+    /// transactions of each type were applied and skipped (and why)
+    #[arg(long)]
+    stats: bool,
+
+    /// Start the output with a comment line giving the version and a hash of the processing
+    /// configuration, to tie the output to the parameters that produced it
+    #[arg(long)]
+    embed_provenance: bool,
+
+    /// Another transaction log, holding the transactions for a separate set of clients, to be
+    /// processed independently and merged into the output (can be given more than once)
+    #[arg(long = "shard", value_name = "PATH", conflicts_with_all = ["presorted", "compare_policy"])]
+    shards: Vec<String>,
+
+    /// The transaction log is sorted by client, so output each account as soon as the next
+    /// client's transactions start, rather than keeping every account until the end
+    #[arg(long, conflicts_with_all = ["reorder_window", "emit_hash", "compare_policy"])]
+    presorted: bool,
+
+    /// Apply the transactions in the order of their timestamp column, rather than the order they
+    /// appear in, reading the whole transaction log into memory to sort it
+    #[arg(long, conflicts_with = "presorted")]
+    sort_by_timestamp: bool,
+
+    /// Read the transaction log twice: first to find the transactions disputes refer to, then to
+    /// process it keeping only those deposits in memory, rather than every one
+    #[arg(long, conflicts_with_all = ["state_out", "shards"])]
+    two_pass: bool,
+
+    /// Only process the first N records of the transaction log (after the header), e.g. to sample
+    /// a huge one. Every record counts, including any that are skipped
+    #[arg(long, value_name = "N")]
+    max_records: Option<usize>,
+
+    /// Parse the transaction log on a separate thread from the one applying the transactions,
+    /// which is faster when parsing dominates, as it usually does
+    #[arg(long)]
+    pipeline: bool,
+
+    /// Report progress through the transaction log to stderr every second: how much of it has
+    /// been read, how many records have been processed and how fast. Only the records are counted
+    /// when the log isn't a regular file (e.g. a pipe or a directory). Nothing is reported when
+    /// stderr isn't a terminal
+    #[arg(long)]
+    progress: bool,
+
+    /// Report progress even when stderr isn't a terminal, e.g. when it's redirected to a file, a
+    /// line at a time
+    #[arg(long, requires = "progress")]
+    force_progress: bool,
+
+    /// Also write a snapshot of the accounts every N transactions, before the final output
+    #[arg(long, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    snapshot_every: Option<usize>,
+
+    /// Only include the accounts that changed since the last snapshot in each snapshot
+    #[arg(long, requires = "snapshot_every")]
+    snapshot_changed_only: bool,
+
+    /// Only process transactions for this client (can be given more than once)
+    #[arg(long = "client", value_name = "ID")]
+    clients: Vec<u16>,
+
+    /// Carry on from the state saved to this file by an earlier run's --state-out
+    #[arg(long, alias = "load-state", value_name = "PATH")]
+    state_in: Option<String>,
+
+    /// Start the accounts off with the balances in this CSV file, with client, available, held and
+    /// locked columns, e.g. carried over from another system. The output of an earlier run can be
+    /// given, as --base, to carry on from it without its disputable deposits
+    #[arg(
+        long,
+        alias = "base",
+        value_name = "PATH",
+        conflicts_with_all = ["state_in", "shards"]
+    )]
+    initial_accounts: Option<String>,
+
+    /// Where to keep the deposits and withdrawals that can still be disputed, along with the IDs
+    /// of those seen so far
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        conflicts_with_all = ["state_in", "state_out", "shards", "compare_policy", "two_pass"]
+    )]
+    deposit_store: DepositStoreKind,
+
+    /// Keep the accounts and disputable deposits in an SQLite database at this path, rather than
+    /// in memory, so large logs don't run out of memory and the state can be inspected with SQL
+    /// afterwards. Any state already in the database is replaced
+    #[cfg(feature = "sqlite")]
+    #[arg(
+        long,
+        conflicts_with = "deposit_store",
+        value_name = "PATH",
+        conflicts_with_all = ["state_in", "state_out", "shards", "compare_policy", "track_history", "two_pass"]
+    )]
+    state_db: Option<String>,
+
+    /// Save the full processing state to this file once done, including open disputes, so a later
+    /// run can carry on from it with --state-in
+    #[arg(
+        long,
+        alias = "save-state",
+        value_name = "PATH",
+        conflicts_with_all = ["presorted", "shards"]
+    )]
+    state_out: Option<String>,
+
+    /// Record every transaction applied to each account, along with the balances it left, so
+    /// that an account's history can be output with --history-for
+    #[arg(long)]
+    track_history: bool,
+
+    /// Write the history of this client's account to stderr once done
+    #[arg(
+        long,
+        value_name = "ID",
+        requires = "track_history",
+        conflicts_with = "presorted"
+    )]
+    history_for: Option<u16>,
+
+    /// Write the details of this client's account to stderr once done, including its open
+    /// disputes, for debugging
+    #[arg(long, value_name = "ID", conflicts_with = "presorted")]
+    show_client: Option<u16>,
+
+    /// Check every account's balances are consistent with its disputes after each transaction,
+    /// logging any inconsistency along with the transaction that caused it. This is always done in
+    /// debug builds
+    #[arg(long)]
+    paranoid: bool,
+
+    /// Check that no funds were created or lost once processing is done: the total across all
+    /// accounts must match the deposits and withdrawals applied, adjusted for chargebacks. Fails if
+    /// they don't, which means there's a bug in the balance arithmetic
+    #[arg(long, conflicts_with = "presorted")]
+    verify: bool,
+
+    /// Serve Prometheus-style metrics on this address (e.g. 127.0.0.1:9100) while processing
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<String>,
+
+    /// Receive transactions over TCP on this address (e.g. 127.0.0.1:7878) once the transaction
+    /// log, if any, has been processed, one CSV row or JSON object per line. A line reading GET is
+    /// answered with the accounts
+    #[cfg(feature = "serve")]
+    #[arg(
+        long,
+        value_name = "ADDR",
+        conflicts_with_all = ["presorted", "shards", "compare_policy", "state_out", "deposit_store", "two_pass"]
+    )]
+    listen: Option<String>,
 }
 
-// We store the accounts in a "database" implemented which is just a hashmap of client ID to Account.
-type AccountDb = HashMap<u16, Account>;
+/// Where the processor keeps the deposits and withdrawals that can still be disputed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum DepositStoreKind {
+    /// In memory, which is fastest.
+    #[default]
+    Memory,
+    /// In a temporary file, so the memory used stays bounded however long the log is. Only on
+    /// Unix, as the file is read and written at offsets without seeking.
+    #[cfg(unix)]
+    Disk,
+}
 
-// Store deposits in a "database" implemented as a hashmap of tx ID -> amount.
-type DepositDb = HashMap<u32, Decimal>;
+/// Parse a `--type-alias` value.
+fn parse_type_alias(alias: &str) -> Result<(String, String), String> {
+    match alias.split_once('=') {
+        Some((name, alias_for)) if !name.is_empty() && !alias_for.is_empty() => {
+            Ok((name.to_ascii_lowercase(), alias_for.to_string()))
+        }
+        _ => Err(format!("expected NAME=TYPE, found {}", alias)),
+    }
+}
 
-/// Process a single transaction record. Returns whether the operation succeeded or not.
-fn handle_record(
-    tx: &Transaction,
-    accounts: &mut AccountDb,
-    deposits: &mut DepositDb,
-) -> Result<(), Box<dyn Error>> {
-    let account = accounts.entry(tx.client).or_insert(Account::new(tx.client));
+impl From<&Args> for ProcessorBuilder {
+    fn from(args: &Args) -> Self {
+        let mut builder = Processor::builder()
+            .strict(args.strict)
+            .dispute_policy(args.dispute_policy)
+            .duplicate_dispute(args.duplicate_dispute)
+            .allow_deposits_when_locked(args.allow_deposits_when_locked)
+            .allow_withdrawing_held(args.allow_withdrawing_held)
+            .max_open_disputes(args.max_open_disputes)
+            .unique_tx_ids(args.unique_tx_ids)
+            .tx_id_scope(args.tx_id_per)
+            .reorder_window(args.reorder_window)
+            .lenient_resolve(args.lenient_resolve)
+            .dispute_withdrawals(args.dispute_withdrawals)
+            .dispute_window(args.dispute_window)
+            .deposit_retention(args.deposit_retention)
+            .dedupe(args.dedupe)
+            .dedupe_window(args.dedupe_window)
+            .deposit_hold(args.deposit_hold)
+            .allow_admin_ops(args.allow_admin_ops)
+            .clients(args.clients.iter().copied());
+        if let Some(limit) = args.negative_limit {
+            builder = builder.negative_limit(limit, args.negative_limit_action);
+        }
+        if let Some(limit) = args.max_total_held {
+            builder = builder.max_total_held(limit, args.held_limit_action);
+        }
+        builder
+    }
+}
+
+/// Passes everything written through to another writer, while hashing it.
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The hex encoded SHA-256 hash of everything written.
+    fn hash(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// An account whose balances differ between a primary and a comparison run. The account may be
+/// missing from one of the runs entirely.
+#[derive(Debug)]
+struct AccountDiff {
+    client: u16,
+    primary: Option<AccountDisplay>,
+    compare: Option<AccountDisplay>,
+}
+
+/// Find the accounts that differ between two runs, ordered by client ID.
+fn diff_accounts(primary: &AccountDb, compare: &AccountDb) -> Vec<AccountDiff> {
+    let clients: BTreeSet<u16> = primary.keys().chain(compare.keys()).copied().collect();
+    clients
+        .into_iter()
+        .map(|client| AccountDiff {
+            client,
+            primary: primary.get(&client).map(Account::to_display),
+            compare: compare.get(&client).map(Account::to_display),
+        })
+        .filter(|diff| diff.primary != diff.compare)
+        .collect()
+}
+
+/// Report the accounts that differ between two runs to stderr.
+fn report_diff(diffs: &[AccountDiff]) {
+    eprintln!("Policy comparison: {} account(s) differ", diffs.len());
+    for diff in diffs {
+        let show = |display: &Option<AccountDisplay>| match display {
+            Some(display) => display.to_string(),
+            None => format!("client={} absent", diff.client),
+        };
+        eprintln!("  primary: {}", show(&diff.primary));
+        eprintln!("  compare: {}", show(&diff.compare));
+    }
+}
+
+/// The transactions read from a transaction log, or the rows that couldn't be parsed.
+type TransactionLog = Box<dyn Iterator<Item = Result<Transaction, ParseError>> + Send>;
+
+/// Read a transaction log, or a directory of them, parsing it in the background and sorting it by
+/// timestamp if asked to. Reading it is counted towards `progress`, if given.
+fn read_log(
+    path: &str,
+    options: ParseOptions,
+    args: &Args,
+    mut progress: Option<&mut Progress>,
+) -> Result<TransactionLog, Box<dyn Error>> {
+    let txs: TransactionLog = if std::path::Path::new(path).is_dir() {
+        Box::new(iter_over_dir(path, options, args.file_order)?)
+    } else if let Some(progress) = progress.as_deref_mut() {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let reader = CountingReader::new(file);
+        if metadata.is_file() {
+            progress.file = Some((metadata.len(), reader.bytes_read()));
+        }
+        Box::new(iter_over_reader(reader, options)?)
+    } else {
+        Box::new(iter_over_file(path, options)?)
+    };
+    let txs: TransactionLog = match args.max_records {
+        Some(max) => Box::new(txs.take(max)),
+        None => txs,
+    };
+    let txs: TransactionLog = match progress {
+        Some(progress) => {
+            let records = Arc::clone(&progress.records);
+            Box::new(txs.inspect(move |_| {
+                records.fetch_add(1, Ordering::Relaxed);
+            }))
+        }
+        None => txs,
+    };
+    let txs: TransactionLog = if args.pipeline {
+        Box::new(parse_in_background(txs))
+    } else {
+        txs
+    };
+    Ok(if args.sort_by_timestamp {
+        Box::new(sort_by_timestamp(txs).into_iter())
+    } else {
+        txs
+    })
+}
+
+/// Where the accounts are written: stdout, or the file given by --output. Everything written to
+/// it goes through a single buffer, so it has to be finished once done.
+///
+/// The file is written under a temporary name in the same directory, and only renamed to the
+/// path given once finished, replacing any file already there. So a run that fails, or crashes,
+/// never leaves a partial file in its place; a failed run removes the temporary file.
+struct Output {
+    buffer: Rc<RefCell<BufWriter<Box<dyn Write>>>>,
+    // The temporary file, its path, and the path it's renamed to once finished.
+    file: Option<(File, PathBuf, PathBuf)>,
+}
+
+impl Output {
+    /// Create the output file, if there is one, so it's known to be writable before processing.
+    /// Stdout is locked for the rest of the run, as nothing else writes to it.
+    fn open(args: &Args) -> Result<Self, Box<dyn Error>> {
+        let Some(path) = &args.output else {
+            return Ok(Output {
+                buffer: Rc::new(RefCell::new(BufWriter::new(Box::new(
+                    std::io::stdout().lock(),
+                )))),
+                file: None,
+            });
+        };
+        let path = PathBuf::from(path);
+        // Renaming the finished report onto a directory would fail, but only once it's written.
+        let name = match path.file_name() {
+            Some(name) if !path.is_dir() => name.to_string_lossy(),
+            _ => return Err(format!("output path {} is a directory", path.display()).into()),
+        };
+        let temp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+        let file = File::create(&temp)
+            .map_err(|e| format!("couldn't create output file {}: {}", path.display(), e))?;
+        Ok(Output {
+            buffer: Rc::new(RefCell::new(BufWriter::new(Box::new(file.try_clone()?)))),
+            file: Some((file, temp, path)),
+        })
+    }
+
+    /// Write out whatever is left in the buffer, and move the file into place.
+    fn finish(mut self) -> std::io::Result<()> {
+        self.flush()?;
+        if let Some((file, temp, path)) = self.file.take() {
+            file.sync_all()?;
+            std::fs::rename(temp, path)?;
+        }
+        Ok(())
+    }
+
+    /// A writer to the output. Every writer shares the buffer, so each carries on from whatever
+    /// was written before.
+    fn writer(&self) -> Box<dyn Write> {
+        Box::new(OutputWriter(Rc::clone(&self.buffer)))
+    }
+
+    /// Write out whatever is left in the buffer.
+    fn flush(&self) -> std::io::Result<()> {
+        self.buffer.borrow_mut().flush()
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Some((_, temp, _)) = &self.file {
+            let _ = std::fs::remove_file(temp);
+        }
+    }
+}
+
+/// One of the writers sharing the output's buffer.
+struct OutputWriter(Rc<RefCell<BufWriter<Box<dyn Write>>>>);
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// What came of the records processed, for --stats and the list of skipped records. This is
+/// gathered by observing the processor, as any library user could, rather than asking it.
+struct Summary {
+    stats: ProcessingStats,
+    // The records that were skipped on the earliest lines, up to `max_rejections` of them, in line
+    // order.
+    rejections: Vec<Rejection>,
+    max_rejections: usize,
+}
+
+impl Summary {
+    fn new(max_rejections: usize) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Summary {
+            stats: ProcessingStats::default(),
+            rejections: Vec::new(),
+            max_rejections,
+        }))
+    }
+
+    // Records held back by the reorder window are skipped out of line order, so the list is kept
+    // sorted, rather than in the order they were skipped.
+    fn reject(&mut self, rejection: Rejection) {
+        let index = self
+            .rejections
+            .partition_point(|kept| kept.line <= rejection.line);
+        if index < self.max_rejections {
+            self.rejections.insert(index, rejection);
+            self.rejections.truncate(self.max_rejections);
+        }
+    }
 
-    match tx.op {
-        Operation::Deposit(amount) => {
-            if deposits.contains_key(&tx.id) {
-                return Err(format!("Already have a transaction with ID {}", tx.id).into());
+    // Count what processing a transaction did with it, beyond what's observed: whether it created
+    // an account.
+    fn record_outcome(&mut self, outcome: &ApplyOutcome) {
+        if *outcome == ApplyOutcome::AccountCreated {
+            self.stats.record_account_created();
+        }
+    }
+}
+
+/// Process the transactions, keeping the summary up to date with what came of each.
+fn process_summarized<A: AccountStore, D: DepositStore>(
+    txs: impl IntoIterator<Item = Result<Transaction, ParseError>>,
+    processor: Processor<A, D>,
+    summary: &Rc<RefCell<Summary>>,
+) -> Result<Processor<A, D>, ProcessError> {
+    process_transactions_with(txs, processor, |_, outcome| {
+        summary.borrow_mut().record_outcome(outcome);
+    })
+}
+
+impl TxObserver for Summary {
+    fn on_applied(&mut self, tx: &Transaction, _account: &Account) {
+        self.stats.record_read();
+        self.stats.record_applied(tx.op.name());
+    }
+
+    fn on_rejected(&mut self, tx: &Transaction, error: &TxError) {
+        self.stats.record_read();
+        self.stats
+            .record_rejected(tx.op.name(), ErrorCategory::from(error), tx.line);
+        self.reject(Rejection::failed(tx, error));
+    }
+
+    fn on_malformed(&mut self, error: &ParseError) {
+        self.stats.record_read();
+        self.stats
+            .record_skip(ErrorCategory::from(error), error.line());
+        self.reject(Rejection::malformed(error));
+    }
+
+    fn on_filtered(&mut self, _tx: &Transaction) {
+        self.stats.record_read();
+    }
+
+    fn on_account_locked(&mut self, _account: &Account) {
+        self.stats.record_account_locked();
+    }
+}
+
+/// Whether the error came from writing to a pipe that was closed, e.g. by `head` once it had
+/// read all it wanted.
+fn is_broken_pipe(e: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        let io_error = match e.downcast_ref::<csv::Error>() {
+            Some(e) => match e.kind() {
+                csv::ErrorKind::Io(e) => Some(e),
+                _ => None,
+            },
+            None => e.downcast_ref::<std::io::Error>(),
+        };
+        if io_error.is_some_and(|e| e.kind() == ErrorKind::BrokenPipe) {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+// How often progress is reported with --progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far through the transaction log processing has got, for --progress.
+#[derive(Default)]
+struct Progress {
+    // The size of the transaction log, if it's a regular file, and how much of it has been read.
+    file: Option<(u64, Arc<AtomicU64>)>,
+    records: Arc<AtomicU64>,
+}
+
+impl Progress {
+    /// Report the progress to stderr every `PROGRESS_INTERVAL` until the returned ticker is
+    /// dropped, and once more then. Each report overwrites the last unless `line_at_a_time`.
+    fn start(self, line_at_a_time: bool) -> ProgressTicker {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let started = Instant::now();
+        let thread = std::thread::spawn(move || loop {
+            // Only a timeout means there's more to come; the ticker is dropped otherwise.
+            let done = stopped.recv_timeout(PROGRESS_INTERVAL) != Err(RecvTimeoutError::Timeout);
+            let read = self
+                .file
+                .as_ref()
+                .map(|(size, read)| (read.load(Ordering::Relaxed), *size));
+            let report = progress_report(
+                read,
+                self.records.load(Ordering::Relaxed),
+                started.elapsed(),
+            );
+            if line_at_a_time {
+                eprintln!("{}", report);
+            } else {
+                // Clear whatever is left of the last report, in case this one is shorter.
+                eprint!("\r{}\x1b[K", report);
+                if done {
+                    eprintln!();
+                }
             }
-            deposits.insert(tx.id, amount);
-            account.deposit(amount)
+            if done {
+                break;
+            }
+        });
+        ProgressTicker {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Reports progress on a thread of its own until dropped.
+struct ProgressTicker {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ProgressTicker {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up to make its last report.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
         }
-        Operation::Withdrawal(amount) => account.withdraw(amount),
-        Operation::Dispute => {
-            let amount = *deposits
-                .get(&tx.id)
-                .ok_or(format!("no transaction with ID {}", tx.id))?;
-            account.dispute(tx.id, amount)
+    }
+}
+
+/// A progress report, given how many bytes of how many have been read, if known, and how many
+/// records have been processed in how long.
+fn progress_report(read: Option<(u64, u64)>, records: u64, elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    let rate = if seconds > 0.0 {
+        records as f64 / seconds
+    } else {
+        0.0
+    };
+    match read {
+        Some((read, size)) => {
+            let percent = if size > 0 {
+                read as f64 * 100.0 / size as f64
+            } else {
+                100.0
+            };
+            format!(
+                "{:.1}% read, {} records processed ({:.0}/s)",
+                percent, records, rate
+            )
         }
-        Operation::Resolve => account.resolve(tx.id),
-        Operation::Chargeback => account.chargeback(tx.id),
+        None => format!("{} records processed ({:.0}/s)", records, rate),
     }
 }
 
-/// Display all the stored accounts.
-fn display_accounts(db: &AccountDb) -> Result<(), Box<dyn Error>> {
-    let mut writer = Writer::from_writer(std::io::stdout());
-    for (_, acc) in db.iter() {
-        writer.serialize(acc.to_display())?
+/// Read the processing state saved by an earlier run.
+fn load_state(path: &str) -> Result<EngineState, Box<dyn Error>> {
+    Ok(EngineState::load(BufReader::new(File::open(path)?))?)
+}
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let parse_options = ParseOptions {
+        currency_symbols: args.currency_symbols,
+        type_aliases: args.type_aliases.iter().cloned().collect(),
+        reject_unexpected_amounts: args.reject_unexpected_amounts,
+        scale: args.scale,
+        precision_policy: args.precision_policy,
+    };
+    let display_options = DisplayOptions {
+        exclude_locked: args.exclude_locked,
+        extended: args.extended_output,
+        totals: args.totals,
+        format: args.output_format,
+    };
+    let output = Output::open(&args)?;
+    #[cfg(unix)]
+    if args.deposit_store == DepositStoreKind::Disk {
+        let path = std::env::temp_dir().join(format!(
+            "transaction-processor-{}.deposits",
+            std::process::id()
+        ));
+        let deposits = DiskDeposits::create(path)?;
+        let mut processor =
+            ProcessorBuilder::from(&args).build_with_stores(AccountDb::new(), deposits);
+        if args.track_history {
+            processor = processor.track_history();
+        }
+        let summary = Summary::new(args.max_rejections_retained);
+        let processor = process_log(
+            &args,
+            processor,
+            &parse_options,
+            display_options,
+            &output,
+            &summary,
+        )?;
+        report(
+            &args,
+            &processor,
+            &summary.borrow(),
+            display_options,
+            &output,
+        )?;
+        output.finish()?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &args.state_db {
+        let (accounts, deposits) = sqlite::open(path)?;
+        let processor = ProcessorBuilder::from(&args).build_with_stores(accounts, deposits);
+        let summary = Summary::new(args.max_rejections_retained);
+        let mut processor = process_log(
+            &args,
+            processor,
+            &parse_options,
+            display_options,
+            &output,
+            &summary,
+        )?;
+        // Check the state could all be written before outputting anything.
+        processor.accounts_mut().commit()?;
+        report(
+            &args,
+            &processor,
+            &summary.borrow(),
+            display_options,
+            &output,
+        )?;
+        output.finish()?;
+        return Ok(());
+    }
+
+    let mut processor = ProcessorBuilder::from(&args).build();
+    if let Some(path) = &args.state_in {
+        processor = processor.restore(load_state(path)?);
+    }
+    if args.track_history {
+        processor = processor.track_history();
+    }
+    if let (true, Some(path)) = (args.two_pass, &args.tx_log) {
+        let referenced = referenced_tx_ids(read_log(path, parse_options.clone(), &args, None)?);
+        processor = processor.keep_only_referenced(referenced);
+    }
+    let summary = Summary::new(args.max_rejections_retained);
+    let mut processor = process_log(
+        &args,
+        processor,
+        &parse_options,
+        display_options,
+        &output,
+        &summary,
+    )?;
+    if args.two_pass {
+        log::info!(
+            "Kept {} deposits to be disputed",
+            processor.deposits().disputable_count()
+        );
+    }
+    #[cfg(feature = "serve")]
+    if let Some(addr) = &args.listen {
+        output.flush()?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            log::info!("Listening for transactions on {}", listener.local_addr()?);
+            serve::serve(listener, processor, parse_options, display_options).await
+        })?;
+        output.finish()?;
+        return Ok(());
+    }
+    for path in &args.shards {
+        let shard = process_summarized(
+            read_log(path, parse_options.clone(), &args, None)?,
+            ProcessorBuilder::from(&args)
+                .build()
+                .observe(Box::new(Rc::clone(&summary)))
+                .retain_rejections(0),
+            &summary,
+        )?;
+        processor.merge_shard(shard)?;
+    }
+    report(
+        &args,
+        &processor,
+        &summary.borrow(),
+        display_options,
+        &output,
+    )?;
+    output.finish()?;
+
+    if let Some(flags) = &args.compare_policy {
+        // The comparison policy is expressed as flags layered on top of this run's arguments.
+        let mut compare_args = args.clone();
+        compare_args.try_update_from(std::iter::once("compare").chain(flags.split_whitespace()))?;
+        let mut compare = ProcessorBuilder::from(&compare_args).build();
+        if let Some(path) = &args.state_in {
+            compare = compare.restore(load_state(path)?);
+        }
+        if let Some(path) = &args.initial_accounts {
+            compare = compare.seed_accounts(read_initial_accounts(File::open(path)?)?);
+        }
+        let tx_log = args
+            .tx_log
+            .as_deref()
+            .ok_or("--compare-policy needs a transaction log")?;
+        let compare = process_transactions(read_log(tx_log, parse_options, &args, None)?, compare)?;
+        report_diff(&diff_accounts(processor.accounts(), compare.accounts()));
+    }
+
+    if let Some(path) = &args.state_out {
+        let mut output = BufWriter::new(File::create(path)?);
+        processor.into_state().save(&mut output)?;
+        output.flush()?;
     }
+
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+/// Set up the processor with the output and checking options, then process the transaction log
+/// with it, if there is one.
+fn process_log<A: AccountStore<Amount = Decimal>, D: DepositStore>(
+    args: &Args,
+    mut processor: Processor<A, D>,
+    parse_options: &ParseOptions,
+    display_options: DisplayOptions,
+    output: &Output,
+    summary: &Rc<RefCell<Summary>>,
+) -> Result<Processor<A, D>, Box<dyn Error>> {
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = &args.metrics_addr {
+        let server = metrics::serve(addr)?;
+        log::info!("Serving metrics on http://{}/metrics", server.addr());
+        processor = processor.serve_metrics(server);
+    }
+    if let Some(path) = &args.initial_accounts {
+        processor = processor.seed_accounts(read_initial_accounts(File::open(path)?)?);
+    }
+    if args.embed_provenance {
+        writeln!(output.writer(), "{}", processor.config().provenance())?;
+    }
+    if let Some(path) = &args.emit_normalized {
+        processor = processor.emit_normalized(Box::new(BufWriter::new(File::create(path)?)));
+    }
+    if let Some(path) = &args.ledger {
+        let ledger = Box::new(BufWriter::new(File::create(path)?));
+        processor = processor.write_ledger(ledger, args.ledger_format);
+    }
+    if args.presorted {
+        processor = processor.stream_accounts(display_options, output.writer());
+    }
+    if args.paranoid {
+        processor = processor.paranoid();
+    }
+    // The summary keeps the skipped records that are reported, so the processor needn't.
+    processor = processor
+        .observe(Box::new(Rc::clone(summary)))
+        .retain_rejections(0);
+    if let Some(every) = args.snapshot_every {
+        processor = processor.emit_snapshots(Snapshots {
+            every,
+            changed_only: args.snapshot_changed_only,
+            display: display_options,
+            output: output.writer(),
+        });
+    }
+    match &args.tx_log {
+        Some(path) if args.progress && (args.force_progress || std::io::stderr().is_terminal()) => {
+            let mut progress = Progress::default();
+            let txs = read_log(path, parse_options.clone(), args, Some(&mut progress))?;
+            let _ticker = progress.start(!std::io::stderr().is_terminal());
+            Ok(process_summarized(txs, processor, summary)?)
+        }
+        Some(path) => Ok(process_summarized(
+            read_log(path, parse_options.clone(), args, None)?,
+            processor,
+            summary,
+        )?),
+        None => Ok(processor),
+    }
+}
+
+/// Check and output the accounts, then report anything of note from processing to stderr.
+fn report<A: AccountStore, D: DepositStore>(
+    args: &Args,
+    processor: &Processor<A, D>,
+    summary: &Summary,
+    display_options: DisplayOptions,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    if args.verify {
+        processor.verify_conservation()?;
+    }
+    let excluded = if let Some(excluded) = processor.excluded_from_stream() {
+        excluded
+    } else {
+        let mut writer = HashingWriter::new(output.writer());
+        let excluded = display_accounts(
+            processor.accounts().iter_accounts(),
+            display_options,
+            &mut writer,
+        )?;
+        if args.emit_hash {
+            eprintln!("sha256: {}", writer.hash());
+        }
+        excluded
+    };
+
+    if let Some(client) = args.history_for {
+        match processor.accounts().get(client) {
+            Some(account) => write_history(account, std::io::stderr())?,
+            None => warn!("No transactions for client {}, so no history", client),
+        }
+    }
+    if let Some(client) = args.show_client {
+        match processor.account(client) {
+            Some(account) => write_account_detail(account, std::io::stderr())?,
+            None => warn!("No transactions for client {}, so no account", client),
+        }
+    }
+
+    if args.stats {
+        eprintln!("{}", summary.stats.report());
+        let size = processor.deposits().size()?;
+        eprintln!("Deposits kept: {}", size.disputable);
+        eprintln!("Transaction IDs seen: {}", size.seen);
+    } else if summary.stats.total_skipped() > 0 {
+        warn!("{}", summary.stats);
+    }
+    if !summary.rejections.is_empty() {
+        eprintln!("Skipped records:");
+        for rejection in &summary.rejections {
+            eprintln!("  {}", rejection);
+        }
+    }
+    if excluded > 0 {
+        warn!("Excluded {} locked account(s) from the output", excluded);
+    }
+
+    Ok(())
+}
+
+fn main() {
     let args = Args::parse();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 
-    // Create a "database" to store the client accounts. In production this would probably be a separate
-    // scalable and reliable database. For this problem just use a hashmap.
-    let mut account_db: AccountDb = HashMap::new();
+    if let Err(e) = run(args) {
+        // Whoever is reading the output has all they want, so there's nothing to complain about.
+        if is_broken_pipe(e.as_ref()) {
+            return;
+        }
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    // Create a "database" to store deposits that might be disputed.
-    // Again, in production this would be a separate DB, but we'll use a hashmap.
-    //
-    // NOTE: It is unclear from the problem statement if withdrawals can also be disputed. Realistically it seems
-    // like they could be. But the description for dispute handling suggests it only covers deposits. I've
-    // assumed we only need to handle desposits.
-    let mut deposit_db: DepositDb = HashMap::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use transaction_processor::transaction::{Operation, Transaction};
 
-    for tx in iter_over_file(args.tx_log.as_str())? {
-        // If this fails we want to just skip over the record, ignoring the result.
-        let _ = handle_record(&tx, &mut account_db, &mut deposit_db);
+    #[test]
+    fn test_progress_report() {
+        let elapsed = Duration::from_secs(4);
+        assert_eq!(
+            progress_report(Some((250, 1000)), 2000, elapsed),
+            "25.0% read, 2000 records processed (500/s)"
+        );
+        // Without the size of the log, e.g. when it's a pipe, only the records can be counted.
+        assert_eq!(
+            progress_report(None, 2000, elapsed),
+            "2000 records processed (500/s)"
+        );
+        assert_eq!(
+            progress_report(Some((0, 0)), 0, Duration::ZERO),
+            "100.0% read, 0 records processed (0/s)"
+        );
     }
 
-    display_accounts(&account_db)?;
+    #[test]
+    fn test_compare_policies() {
+        let records = || {
+            [
+                Transaction::new(1, 1, Operation::Deposit(dec!(2.0))),
+                Transaction::new(2, 1, Operation::Withdrawal(dec!(1.5))),
+                Transaction::new(1, 1, Operation::Dispute(None)),
+                Transaction::new(3, 2, Operation::Deposit(dec!(1.0))),
+                Transaction::new(3, 2, Operation::Dispute(None)),
+            ]
+            .into_iter()
+            .map(Ok)
+        };
 
-    Ok(())
+        let primary = process_transactions(records(), Processor::builder().build()).unwrap();
+        let primary = primary.accounts();
+        let compare_processor = Processor::builder()
+            .dispute_policy(DisputePolicy::AllowNegative)
+            .build();
+        let compare = process_transactions(records(), compare_processor).unwrap();
+        let compare = compare.accounts();
+
+        // Only client 1 has a dispute larger than its available funds.
+        let diffs = diff_accounts(primary, compare);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].client, 1);
+        assert_eq!(diffs[0].primary, Some(primary[&1].to_display()));
+        assert_eq!(diffs[0].compare, Some(compare[&1].to_display()));
+
+        assert!(diff_accounts(primary, primary).is_empty());
+    }
+
+    #[test]
+    fn test_output_to_file() {
+        let dir = std::env::temp_dir();
+        let log = dir.join(format!("output-log-{}.csv", std::process::id()));
+        let path = dir.join(format!("output-{}.csv", std::process::id()));
+        std::fs::write(&log, "type,client,tx,amount\ndeposit,1,1,2.5\n").unwrap();
+
+        let args = Args::parse_from([
+            "test",
+            log.to_str().unwrap(),
+            "--embed-provenance",
+            "--output",
+            path.to_str().unwrap(),
+        ]);
+        run(args).unwrap();
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&log).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = output.lines().collect();
+        assert!(lines[0].starts_with("# transaction-processor"));
+        assert_eq!(
+            lines[1..],
+            [
+                "client,available,held,total,locked",
+                "1,2.5000,0.0000,2.5000,false"
+            ]
+        );
+
+        // The output file can't be created in a directory that doesn't exist.
+        let missing = dir.join("no-such-directory").join("output.csv");
+        let args = Args::parse_from(["test", "log.csv", "--output", missing.to_str().unwrap()]);
+        let e = run(args).unwrap_err();
+        assert!(e.to_string().starts_with("couldn't create output file"));
+
+        // Nor can it replace a directory, which is caught before processing.
+        for path in [dir.clone(), dir.join("..")] {
+            let args = Args::parse_from(["test", "log.csv", "--output", path.to_str().unwrap()]);
+            let e = run(args).unwrap_err();
+            assert!(e.to_string().ends_with("is a directory"), "{}", e);
+        }
+    }
+
+    // A directory for a test's files, which is removed when dropped, even if the test fails.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+            // Left behind if an earlier run was killed.
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir(&dir).unwrap();
+            TestDir(dir)
+        }
+
+        // The files in the directory, sorted by name.
+        fn files(&self) -> Vec<std::ffi::OsString> {
+            let mut files: Vec<_> = std::fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_output_is_replaced_atomically() {
+        let dir = TestDir::new("atomic-output");
+        let path = dir.0.join("accounts.csv");
+        std::fs::write(&path, "left from an earlier run\n").unwrap();
+        let log = dir.0.join("log.csv");
+        std::fs::write(
+            &log,
+            "type,client,tx,amount\ndeposit,1,1,2.5\ndeposit,2,2,1.0\n",
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "test",
+            log.to_str().unwrap(),
+            "--output",
+            path.to_str().unwrap(),
+        ]);
+
+        // Part of the report is written out before serializing the rest of it fails, but the
+        // existing file is left untouched, and the partial one removed.
+        let write_report = || -> Result<(), Box<dyn Error>> {
+            let output = Output::open(&args)?;
+            display_accounts(
+                [&Account::new(1)],
+                DisplayOptions::default(),
+                output.writer(),
+            )?;
+            output.flush()?;
+            assert_eq!(dir.files().len(), 3);
+            // A map can't be written as a CSV row.
+            csv::Writer::from_writer(output.writer())
+                .serialize(std::collections::HashMap::from([(1, 2)]))?;
+            output.finish()?;
+            Ok(())
+        };
+        let e = write_report().unwrap_err();
+        assert!(e.downcast_ref::<csv::Error>().is_some(), "{}", e);
+        assert_eq!(dir.files(), ["accounts.csv", "log.csv"]);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "left from an earlier run\n"
+        );
+
+        // A successful run replaces it.
+        run(args.clone()).unwrap();
+        assert_eq!(dir.files(), ["accounts.csv", "log.csv"]);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "client,available,held,total,locked\n1,2.5000,0.0000,2.5000,false\n2,1.0000,0.0000,1.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_strict() {
+        let log = std::env::temp_dir().join(format!("strict-{}.csv", std::process::id()));
+        std::fs::write(
+            &log,
+            "type,client,tx,amount\ndeposit,1,1,2.5\nincorrect,1,2,1.0\n",
+        )
+        .unwrap();
+        let result = run(Args::parse_from([
+            "test",
+            log.to_str().unwrap(),
+            "--strict",
+        ]));
+        std::fs::remove_file(&log).unwrap();
+        // The malformed row aborts the run, rather than being skipped.
+        let e = result.unwrap_err();
+        assert!(e.to_string().starts_with("line 3: invalid record"), "{}", e);
+    }
+
+    #[test]
+    fn test_max_records() {
+        let log = std::env::temp_dir().join(format!("max-records-{}.csv", std::process::id()));
+        std::fs::write(
+            &log,
+            "type,client,tx,amount\ndeposit,1,1,2.5\ndeposit,1,x,1.0\ndeposit,2,3,1.0\ndeposit,1,4,7.0\n",
+        )
+        .unwrap();
+        let args = Args::parse_from(["test", log.to_str().unwrap(), "--max-records", "3"]);
+        let processor = process_transactions(
+            read_log(log.to_str().unwrap(), ParseOptions::default(), &args, None).unwrap(),
+            Processor::builder().build(),
+        )
+        .unwrap();
+        std::fs::remove_file(&log).unwrap();
+
+        // The malformed record counts towards the limit, so the last deposit isn't processed.
+        assert_eq!(processor.stats().records(), 3);
+        assert_eq!(processor.accounts()[&1].available(), dec!(2.5));
+        assert_eq!(processor.accounts()[&2].available(), dec!(1.0));
+    }
+
+    #[test]
+    fn test_summary_matches_processor() {
+        let log = "type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,3,
+deposit,1,x,1.0
+withdrawal,1,3,8.0
+deposit,2,4,1.0
+chargeback,1,3,
+dispute,1,1,
+chargeback,1,1,
+resolve,1,9,
+";
+        let args = Args::parse_from(["test", "log.csv", "--reorder-window", "2", "--client", "1"]);
+        let summary = Summary::new(2);
+        let processor = process_summarized(
+            iter_over_reader(log.as_bytes(), ParseOptions::default()).unwrap(),
+            ProcessorBuilder::from(&args)
+                .build()
+                .observe(Box::new(Rc::clone(&summary))),
+            &summary,
+        )
+        .unwrap();
+
+        let summary = summary.borrow();
+        assert_eq!(
+            summary.stats.report().to_string(),
+            processor.stats().report().to_string()
+        );
+        assert_eq!(summary.stats.to_string(), processor.stats().to_string());
+        assert_eq!(summary.stats.accounts_created(), 1);
+        assert_eq!(summary.stats.accounts_locked(), 1);
+        // The dispute on line 3 is held back, so is skipped after the rows that follow it, but
+        // it's listed first.
+        let mut rejections = processor.rejections().to_vec();
+        rejections.sort_by_key(|rejection| rejection.line);
+        assert_eq!(rejections[0].line, 3);
+        assert_ne!(processor.rejections()[0].line, 3);
+        assert_eq!(summary.rejections, rejections[..2]);
+    }
+
+    #[test]
+    fn test_snapshot_every_must_be_positive() {
+        let args = Args::parse_from(["test", "log.csv", "--snapshot-every", "5"]);
+        assert_eq!(args.snapshot_every, Some(5));
+        assert!(Args::try_parse_from(["test", "log.csv", "--snapshot-every", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_emit_applied_alias() {
+        let args = Args::parse_from(["test", "log.csv", "--emit-applied", "applied.csv"]);
+        assert_eq!(args.emit_normalized.as_deref(), Some("applied.csv"));
+    }
+
+    #[test]
+    fn test_provenance() {
+        let config = |args: &[&str]| {
+            let args = Args::parse_from(["test", "log.csv"].iter().chain(args));
+            ProcessorBuilder::from(&args).build().config().provenance()
+        };
+
+        let default = config(&[]);
+        assert!(default.starts_with(&format!(
+            "# transaction-processor {} config-sha256 ",
+            env!("CARGO_PKG_VERSION")
+        )));
+        // Only the processing configuration matters, not e.g. the output format.
+        assert_eq!(default, config(&["--emit-hash"]));
+        assert_eq!(
+            config(&["--client", "1", "--client", "2"]),
+            config(&["--client", "2", "--client", "1"])
+        );
+        assert_ne!(default, config(&["--lenient-resolve"]));
+        assert_ne!(default, config(&["--negative-limit", "10"]));
+    }
+
+    #[test]
+    fn test_output_hash_is_independent_of_input_order() {
+        let hash = |records: [Transaction; 4]| {
+            let processor =
+                process_transactions(records.map(Ok), Processor::builder().build()).unwrap();
+            let mut output = HashingWriter::new(Vec::new());
+            display_accounts(
+                processor.accounts().values(),
+                DisplayOptions::default(),
+                &mut output,
+            )
+            .unwrap();
+            output.hash()
+        };
+
+        let first = hash([
+            Transaction::new(1, 1, Operation::Deposit(dec!(5.0))),
+            Transaction::new(2, 2, Operation::Deposit(dec!(3.0))),
+            Transaction::new(3, 3, Operation::Deposit(dec!(1.5))),
+            Transaction::new(4, 1, Operation::Withdrawal(dec!(2.0))),
+        ]);
+        let second = hash([
+            Transaction::new(3, 3, Operation::Deposit(dec!(1.5))),
+            Transaction::new(1, 1, Operation::Deposit(dec!(5.0))),
+            Transaction::new(4, 1, Operation::Withdrawal(dec!(2.0))),
+            Transaction::new(2, 2, Operation::Deposit(dec!(3.0))),
+        ]);
+        assert_eq!(first, second);
+    }
 }
@@ -0,0 +1,148 @@
+use crate::stats::ProcessingStats;
+use rust_decimal::Decimal;
+use std::error::Error;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use tiny_http::{Header, Response, Server};
+
+/// An HTTP endpoint exposing operational metrics in the Prometheus text format. The metrics are
+/// rendered whenever they're published, and served as-is to every scrape until the next publish.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    text: Arc<Mutex<String>>,
+}
+
+/// Start serving metrics on `addr` (e.g. `127.0.0.1:9100`) from a background thread.
+pub fn serve(addr: &str) -> Result<MetricsServer, Box<dyn Error>> {
+    let server = Server::http(addr).map_err(|e| e as Box<dyn Error>)?;
+    let addr = server
+        .server_addr()
+        .to_ip()
+        .ok_or("metrics address isn't an IP address")?;
+    let text = Arc::new(Mutex::new(String::new()));
+
+    let shared = Arc::clone(&text);
+    std::thread::spawn(move || {
+        let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+            .expect("static header is valid");
+        for request in server.incoming_requests() {
+            let body = shared
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone();
+            let response = Response::from_string(body).with_header(content_type.clone());
+            let _ = request.respond(response);
+        }
+    });
+
+    Ok(MetricsServer { addr, text })
+}
+
+impl MetricsServer {
+    /// The address the metrics are served on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Update the metrics served to the current state of processing.
+    pub fn publish(&self, stats: &ProcessingStats, locked_accounts: usize, total_held: Decimal) {
+        let text = render(stats, locked_accounts, total_held);
+        *self.text.lock().unwrap_or_else(PoisonError::into_inner) = text;
+    }
+}
+
+/// Render the metrics in the Prometheus text exposition format.
+fn render(stats: &ProcessingStats, locked_accounts: usize, total_held: Decimal) -> String {
+    // Writing to a string can't fail.
+    let mut text = String::new();
+    text.push_str("# HELP txproc_transactions_applied_total Transactions applied, by type.\n");
+    text.push_str("# TYPE txproc_transactions_applied_total counter\n");
+    for (op, count) in stats.applied() {
+        let _ = writeln!(
+            text,
+            "txproc_transactions_applied_total{{type=\"{}\"}} {}",
+            op, count
+        );
+    }
+    text.push_str("# HELP txproc_records_skipped_total Records skipped, by reason.\n");
+    text.push_str("# TYPE txproc_records_skipped_total counter\n");
+    for (category, summary) in stats.skipped() {
+        let _ = writeln!(
+            text,
+            "txproc_records_skipped_total{{reason=\"{}\"}} {}",
+            category, summary.count
+        );
+    }
+    text.push_str("# HELP txproc_accounts_locked Accounts locked by a chargeback.\n");
+    text.push_str("# TYPE txproc_accounts_locked gauge\n");
+    let _ = writeln!(text, "txproc_accounts_locked {}", locked_accounts);
+    text.push_str("# HELP txproc_funds_held Funds held for disputes across all accounts.\n");
+    text.push_str("# TYPE txproc_funds_held gauge\n");
+    let _ = writeln!(text, "txproc_funds_held {}", total_held);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::ErrorCategory;
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let mut stats = ProcessingStats::default();
+        stats.record_applied("deposit");
+        stats.record_applied("deposit");
+        stats.record_applied("dispute");
+        stats.record_skip(ErrorCategory::InsufficientFunds, 4);
+
+        let server = serve("127.0.0.1:0").unwrap();
+        server.publish(&stats, 1, dec!(2.5));
+
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (headers, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(headers.starts_with("HTTP/1.1 200"));
+        assert!(headers.contains("text/plain; version=0.0.4"));
+
+        // Every line is either a comment or a sample of a metric declared by a TYPE comment.
+        let mut types = HashMap::new();
+        let mut samples = HashMap::new();
+        for line in body.lines() {
+            if let Some(declaration) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = declaration.split_once(' ').unwrap();
+                types.insert(name.to_string(), kind.to_string());
+            } else if !line.starts_with('#') {
+                let (series, value) = line.rsplit_once(' ').unwrap();
+                let name = series.split('{').next().unwrap();
+                assert!(types.contains_key(name), "undeclared metric {}", name);
+                samples.insert(series.to_string(), value.parse::<f64>().unwrap());
+            }
+        }
+
+        assert_eq!(types["txproc_transactions_applied_total"], "counter");
+        assert_eq!(types["txproc_funds_held"], "gauge");
+        assert_eq!(
+            samples["txproc_transactions_applied_total{type=\"deposit\"}"],
+            2.0
+        );
+        assert_eq!(
+            samples["txproc_transactions_applied_total{type=\"dispute\"}"],
+            1.0
+        );
+        assert_eq!(
+            samples["txproc_records_skipped_total{reason=\"insufficient funds\"}"],
+            1.0
+        );
+        assert_eq!(samples["txproc_accounts_locked"], 1.0);
+        assert_eq!(samples["txproc_funds_held"], 2.5);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::engine::{display_accounts, DisplayOptions, Processor};
+use crate::store::{AccountStore, DepositStore};
+use crate::transaction::{parse_line, ParseOptions};
+use log::{info, warn};
+use std::error::Error;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+
+// How many lines can be waiting to be applied before connections stop being read from.
+const QUEUE_SIZE: usize = 1024;
+
+/// A request from a connection to the task applying the transactions.
+enum Command {
+    /// Parse and apply a transaction, sending why to the connection if it can't be processed.
+    Apply(String, mpsc::Sender<String>),
+    /// Reply with the accounts, as CSV.
+    Dump(oneshot::Sender<String>),
+}
+
+/// Receive transactions over TCP from any number of connections, and apply them to `processor` in
+/// the order they arrive. Each line is a transaction, either as a CSV row with the usual columns
+/// (type, client, tx, amount) and no header, or as a JSON object with the same fields. A line
+/// reading `GET` is answered with the accounts as CSV, followed by a blank line.
+///
+/// A transaction that can't be processed (e.g. a malformed one in strict mode) only fails the
+/// connection that sent it: the error is sent back as a line starting `error:`, and the connection
+/// is closed, but every other connection is still served.
+///
+/// The processor is owned by the returned future, which applies every transaction itself, so it
+/// has to be run with `block_on` rather than spawned. It runs until accepting a connection fails.
+pub async fn serve<A: AccountStore, D: DepositStore>(
+    listener: TcpListener,
+    mut processor: Processor<A, D>,
+    options: ParseOptions,
+    display: DisplayOptions,
+) -> Result<(), Box<dyn Error>> {
+    let (commands, mut received) = mpsc::channel(QUEUE_SIZE);
+    let mut accepting = tokio::spawn(accept(listener, commands));
+
+    // Lines are numbered in the order they're applied, across all connections.
+    let mut line = 0;
+    loop {
+        tokio::select! {
+            result = &mut accepting => return Ok(result??),
+            Some(command) = received.recv() => match command {
+                Command::Apply(text, errors) => {
+                    line += 1;
+                    let result = match parse_line(&text, line, &options) {
+                        Ok(tx) => processor.process(&tx).map(drop),
+                        Err(e) => processor.skip_malformed(e),
+                    };
+                    if let Err(e) = result {
+                        warn!("Failed to process line {}: {}", line, e);
+                        // Only the first error is reported, as the connection is closed after it.
+                        let _ = errors.try_send(e.to_string());
+                    }
+                }
+                Command::Dump(reply) => {
+                    let mut output = Vec::new();
+                    display_accounts(processor.accounts().iter_accounts(), display, &mut output)?;
+                    // The connection may have closed while waiting.
+                    let _ = reply.send(String::from_utf8(output)?);
+                }
+            },
+        }
+    }
+}
+
+// Accept connections, reading each one from a task of its own.
+async fn accept(listener: TcpListener, commands: mpsc::Sender<Command>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        info!("Accepted a connection from {}", peer);
+        let commands = commands.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, commands).await {
+                warn!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+// Pass each line read from a connection on to be applied, answering any requests for the accounts,
+// until one of its transactions can't be processed.
+async fn handle(stream: TcpStream, commands: mpsc::Sender<Command>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let (errors, mut failed) = mpsc::channel::<String>(1);
+    loop {
+        let line = tokio::select! {
+            biased;
+            Some(e) = failed.recv() => return report(&mut writer, &e).await,
+            line = lines.next_line() => match line? {
+                Some(line) => line,
+                None => break,
+            },
+        };
+        let line = line.trim();
+        // Blank lines, and the header a CSV log starts with, are ignored.
+        if line.is_empty() || line.to_ascii_lowercase().starts_with("type,") {
+            continue;
+        }
+
+        // Sending only fails once the server has stopped, so there's nothing left to do.
+        if line.eq_ignore_ascii_case("GET") {
+            let (reply, accounts) = oneshot::channel();
+            if commands.send(Command::Dump(reply)).await.is_err() {
+                break;
+            }
+            let Ok(accounts) = accounts.await else {
+                break;
+            };
+            // The lines sent before the request have all been processed, so any error from them
+            // is waiting already.
+            if let Ok(e) = failed.try_recv() {
+                return report(&mut writer, &e).await;
+            }
+            writer.write_all(accounts.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+        } else if commands
+            .send(Command::Apply(line.to_string(), errors.clone()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Tell the client why one of its transactions couldn't be processed, before the connection closes.
+async fn report(writer: &mut OwnedWriteHalf, error: &str) -> io::Result<()> {
+    writer
+        .write_all(format!("error: {}\n", error).as_bytes())
+        .await?;
+    writer.shutdown().await
+}
@@ -0,0 +1,401 @@
+use crate::account::{Account, DisputableTx, DisputeDirection};
+use crate::engine::SeenTx;
+use crate::error::StoreError;
+use crate::store::{AccountStore, DepositStore, StoreSize};
+use rusqlite::{params, Connection, OptionalExtension};
+use rust_decimal::Decimal;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::rc::Rc;
+
+// How many rows are written in each SQLite transaction. Committing every row would make writes
+// far slower, while committing only at the end would leave nothing inspectable after a crash.
+const BATCH_SIZE: usize = 10_000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        client INTEGER PRIMARY KEY,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        total TEXT NOT NULL,
+        locked INTEGER NOT NULL,
+        state TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS deposits (
+        tx INTEGER PRIMARY KEY,
+        amount TEXT NOT NULL,
+        direction TEXT NOT NULL,
+        line INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS seen (
+        tx INTEGER PRIMARY KEY,
+        line INTEGER NOT NULL,
+        client INTEGER NOT NULL,
+        direction TEXT NOT NULL,
+        paired INTEGER NOT NULL
+    );
+    DELETE FROM accounts;
+    DELETE FROM deposits;
+    DELETE FROM seen;
+";
+
+/// The connection shared by the account and deposit stores, which batches their writes into
+/// transactions. The account store can't fail, so the first error writing an account is kept to be
+/// returned on commit, while the deposit store returns its errors straight away.
+struct Database {
+    conn: Connection,
+    uncommitted: Cell<usize>,
+    error: RefCell<Option<rusqlite::Error>>,
+}
+
+impl Database {
+    // Keep the first error, and carry on as though the statement had no effect.
+    fn check<T>(&self, result: rusqlite::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.error.borrow_mut().get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    // Note a row was written, committing the batch once it's full.
+    fn wrote(&self) -> rusqlite::Result<()> {
+        self.uncommitted.set(self.uncommitted.get() + 1);
+        if self.uncommitted.get() >= BATCH_SIZE {
+            self.uncommitted.set(0);
+            self.conn.execute_batch("COMMIT; BEGIN")?;
+        }
+        Ok(())
+    }
+}
+
+/// Open (or create) the SQLite database at `path` to keep the state of a run in, replacing any
+/// state already in it. The accounts and disputable deposits are kept in the `accounts` and
+/// `deposits` tables, with amounts stored as text so they're exact, and where each deposit and
+/// withdrawal was first seen in the `seen` table.
+pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<(SqliteAccounts, SqliteDeposits)> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    conn.execute_batch("BEGIN")?;
+    let db = Rc::new(Database {
+        conn,
+        uncommitted: Cell::new(0),
+        error: RefCell::new(None),
+    });
+
+    let accounts = SqliteAccounts {
+        db: Rc::clone(&db),
+        accounts: HashMap::new(),
+        dirty: HashSet::new(),
+    };
+    Ok((accounts, SqliteDeposits { db }))
+}
+
+/// The client accounts, kept in SQLite. There are at most 65,536 of them, so they're also kept in
+/// memory, and only written out when they leave the store or on [`SqliteAccounts::commit`].
+pub struct SqliteAccounts {
+    db: Rc<Database>,
+    accounts: HashMap<u16, Account>,
+    // The accounts that may have changed since they were last written.
+    dirty: HashSet<u16>,
+}
+
+impl SqliteAccounts {
+    fn write(&self, account: &Account) {
+        let state = match serde_json::to_string(account) {
+            Ok(state) => state,
+            Err(e) => {
+                let e = rusqlite::Error::ToSqlConversionFailure(Box::new(e));
+                self.db.error.borrow_mut().get_or_insert(e);
+                return;
+            }
+        };
+        self.db.check(self.db.conn.execute(
+            "INSERT OR REPLACE INTO accounts (client, available, held, total, locked, state)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                account.client(),
+                account.available().to_string(),
+                account.held().to_string(),
+                account.total_balance().to_string(),
+                account.is_locked(),
+                state,
+            ],
+        ));
+        self.db.check(self.db.wrote());
+    }
+
+    /// Write out the accounts that changed, and commit everything written to the database,
+    /// returning the first error from writing an account since it was opened.
+    pub fn commit(&mut self) -> rusqlite::Result<()> {
+        for client in std::mem::take(&mut self.dirty) {
+            if let Some(account) = self.accounts.get(&client) {
+                self.write(account);
+            }
+        }
+        if let Some(e) = self.db.error.take() {
+            return Err(e);
+        }
+        self.db.uncommitted.set(0);
+        self.db.conn.execute_batch("COMMIT; BEGIN")
+    }
+}
+
+impl AccountStore for SqliteAccounts {
+    type Amount = Decimal;
+
+    fn get(&self, client: u16) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    fn get_mut(&mut self, client: u16) -> Option<&mut Account> {
+        self.dirty.insert(client);
+        self.accounts.get_mut(&client)
+    }
+
+    fn get_or_create(&mut self, client: u16, new: impl FnOnce() -> Account) -> &mut Account {
+        self.dirty.insert(client);
+        self.accounts.entry(client).or_insert_with(new)
+    }
+
+    fn remove(&mut self, client: u16) -> Option<Account> {
+        let account = self.accounts.remove(&client)?;
+        if self.dirty.remove(&client) {
+            self.write(&account);
+        }
+        Some(account)
+    }
+
+    fn iter_accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+}
+
+/// The transactions that might be disputed, kept only in SQLite.
+pub struct SqliteDeposits {
+    db: Rc<Database>,
+}
+
+fn direction_to_sql(direction: DisputeDirection) -> &'static str {
+    match direction {
+        DisputeDirection::Credit => "credit",
+        DisputeDirection::Debit => "debit",
+    }
+}
+
+fn direction_from_sql(direction: &str) -> DisputeDirection {
+    match direction {
+        "debit" => DisputeDirection::Debit,
+        _ => DisputeDirection::Credit,
+    }
+}
+
+impl DepositStore for SqliteDeposits {
+    fn disputable(&self, tx_id: u32) -> Result<Option<DisputableTx>, StoreError> {
+        let row = self
+            .db
+            .conn
+            .prepare_cached("SELECT amount, direction, line FROM deposits WHERE tx = ?1")
+            .and_then(|mut statement| {
+                statement
+                    .query_row([tx_id], |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, u64>(2)?,
+                        ))
+                    })
+                    .optional()
+            })
+            .map_err(StoreError::new)?;
+        let Some((amount, direction, line)) = row else {
+            return Ok(None);
+        };
+
+        Ok(Some(DisputableTx {
+            amount: amount.parse().map_err(StoreError::new)?,
+            direction: direction_from_sql(&direction),
+            line,
+        }))
+    }
+
+    fn insert_deposit(&mut self, tx_id: u32, deposit: DisputableTx) -> Result<(), StoreError> {
+        self.db
+            .conn
+            .prepare_cached(
+                "INSERT OR IGNORE INTO deposits (tx, amount, direction, line)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    tx_id,
+                    deposit.amount.to_string(),
+                    direction_to_sql(deposit.direction),
+                    deposit.line
+                ])
+            })
+            .and_then(|_| self.db.wrote())
+            .map_err(StoreError::new)
+    }
+
+    fn remove_deposit(&mut self, tx_id: u32) -> Result<(), StoreError> {
+        self.db
+            .conn
+            .prepare_cached("DELETE FROM deposits WHERE tx = ?1")
+            .and_then(|mut statement| statement.execute([tx_id]))
+            .and_then(|_| self.db.wrote())
+            .map_err(StoreError::new)
+    }
+
+    fn contains_tx(&self, tx_id: u32) -> Result<bool, StoreError> {
+        self.db
+            .conn
+            .prepare_cached("SELECT 1 FROM deposits WHERE tx = ?1")
+            .and_then(|mut statement| statement.exists([tx_id]))
+            .map_err(StoreError::new)
+    }
+
+    fn seen(&self, tx_id: u32) -> Result<Option<SeenTx>, StoreError> {
+        self.db
+            .conn
+            .prepare_cached("SELECT line, client, direction, paired FROM seen WHERE tx = ?1")
+            .and_then(|mut statement| {
+                statement
+                    .query_row([tx_id], |row| {
+                        Ok(SeenTx {
+                            line: row.get(0)?,
+                            client: row.get(1)?,
+                            direction: direction_from_sql(&row.get::<_, String>(2)?),
+                            paired: row.get(3)?,
+                        })
+                    })
+                    .optional()
+            })
+            .map_err(StoreError::new)
+    }
+
+    fn mark_seen(&mut self, tx_id: u32, seen: SeenTx) -> Result<(), StoreError> {
+        self.db
+            .conn
+            .prepare_cached(
+                "INSERT OR REPLACE INTO seen (tx, line, client, direction, paired)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    tx_id,
+                    seen.line,
+                    seen.client,
+                    direction_to_sql(seen.direction),
+                    seen.paired
+                ])
+            })
+            .and_then(|_| self.db.wrote())
+            .map_err(StoreError::new)
+    }
+
+    fn size(&self) -> Result<StoreSize, StoreError> {
+        self.db
+            .conn
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM deposits), (SELECT COUNT(*) FROM seen)",
+                [],
+                |row| {
+                    Ok(StoreSize {
+                        disputable: row.get(0)?,
+                        seen: row.get(1)?,
+                    })
+                },
+            )
+            .map_err(StoreError::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        display_accounts, process_transactions, DisplayOptions, ProcessError, Processor,
+    };
+    use crate::transaction::{self, Operation, ParseOptions, Transaction};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_sqlite_matches_in_memory() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.25
+withdrawal,1,3,2.5
+dispute,1,1,
+resolve,1,1,
+deposit,3,4,1.0
+dispute,2,2,
+chargeback,2,2,
+deposit,1,5,0.1234
+dispute,1,5,
+withdrawal,3,6,4.0
+dispute,3,99,
+deposit,1,1,3.0
+";
+        let records =
+            || transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+        let output = |accounts: Vec<&Account>| {
+            let mut output = Vec::new();
+            display_accounts(accounts, DisplayOptions::default(), &mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            let mut lines: Vec<_> = output.lines().map(str::to_string).collect();
+            lines.sort();
+            lines
+        };
+
+        let in_memory = process_transactions(records(), Processor::builder().build()).unwrap();
+        let (accounts, deposits) = open(":memory:").unwrap();
+        let processor = Processor::builder().build_with_stores(accounts, deposits);
+        let sqlite = process_transactions(records(), processor).unwrap();
+        assert_eq!(
+            output(sqlite.accounts().iter_accounts().collect()),
+            output(in_memory.accounts().values().collect())
+        );
+        assert_eq!(
+            sqlite.deposits().size().unwrap(),
+            in_memory.deposits().size().unwrap()
+        );
+
+        // The state can be inspected with SQL once committed.
+        let mut accounts = sqlite.into_accounts();
+        accounts.commit().unwrap();
+        let conn = &accounts.db.conn;
+        let count = |table: &str| -> u32 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count("accounts"), 3);
+        assert_eq!(count("deposits"), 4);
+        assert_eq!(count("seen"), 6);
+        let (total, locked): (String, bool) = conn
+            .query_row(
+                "SELECT total, locked FROM accounts WHERE client = 2",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(total.parse::<Decimal>().unwrap(), Decimal::ZERO);
+        assert!(locked);
+    }
+
+    #[test]
+    fn test_lookup_errors_abort_processing() {
+        let (accounts, deposits) = open(":memory:").unwrap();
+        deposits.db.conn.execute_batch("DROP TABLE seen").unwrap();
+        let records = [Ok(Transaction::new(1, 1, Operation::Deposit(dec!(1.0))))];
+        let processor = Processor::builder().build_with_stores(accounts, deposits);
+        let err = process_transactions(records, processor).err().unwrap();
+        assert!(matches!(err, ProcessError::Store(_)), "{}", err);
+    }
+}
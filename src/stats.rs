@@ -0,0 +1,342 @@
+use crate::error::TxError;
+use crate::transaction::ParseError;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The number of example line numbers kept for each category of skipped record.
+const MAX_EXAMPLES: usize = 3;
+
+/// Broad categories of reasons a record can be skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorCategory {
+    Parse,
+    UnknownType,
+    InsufficientFunds,
+    Locked,
+    Closed,
+    UnknownDispute,
+    Duplicate,
+    TooManyDisputes,
+    LateDispute,
+    Overflow,
+    Other,
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCategory::Parse => "parse error",
+            ErrorCategory::UnknownType => "unknown type",
+            ErrorCategory::InsufficientFunds => "insufficient funds",
+            ErrorCategory::Locked => "account locked",
+            ErrorCategory::Closed => "account closed",
+            ErrorCategory::UnknownDispute => "unknown dispute",
+            ErrorCategory::Duplicate => "duplicate",
+            ErrorCategory::TooManyDisputes => "too many disputes",
+            ErrorCategory::LateDispute => "late dispute",
+            ErrorCategory::Overflow => "overflow",
+            ErrorCategory::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+impl From<&ParseError> for ErrorCategory {
+    fn from(e: &ParseError) -> Self {
+        match e {
+            ParseError::InvalidRecord { reason, .. } => ErrorCategory::from(reason),
+            _ => ErrorCategory::Parse,
+        }
+    }
+}
+
+impl From<&TxError> for ErrorCategory {
+    fn from(e: &TxError) -> Self {
+        match e {
+            TxError::AccountLocked { .. } => ErrorCategory::Locked,
+            TxError::AccountClosed { .. } => ErrorCategory::Closed,
+            TxError::InsufficientFunds => ErrorCategory::InsufficientFunds,
+            TxError::UnknownTx { .. } | TxError::UnknownDispute { .. } => {
+                ErrorCategory::UnknownDispute
+            }
+            TxError::DisputeAlreadyOpen { .. }
+            | TxError::AlreadyChargedBack { .. }
+            | TxError::RepeatedRecord { .. }
+            | TxError::DuplicateTx(_) => ErrorCategory::Duplicate,
+            TxError::TooManyDisputes { .. } => ErrorCategory::TooManyDisputes,
+            TxError::DisputeWindowExpired { .. } | TxError::DepositEvicted { .. } => {
+                ErrorCategory::LateDispute
+            }
+            TxError::Overflow => ErrorCategory::Overflow,
+            TxError::InvalidDisputeAmount { .. }
+            | TxError::FundsRemaining { .. }
+            | TxError::CurrencyMismatch { .. }
+            | TxError::LateOpeningBalance { .. }
+            | TxError::UnknownReservation { .. }
+            | TxError::NegativeHeld { .. }
+            | TxError::AdminOpNotAllowed { .. }
+            | TxError::InexactAmount { .. } => ErrorCategory::Other,
+            TxError::UnknownType(_) => ErrorCategory::UnknownType,
+            TxError::MissingAmount
+            | TxError::TooManyFields
+            | TxError::InvalidAmount(_)
+            | TxError::NegativeAmount(_)
+            | TxError::UnexpectedAmount(_)
+            | TxError::TooPrecise { .. } => ErrorCategory::Parse,
+        }
+    }
+}
+
+/// The records skipped for a single category of reason.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CategorySummary {
+    pub count: usize,
+    /// The lines of the earliest few skipped records, as examples, in line order. Records aren't
+    /// always skipped in line order (e.g. when held back by the reorder window), so this doesn't
+    /// depend on the order they were skipped in.
+    pub example_lines: Vec<u64>,
+}
+
+/// Statistics gathered while processing a transaction log.
+#[derive(Debug, Default)]
+pub struct ProcessingStats {
+    /// How many records were read, whether or not they could be parsed.
+    records: usize,
+    skipped: BTreeMap<ErrorCategory, CategorySummary>,
+    /// How many transactions of each type were applied successfully.
+    applied: BTreeMap<&'static str, usize>,
+    /// How many transactions of each type were skipped, for each category of reason.
+    rejected: BTreeMap<(&'static str, ErrorCategory), usize>,
+    accounts_created: usize,
+    /// How many times an account was locked, by a chargeback or a freeze.
+    accounts_locked: usize,
+}
+
+impl ProcessingStats {
+    /// Record that a record was read from the transaction log.
+    pub fn record_read(&mut self) {
+        self.records += 1;
+    }
+
+    /// Record that a transaction of type `op` was applied.
+    pub fn record_applied(&mut self, op: &'static str) {
+        *self.applied.entry(op).or_default() += 1;
+    }
+
+    /// Record that a transaction of type `op` on `line` was skipped.
+    pub fn record_rejected(&mut self, op: &'static str, category: ErrorCategory, line: u64) {
+        *self.rejected.entry((op, category)).or_default() += 1;
+        self.record_skip(category, line);
+    }
+
+    /// Record that an account was created.
+    pub fn record_account_created(&mut self) {
+        self.accounts_created += 1;
+    }
+
+    /// Record that an account was locked.
+    pub fn record_account_locked(&mut self) {
+        self.accounts_locked += 1;
+    }
+
+    /// Record that the record on `line` was skipped.
+    pub fn record_skip(&mut self, category: ErrorCategory, line: u64) {
+        let summary = self.skipped.entry(category).or_default();
+        summary.count += 1;
+        let index = summary.example_lines.partition_point(|&l| l <= line);
+        if index < MAX_EXAMPLES {
+            summary.example_lines.insert(index, line);
+            summary.example_lines.truncate(MAX_EXAMPLES);
+        }
+    }
+
+    /// Combine the statistics gathered while processing another transaction log into these.
+    pub fn merge(&mut self, other: ProcessingStats) {
+        for (category, summary) in other.skipped {
+            let merged = self.skipped.entry(category).or_default();
+            merged.count += summary.count;
+            merged.example_lines.extend(summary.example_lines);
+            merged.example_lines.sort_unstable();
+            merged.example_lines.truncate(MAX_EXAMPLES);
+        }
+        for (op, count) in other.applied {
+            *self.applied.entry(op).or_default() += count;
+        }
+        for (key, count) in other.rejected {
+            *self.rejected.entry(key).or_default() += count;
+        }
+        self.records += other.records;
+        self.accounts_created += other.accounts_created;
+        self.accounts_locked += other.accounts_locked;
+    }
+
+    /// How many records were read from the transaction log.
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    /// How many records couldn't be parsed, including those of an unknown type.
+    pub fn parse_failures(&self) -> usize {
+        [ErrorCategory::Parse, ErrorCategory::UnknownType]
+            .iter()
+            .filter_map(|category| self.skipped.get(category))
+            .map(|summary| summary.count)
+            .sum()
+    }
+
+    /// How many transactions of each type were skipped, for each category of reason.
+    pub fn rejected(&self) -> impl Iterator<Item = (&'static str, ErrorCategory, usize)> + '_ {
+        self.rejected
+            .iter()
+            .map(|((op, category), count)| (*op, *category, *count))
+    }
+
+    /// How many accounts were created.
+    pub fn accounts_created(&self) -> usize {
+        self.accounts_created
+    }
+
+    /// How many times an account was locked, by a chargeback or a freeze.
+    pub fn accounts_locked(&self) -> usize {
+        self.accounts_locked
+    }
+
+    /// A report of all the statistics, one per line, for when something looks wrong.
+    pub fn report(&self) -> StatsReport<'_> {
+        StatsReport(self)
+    }
+
+    /// The total number of skipped records.
+    pub fn total_skipped(&self) -> usize {
+        self.skipped.values().map(|summary| summary.count).sum()
+    }
+
+    /// The records skipped for each category of reason.
+    #[cfg(feature = "metrics")]
+    pub fn skipped(&self) -> impl Iterator<Item = (ErrorCategory, &CategorySummary)> {
+        self.skipped
+            .iter()
+            .map(|(category, summary)| (*category, summary))
+    }
+
+    /// How many transactions of each type were applied.
+    pub fn applied(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        self.applied.iter().map(|(op, count)| (*op, *count))
+    }
+}
+
+impl fmt::Display for ProcessingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Skipped {} record(s)", self.total_skipped())?;
+        for (category, summary) in &self.skipped {
+            let lines: Vec<String> = summary.example_lines.iter().map(u64::to_string).collect();
+            let plural = if lines.len() > 1 { "s" } else { "" };
+            write!(
+                f,
+                "\n  {}: {} (e.g. line{} {})",
+                category,
+                summary.count,
+                plural,
+                lines.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Every statistic gathered while processing, as returned by [`ProcessingStats::report`].
+pub struct StatsReport<'a>(&'a ProcessingStats);
+
+impl fmt::Display for StatsReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.0;
+        writeln!(f, "Records read: {}", stats.records)?;
+        writeln!(f, "Parse failures: {}", stats.parse_failures())?;
+        writeln!(f, "Accounts created: {}", stats.accounts_created)?;
+        write!(f, "Accounts locked: {}", stats.accounts_locked)?;
+        write!(f, "\nApplied:")?;
+        for (op, count) in &stats.applied {
+            write!(f, "\n  {}: {}", op, count)?;
+        }
+        write!(f, "\nRejected:")?;
+        for ((op, category), count) in &stats.rejected {
+            write!(f, "\n  {} ({}): {}", op, category, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_groups_by_category() {
+        let mut stats = ProcessingStats::default();
+        for line in [2, 4, 5, 7, 9] {
+            stats.record_skip(ErrorCategory::InsufficientFunds, line);
+        }
+        stats.record_skip(ErrorCategory::Parse, 3);
+
+        assert_eq!(stats.total_skipped(), 6);
+        assert_eq!(
+            stats.skipped[&ErrorCategory::InsufficientFunds],
+            CategorySummary {
+                count: 5,
+                example_lines: vec![2, 4, 5],
+            }
+        );
+        assert_eq!(
+            stats.to_string(),
+            "Skipped 6 record(s)\n  parse error: 1 (e.g. line 3)\n  insufficient funds: 5 (e.g. lines 2, 4, 5)"
+        );
+    }
+
+    #[test]
+    fn test_examples_are_line_ordered() {
+        let mut stats = ProcessingStats::default();
+        for line in [9, 5, 7, 2, 4] {
+            stats.record_skip(ErrorCategory::UnknownDispute, line);
+        }
+
+        assert_eq!(
+            stats.skipped[&ErrorCategory::UnknownDispute].example_lines,
+            vec![2, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let mut stats = ProcessingStats::default();
+        for _ in 0..3 {
+            stats.record_read();
+        }
+        stats.record_account_created();
+        stats.record_applied("deposit");
+        stats.record_skip(ErrorCategory::Parse, 2);
+        stats.record_rejected("withdrawal", ErrorCategory::InsufficientFunds, 3);
+
+        assert_eq!(
+            stats.report().to_string(),
+            "Records read: 3\nParse failures: 1\nAccounts created: 1\nAccounts locked: 0\nApplied:\n  deposit: 1\nRejected:\n  withdrawal (insufficient funds): 1"
+        );
+        assert_eq!(stats.total_skipped(), 2);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut stats = ProcessingStats::default();
+        stats.record_skip(ErrorCategory::Parse, 3);
+        stats.record_skip(ErrorCategory::Parse, 8);
+        let mut other = ProcessingStats::default();
+        other.record_skip(ErrorCategory::Parse, 2);
+        other.record_skip(ErrorCategory::Parse, 5);
+        other.record_skip(ErrorCategory::Locked, 7);
+
+        stats.merge(other);
+        assert_eq!(
+            stats.to_string(),
+            "Skipped 5 record(s)\n  parse error: 4 (e.g. lines 2, 3, 5)\n  account locked: 1 (e.g. line 7)"
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use crate::account::{Account, DisputableTx, TxLookup};
+use crate::amount::Amount;
+use crate::engine::{DepositDb, SeenTx};
+use crate::error::StoreError;
+use std::collections::HashMap;
+
+/// Where a processor keeps the client accounts. A `HashMap` of client ID to account is the
+/// default, but a production deployment would probably keep them in a separate, scalable and
+/// reliable database instead.
+pub trait AccountStore {
+    /// The type the accounts keep their balances in.
+    type Amount: Amount;
+
+    /// The account for `client`, if there is one.
+    fn get(&self, client: u16) -> Option<&Account<Self::Amount>>;
+
+    /// The account for `client`, if there is one, to update.
+    fn get_mut(&mut self, client: u16) -> Option<&mut Account<Self::Amount>>;
+
+    /// The account for `client`, which is created by `new` if there isn't one yet.
+    fn get_or_create(
+        &mut self,
+        client: u16,
+        new: impl FnOnce() -> Account<Self::Amount>,
+    ) -> &mut Account<Self::Amount>;
+
+    /// Take the account for `client` out of the store, e.g. once it has been output.
+    fn remove(&mut self, client: u16) -> Option<Account<Self::Amount>>;
+
+    /// Every account in the store, in no particular order (a `HashMap`'s order even differs
+    /// between runs). [`crate::engine::display_accounts`] sorts them by client, so the output is
+    /// the same every time.
+    fn iter_accounts(&self) -> impl Iterator<Item = &Account<Self::Amount>>;
+}
+
+/// Where a processor keeps the deposits (and withdrawals) that disputes can refer to, along with
+/// where every deposit and withdrawal was first seen. Transaction IDs are global, so these are
+/// kept apart from the accounts. There's one of each for every transaction in the log, so they
+/// can far outgrow the accounts, and may be kept somewhere that can fail, which aborts processing.
+pub trait DepositStore {
+    /// The transaction with the ID that might be disputed, if one is kept.
+    fn disputable(&self, tx_id: u32) -> Result<Option<DisputableTx>, StoreError>;
+
+    /// Keep a transaction that might later be disputed, unless one with the same ID is already
+    /// kept, as disputes apply to whichever came first.
+    fn insert_deposit(&mut self, tx_id: u32, deposit: DisputableTx) -> Result<(), StoreError>;
+
+    /// Stop keeping the transaction with the ID, so it can no longer be disputed. Where it was
+    /// seen is still kept.
+    fn remove_deposit(&mut self, tx_id: u32) -> Result<(), StoreError>;
+
+    /// Whether a transaction with the ID is kept.
+    fn contains_tx(&self, tx_id: u32) -> Result<bool, StoreError>;
+
+    /// Where the deposit or withdrawal with the ID was first seen, if it has been.
+    fn seen(&self, tx_id: u32) -> Result<Option<SeenTx>, StoreError>;
+
+    /// Record where the deposit or withdrawal with the ID was seen, replacing any earlier record.
+    fn mark_seen(&mut self, tx_id: u32, seen: SeenTx) -> Result<(), StoreError>;
+
+    /// How much the store is keeping, to report how big it has grown.
+    fn size(&self) -> Result<StoreSize, StoreError>;
+}
+
+/// How much a [`DepositStore`] is keeping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreSize {
+    /// How many transactions are kept to be disputed.
+    pub disputable: usize,
+    /// How many deposit and withdrawal IDs have been seen.
+    pub seen: usize,
+}
+
+impl<N: Amount> AccountStore for HashMap<u16, Account<N>> {
+    type Amount = N;
+
+    fn get(&self, client: u16) -> Option<&Account<N>> {
+        HashMap::get(self, &client)
+    }
+
+    fn get_mut(&mut self, client: u16) -> Option<&mut Account<N>> {
+        HashMap::get_mut(self, &client)
+    }
+
+    fn get_or_create(&mut self, client: u16, new: impl FnOnce() -> Account<N>) -> &mut Account<N> {
+        self.entry(client).or_insert_with(new)
+    }
+
+    fn remove(&mut self, client: u16) -> Option<Account<N>> {
+        HashMap::remove(self, &client)
+    }
+
+    fn iter_accounts(&self) -> impl Iterator<Item = &Account<N>> {
+        self.values()
+    }
+}
+
+impl DepositStore for DepositDb {
+    fn disputable(&self, tx_id: u32) -> Result<Option<DisputableTx>, StoreError> {
+        Ok(self.disputable.disputable(tx_id))
+    }
+
+    fn insert_deposit(&mut self, tx_id: u32, deposit: DisputableTx) -> Result<(), StoreError> {
+        self.disputable.entry(tx_id).or_insert(deposit);
+        Ok(())
+    }
+
+    fn remove_deposit(&mut self, tx_id: u32) -> Result<(), StoreError> {
+        self.disputable.remove(&tx_id);
+        Ok(())
+    }
+
+    fn contains_tx(&self, tx_id: u32) -> Result<bool, StoreError> {
+        Ok(self.disputable.contains_key(&tx_id))
+    }
+
+    fn seen(&self, tx_id: u32) -> Result<Option<SeenTx>, StoreError> {
+        Ok(self.seen.get(&tx_id).copied())
+    }
+
+    fn mark_seen(&mut self, tx_id: u32, seen: SeenTx) -> Result<(), StoreError> {
+        self.seen.insert(tx_id, seen);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<StoreSize, StoreError> {
+        Ok(StoreSize {
+            disputable: self.disputable.len(),
+            seen: self.seen.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{process_transactions, Processor};
+    use crate::transaction::{self, ParseOptions};
+    use rust_decimal::Decimal;
+    use std::collections::BTreeMap;
+
+    // Stores kept in ordered maps rather than hashmaps, to check the processor only relies on the
+    // traits.
+    impl AccountStore for BTreeMap<u16, Account> {
+        type Amount = Decimal;
+
+        fn get(&self, client: u16) -> Option<&Account> {
+            BTreeMap::get(self, &client)
+        }
+
+        fn get_mut(&mut self, client: u16) -> Option<&mut Account> {
+            BTreeMap::get_mut(self, &client)
+        }
+
+        fn get_or_create(&mut self, client: u16, new: impl FnOnce() -> Account) -> &mut Account {
+            self.entry(client).or_insert_with(new)
+        }
+
+        fn remove(&mut self, client: u16) -> Option<Account> {
+            BTreeMap::remove(self, &client)
+        }
+
+        fn iter_accounts(&self) -> impl Iterator<Item = &Account> {
+            self.values()
+        }
+    }
+
+    #[derive(Default)]
+    struct BTreeDeposits {
+        disputable: BTreeMap<u32, DisputableTx>,
+        seen: BTreeMap<u32, SeenTx>,
+    }
+
+    impl DepositStore for BTreeDeposits {
+        fn disputable(&self, tx_id: u32) -> Result<Option<DisputableTx>, StoreError> {
+            Ok(self.disputable.get(&tx_id).copied())
+        }
+
+        fn insert_deposit(&mut self, tx_id: u32, deposit: DisputableTx) -> Result<(), StoreError> {
+            self.disputable.entry(tx_id).or_insert(deposit);
+            Ok(())
+        }
+
+        fn remove_deposit(&mut self, tx_id: u32) -> Result<(), StoreError> {
+            self.disputable.remove(&tx_id);
+            Ok(())
+        }
+
+        fn contains_tx(&self, tx_id: u32) -> Result<bool, StoreError> {
+            Ok(self.disputable.contains_key(&tx_id))
+        }
+
+        fn seen(&self, tx_id: u32) -> Result<Option<SeenTx>, StoreError> {
+            Ok(self.seen.get(&tx_id).copied())
+        }
+
+        fn mark_seen(&mut self, tx_id: u32, seen: SeenTx) -> Result<(), StoreError> {
+            self.seen.insert(tx_id, seen);
+            Ok(())
+        }
+
+        fn size(&self) -> Result<StoreSize, StoreError> {
+            Ok(StoreSize {
+                disputable: self.disputable.len(),
+                seen: self.seen.len(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_custom_stores() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+dispute,1,1,
+resolve,1,1,
+dispute,2,2,
+chargeback,2,2,
+deposit,2,4,1.0
+withdrawal,1,5,100.0
+dispute,1,99,
+";
+        let records =
+            || transaction::iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+
+        let default = process_transactions(records(), Processor::builder().build()).unwrap();
+        let custom = process_transactions(
+            records(),
+            Processor::builder().build_with_stores(BTreeMap::new(), BTreeDeposits::default()),
+        )
+        .unwrap();
+
+        let custom_accounts = custom.accounts();
+        assert_eq!(custom_accounts.len(), 2);
+        for (client, account) in default.accounts() {
+            assert_eq!(&custom_accounts[client], account);
+        }
+        assert!(custom_accounts[&2].is_locked());
+        assert_eq!(
+            custom.stats().total_skipped(),
+            default.stats().total_skipped()
+        );
+        custom.verify_conservation().unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+//! Support for testing code that uses the library: a `proptest` generator of well-formed
+//! transaction sequences. Outside this crate's own tests it needs the `testing` feature.
+
+use crate::transaction::{Operation, Transaction};
+use proptest::prelude::*;
+use proptest::sample::Index;
+use rust_decimal::Decimal;
+
+/// A step of a generated sequence, before the disputes and the like are tied to a transaction
+/// that came before them.
+#[derive(Debug, Clone)]
+enum Step {
+    Deposit(u16, Decimal),
+    Withdrawal(u16, Decimal),
+    Dispute(Index),
+    Resolve(Index),
+    Chargeback(Index),
+}
+
+/// Generate an amount of up to 100, with up to four decimal places.
+pub fn arb_amount() -> impl Strategy<Value = Decimal> {
+    (1i64..1_000_000).prop_map(|units| Decimal::new(units, 4))
+}
+
+/// Generate a well-formed sequence of up to `max_len` transactions for clients 1 to `clients`.
+/// Every deposit and withdrawal has an ID of its own, numbered in order, and each record is on the
+/// line after the one before, as though read from a log. A dispute only ever refers to an
+/// undisputed deposit made before it by the same client, and a resolve or chargeback only to a
+/// deposit under dispute, so they're only skipped for reasons the log can't know about (e.g. the
+/// account being locked). A dispute, resolve or chargeback with nothing to refer to becomes a
+/// deposit instead.
+pub fn arb_transactions(clients: u16, max_len: usize) -> impl Strategy<Value = Vec<Transaction>> {
+    let step = prop_oneof![
+        4 => (1..=clients, arb_amount()).prop_map(|(client, amount)| Step::Deposit(client, amount)),
+        3 => (1..=clients, arb_amount())
+            .prop_map(|(client, amount)| Step::Withdrawal(client, amount)),
+        2 => any::<Index>().prop_map(Step::Dispute),
+        1 => any::<Index>().prop_map(Step::Resolve),
+        1 => any::<Index>().prop_map(Step::Chargeback),
+    ];
+    proptest::collection::vec(step, 0..=max_len).prop_map(resolve_steps)
+}
+
+// Turn the steps into transactions, tying each dispute, resolve and chargeback to an earlier
+// deposit.
+fn resolve_steps(steps: Vec<Step>) -> Vec<Transaction> {
+    // The deposits that can be disputed, and those under dispute, as (ID, client).
+    let mut undisputed: Vec<(u32, u16)> = Vec::new();
+    let mut disputed: Vec<(u32, u16)> = Vec::new();
+    let mut next_id = 1;
+    let mut txs = Vec::with_capacity(steps.len());
+    for step in steps {
+        let mut tx = match step {
+            Step::Dispute(index) if !undisputed.is_empty() => {
+                let (id, client) = undisputed.swap_remove(index.index(undisputed.len()));
+                disputed.push((id, client));
+                Transaction::new(id, client, Operation::Dispute(None))
+            }
+            Step::Resolve(index) | Step::Chargeback(index) if !disputed.is_empty() => {
+                let (id, client) = disputed.swap_remove(index.index(disputed.len()));
+                let op = match step {
+                    Step::Resolve(_) => Operation::Resolve,
+                    _ => Operation::Chargeback,
+                };
+                Transaction::new(id, client, op)
+            }
+            Step::Withdrawal(client, amount) => {
+                next_id += 1;
+                Transaction::new(next_id - 1, client, Operation::Withdrawal(amount))
+            }
+            step => {
+                // A dispute, resolve or chargeback with nothing to refer to yet is a deposit.
+                let (client, amount) = match step {
+                    Step::Deposit(client, amount) => (client, amount),
+                    _ => (1, Decimal::ONE),
+                };
+                undisputed.push((next_id, client));
+                next_id += 1;
+                Transaction::new(next_id - 1, client, Operation::Deposit(amount))
+            }
+        };
+        // Line 1 is the header.
+        tx.line = txs.len() as u64 + 2;
+        txs.push(tx);
+    }
+    txs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Processor;
+    use std::collections::HashMap;
+
+    proptest! {
+        #[test]
+        fn test_disputes_follow_their_deposit(txs in arb_transactions(3, 100)) {
+            let mut deposits = HashMap::new();
+            for tx in &txs {
+                match tx.op {
+                    Operation::Deposit(_) => {
+                        prop_assert!(deposits.insert(tx.id, tx.client).is_none());
+                    }
+                    Operation::Dispute(_) | Operation::Resolve | Operation::Chargeback => {
+                        prop_assert_eq!(deposits.get(&tx.id), Some(&tx.client));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        #[test]
+        fn test_total_balance_is_conserved(txs in arb_transactions(5, 200)) {
+            // What every deposit and withdrawal applied moved, less what was charged back.
+            let mut expected = Decimal::ZERO;
+            let mut amounts = HashMap::new();
+            let mut processor = Processor::builder().build();
+            for tx in &txs {
+                let skipped = processor.stats().total_skipped();
+                processor.process(tx).unwrap();
+                let applied = processor.stats().total_skipped() == skipped;
+                match tx.op {
+                    Operation::Deposit(amount) => {
+                        amounts.insert(tx.id, amount);
+                        if applied {
+                            expected += amount;
+                        }
+                    }
+                    Operation::Withdrawal(amount) if applied => expected -= amount,
+                    Operation::Chargeback if applied => expected -= amounts[&tx.id],
+                    _ => {}
+                }
+            }
+            processor.finish().unwrap();
+
+            let total: Decimal = processor.accounts().values().map(|a| a.total_balance()).sum();
+            prop_assert_eq!(total, expected);
+            prop_assert!(processor.verify_conservation().is_ok());
+            for account in processor.accounts().values() {
+                prop_assert!(account.held() >= Decimal::ZERO);
+                prop_assert_eq!(account.check_invariants(), vec![]);
+            }
+        }
+    }
+}
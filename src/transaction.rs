@@ -1,41 +1,290 @@
-use csv::{ReaderBuilder, Trim};
-use rust_decimal::Decimal;
-use serde::Deserialize;
+use crate::account::format_amount;
+use crate::amount::Amount;
+use crate::error::TxError;
+use csv::{ReaderBuilder, StringRecord, Trim, Writer};
+use log::info;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 /// The representation of a record in the transaction log.
 /// Note that this is private to the module and is just used for deserailization.
 /// The module converts these to instances of Transaction which are use the type system
 /// to ensure correctness.  
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Record {
-    #[serde(alias = "type")]
+    #[serde(alias = "type", rename(serialize = "type"))]
     _type: String,
     client: u16,
     tx: u32,
 
     /// This field may or may not be present depending on the transaction type
-    /// (present for deposit or withdrawal, otherwise absent).
-    amount: Option<Decimal>,
+    /// (present for deposit or withdrawal, optional for dispute, otherwise absent). It is kept as
+    /// a string so that its syntax can be checked strictly before it is parsed.
+    amount: Option<String>,
+
+    /// When the transaction happened, for logs whose order isn't chronological. Transactions are
+    /// logged in the order they're applied, so it's not written back out.
+    #[serde(default, skip_serializing)]
+    timestamp: Option<u64>,
 }
 
 /// Struct representing a single transaction. All transactions have a id and reference a client.
 /// Some also have type-specific fields.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Transaction {
+    /// The ID of the deposit or withdrawal, or of the one a dispute, resolve or chargeback refers to.
     pub id: u32,
     pub client: u16,
     pub op: Operation,
+
+    /// The line of the transaction log this was read from, for diagnostics (0 if unknown).
+    pub line: u64,
+
+    /// The currency of the amount, if it could be inferred from a currency symbol.
+    pub currency: Option<Currency>,
+
+    /// When the transaction happened, if the transaction log has a `timestamp` column.
+    pub timestamp: Option<u64>,
+}
+
+impl Transaction {
+    /// A transaction that didn't come from a transaction log, so has no line, with no currency or
+    /// timestamp.
+    pub fn new(id: u32, client: u16, op: Operation) -> Self {
+        Transaction {
+            id,
+            client,
+            op,
+            line: 0,
+            currency: None,
+            timestamp: None,
+        }
+    }
+}
+
+/// The currencies that can be inferred from a symbol prefixing an amount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Currency {
+    Usd,
+    Eur,
+    Gbp,
+    Jpy,
+}
+
+impl Currency {
+    fn from_symbol(symbol: char) -> Option<Self> {
+        match symbol {
+            '$' => Some(Currency::Usd),
+            '€' => Some(Currency::Eur),
+            '£' => Some(Currency::Gbp),
+            '¥' => Some(Currency::Jpy),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self {
+            Currency::Usd => "USD",
+            Currency::Eur => "EUR",
+            Currency::Gbp => "GBP",
+            Currency::Jpy => "JPY",
+        };
+        f.write_str(code)
+    }
+}
+
+/// Options controlling how the transaction log is parsed.
+#[derive(Debug, Default, Clone)]
+pub struct ParseOptions {
+    /// Accept amounts prefixed with a currency symbol (e.g. `$100.00`), inferring the currency
+    /// from it.
+    pub currency_symbols: bool,
+    /// Other names used for transaction types in the `type` column (e.g. `credit` for `deposit`),
+    /// in lowercase, mapped to the type they stand for. Like the types, they match in any case.
+    pub type_aliases: HashMap<String, String>,
+    /// Reject a row with an amount for a type of transaction that doesn't take one (e.g. a
+    /// resolve or chargeback), rather than ignoring the amount, as it suggests the upstream system
+    /// is confused. Disputes, resolves and chargebacks should never carry an amount in this mode,
+    /// so a dispute with one is rejected too, rather than disputing part of the deposit.
+    pub reject_unexpected_amounts: bool,
+    /// The most decimal places the amount of a deposit or withdrawal can have, if limited.
+    pub scale: Option<u32>,
+    /// What to do with a deposit or withdrawal whose amount has more decimal places than `scale`.
+    pub precision_policy: PrecisionPolicy,
+}
+
+/// What to do with an amount that has more decimal places than the configured scale.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PrecisionPolicy {
+    /// Reject the transaction.
+    #[default]
+    Reject,
+    /// Round the amount to the scale, with halfway cases rounded to even (banker's rounding).
+    Round,
+}
+
+// Helper function that limits the amount of a deposit or withdrawal to `scale` decimal places,
+// according to the policy. Trailing zeros don't count towards the decimal places.
+fn limit_precision(
+    op: Operation,
+    scale: u32,
+    policy: PrecisionPolicy,
+) -> Result<Operation, TxError> {
+    let limit = |amount: Decimal| {
+        if amount.normalize().scale() <= scale {
+            Ok(amount)
+        } else if policy == PrecisionPolicy::Round {
+            Ok(amount.round_dp_with_strategy(scale, RoundingStrategy::MidpointNearestEven))
+        } else {
+            Err(TxError::TooPrecise { amount, scale })
+        }
+    };
+    match op {
+        Operation::Deposit(amount) => limit(amount).map(Operation::Deposit),
+        Operation::Withdrawal(amount) => limit(amount).map(Operation::Withdrawal),
+        op => Ok(op),
+    }
+}
+
+/// Formats a transaction for logs, e.g. `deposit client=1 tx=5 amount=2.0000`.
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} client={} tx={}",
+            self.op.name(),
+            self.client,
+            self.id
+        )?;
+        match self.op.amount() {
+            Some(amount) => write!(f, " amount={}", format_amount(amount)),
+            None => Ok(()),
+        }
+    }
 }
 
 /// The different types of operations that transactions can represent, plus any associated data.
-#[derive(Debug)]
-pub enum Operation {
-    Deposit(Decimal),
-    Withdrawal(Decimal),
-    Dispute,
+#[derive(Debug, Clone)]
+pub enum Operation<N: Amount = Decimal> {
+    Deposit(N),
+    Withdrawal(N),
+    /// Dispute a deposit. If an amount is given only that portion of the deposit is disputed,
+    /// otherwise the whole deposit is.
+    Dispute(Option<N>),
     Resolve,
     Chargeback,
+    /// Close the client's account, which must have no funds left.
+    Close,
+    /// Set the starting balance of a new account, e.g. when migrating from another system. Unlike
+    /// a deposit this can't be disputed, and its transaction ID is ignored.
+    OpeningBalance(N),
+    /// Authorize a withdrawal, reserving the funds until it's committed or released. The
+    /// transaction ID identifies the reservation.
+    Reserve(N),
+    /// Complete a reserved withdrawal, removing the reserved funds from the account.
+    Commit,
+    /// Cancel a reserved withdrawal, returning the reserved funds to the account.
+    Release,
+    /// Lock the client's account, as an administrative action rather than because of a chargeback.
+    Freeze,
+    /// Unlock the client's account, however it came to be locked.
+    Thaw,
+}
+
+/// Formats an operation for logs, e.g. `withdrawal amount=1.5000` or `resolve`.
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())?;
+        match self.amount() {
+            Some(amount) => write!(f, " amount={}", format_amount(amount)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<N: Amount> Operation<N> {
+    /// The name of the operation, as it appears in the `type` column of the transaction log.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Deposit(_) => "deposit",
+            Operation::Withdrawal(_) => "withdrawal",
+            Operation::Dispute(_) => "dispute",
+            Operation::Resolve => "resolve",
+            Operation::Chargeback => "chargeback",
+            Operation::Close => "close",
+            Operation::OpeningBalance(_) => "opening_balance",
+            Operation::Reserve(_) => "reserve",
+            Operation::Commit => "commit",
+            Operation::Release => "release",
+            Operation::Freeze => "freeze",
+            Operation::Thaw => "thaw",
+        }
+    }
+
+    /// The amount the operation is for, if it has one.
+    pub fn amount(&self) -> Option<N> {
+        match self {
+            Operation::Deposit(amount)
+            | Operation::Withdrawal(amount)
+            | Operation::OpeningBalance(amount)
+            | Operation::Reserve(amount) => Some(*amount),
+            Operation::Dispute(amount) => *amount,
+            Operation::Resolve
+            | Operation::Chargeback
+            | Operation::Close
+            | Operation::Commit
+            | Operation::Release
+            | Operation::Freeze
+            | Operation::Thaw => None,
+        }
+    }
+
+    /// Whether the operation references an earlier transaction, rather than being a new one.
+    pub fn references_tx(&self) -> bool {
+        matches!(
+            self,
+            Operation::Dispute(_)
+                | Operation::Resolve
+                | Operation::Chargeback
+                | Operation::Commit
+                | Operation::Release
+        )
+    }
+}
+
+impl Operation {
+    /// The operation with its amount converted to an `N`, or `None` if the amount can't be
+    /// represented exactly as one.
+    pub fn to_amount<N: Amount>(&self) -> Option<Operation<N>> {
+        Some(match *self {
+            Operation::Deposit(amount) => Operation::Deposit(N::from_decimal_exact(amount)?),
+            Operation::Withdrawal(amount) => Operation::Withdrawal(N::from_decimal_exact(amount)?),
+            Operation::Dispute(Some(amount)) => {
+                Operation::Dispute(Some(N::from_decimal_exact(amount)?))
+            }
+            Operation::Dispute(None) => Operation::Dispute(None),
+            Operation::Resolve => Operation::Resolve,
+            Operation::Chargeback => Operation::Chargeback,
+            Operation::Close => Operation::Close,
+            Operation::OpeningBalance(amount) => {
+                Operation::OpeningBalance(N::from_decimal_exact(amount)?)
+            }
+            Operation::Reserve(amount) => Operation::Reserve(N::from_decimal_exact(amount)?),
+            Operation::Commit => Operation::Commit,
+            Operation::Release => Operation::Release,
+            Operation::Freeze => Operation::Freeze,
+            Operation::Thaw => Operation::Thaw,
+        })
+    }
 }
 
 /// Convert a raw record into a transaction.
@@ -44,58 +293,609 @@ pub enum Operation {
 /// https://github.com/BurntSushi/rust-csv/issues/211 this is not supported. So instead implement
 /// TryFrom for the conversion.
 impl TryFrom<Record> for Transaction {
-    type Error = Box<dyn Error>;
+    type Error = TxError;
 
     fn try_from(record: Record) -> Result<Self, Self::Error> {
-        let op = match record._type.as_str() {
-            "deposit" => Operation::Deposit(record.amount.ok_or("No amount value present")?),
-            "withdrawal" => Operation::Withdrawal(record.amount.ok_or("No amount value present")?),
-            "dispute" => Operation::Dispute,
+        let amount = record.amount.as_deref().map(parse_amount).transpose()?;
+        // Upstream systems aren't consistent about the case of the type.
+        let op = match record._type.to_ascii_lowercase().as_str() {
+            "deposit" => Operation::Deposit(amount.ok_or(TxError::MissingAmount)?),
+            "withdrawal" => Operation::Withdrawal(amount.ok_or(TxError::MissingAmount)?),
+            "dispute" => Operation::Dispute(amount),
             "resolve" => Operation::Resolve,
             "chargeback" => Operation::Chargeback,
-            _ => return Err(format!("Unregognized transaction type {}", record._type).into()),
+            "close" => Operation::Close,
+            "opening_balance" => Operation::OpeningBalance(amount.ok_or(TxError::MissingAmount)?),
+            "reserve" => Operation::Reserve(amount.ok_or(TxError::MissingAmount)?),
+            "commit" => Operation::Commit,
+            "release" => Operation::Release,
+            "freeze" => Operation::Freeze,
+            "thaw" => Operation::Thaw,
+            _ => return Err(TxError::UnknownType(record._type)),
         };
 
         Ok(Transaction {
             id: record.tx,
             client: record.client,
             op,
+            line: 0,
+            currency: None,
+            timestamp: record.timestamp,
         })
     }
 }
 
+/// Parse an amount, which must be plain decimal digits with at most one decimal point. Exponents,
+/// grouping separators and the like are rejected, as are negative amounts.
+fn parse_amount(amount: &str) -> Result<Decimal, TxError> {
+    let invalid = || TxError::InvalidAmount(amount.to_string());
+
+    let (negative, digits) = match amount.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, amount),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if whole.len() + fraction.len() == 0 || !is_digits(whole) || !is_digits(fraction) {
+        return Err(invalid());
+    }
+
+    let value = Decimal::from_str_exact(amount).map_err(|_| invalid())?;
+    if negative && !value.is_zero() {
+        return Err(TxError::NegativeAmount(value));
+    }
+    Ok(value)
+}
+
+/// Convert a transaction back into a record, in canonical form.
+impl From<&Transaction> for Record {
+    fn from(tx: &Transaction) -> Self {
+        Record {
+            _type: tx.op.name().to_string(),
+            client: tx.client,
+            tx: tx.id,
+            amount: tx.op.amount().map(|amount| amount.normalize().to_string()),
+            timestamp: tx.timestamp,
+        }
+    }
+}
+
+/// An error for a single row of the transaction log that could not be turned into a transaction.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The row could not be deserialized, e.g. a numeric field was non-numeric or out of range.
+    MalformedField { line: u64, source: csv::Error },
+    /// A transaction given as JSON could not be deserialized.
+    MalformedJson {
+        line: u64,
+        source: serde_json::Error,
+    },
+    /// The row was well formed but did not describe a valid transaction, e.g. an unrecognized
+    /// type or a missing amount.
+    InvalidRecord { line: u64, reason: TxError },
+    /// The header doesn't have the expected columns, so none of the rows can be read.
+    InvalidHeader {
+        missing: Vec<String>,
+        unexpected: Vec<String>,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MalformedField { line, source } => {
+                write!(f, "line {}: malformed field: {}", line, source)
+            }
+            ParseError::MalformedJson { line, source } => {
+                write!(f, "line {}: malformed JSON: {}", line, source)
+            }
+            ParseError::InvalidRecord { line, reason } => {
+                write!(f, "line {}: invalid record: {}", line, reason)
+            }
+            ParseError::InvalidHeader {
+                missing,
+                unexpected,
+            } => {
+                write!(f, "line 1: invalid header:")?;
+                if !missing.is_empty() {
+                    write!(f, " missing column(s) {}", missing.join(", "))?;
+                }
+                if !unexpected.is_empty() {
+                    write!(f, " unexpected column(s) {}", unexpected.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ParseError {
+    /// The line of the transaction log the error occurred on.
+    pub fn line(&self) -> u64 {
+        match self {
+            ParseError::MalformedField { line, .. }
+            | ParseError::MalformedJson { line, .. }
+            | ParseError::InvalidRecord { line, .. } => *line,
+            ParseError::InvalidHeader { .. } => 1,
+        }
+    }
+
+    // The same error, as though it was `offset` lines further into the log.
+    fn offset_line(self, offset: u64) -> Self {
+        match self {
+            ParseError::MalformedField { line, source } => ParseError::MalformedField {
+                line: line + offset,
+                source,
+            },
+            ParseError::MalformedJson { line, source } => ParseError::MalformedJson {
+                line: line + offset,
+                source,
+            },
+            ParseError::InvalidRecord { line, reason } => ParseError::InvalidRecord {
+                line: line + offset,
+                reason,
+            },
+            header @ ParseError::InvalidHeader { .. } => header,
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Passes reads through to another reader, while counting the bytes read, so how far through a
+/// transaction log parsing has got can be checked from another thread, e.g. to report progress.
+pub struct CountingReader<R> {
+    inner: R,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        CountingReader {
+            inner,
+            bytes_read: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The number of bytes read so far, which keeps counting as the reader is used.
+    pub fn bytes_read(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.bytes_read)
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes_read.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
 /// Iterate over the transancations in a transaction log csv file.
 pub fn iter_over_file(
     file_path: &str,
-) -> Result<impl Iterator<Item = Transaction>, Box<dyn Error>> {
-    Ok(iter_over_reader(std::fs::File::open(file_path)?))
+    options: ParseOptions,
+) -> Result<impl Iterator<Item = Result<Transaction, ParseError>>, Box<dyn Error>> {
+    Ok(iter_over_reader(File::open(file_path)?, options)?)
+}
+
+/// The order to process the transaction logs in a directory in, by filename.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FileOrder {
+    /// Oldest first, for logs named by date or sequence number.
+    #[default]
+    Ascending,
+    /// Newest first.
+    Descending,
+}
+
+/// Iterate over the transactions in every `.csv` file in a directory, one file after another, in
+/// order of filename. Other files are ignored. The line numbers carry on from one file to the
+/// next, as though the files were a single log, so they keep increasing.
+pub fn iter_over_dir(
+    dir: &str,
+    options: ParseOptions,
+    order: FileOrder,
+) -> Result<impl Iterator<Item = Result<Transaction, ParseError>>, Box<dyn Error>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_csv = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        if is_csv && path.is_file() {
+            // Check every file's header up front, so a bad one stops the run before anything is
+            // processed. The files are only kept open while they're read, as there may be lots.
+            let _ = iter_over_reader(File::open(&path)?, options.clone())
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    if order == FileOrder::Descending {
+        paths.reverse();
+    }
+
+    let mut paths = paths.into_iter();
+    let mut current: Option<Box<dyn Iterator<Item = Result<Transaction, ParseError>> + Send>> =
+        None;
+    let (mut offset, mut last_line) = (0, 0);
+    Ok(std::iter::from_fn(move || loop {
+        if let Some(result) = current.as_mut().and_then(Iterator::next) {
+            let result = match result {
+                Ok(tx) => Ok(Transaction {
+                    line: tx.line + offset,
+                    ..tx
+                }),
+                Err(e) => Err(e.offset_line(offset)),
+            };
+            let line = match &result {
+                Ok(tx) => tx.line,
+                Err(e) => e.line(),
+            };
+            last_line = last_line.max(line);
+            return Some(result);
+        }
+
+        let path = paths.next()?;
+        offset = last_line;
+        info!("Reading {} from line {}", path.display(), offset + 1);
+        current = Some(match File::open(&path) {
+            Ok(file) => match iter_over_reader(file, options.clone()) {
+                Ok(txs) => Box::new(txs),
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            },
+            Err(e) => Box::new(std::iter::once(Err(ParseError::MalformedField {
+                line: 1,
+                source: e.into(),
+            }))),
+        });
+    }))
+}
+
+// How many transactions are sent from the parsing thread at a time, and how many of these batches
+// can be waiting to be applied before parsing blocks.
+const PIPELINE_BATCH_SIZE: usize = 1024;
+const PIPELINE_DEPTH: usize = 16;
+
+/// Parse transactions on a thread of their own, so the parsing overlaps with whatever is done
+/// with them, e.g. applying them to the accounts. They're passed back in batches, in the same
+/// order, with only a bounded number in flight, so the parsing can't run far ahead of the
+/// processing. A panic while parsing is resumed once the transactions parsed before it are used.
+pub fn parse_in_background<I>(txs: I) -> impl Iterator<Item = Result<Transaction, ParseError>>
+where
+    I: Iterator<Item = Result<Transaction, ParseError>> + Send + 'static,
+{
+    let (sender, receiver) = std::sync::mpsc::sync_channel(PIPELINE_DEPTH);
+    let mut parser = Some(std::thread::spawn(move || {
+        let mut txs = txs;
+        loop {
+            let batch: Vec<_> = txs.by_ref().take(PIPELINE_BATCH_SIZE).collect();
+            // Sending only fails if the transactions are no longer wanted.
+            if batch.is_empty() || sender.send(batch).is_err() {
+                break;
+            }
+        }
+    }));
+    receiver
+        .into_iter()
+        .flatten()
+        .chain(std::iter::from_fn(move || {
+            if let Some(Err(panic)) = parser.take().map(std::thread::JoinHandle::join) {
+                std::panic::resume_unwind(panic);
+            }
+            None
+        }))
 }
 
-fn iter_over_reader<R>(reader: R) -> impl Iterator<Item = Transaction>
+/// The columns the header of a transaction log must have, in any order.
+const EXPECTED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// The columns the header of a transaction log can also have. The sequence number is ignored, so
+/// a ledger can be read back in.
+const OPTIONAL_COLUMNS: [&str; 2] = ["timestamp", "seq"];
+
+/// Check the header has exactly the expected columns. The rows are deserialized by column name,
+/// so a misspelled column would otherwise make every row fail (or the amount silently missing).
+fn validate_header(headers: &StringRecord) -> Result<(), ParseError> {
+    let missing: Vec<_> = EXPECTED_COLUMNS
+        .iter()
+        .filter(|column| !headers.iter().any(|header| header == **column))
+        .map(|column| column.to_string())
+        .collect();
+    let unexpected: Vec<_> = headers
+        .iter()
+        .filter(|header| !EXPECTED_COLUMNS.contains(header) && !OPTIONAL_COLUMNS.contains(header))
+        .map(str::to_string)
+        .collect();
+
+    if missing.is_empty() && unexpected.is_empty() {
+        Ok(())
+    } else {
+        Err(ParseError::InvalidHeader {
+            missing,
+            unexpected,
+        })
+    }
+}
+
+// The byte-order mark that may start a UTF-8 encoded file.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Iterate over the transactions read from `reader`. The header is checked up front, and an error
+/// returned if it's invalid. Rows that cannot be parsed are returned as errors, for the caller to
+/// decide whether to skip them.
+pub fn iter_over_reader<R>(
+    reader: R,
+    options: ParseOptions,
+) -> Result<impl Iterator<Item = Result<Transaction, ParseError>>, ParseError>
 where
     R: std::io::Read,
 {
+    // Logs exported by some Windows tools start with a UTF-8 byte-order mark, which would otherwise
+    // end up as part of the first column name.
+    let mut reader = BufReader::new(reader);
+    let start = reader.fill_buf().map_err(|e| ParseError::MalformedField {
+        line: 1,
+        source: e.into(),
+    })?;
+    if start.starts_with(UTF8_BOM) {
+        reader.consume(UTF8_BOM.len());
+    }
+
     // Build a reader.
     // - The CSV has a header we need to strip.
-    // - The CSV has variable numbers of columns so we need `flexible` to be set.
+    // - The CSV has variable numbers of columns (up to the number in the header) so we need
+    //   `flexible` to be set.
     // - The CSV fields contain whitespace which much be stripped.
-    let rdr = ReaderBuilder::new()
+    let mut rdr = ReaderBuilder::new()
         .has_headers(true)
         .flexible(true)
         .trim(Trim::All)
         .from_reader(reader);
 
-    // Deserailize, skipping any errors.
-    // TODO: Add logging when encountering errors.
-    rdr.into_deserialize()
-        .filter_map(|elem| elem.ok())
-        .filter_map(|rec: Record| rec.try_into().ok())
+    let headers = rdr
+        .headers()
+        .map_err(|source| ParseError::MalformedField { line: 1, source })?;
+    validate_header(headers)?;
+
+    let mut row = StringRecord::new();
+    Ok(std::iter::from_fn(move || {
+        let malformed = |source: csv::Error| ParseError::MalformedField {
+            line: source.position().map_or(0, |pos| pos.line()),
+            source,
+        };
+
+        match rdr.read_record(&mut row) {
+            Ok(false) => None,
+            Err(e) => Some(Err(malformed(e))),
+            Ok(true) => {
+                let line = row.position().map_or(0, |pos| pos.line());
+                let headers = match rdr.headers() {
+                    Ok(headers) => headers,
+                    Err(e) => return Some(Err(malformed(e))),
+                };
+                Some(parse_row(&mut row, headers, line, &options))
+            }
+        }
+    }))
+}
+
+// Helper function that turns a row read from a transaction log into a transaction.
+fn parse_row(
+    row: &mut StringRecord,
+    headers: &StringRecord,
+    line: u64,
+    options: &ParseOptions,
+) -> Result<Transaction, ParseError> {
+    // Extra fields are most likely part of an amount with an unquoted separator, so must not be
+    // silently dropped.
+    if row.len() > headers.len() {
+        let reason = TxError::TooManyFields;
+        return Err(ParseError::InvalidRecord { line, reason });
+    }
+
+    let currency = if options.currency_symbols {
+        strip_currency_symbol(row, headers)
+    } else {
+        None
+    };
+    let record = row
+        .deserialize::<Record>(Some(headers))
+        .map_err(|source| ParseError::MalformedField { line, source })?;
+    to_transaction(record, line, currency, options)
+        .map_err(|reason| ParseError::InvalidRecord { line, reason })
+}
+
+// Helper function that turns a record into a transaction, applying the parse options.
+fn to_transaction(
+    mut rec: Record,
+    line: u64,
+    currency: Option<Currency>,
+    options: &ParseOptions,
+) -> Result<Transaction, TxError> {
+    let name = rec._type.to_ascii_lowercase();
+    if let Some(alias_for) = options.type_aliases.get(&name) {
+        rec._type.clone_from(alias_for);
+    }
+    let has_amount = rec.amount.is_some();
+    let tx = Transaction::try_from(rec)?;
+    let unexpected = tx.op.amount().is_none() || tx.op.references_tx();
+    if options.reject_unexpected_amounts && has_amount && unexpected {
+        let name = tx.op.name().to_string();
+        return Err(TxError::UnexpectedAmount(name));
+    }
+    let op = match options.scale {
+        Some(scale) => limit_precision(tx.op, scale, options.precision_policy)?,
+        None => tx.op,
+    };
+    Ok(Transaction {
+        op,
+        line,
+        currency,
+        ..tx
+    })
+}
+
+/// Parse a single transaction, given either as a row without a header, with the columns in the
+/// usual order (type, client, tx, amount), or as a JSON object with the same fields, whose amount
+/// can be a string or a number. `line` is where the transaction came from, for errors.
+pub fn parse_line(
+    text: &str,
+    line: u64,
+    options: &ParseOptions,
+) -> Result<Transaction, ParseError> {
+    if text.trim_start().starts_with('{') {
+        let malformed = |source| ParseError::MalformedJson { line, source };
+        let mut value: serde_json::Value = serde_json::from_str(text).map_err(malformed)?;
+        // Numbers are turned back into text, so the amount is checked like any other.
+        let amount = value
+            .get("amount")
+            .and_then(serde_json::Value::as_number)
+            .map(|amount| amount.to_string());
+        if let Some(amount) = amount {
+            value["amount"] = serde_json::Value::String(amount);
+        }
+        let record = Record::deserialize(value).map_err(malformed)?;
+        return to_transaction(record, line, None, options)
+            .map_err(|reason| ParseError::InvalidRecord { line, reason });
+    }
+
+    let headers = StringRecord::from(EXPECTED_COLUMNS.to_vec());
+    let mut row = StringRecord::new();
+    ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(text.as_bytes())
+        .read_record(&mut row)
+        .map_err(|source| ParseError::MalformedField { line, source })?;
+    parse_row(&mut row, &headers, line, options)
+}
+
+// Helper function that removes a currency symbol from the start of a row's amount, returning the
+// currency it indicates.
+fn strip_currency_symbol(row: &mut StringRecord, headers: &StringRecord) -> Option<Currency> {
+    let index = headers.iter().position(|header| header == "amount")?;
+    let symbol = row.get(index)?.chars().next()?;
+    let currency = Currency::from_symbol(symbol)?;
+
+    let stripped: StringRecord = row
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            if i == index {
+                &field[symbol.len_utf8()..]
+            } else {
+                field
+            }
+        })
+        .collect();
+    let position = row.position().cloned();
+    *row = stripped;
+    if let Some(position) = position {
+        row.set_position(Some(position));
+    }
+    Some(currency)
+}
+
+/// Writes transactions as a transaction log in canonical form, with a fixed column order, no
+/// padding and amounts without trailing zeros, so it can be read back by `iter_over_reader`.
+pub struct NormalizedWriter<W: Write> {
+    writer: Writer<W>,
+}
+
+impl<W: Write> NormalizedWriter<W> {
+    pub fn new(writer: W) -> Self {
+        NormalizedWriter {
+            writer: Writer::from_writer(writer),
+        }
+    }
+
+    pub fn write(&mut self, tx: &Transaction) -> Result<(), csv::Error> {
+        self.writer.serialize(Record::from(tx))
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// The format a ledger is written in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LedgerFormat {
+    /// CSV, with a header.
+    #[default]
+    Csv,
+    /// A JSON object per line.
+    Ndjson,
+}
+
+/// A transaction that was applied, numbered in the order it was applied.
+#[derive(Serialize)]
+struct LedgerEntry {
+    seq: u64,
+    #[serde(rename = "type")]
+    op: &'static str,
+    client: u16,
+    tx: u32,
+    amount: Option<String>,
+}
+
+enum LedgerOutput<W: Write> {
+    Csv(Box<Writer<W>>),
+    Ndjson(W),
+}
+
+/// Writes a ledger of the transactions that were applied, in the order they were applied, each
+/// with a sequence number. Its columns are those of a transaction log, so it can be replayed (with
+/// the same configuration) to reproduce the same balances. Skipped records and currencies aren't
+/// written, so a replay differs when a deposit hold or dispute window counts the skipped records.
+pub struct LedgerWriter<W: Write> {
+    output: LedgerOutput<W>,
+    seq: u64,
+}
+
+impl<W: Write> LedgerWriter<W> {
+    pub fn new(writer: W, format: LedgerFormat) -> Self {
+        let output = match format {
+            LedgerFormat::Csv => LedgerOutput::Csv(Box::new(Writer::from_writer(writer))),
+            LedgerFormat::Ndjson => LedgerOutput::Ndjson(writer),
+        };
+        LedgerWriter { output, seq: 0 }
+    }
+
+    pub fn write(&mut self, tx: &Transaction) -> std::io::Result<()> {
+        self.seq += 1;
+        let entry = LedgerEntry {
+            seq: self.seq,
+            op: tx.op.name(),
+            client: tx.client,
+            tx: tx.id,
+            amount: Record::from(tx).amount,
+        };
+        match &mut self.output {
+            LedgerOutput::Csv(writer) => Ok(writer.serialize(entry)?),
+            LedgerOutput::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, &entry)?;
+                writer.write_all(b"\n")
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.output {
+            LedgerOutput::Csv(writer) => writer.flush(),
+            LedgerOutput::Ndjson(writer) => writer.flush(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use rust_decimal_macros::dec;
+    use std::io::Cursor;
 
     #[test]
     fn test_mainline_parsing() {
@@ -107,11 +907,12 @@ resolve, 1, 1
 chargeback, 1, 1
 ";
 
-        let mut it = iter_over_reader(input.as_bytes());
+        let mut it = iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
 
-        let tx = it.next().unwrap();
+        let tx = it.next().unwrap().unwrap();
         assert_eq!(tx.id, 1);
         assert_eq!(tx.client, 1);
+        assert_eq!(tx.line, 2);
         match tx.op {
             Operation::Deposit(val) => {
                 assert_eq!(val, dec!(2.0))
@@ -119,7 +920,7 @@ chargeback, 1, 1
             _ => panic!(),
         };
 
-        let tx = it.next().unwrap();
+        let tx = it.next().unwrap().unwrap();
         assert_eq!(tx.id, 2);
         assert_eq!(tx.client, 1);
         match tx.op {
@@ -129,17 +930,17 @@ chargeback, 1, 1
             _ => panic!(),
         };
 
-        let tx = it.next().unwrap();
+        let tx = it.next().unwrap().unwrap();
         assert_eq!(tx.id, 1);
         assert_eq!(tx.client, 1);
-        assert!(matches!(tx.op, Operation::Dispute));
+        assert!(matches!(tx.op, Operation::Dispute(None)));
 
-        let tx = it.next().unwrap();
+        let tx = it.next().unwrap().unwrap();
         assert_eq!(tx.id, 1);
         assert_eq!(tx.client, 1);
         assert!(matches!(tx.op, Operation::Resolve));
 
-        let tx = it.next().unwrap();
+        let tx = it.next().unwrap().unwrap();
         assert_eq!(tx.id, 1);
         assert_eq!(tx.client, 1);
         assert!(matches!(tx.op, Operation::Chargeback));
@@ -147,13 +948,34 @@ chargeback, 1, 1
         assert!(it.next().is_none());
     }
 
+    #[test]
+    fn test_partial_dispute_parsing() {
+        let input = r"type, client, tx, amount
+dispute, 1, 1, 0.5
+";
+
+        let mut it = iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+        let tx = it.next().unwrap().unwrap();
+        match tx.op {
+            Operation::Dispute(Some(val)) => assert_eq!(val, dec!(0.5)),
+            _ => panic!(),
+        };
+    }
+
     #[test]
     fn test_bad_type() {
         let input = r"type, client, tx, amount
 incorrect, 1, 1, 2.0
 ";
 
-        let mut it = iter_over_reader(input.as_bytes());
+        let mut it = iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+        match it.next().unwrap() {
+            Err(ParseError::InvalidRecord { line, reason }) => {
+                assert_eq!(line, 2);
+                assert_eq!(reason, TxError::UnknownType("incorrect".to_string()));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
         assert!(it.next().is_none());
     }
 
@@ -163,7 +985,14 @@ incorrect, 1, 1, 2.0
 deposit, 1, 1
 ";
 
-        let mut it = iter_over_reader(input.as_bytes());
+        let mut it = iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+        assert!(matches!(
+            it.next().unwrap(),
+            Err(ParseError::InvalidRecord {
+                reason: TxError::MissingAmount,
+                ..
+            })
+        ));
         assert!(it.next().is_none());
     }
 
@@ -173,7 +1002,14 @@ deposit, 1, 1
 withdrawal, 1, 1
 ";
 
-        let mut it = iter_over_reader(input.as_bytes());
+        let mut it = iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+        assert!(matches!(
+            it.next().unwrap(),
+            Err(ParseError::InvalidRecord {
+                reason: TxError::MissingAmount,
+                ..
+            })
+        ));
         assert!(it.next().is_none());
     }
 
@@ -185,7 +1021,9 @@ withdrawal, 1, 1, 1.0
 withdrawal, 1, 1
 ";
 
-        let mut it = iter_over_reader(input.as_bytes());
+        let mut it = iter_over_reader(input.as_bytes(), ParseOptions::default())
+            .unwrap()
+            .filter_map(Result::ok);
         let tx = it.next().unwrap();
         assert_eq!(tx.id, 1);
         assert_eq!(tx.client, 1);
@@ -198,4 +1036,545 @@ withdrawal, 1, 1
 
         assert!(it.next().is_none());
     }
+
+    #[test]
+    fn test_out_of_range_client() {
+        let input = r"type, client, tx, amount
+deposit, 999999, 1, 1.0
+deposit, 1, 2, 1.0
+";
+
+        let mut it = iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+        match it.next().unwrap() {
+            Err(ParseError::MalformedField { line, .. }) => assert_eq!(line, 2),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Subsequent rows are unaffected.
+        let tx = it.next().unwrap().unwrap();
+        assert_eq!(tx.id, 2);
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn test_normalized_round_trip() {
+        let input = r"type,   client, tx, amount
+deposit,    1, 1, 2.5000
+withdrawal, 1, 2, 1.0
+dispute,    1, 1, 0.50
+resolve,    1, 1,
+chargeback, 1, 1,
+";
+
+        let mut buf = Vec::new();
+        let mut writer = NormalizedWriter::new(&mut buf);
+        for tx in iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap() {
+            writer.write(&tx.unwrap()).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "type,client,tx,amount
+deposit,1,1,2.5
+withdrawal,1,2,1
+dispute,1,1,0.5
+resolve,1,1,
+chargeback,1,1,
+"
+        );
+    }
+
+    #[test]
+    fn test_types_are_case_insensitive() {
+        let input = r"type, client, tx, amount
+Deposit, 1, 1, 5.0
+WITHDRAWAL, 1, 2, 2.0
+Dispute, 1, 1,
+";
+        let mut it = iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+        let tx = it.next().unwrap().unwrap();
+        assert!(matches!(tx.op, Operation::Deposit(amount) if amount == dec!(5.0)));
+        let tx = it.next().unwrap().unwrap();
+        assert!(matches!(tx.op, Operation::Withdrawal(amount) if amount == dec!(2.0)));
+        let tx = it.next().unwrap().unwrap();
+        assert!(matches!(tx.op, Operation::Dispute(None)));
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn test_type_aliases() {
+        let input = r"type, client, tx, amount
+credit, 1, 1, 5.0
+DEBIT, 1, 2, 2.0
+deposit, 1, 3, 1.0
+refund, 1, 4, 1.0
+";
+        let options = ParseOptions {
+            type_aliases: HashMap::from([
+                ("credit".to_string(), "deposit".to_string()),
+                ("debit".to_string(), "withdrawal".to_string()),
+                ("refund".to_string(), "reversal".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let mut it = iter_over_reader(input.as_bytes(), options).unwrap();
+
+        let tx = it.next().unwrap().unwrap();
+        assert!(matches!(tx.op, Operation::Deposit(amount) if amount == dec!(5.0)));
+        let tx = it.next().unwrap().unwrap();
+        assert!(matches!(tx.op, Operation::Withdrawal(amount) if amount == dec!(2.0)));
+        // The usual names still work.
+        let tx = it.next().unwrap().unwrap();
+        assert!(matches!(tx.op, Operation::Deposit(_)));
+        // An alias for a type that doesn't exist is still an unknown type.
+        assert!(matches!(
+            it.next(),
+            Some(Err(ParseError::InvalidRecord {
+                line: 5,
+                reason: TxError::UnknownType(_)
+            }))
+        ));
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn test_currency_symbols() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, $100.00
+deposit, 1, 2, €50
+withdrawal, 1, 3, 25
+";
+        let options = ParseOptions {
+            currency_symbols: true,
+            ..Default::default()
+        };
+        let txs: Vec<_> = iter_over_reader(input.as_bytes(), options)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+
+        assert!(matches!(txs[0].op, Operation::Deposit(amount) if amount == dec!(100.00)));
+        assert_eq!(txs[0].currency, Some(Currency::Usd));
+        assert_eq!(txs[0].line, 2);
+        assert!(matches!(txs[1].op, Operation::Deposit(amount) if amount == dec!(50)));
+        assert_eq!(txs[1].currency, Some(Currency::Eur));
+        assert_eq!(txs[2].currency, None);
+
+        // Without the option, the symbol makes the amount invalid.
+        let mut it = iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+        assert!(matches!(
+            it.next(),
+            Some(Err(ParseError::InvalidRecord {
+                line: 2,
+                reason: TxError::InvalidAmount(_)
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_strict_amounts() {
+        let input = r#"type, client, tx, amount
+deposit, 1, 1, 1e3
+deposit, 1, 2,"1,000.00"
+deposit, 1, 3, 1,000.00
+deposit, 1, 4, 1.2.3
+deposit, 1, 5, -5.0
+deposit, 1, 6, .
+deposit, 1, 7, 1234.5678
+"#;
+
+        let results: Vec<_> = iter_over_reader(input.as_bytes(), ParseOptions::default())
+            .unwrap()
+            .collect();
+        let reasons: Vec<_> = results[..6]
+            .iter()
+            .map(|result| match result {
+                Err(ParseError::InvalidRecord { reason, .. }) => reason,
+                other => panic!("unexpected result {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                &TxError::InvalidAmount("1e3".to_string()),
+                &TxError::InvalidAmount("1,000.00".to_string()),
+                &TxError::TooManyFields,
+                &TxError::InvalidAmount("1.2.3".to_string()),
+                &TxError::NegativeAmount(dec!(-5.0)),
+                &TxError::InvalidAmount(".".to_string()),
+            ]
+        );
+
+        match &results[6] {
+            Ok(Transaction {
+                op: Operation::Deposit(amount),
+                ..
+            }) => assert_eq!(*amount, dec!(1234.5678)),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let input = r"type, client, tx
+deposit, 1, 1";
+        let err = iter_over_reader(input.as_bytes(), ParseOptions::default()).err();
+        assert!(matches!(
+            err,
+            Some(ParseError::InvalidHeader { missing, unexpected })
+                if missing == ["amount"] && unexpected.is_empty()
+        ));
+
+        let input = r"type, clientt, tx, amount
+deposit, 1, 1, 1.0";
+        let err = iter_over_reader(input.as_bytes(), ParseOptions::default()).err();
+        assert_eq!(
+            err.unwrap().to_string(),
+            "line 1: invalid header: missing column(s) client unexpected column(s) clientt"
+        );
+
+        // The columns can be in any order.
+        let input = r"amount, tx, client, type
+1.0, 7, 2, deposit";
+        let mut it = iter_over_reader(input.as_bytes(), ParseOptions::default()).unwrap();
+        let tx = it.next().unwrap().unwrap();
+        assert_eq!((tx.id, tx.client), (7, 2));
+        assert!(matches!(tx.op, Operation::Deposit(amount) if amount == dec!(1.0)));
+    }
+
+    #[test]
+    fn test_byte_order_mark() {
+        let input = "\u{feff}type, client, tx, amount
+deposit, 1, 1, 1.5
+withdrawal, 1, 2, 0.5";
+        let results: Vec<_> = iter_over_reader(input.as_bytes(), ParseOptions::default())
+            .unwrap()
+            .collect();
+        assert_eq!(results.len(), 2);
+        let deposit = results[0].as_ref().unwrap();
+        assert!(matches!(deposit.op, Operation::Deposit(amount) if amount == dec!(1.5)));
+        assert_eq!(deposit.line, 2);
+    }
+
+    #[test]
+    fn test_precision_policy() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, 1.123456
+withdrawal, 1, 2, 0.000050
+deposit, 1, 3, 2.500000
+dispute, 1, 1,";
+        let parse = |policy| {
+            let options = ParseOptions {
+                scale: Some(4),
+                precision_policy: policy,
+                ..Default::default()
+            };
+            iter_over_reader(input.as_bytes(), options)
+                .unwrap()
+                .collect::<Vec<_>>()
+        };
+
+        let rejected = parse(PrecisionPolicy::Reject);
+        assert!(matches!(
+            &rejected[0],
+            Err(ParseError::InvalidRecord { line: 2, reason: TxError::TooPrecise { amount, scale: 4 } })
+                if *amount == dec!(1.123456)
+        ));
+        assert!(rejected[1].is_err());
+        // Trailing zeros aren't significant.
+        assert!(
+            matches!(rejected[2].as_ref().unwrap().op, Operation::Deposit(amount) if amount == dec!(2.5))
+        );
+        assert!(rejected[3].is_ok());
+
+        let rounded = parse(PrecisionPolicy::Round);
+        assert!(
+            matches!(rounded[0].as_ref().unwrap().op, Operation::Deposit(amount) if amount == dec!(1.1235))
+        );
+        // Halfway cases round to even.
+        assert!(
+            matches!(rounded[1].as_ref().unwrap().op, Operation::Withdrawal(amount) if amount == dec!(0.0000))
+        );
+        assert!(rounded.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn test_timestamp_column() {
+        let input = r"type, client, tx, amount, timestamp
+deposit, 1, 1, 1.0, 1700000000
+withdrawal, 1, 2, 1.0,
+deposit, 1, 3, 1.0, soon";
+        let results: Vec<_> = iter_over_reader(input.as_bytes(), ParseOptions::default())
+            .unwrap()
+            .collect();
+        assert_eq!(results[0].as_ref().unwrap().timestamp, Some(1700000000));
+        assert_eq!(results[1].as_ref().unwrap().timestamp, None);
+        assert!(matches!(
+            results[2],
+            Err(ParseError::MalformedField { line: 4, .. })
+        ));
+
+        // The timestamp isn't part of the normalized log.
+        let mut writer = NormalizedWriter::new(Vec::new());
+        writer.write(results[0].as_ref().unwrap()).unwrap();
+        let output = String::from_utf8(writer.writer.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "type,client,tx,amount\ndeposit,1,1,1\n");
+    }
+
+    #[test]
+    fn test_reject_unexpected_amounts() {
+        let input = r"type, client, tx, amount
+deposit, 1, 1, 5.0
+dispute, 1, 1,
+dispute, 1, 1, 2.0
+resolve, 1, 1,
+resolve, 1, 1, 2.0
+chargeback, 1, 1, 0";
+        let parse = |reject_unexpected_amounts| {
+            let options = ParseOptions {
+                reject_unexpected_amounts,
+                ..Default::default()
+            };
+            iter_over_reader(input.as_bytes(), options)
+                .unwrap()
+                .collect::<Vec<_>>()
+        };
+
+        // By default the amounts are ignored.
+        let results = parse(false);
+        assert!(results.iter().all(Result::is_ok));
+        assert!(matches!(
+            results[1],
+            Ok(Transaction {
+                op: Operation::Dispute(None),
+                ..
+            })
+        ));
+        assert!(matches!(
+            results[2],
+            Ok(Transaction { op: Operation::Dispute(Some(amount)), .. }) if amount == dec!(2.0)
+        ));
+        assert!(matches!(
+            results[4],
+            Ok(Transaction {
+                op: Operation::Resolve,
+                ..
+            })
+        ));
+
+        // Rows referring to an earlier transaction can't have an amount, even disputes.
+        let results = parse(true);
+        assert!(matches!(
+            results[1],
+            Ok(Transaction {
+                op: Operation::Dispute(None),
+                ..
+            })
+        ));
+        assert!(matches!(
+            &results[2],
+            Err(ParseError::InvalidRecord { line: 4, reason: TxError::UnexpectedAmount(t) })
+                if t == "dispute"
+        ));
+        assert!(results[3].is_ok());
+        assert!(matches!(
+            &results[4],
+            Err(ParseError::InvalidRecord { line: 6, reason: TxError::UnexpectedAmount(t) })
+                if t == "resolve"
+        ));
+        assert_eq!(
+            results[5].as_ref().unwrap_err().to_string(),
+            "line 7: invalid record: Unexpected amount for chargeback"
+        );
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let options = ParseOptions::default();
+        let tx = parse_line(" deposit, 1, 2, 3.5", 7, &options).unwrap();
+        assert_eq!((tx.client, tx.id, tx.line), (1, 2, 7));
+        assert!(matches!(tx.op, Operation::Deposit(amount) if amount == dec!(3.5)));
+        assert!(matches!(
+            parse_line("dispute,1,2", 1, &options).unwrap().op,
+            Operation::Dispute(None)
+        ));
+
+        // Amounts in JSON can be numbers or strings.
+        let tx = parse_line(
+            r#"{"type": "withdrawal", "client": 4, "tx": 5, "amount": 1.25}"#,
+            2,
+            &options,
+        )
+        .unwrap();
+        assert_eq!((tx.client, tx.id), (4, 5));
+        assert!(matches!(tx.op, Operation::Withdrawal(amount) if amount == dec!(1.25)));
+        let tx = parse_line(
+            r#"{"type": "deposit", "client": 4, "tx": 6, "amount": "0.0001"}"#,
+            3,
+            &options,
+        )
+        .unwrap();
+        assert!(matches!(tx.op, Operation::Deposit(amount) if amount == dec!(0.0001)));
+
+        // The parse options apply to both.
+        let options = ParseOptions {
+            reject_unexpected_amounts: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse_line("resolve,1,2,1.0", 4, &options),
+            Err(ParseError::InvalidRecord {
+                line: 4,
+                reason: TxError::UnexpectedAmount(_)
+            })
+        ));
+        assert!(matches!(
+            parse_line(
+                r#"{"type": "resolve", "client": 1, "tx": 2, "amount": 1}"#,
+                5,
+                &options
+            ),
+            Err(ParseError::InvalidRecord {
+                line: 5,
+                reason: TxError::UnexpectedAmount(_)
+            })
+        ));
+
+        assert!(matches!(
+            parse_line("deposit,1,2,3.0,4", 6, &options),
+            Err(ParseError::InvalidRecord {
+                line: 6,
+                reason: TxError::TooManyFields
+            })
+        ));
+        assert!(matches!(
+            parse_line("deposit,x,2,3.0", 7, &options),
+            Err(ParseError::MalformedField { line: 7, .. })
+        ));
+        let e = parse_line(r#"{"type": "deposit", "client": 1"#, 8, &options).unwrap_err();
+        assert!(matches!(e, ParseError::MalformedJson { line: 8, .. }));
+        assert!(e.to_string().starts_with("line 8: malformed JSON: "));
+    }
+
+    #[test]
+    fn test_display() {
+        let display = |op| Transaction::new(5, 1, op).to_string();
+        assert_eq!(
+            display(Operation::Deposit(dec!(2))),
+            "deposit client=1 tx=5 amount=2.0000"
+        );
+        assert_eq!(
+            display(Operation::Withdrawal(dec!(1.23456))),
+            "withdrawal client=1 tx=5 amount=1.2346"
+        );
+        assert_eq!(display(Operation::Dispute(None)), "dispute client=1 tx=5");
+        assert_eq!(
+            display(Operation::Dispute(Some(dec!(0.5)))),
+            "dispute client=1 tx=5 amount=0.5000"
+        );
+        assert_eq!(display(Operation::Resolve), "resolve client=1 tx=5");
+        assert_eq!(display(Operation::Chargeback), "chargeback client=1 tx=5");
+        assert_eq!(display(Operation::Close), "close client=1 tx=5");
+        assert_eq!(
+            display(Operation::OpeningBalance(dec!(100))),
+            "opening_balance client=1 tx=5 amount=100.0000"
+        );
+        assert_eq!(
+            display(Operation::Reserve(dec!(3.25))),
+            "reserve client=1 tx=5 amount=3.2500"
+        );
+        assert_eq!(display(Operation::Commit), "commit client=1 tx=5");
+        assert_eq!(display(Operation::Release), "release client=1 tx=5");
+
+        assert_eq!(
+            Operation::Withdrawal(dec!(1.5)).to_string(),
+            "withdrawal amount=1.5000"
+        );
+        assert_eq!(Operation::Resolve.to_string(), "resolve");
+    }
+
+    #[test]
+    fn test_iter_over_dir() {
+        let dir = std::env::temp_dir().join(format!("txproc-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+        write(
+            "2024-01-01.csv",
+            "type,client,tx,amount\ndeposit,1,1,10.0\n",
+        );
+        write("2024-01-02.csv", "type,client,tx,amount\ndispute,1,1,\n");
+        write("2024-01-03.CSV", "type,client,tx,amount\nchargeback,1,1,\n");
+        write("notes.txt", "not a transaction log");
+
+        let read = |order| {
+            iter_over_dir(dir.to_str().unwrap(), ParseOptions::default(), order)
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        };
+        let txs = read(FileOrder::Ascending);
+        assert_eq!(txs.len(), 3);
+        assert!(matches!(txs[0].op, Operation::Deposit(_)));
+        assert!(matches!(txs[2].op, Operation::Chargeback));
+        // Each file's header takes up a line.
+        let lines: Vec<_> = txs.iter().map(|tx| tx.line).collect();
+        assert_eq!(lines, [2, 4, 6]);
+
+        let txs = read(FileOrder::Descending);
+        assert!(matches!(txs[0].op, Operation::Chargeback));
+        assert!(matches!(txs[2].op, Operation::Deposit(_)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_in_background() {
+        // Enough rows for several batches, some of them malformed.
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=5000 {
+            if tx % 777 == 0 {
+                input.push_str("deposit,x,1,1.0\n");
+            } else {
+                input.push_str(&format!("deposit,{},{},{}.5\n", tx % 7, tx, tx));
+            }
+        }
+        let describe = |result: Result<Transaction, ParseError>| match result {
+            Ok(tx) => format!("{} on line {}", tx, tx.line),
+            Err(e) => e.to_string(),
+        };
+        let parse =
+            || iter_over_reader(Cursor::new(input.clone()), ParseOptions::default()).unwrap();
+
+        let expected: Vec<_> = parse().map(describe).collect();
+        let pipelined: Vec<_> = parse_in_background(parse()).map(describe).collect();
+        assert_eq!(pipelined.len(), 5000);
+        assert_eq!(pipelined, expected);
+
+        // Stopping early leaves the parsing thread to finish by itself.
+        assert_eq!(parse_in_background(parse()).take(3).count(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "parsing failed")]
+    fn test_parse_in_background_panic() {
+        let txs = (1..3).map(|id| {
+            assert!(id < 2, "parsing failed");
+            Ok(Transaction::new(id, 1, Operation::Resolve))
+        });
+        assert_eq!(parse_in_background(txs).count(), 1);
+    }
+
+    #[test]
+    fn test_counting_reader() {
+        let input = "type,client,tx,amount\ndeposit,1,1,2.0\n";
+        let reader = CountingReader::new(input.as_bytes());
+        let bytes_read = reader.bytes_read();
+        let txs: Vec<_> = iter_over_reader(reader, ParseOptions::default())
+            .unwrap()
+            .collect();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(bytes_read.load(Ordering::Relaxed), input.len() as u64);
+    }
 }
@@ -0,0 +1,113 @@
+//! Running the binary, as a shell pipeline would.
+
+use std::process::{Command, Stdio};
+
+// Write a log of deposits for `clients` clients to a temporary file named after `name`.
+fn write_log(name: &str, clients: u16) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}.csv", name, std::process::id()));
+    let mut log = String::from("type,client,tx,amount\n");
+    for client in 0..clients {
+        log.push_str(&format!("deposit,{},{},1.5\n", client, client));
+    }
+    std::fs::write(&path, log).unwrap();
+    path
+}
+
+#[test]
+fn test_output_to_stdout() {
+    let log = write_log("cli-stdout", 1000);
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction-processor"))
+        .arg(&log)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&log).unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 1001);
+    assert_eq!(lines[1], "0,1.5000,0.0000,1.5000,false");
+    assert_eq!(lines[1000], "999,1.5000,0.0000,1.5000,false");
+}
+
+#[test]
+fn test_output_to_closed_pipe() {
+    let log = write_log("cli-closed-pipe", 1000);
+    // Close the reading end before the process starts, as `head` does once it has read enough.
+    let (reader, writer) = std::io::pipe().unwrap();
+    drop(reader);
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction-processor"))
+        .arg(&log)
+        .stdout(Stdio::from(writer))
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    std::fs::remove_file(&log).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn test_two_pass_stats() {
+    let path = std::env::temp_dir().join(format!("cli-two-pass-{}.csv", std::process::id()));
+    let mut log = String::from("type,client,tx,amount\n");
+    for tx in 1..=100 {
+        log.push_str(&format!("deposit,1,{},1.0\n", tx));
+    }
+    log.push_str("dispute,1,7,\ndispute,1,42,\n");
+    std::fs::write(&path, log).unwrap();
+    let stats = |two_pass: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_transaction-processor"));
+        command.arg(&path).arg("--stats");
+        if two_pass {
+            command.arg("--two-pass");
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        (output.stdout, String::from_utf8(output.stderr).unwrap())
+    };
+    let (single_output, single_stats) = stats(false);
+    let (two_pass_output, two_pass_stats) = stats(true);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(two_pass_output, single_output);
+    // Only the disputed deposits are kept, but every ID is still seen.
+    assert!(single_stats.contains("Deposits kept: 100\nTransaction IDs seen: 100\n"));
+    assert!(two_pass_stats.contains("Deposits kept: 2\nTransaction IDs seen: 100\n"));
+}
+
+#[test]
+fn test_pipeline_output_is_identical() {
+    // Enough rows for several batches, with malformed rows and disputes among them.
+    let path = std::env::temp_dir().join(format!("cli-pipeline-{}.csv", std::process::id()));
+    let mut log = String::from("type,client,tx,amount\n");
+    for tx in 1..=5000 {
+        if tx % 777 == 0 {
+            log.push_str("deposit,x,1,1.0\n");
+        } else if tx % 13 == 0 {
+            log.push_str(&format!("dispute,{},{},\n", (tx - 7) % 7, tx - 7));
+        } else {
+            log.push_str(&format!("deposit,{},{},{}.5\n", tx % 7, tx, tx));
+        }
+    }
+    std::fs::write(&path, log).unwrap();
+    let run = |pipeline: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_transaction-processor"));
+        command.arg(&path).arg("--stats").env("RUST_LOG", "off");
+        if pipeline {
+            command.arg("--pipeline");
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        (output.stdout, output.stderr)
+    };
+    let (output, stats) = run(false);
+    let (pipelined_output, pipelined_stats) = run(true);
+    std::fs::remove_file(&path).unwrap();
+
+    // A header, then a row for each of the seven clients.
+    assert_eq!(output.iter().filter(|&&byte| byte == b'\n').count(), 8);
+    assert_eq!(pipelined_output, output);
+    assert_eq!(pipelined_stats, stats);
+}
@@ -0,0 +1,491 @@
+//! Processing transactions through the library's public API, as a user of the crate would.
+
+mod support;
+
+use rust_decimal_macros::dec;
+use std::io::Cursor;
+use support::LogSpec;
+use transaction_processor::engine::{
+    display_accounts, process_transactions, process_transactions_with, read_initial_accounts,
+    referenced_tx_ids, write_account_detail, ApplyOutcome, DisplayOptions, EngineState,
+    InitialAccountsError, Processor,
+};
+use transaction_processor::stats::ErrorCategory;
+use transaction_processor::transaction::{
+    iter_over_dir, iter_over_reader, FileOrder, Operation, ParseOptions, Transaction,
+};
+
+#[test]
+fn test_process_in_memory_transactions() {
+    let txs = vec![
+        Transaction::new(1, 1, Operation::Deposit(dec!(10.0))),
+        Transaction::new(2, 2, Operation::Deposit(dec!(5.0))),
+        Transaction::new(3, 1, Operation::Withdrawal(dec!(2.5))),
+        Transaction::new(2, 2, Operation::Dispute(None)),
+        Transaction::new(2, 2, Operation::Chargeback),
+        // More than client 1 has left, so skipped.
+        Transaction::new(4, 1, Operation::Withdrawal(dec!(100.0))),
+    ];
+    let processor =
+        process_transactions(txs.into_iter().map(Ok), Processor::builder().build()).unwrap();
+
+    let accounts = processor.accounts();
+    assert_eq!(accounts.len(), 2);
+    assert_eq!(accounts[&1].available(), dec!(7.5));
+    assert_eq!(accounts[&1].held(), dec!(0));
+    assert!(!accounts[&1].is_locked());
+    assert_eq!(accounts[&2].total_balance(), dec!(0));
+    assert!(accounts[&2].is_locked());
+
+    assert_eq!(processor.stats().total_skipped(), 1);
+    assert!(processor.verify_conservation().is_ok());
+}
+
+#[test]
+fn test_process_one_transaction_at_a_time() {
+    let mut processor = Processor::builder().dispute_withdrawals(true).build();
+    let txs = [
+        Transaction::new(1, 7, Operation::Deposit(dec!(3.0))),
+        Transaction::new(2, 7, Operation::Withdrawal(dec!(1.0))),
+        Transaction::new(2, 7, Operation::Dispute(None)),
+    ];
+    for tx in &txs {
+        processor.process(tx).unwrap();
+    }
+    processor.finish().unwrap();
+
+    let accounts = processor.into_accounts();
+    assert_eq!(accounts[&7].available(), dec!(2.0));
+    assert_eq!(
+        accounts[&7].open_disputes().collect::<Vec<_>>(),
+        [(2, dec!(1.0))]
+    );
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_account_builder() {
+    use transaction_processor::account::AccountBuilder;
+
+    let account = AccountBuilder::new(1)
+        .available(dec!(4.0))
+        .held(dec!(1.0))
+        .dispute(9, dec!(1.0))
+        .build();
+    assert_eq!(account.total_balance(), dec!(5.0));
+    assert!(account.is_disputed(9));
+    assert!(account.check_invariants().is_empty());
+}
+
+#[test]
+fn test_process_directory() {
+    let dir = std::env::temp_dir().join(format!("txproc-logs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let process = |order| {
+        let txs = iter_over_dir(dir.to_str().unwrap(), ParseOptions::default(), order).unwrap();
+        process_transactions(txs, Processor::builder().build()).unwrap()
+    };
+
+    // An empty directory has no transactions, so no accounts.
+    assert!(process(FileOrder::Ascending).accounts().is_empty());
+
+    let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+    write("01.csv", "type,client,tx,amount\ndeposit,1,1,10.0\n");
+    write("02.csv", "type,client,tx,amount\ndispute,1,1,\n");
+    write("03.csv", "type,client,tx,amount\nchargeback,1,1,\n");
+    write("README", "type,client,tx,amount\nwithdrawal,1,2,5.0\n");
+
+    // Oldest first, the deposit is disputed then charged back.
+    let processor = process(FileOrder::Ascending);
+    let account = &processor.accounts()[&1];
+    assert_eq!(account.total_balance(), dec!(0));
+    assert!(account.is_locked());
+    assert_eq!(processor.stats().total_skipped(), 0);
+
+    // Newest first, the chargeback and dispute come before the deposit they refer to.
+    let processor = process(FileOrder::Descending);
+    let account = &processor.accounts()[&1];
+    assert_eq!(account.available(), dec!(10.0));
+    assert!(!account.is_locked());
+    assert_eq!(processor.stats().total_skipped(), 2);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_query_accounts() {
+    let log = "\
+type,client,tx,amount
+deposit,2,1,10.0
+deposit,1,2,4.0
+dispute,2,1,
+withdrawal,3,3,1.0
+withdrawal,1,4,1.0
+";
+    let processor = process_transactions(
+        iter_over_reader(log.as_bytes(), ParseOptions::default()).unwrap(),
+        Processor::builder().build(),
+    )
+    .unwrap();
+
+    assert_eq!(processor.account(2).unwrap().held(), dec!(10.0));
+    assert!(processor.account(4).is_none());
+    // Client 3's only transaction failed, but it still has an account, with nothing in it.
+    let account = processor.account(3).unwrap();
+    assert_eq!(account.total_balance(), dec!(0));
+    assert!(!account.is_locked());
+    assert_eq!(
+        processor
+            .accounts_iter()
+            .map(|account| account.client())
+            .collect::<Vec<_>>(),
+        [1, 2, 3]
+    );
+
+    let disputed = processor.deposit(1).unwrap().unwrap();
+    assert_eq!(disputed.seen.client, 2);
+    assert_eq!(disputed.seen.line, 2);
+    assert!(disputed.disputed);
+    assert!(!processor.deposit(2).unwrap().unwrap().disputed);
+    // The failed withdrawal was seen, but isn't kept to be disputed.
+    let failed = processor.deposit(3).unwrap().unwrap();
+    assert!(failed.disputable.is_none());
+    assert!(processor.deposit(99).unwrap().is_none());
+
+    let mut detail = Vec::new();
+    write_account_detail(processor.account(2).unwrap(), &mut detail).unwrap();
+    assert_eq!(
+        String::from_utf8(detail).unwrap(),
+        "Client 2 (open): available 0.0, held 10.0, total 10.0\n  dispute of 1 holding 10.0\n"
+    );
+}
+
+#[test]
+fn test_output_is_deterministic() {
+    let mut log = String::from("type,client,tx,amount\n");
+    for tx in 1..=2000u32 {
+        log.push_str(&format!(
+            "deposit,{},{},{}.5\n",
+            tx * 7919 % 1000,
+            tx,
+            tx % 10
+        ));
+    }
+    let output = || {
+        let processor = process_transactions(
+            iter_over_reader(log.as_bytes(), ParseOptions::default()).unwrap(),
+            Processor::builder().build(),
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        display_accounts(
+            processor.accounts().values(),
+            DisplayOptions::default(),
+            &mut output,
+        )
+        .unwrap();
+        output
+    };
+
+    // Each run's accounts are kept in a hash map of its own, iterated in a different order.
+    let first = output();
+    for _ in 0..5 {
+        assert_eq!(output(), first);
+    }
+    let clients: Vec<u16> = String::from_utf8(first)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(clients.len(), 1000);
+    assert!(clients.is_sorted());
+}
+
+#[test]
+fn test_stats() {
+    let log = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+withdrawal,1,4,100.0
+bogus,1,5,1.0
+deposit,x,6,1.0
+dispute,2,2,
+chargeback,2,2,
+deposit,2,7,1.0
+dispute,3,99,
+";
+    let processor = process_transactions(
+        iter_over_reader(log.as_bytes(), ParseOptions::default()).unwrap(),
+        Processor::builder().build(),
+    )
+    .unwrap();
+
+    let stats = processor.stats();
+    assert_eq!(stats.records(), 10);
+    assert_eq!(stats.parse_failures(), 2);
+    assert_eq!(
+        stats.applied().collect::<Vec<_>>(),
+        [
+            ("chargeback", 1),
+            ("deposit", 2),
+            ("dispute", 1),
+            ("withdrawal", 1)
+        ]
+    );
+    assert_eq!(
+        stats.rejected().collect::<Vec<_>>(),
+        [
+            ("deposit", ErrorCategory::Locked, 1),
+            ("dispute", ErrorCategory::UnknownDispute, 1),
+            ("withdrawal", ErrorCategory::InsufficientFunds, 1),
+        ]
+    );
+    // Client 3's account is kept, but nothing was ever applied to it.
+    assert_eq!(stats.accounts_created(), 2);
+    assert_eq!(processor.accounts().len(), 3);
+    assert_eq!(stats.accounts_locked(), 1);
+}
+
+#[test]
+fn test_two_pass() {
+    // Lots of deposits, a handful of which are disputed.
+    let mut log = String::from("type,client,tx,amount\n");
+    for tx in 1..=10_000 {
+        log.push_str(&format!("deposit,{},{},{}.25\n", tx % 50, tx, tx % 10));
+        if tx % 2500 == 0 {
+            log.push_str(&format!("dispute,{},{},\n", tx % 50, tx / 2));
+        }
+    }
+    log.push_str("chargeback,0,5000,\nresolve,0,1250,\nwithdrawal,1,20000,1.0\n");
+    let read = || iter_over_reader(log.as_bytes(), ParseOptions::default()).unwrap();
+
+    let one_pass = process_transactions(read(), Processor::builder().build()).unwrap();
+    let referenced = referenced_tx_ids(read());
+    assert_eq!(referenced.len(), 4);
+    let two_pass = process_transactions(
+        read(),
+        Processor::builder()
+            .build()
+            .keep_only_referenced(referenced),
+    )
+    .unwrap();
+
+    assert_eq!(two_pass.accounts(), one_pass.accounts());
+    assert_eq!(
+        two_pass.stats().total_skipped(),
+        one_pass.stats().total_skipped()
+    );
+    assert_eq!(one_pass.deposits().disputable_count(), 10_000);
+    assert_eq!(two_pass.deposits().disputable_count(), 4);
+}
+
+#[test]
+fn test_resume_from_saved_state() {
+    let log = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,4.0
+withdrawal,1,3,2.5
+dispute,2,2,
+deposit,3,4,1.0
+deposit,1,5,3.0
+resolve,2,2,
+dispute,1,1,
+chargeback,1,1,
+deposit,3,6,2.0
+dispute,3,4,
+";
+    // Yesterday's log, then today's, each with the header.
+    let lines: Vec<_> = log.lines().collect();
+    let yesterday = lines[..6].join("\n");
+    let today = [&lines[..1], &lines[6..]].concat().join("\n");
+    let read = |log: &str| {
+        iter_over_reader(Cursor::new(log.to_string()), ParseOptions::default()).unwrap()
+    };
+    let output = |processor: &Processor| {
+        let mut accounts: Vec<_> = processor.accounts().values().collect();
+        accounts.sort_by_key(|account| account.client());
+        let mut output = Vec::new();
+        display_accounts(accounts, DisplayOptions::default(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+
+    let single_run = process_transactions(read(log), Processor::builder().build()).unwrap();
+
+    let first = process_transactions(read(&yesterday), Processor::builder().build()).unwrap();
+    let mut saved = Vec::new();
+    first.into_state().save(&mut saved).unwrap();
+    let state = EngineState::load(saved.as_slice()).unwrap();
+    let second =
+        process_transactions(read(&today), Processor::builder().build().restore(state)).unwrap();
+
+    // The disputes today of yesterday's deposits go through just the same.
+    assert!(second.accounts()[&1].is_locked());
+    assert_eq!(second.accounts()[&3].held(), dec!(1.0));
+    assert_eq!(output(&second), output(&single_run));
+}
+
+#[test]
+fn test_initial_accounts() {
+    let initial = "client,available,held,locked
+1,10.0,0.0,true
+2,5.0,2.0,false
+";
+    let log = "type,client,tx,amount
+withdrawal,1,1,1.0
+deposit,1,2,1.0
+withdrawal,2,3,1.0
+resolve,2,99,
+";
+    let accounts = read_initial_accounts(initial.as_bytes()).unwrap();
+    let processor = Processor::builder().build().seed_accounts(accounts);
+    let processor = process_transactions(
+        iter_over_reader(Cursor::new(log), ParseOptions::default()).unwrap(),
+        processor,
+    )
+    .unwrap();
+
+    // The locked account stays as it was.
+    let accounts = processor.accounts();
+    assert!(accounts[&1].is_locked());
+    assert_eq!(accounts[&1].available(), dec!(10.0));
+    // The held funds aren't for any dispute known here, so can't be resolved.
+    assert_eq!(accounts[&2].available(), dec!(4.0));
+    assert_eq!(accounts[&2].held(), dec!(2.0));
+    assert!(accounts[&2].check_invariants().is_empty());
+    assert_eq!(processor.stats().total_skipped(), 3);
+    processor.verify_conservation().unwrap();
+
+    let duplicate = "client,available\n1,1.0\n2,1.0\n1,2.0\n";
+    assert!(matches!(
+        read_initial_accounts(duplicate.as_bytes()),
+        Err(InitialAccountsError::DuplicateClient { client: 1, line: 4 })
+    ));
+}
+
+#[test]
+fn test_chain_runs_from_base_output() {
+    let log = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,4.0
+deposit,3,3,2.0
+withdrawal,1,4,2.5
+dispute,2,2,
+deposit,2,6,1.5
+deposit,1,5,3.0
+withdrawal,1,7,1.0
+dispute,1,5,
+dispute,3,3,
+";
+    let lines: Vec<_> = log.lines().collect();
+    let day_one = lines[..6].join("\n");
+    let day_two = [&lines[..1], &lines[6..]].concat().join("\n");
+    let read = |log: &str| {
+        iter_over_reader(Cursor::new(log.to_string()), ParseOptions::default()).unwrap()
+    };
+    let output = |processor: &Processor, options: DisplayOptions| {
+        let mut output = Vec::new();
+        display_accounts(processor.accounts().values(), options, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+
+    let combined = process_transactions(read(log), Processor::builder().build()).unwrap();
+
+    // Day one's output, with its totals row, is day two's base.
+    let first = process_transactions(read(&day_one), Processor::builder().build()).unwrap();
+    let base = output(
+        &first,
+        DisplayOptions {
+            totals: true,
+            ..Default::default()
+        },
+    );
+    let accounts = read_initial_accounts(base.as_bytes()).unwrap();
+    let second = process_transactions(
+        read(&day_two),
+        Processor::builder().build().seed_accounts(accounts),
+    )
+    .unwrap();
+
+    // The accounts without disputes of day one's deposits come out the same. Client 2's deposit
+    // was disputed on day one, so its funds are still held.
+    let combined_output = output(&combined, DisplayOptions::default());
+    let second_output = output(&second, DisplayOptions::default());
+    for client in ["1,", "2,"] {
+        let line = |output: &str| {
+            output
+                .lines()
+                .find(|line| line.starts_with(client))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(line(&second_output), line(&combined_output));
+    }
+
+    // Day one's deposits aren't known on day two, so client 3's dispute of one is skipped.
+    assert_eq!(combined.accounts()[&3].held(), dec!(2.0));
+    assert_eq!(second.accounts()[&3].held(), dec!(0.0));
+    assert_eq!(second.stats().total_skipped(), 1);
+}
+
+#[test]
+fn test_callback_for_each_transaction() {
+    let txs = vec![
+        Transaction::new(1, 1, Operation::Deposit(dec!(10.0))),
+        Transaction::new(2, 1, Operation::Deposit(dec!(5.0))),
+        Transaction::new(3, 2, Operation::Deposit(dec!(1.0))),
+        Transaction::new(4, 2, Operation::Withdrawal(dec!(0.5))),
+        // The ID was already used, so skipped.
+        Transaction::new(1, 2, Operation::Deposit(dec!(3.0))),
+    ];
+    let mut deposits = 0;
+    let mut processed = Vec::new();
+    let processor = process_transactions_with(
+        txs.into_iter().map(Ok),
+        Processor::builder().build(),
+        |tx, outcome| {
+            let applied = matches!(
+                outcome,
+                ApplyOutcome::Applied | ApplyOutcome::AccountCreated
+            );
+            if applied && matches!(tx.op, Operation::Deposit(_)) {
+                deposits += 1;
+            }
+            processed.push(tx.id);
+        },
+    )
+    .unwrap();
+
+    assert_eq!(deposits, 3);
+    assert_eq!(processed, [1, 2, 3, 4, 1]);
+    assert_eq!(processor.accounts()[&2].available(), dec!(0.5));
+}
+
+#[test]
+fn test_generated_log() {
+    let spec = LogSpec {
+        records: 20_000,
+        clients: 50,
+        dispute_ratio: 0.2,
+    };
+    let log = spec.generate();
+    assert_eq!(log, spec.generate());
+
+    let txs = iter_over_reader(log.as_bytes(), ParseOptions::default()).unwrap();
+    let processor = process_transactions(txs, Processor::builder().build()).unwrap();
+    processor.verify_conservation().unwrap();
+    let stats = processor.stats();
+    assert_eq!(stats.records(), 20_000);
+    assert_eq!(stats.parse_failures(), 0);
+    assert_eq!(processor.accounts().len(), 50);
+    assert!(processor
+        .accounts()
+        .values()
+        .any(|account| account.held() > dec!(0)));
+    assert!(!processor
+        .accounts()
+        .values()
+        .any(|account| account.is_locked()));
+}
@@ -0,0 +1,115 @@
+//! Receiving transactions over TCP, as a client of `--listen` would send them.
+#![cfg(feature = "serve")]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use transaction_processor::engine::{DisplayOptions, Processor};
+use transaction_processor::serve::serve;
+use transaction_processor::transaction::ParseOptions;
+
+// Read the accounts sent in reply to a GET, sorted by client.
+fn read_accounts(reader: &mut impl BufRead) -> Vec<String> {
+    let mut accounts = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        accounts.push(line.to_string());
+    }
+    accounts[1..].sort();
+    accounts
+}
+
+// Start a server on a thread of its own, as the processor can't leave the thread it's built on.
+fn start(strict: bool) -> std::net::SocketAddr {
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addr_tx.send(listener.local_addr().unwrap()).unwrap();
+            let processor = Processor::builder().strict(strict).build();
+            serve(
+                listener,
+                processor,
+                ParseOptions::default(),
+                DisplayOptions::default(),
+            )
+            .await
+            .unwrap();
+        });
+    });
+    addr_rx.recv().unwrap()
+}
+
+#[test]
+fn test_serve() {
+    let addr = start(false);
+
+    let mut csv = TcpStream::connect(addr).unwrap();
+    let mut csv_reader = BufReader::new(csv.try_clone().unwrap());
+    csv.write_all(
+        b"type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\n\nwithdrawal,1,3,2.5\nGET\n",
+    )
+    .unwrap();
+    assert_eq!(
+        read_accounts(&mut csv_reader),
+        [
+            "client,available,held,total,locked",
+            "1,7.5000,0.0000,7.5000,false",
+            "2,5.0000,0.0000,5.0000,false",
+        ]
+    );
+
+    // Transactions from another connection apply to the same accounts.
+    let mut json = TcpStream::connect(addr).unwrap();
+    let mut json_reader = BufReader::new(json.try_clone().unwrap());
+    json.write_all(b"{\"type\":\"deposit\",\"client\":3,\"tx\":4,\"amount\":1.25}\n")
+        .unwrap();
+    json.write_all(b"{\"type\":\"dispute\",\"client\":2,\"tx\":2}\nnot a transaction\nget\n")
+        .unwrap();
+    let accounts = read_accounts(&mut json_reader);
+    assert_eq!(
+        accounts,
+        [
+            "client,available,held,total,locked",
+            "1,7.5000,0.0000,7.5000,false",
+            "2,0.0000,5.0000,5.0000,false",
+            "3,1.2500,0.0000,1.2500,false",
+        ]
+    );
+
+    csv.write_all(b"GET\n").unwrap();
+    assert_eq!(read_accounts(&mut csv_reader), accounts);
+}
+
+#[test]
+fn test_serve_error_closes_only_its_connection() {
+    let addr = start(true);
+
+    let mut failing = TcpStream::connect(addr).unwrap();
+    let mut failing_reader = BufReader::new(failing.try_clone().unwrap());
+    failing
+        .write_all(b"deposit,1,1,10.0\nnot a transaction\nGET\n")
+        .unwrap();
+    let mut reply = String::new();
+    failing_reader.read_to_string(&mut reply).unwrap();
+    assert!(reply.starts_with("error: "), "{}", reply);
+    assert_eq!(reply.lines().count(), 1);
+
+    // The server carries on, with the transactions applied before the error.
+    let mut other = TcpStream::connect(addr).unwrap();
+    let mut other_reader = BufReader::new(other.try_clone().unwrap());
+    other.write_all(b"deposit,2,2,5.0\nGET\n").unwrap();
+    assert_eq!(
+        read_accounts(&mut other_reader),
+        [
+            "client,available,held,total,locked",
+            "1,10.0000,0.0000,10.0000,false",
+            "2,5.0000,0.0000,5.0000,false",
+        ]
+    );
+}
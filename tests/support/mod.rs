@@ -0,0 +1,80 @@
+//! Support shared by the integration tests and the benchmarks: a generator of synthetic
+//! transaction logs, as large and as dispute-heavy as needed.
+
+use std::fmt::Write;
+
+/// The shape of a synthetic transaction log.
+#[derive(Debug, Clone, Copy)]
+pub struct LogSpec {
+    /// How many records the log has, after the header.
+    pub records: u32,
+    /// How many clients the records are spread over, with IDs counting up from 0. There can be at
+    /// most 65,536.
+    pub clients: u32,
+    /// Roughly what fraction of the records are disputes, or resolves of earlier disputes.
+    pub dispute_ratio: f64,
+}
+
+impl Default for LogSpec {
+    fn default() -> Self {
+        LogSpec {
+            records: 100_000,
+            clients: 1000,
+            dispute_ratio: 0.1,
+        }
+    }
+}
+
+impl LogSpec {
+    /// Generate the log as CSV, with a header. The same spec always generates the same log.
+    ///
+    /// Every deposit and withdrawal has an ID of its own. A dispute only refers to a client's
+    /// latest undisputed deposit, and a resolve only to a deposit under dispute, so the disputes
+    /// mostly succeed. There are no chargebacks, so no account is locked. A third of the other
+    /// records are withdrawals, some of which fail for lack of funds.
+    pub fn generate(&self) -> String {
+        assert!(
+            (1..=65_536).contains(&self.clients),
+            "there can be 1 to 65,536 clients"
+        );
+        // A xorshift generator is plenty random enough, and needs no dependency.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        // Each client's latest undisputed deposit, and the deposits under dispute, by client.
+        let mut undisputed = vec![None; self.clients as usize];
+        let mut disputed = Vec::new();
+
+        let mut log = String::from("type,client,tx,amount\n");
+        for tx in 1..=self.records {
+            let random = next();
+            let client = (random % u64::from(self.clients)) as usize;
+            let roll = (random >> 32) as f64 / f64::from(u32::MAX);
+            if roll < self.dispute_ratio {
+                if random & 1 == 0 {
+                    if let Some((client, id)) = disputed.pop() {
+                        let _ = writeln!(log, "resolve,{},{},", client, id);
+                        continue;
+                    }
+                }
+                if let Some(id) = undisputed[client].take() {
+                    disputed.push((client, id));
+                    let _ = writeln!(log, "dispute,{},{},", client, id);
+                    continue;
+                }
+            }
+            if random % 3 == 0 {
+                let _ = writeln!(log, "withdrawal,{},{},0.5", client, tx);
+            } else {
+                let (units, fraction) = ((random >> 8) % 100 + 1, (random >> 16) % 10_000);
+                let _ = writeln!(log, "deposit,{},{},{}.{:04}", client, tx, units, fraction);
+                undisputed[client] = Some(tx);
+            }
+        }
+        log
+    }
+}